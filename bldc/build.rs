@@ -13,6 +13,11 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+// Where each bootloader slot is; has to match `protocol::update`. Images only run from the slot
+// they were linked for, so pick one with `PINO_SLOT=a` or `PINO_SLOT=b` (a if unset).
+const SLOTS: [(&str, &str); 2] = [("a", "0x8040000"), ("b", "0x8060000")];
+const SLOT_SIZE: &str = "128K";
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -21,6 +26,20 @@ fn main() {
         .unwrap()
         .write_all(include_bytes!("memory.x"))
         .unwrap();
+
+    // Along with the slot it `INCLUDE`s.
+    let slot = env::var("PINO_SLOT").unwrap_or_else(|_| "a".into());
+    let (_, origin) = SLOTS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&slot))
+        .unwrap_or_else(|| panic!("PINO_SLOT should be a or b, not '{}'", slot));
+    writeln!(
+        File::create(out.join("slot.x")).unwrap(),
+        "FLASH (rx) : ORIGIN = {}, LENGTH = {}",
+        origin,
+        SLOT_SIZE
+    )
+    .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
@@ -28,4 +47,5 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-env-changed=PINO_SLOT");
 }
@@ -8,7 +8,12 @@ MEMORY
   /* The CCMRAM can technically be mapped as an extension to regular ram, but it
      operates a SRAM speeds, *not* CCMRAM (uses SRAM bus). */
   /* CCMRAM (xrw)    : ORIGIN = 0x20018000, LENGTH = 32K  */
-  FLASH (rx)      : ORIGIN = 0x8000000, LENGTH = 512K
+  /* The application runs from one of two slots in bank 2, picked by build.rs; see
+     protocol/src/update.rs for the whole layout. The boot record and config store sit just past the
+     bootloader in bank 1. See config/internal_flash.rs */
+  INCLUDE slot.x
+  BOOT_RECORD (r) : ORIGIN = 0x8008000, LENGTH = 4K
+  CONFIG (r)      : ORIGIN = 0x8009000, LENGTH = 4K
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,62 @@
+use protocol::id::NodeAddress;
+use protocol::update::{Handoff, HANDOFF_WORDS};
+
+use crate::crash;
+
+// The application's end of the handoff with the bootloader; see `protocol::update`. It lives in
+// TAMP's backup registers, which nothing else uses. They sit behind the backup domain's write
+// protection, and on the RTC's APB clock, neither of which the driver cares about otherwise, so we
+// just turn both on whenever we need them.
+
+fn read() -> Handoff {
+    #[allow(unused_mut)]
+    let mut words = [0; HANDOFF_WORDS];
+    // Safety: the bits we set are only ever set, never cleared, by anyone, and nothing else touches
+    // the backup registers.
+    #[cfg(not(feature = "host"))]
+    unsafe {
+        use stm32g4::stm32g474::{PWR, RCC, TAMP};
+        (*RCC::ptr()).apb1enr1.modify(|_, w| w.rtcapben().set_bit());
+        (*PWR::ptr()).cr1.modify(|_, w| w.dbp().set_bit());
+        let tamp = &*TAMP::ptr();
+        words = [
+            tamp.bkp0r.read().bits(),
+            tamp.bkp1r.read().bits(),
+            tamp.bkp2r.read().bits(),
+        ];
+    }
+    Handoff::from_words(&words)
+}
+
+fn write(handoff: &Handoff) {
+    #[allow(unused_variables)]
+    let words = handoff.to_words();
+    // Safety: as above. `read` has already unlocked them.
+    #[cfg(not(feature = "host"))]
+    unsafe {
+        let tamp = &*stm32g4::stm32g474::TAMP::ptr();
+        tamp.bkp0r.write(|w| w.bits(words[0]));
+        tamp.bkp1r.write(|w| w.bits(words[1]));
+        tamp.bkp2r.write(|w| w.bits(words[2]));
+    }
+}
+
+// We came up and heard from the host, so if this image is on trial the bootloader can keep it.
+pub fn mark_healthy() {
+    write(&Handoff {
+        healthy: true,
+        ..read()
+    });
+}
+
+// Pulls the power stage's plug first, same as a panic does, since the bootloader won't.
+pub fn reboot_to_bootloader(node: NodeAddress) -> ! {
+    cortex_m::interrupt::disable();
+    crash::safe_power_stage();
+    write(&Handoff {
+        stay: true,
+        node: Some(node),
+        ..read()
+    });
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,517 @@
+use super::drive::Mode;
+use super::sdo::Abort;
+use super::{EMCY, RPDO, SYNC, TPDO};
+use crate::comms::messages::{
+    MAX_CURRENT, MAX_DAMPING, MAX_STIFFNESS, MAX_TORQUE_CONSTANT, MIN_TORQUE_CONSTANT,
+};
+
+// The object dictionary: everything a master can read or write over SDO, and map into PDOs. Ranges
+// are as usual: 0x1000-0x1FFF is the communication profile (CiA 301), 0x2000 on is ours, and
+// 0x6000 on is the drive profile (CiA 402). Anything that isn't in here doesn't exist.
+//
+// Every value's stored as a `u32`, since nothing we've got that can change is any bigger. Strings
+// never change, so they're just constants.
+
+pub const DEVICE_TYPE: u16 = 0x1000;
+pub const ERROR_REGISTER: u16 = 0x1001;
+pub const SYNC_COB_ID: u16 = 0x1005;
+pub const DEVICE_NAME: u16 = 0x1008;
+pub const SOFTWARE_VERSION: u16 = 0x100A;
+pub const EMCY_COB_ID: u16 = 0x1014;
+pub const HEARTBEAT_TIME: u16 = 0x1017;
+pub const IDENTITY: u16 = 0x1018;
+pub const RPDO_COMMUNICATION: u16 = 0x1400;
+pub const RPDO_MAPPING: u16 = 0x1600;
+pub const TPDO_COMMUNICATION: u16 = 0x1800;
+pub const TPDO_MAPPING: u16 = 0x1A00;
+// Stiffness (Nm/rad), damping (Nm*s/rad) and torque constant (Nm/A) for `PositionVelocity`.
+pub const GAINS: u16 = 0x2000;
+pub const ERROR_CODE: u16 = 0x603F;
+pub const CONTROLWORD: u16 = 0x6040;
+pub const STATUSWORD: u16 = 0x6041;
+pub const MODES_OF_OPERATION: u16 = 0x6060;
+pub const MODES_OF_OPERATION_DISPLAY: u16 = 0x6061;
+pub const POSITION_ACTUAL: u16 = 0x6064;
+pub const POSITION_WINDOW: u16 = 0x6067;
+pub const VELOCITY_ACTUAL: u16 = 0x606C;
+pub const TARGET_TORQUE: u16 = 0x6071;
+pub const RATED_CURRENT: u16 = 0x6075;
+pub const TARGET_POSITION: u16 = 0x607A;
+pub const TARGET_VELOCITY: u16 = 0x60FF;
+pub const SUPPORTED_MODES: u16 = 0x6502;
+
+// Sub-indices of the PDO communication parameters. RPDOs only have the first two.
+pub const PDO_COB_ID: u8 = 1;
+pub const PDO_TRANSMISSION: u8 = 2;
+pub const PDO_INHIBIT_TIME: u8 = 3;
+pub const PDO_EVENT_TIMER: u8 = 5;
+// Set in a PDO's COB-ID while it's not in use.
+pub const PDO_INVALID: u32 = 1 << 31;
+const PDO_EXTENDED: u32 = 1 << 29;
+pub const PDOS: usize = 4;
+pub const MAX_MAPPED: usize = 8;
+// Longest thing that can be read out, which is a string.
+pub const MAX_SIZE: usize = 32;
+
+// A servo drive (0x02) running CiA 402 (0x192).
+const DEVICE_TYPE_VALUE: u32 = 0x0002_0192;
+const DEVICE_NAME_VALUE: &str = "pino";
+const SOFTWARE_VERSION_VALUE: &str = concat!("pino-rs ", env!("CARGO_PKG_VERSION"));
+// We don't have a vendor ID from CiA, so vendor and product are both zero.
+const IDENTITY_VALUE: [u32; 3] = [0, 0, 1];
+// Profile position, profile velocity and profile torque.
+const SUPPORTED_MODES_VALUE: u32 = 1 << 0 | 1 << 2 | 1 << 3;
+const DEFAULT_HEARTBEAT_TIME: u32 = 1000;
+// Every time something changes, or every SYNC.
+const EVENT: u32 = 255;
+const EVERY_SYNC: u32 = 1;
+
+// An entry in a PDO mapping: which object, and how many bits of it.
+pub const fn mapping(index: u16, sub: u8, bits: u32) -> u32 {
+    (index as u32) << 16 | (sub as u32) << 8 | bits
+}
+
+pub fn unmap(entry: u32) -> (u16, u8, usize) {
+    (
+        (entry >> 16) as u16,
+        (entry >> 8) as u8,
+        (entry & 0xFF) as usize,
+    )
+}
+
+const CONTROLWORD_MAPPING: u32 = mapping(CONTROLWORD, 0, 16);
+const STATUSWORD_MAPPING: u32 = mapping(STATUSWORD, 0, 16);
+// What each PDO starts out with, along with its transmission type. Every one of them goes out
+// with the control or status word, so the master always knows what it's looking at.
+const DEFAULT_RPDOS: [(u32, [u32; 2]); PDOS] = [
+    (
+        EVENT,
+        [CONTROLWORD_MAPPING, mapping(MODES_OF_OPERATION, 0, 8)],
+    ),
+    (
+        EVENT,
+        [CONTROLWORD_MAPPING, mapping(TARGET_POSITION, 0, 32)],
+    ),
+    (
+        EVENT,
+        [CONTROLWORD_MAPPING, mapping(TARGET_VELOCITY, 0, 32)],
+    ),
+    (EVENT, [CONTROLWORD_MAPPING, mapping(TARGET_TORQUE, 0, 16)]),
+];
+const DEFAULT_TPDOS: [(u32, [u32; 2]); PDOS] = [
+    (
+        EVENT,
+        [
+            STATUSWORD_MAPPING,
+            mapping(MODES_OF_OPERATION_DISPLAY, 0, 8),
+        ],
+    ),
+    (
+        EVERY_SYNC,
+        [STATUSWORD_MAPPING, mapping(POSITION_ACTUAL, 0, 32)],
+    ),
+    (
+        EVERY_SYNC,
+        [STATUSWORD_MAPPING, mapping(VELOCITY_ACTUAL, 0, 32)],
+    ),
+    (EVENT, [STATUSWORD_MAPPING, mapping(ERROR_CODE, 0, 16)]),
+];
+
+// Where each variable lives in `Dictionary::values`.
+const HEARTBEAT_TIME_SLOT: usize = 0;
+const ERROR_REGISTER_SLOT: usize = 1;
+// COB-ID, transmission type, inhibit time, event timer, number of mapped objects, then the
+// mapping itself. RPDOs leave the inhibit time and event timer be.
+const PDO_SLOTS: usize = 5 + MAX_MAPPED;
+const PDO_MAPPED: usize = 4;
+const RPDO_SLOT: usize = 2;
+const TPDO_SLOT: usize = RPDO_SLOT + PDOS * PDO_SLOTS;
+const GAINS_SLOT: usize = TPDO_SLOT + PDOS * PDO_SLOTS;
+const DRIVE_SLOT: usize = GAINS_SLOT + 3;
+const SLOTS: usize = DRIVE_SLOT + 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    F32,
+    Str,
+}
+
+impl Kind {
+    // In bytes. Strings are however long they are, so zero.
+    pub fn size(&self) -> usize {
+        match self {
+            Kind::U8 | Kind::I8 => 1,
+            Kind::U16 | Kind::I16 => 2,
+            Kind::U32 | Kind::I32 | Kind::F32 => 4,
+            Kind::Str => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Const(u32),
+    Str(&'static str),
+    // Index into `Dictionary::values`.
+    Var(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Object {
+    pub kind: Kind,
+    pub access: Access,
+    // Whether it can go in a PDO.
+    pub mappable: bool,
+    pub value: Value,
+}
+
+const fn constant(kind: Kind, value: u32) -> Object {
+    Object {
+        kind,
+        access: Access::ReadOnly,
+        mappable: false,
+        value: Value::Const(value),
+    }
+}
+
+const fn string(value: &'static str) -> Object {
+    Object {
+        kind: Kind::Str,
+        access: Access::ReadOnly,
+        mappable: false,
+        value: Value::Str(value),
+    }
+}
+
+const fn variable(kind: Kind, access: Access, slot: usize) -> Object {
+    Object {
+        kind,
+        access,
+        mappable: false,
+        value: Value::Var(slot),
+    }
+}
+
+const fn mappable(kind: Kind, access: Access, slot: usize) -> Object {
+    Object {
+        mappable: true,
+        ..variable(kind, access, slot)
+    }
+}
+
+// Whichever of the four PDOs `index` is about, given the first index of its range.
+fn pdo_slot(base: usize, index: u16, field: usize) -> usize {
+    base + (index & 0xFF) as usize * PDO_SLOTS + field
+}
+
+// A value as it goes over the bus: little-endian, and only as long as it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Data {
+    bytes: [u8; MAX_SIZE],
+    len: usize,
+}
+
+impl Data {
+    pub fn new(bytes: &[u8]) -> Data {
+        let len = bytes.len().min(MAX_SIZE);
+        let mut data = Data {
+            bytes: [0; MAX_SIZE],
+            len,
+        };
+        data.bytes[..len].copy_from_slice(&bytes[..len]);
+        data
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+pub struct Dictionary {
+    node_id: u8,
+    values: [u32; SLOTS],
+}
+
+impl Dictionary {
+    pub fn new(node_id: u8) -> Dictionary {
+        let mut dictionary = Dictionary {
+            node_id,
+            values: [0; SLOTS],
+        };
+        dictionary.reset_communication();
+        dictionary.set(GAINS, 3, 1f32.to_bits());
+        dictionary.set(POSITION_WINDOW, 0, 10);
+        dictionary.set(RATED_CURRENT, 0, 1000);
+        dictionary
+    }
+
+    // Puts the communication profile back how it started, for NMT reset communication.
+    pub fn reset_communication(&mut self) {
+        self.set(HEARTBEAT_TIME, 0, DEFAULT_HEARTBEAT_TIME);
+        let node_id = self.node_id as u32;
+        let defaults = [
+            (RPDO_COMMUNICATION, RPDO_MAPPING, RPDO, DEFAULT_RPDOS),
+            (TPDO_COMMUNICATION, TPDO_MAPPING, TPDO, DEFAULT_TPDOS),
+        ];
+        for (communication, mapping, cob_ids, pdos) in defaults {
+            for (n, (transmission, entries)) in pdos.iter().enumerate() {
+                let n = n as u16;
+                self.set(
+                    communication + n,
+                    PDO_COB_ID,
+                    cob_ids[n as usize] as u32 + node_id,
+                );
+                self.set(communication + n, PDO_TRANSMISSION, *transmission);
+                self.set(communication + n, PDO_INHIBIT_TIME, 0);
+                self.set(communication + n, PDO_EVENT_TIMER, 0);
+                for sub in 1..=MAX_MAPPED as u8 {
+                    let entry = entries.get(sub as usize - 1).copied().unwrap_or(0);
+                    self.set(mapping + n, sub, entry);
+                }
+                self.set(mapping + n, 0, entries.len() as u32);
+            }
+        }
+    }
+
+    pub fn node_id(&self) -> u8 {
+        self.node_id
+    }
+
+    pub fn describe(&self, index: u16, sub: u8) -> Result<Object, Abort> {
+        use Access::*;
+        use Kind::*;
+        let node_id = self.node_id as u32;
+        Ok(match (index, sub) {
+            (DEVICE_TYPE, 0) => constant(U32, DEVICE_TYPE_VALUE),
+            (ERROR_REGISTER, 0) => mappable(U8, ReadOnly, ERROR_REGISTER_SLOT),
+            (SYNC_COB_ID, 0) => constant(U32, SYNC as u32),
+            (DEVICE_NAME, 0) => string(DEVICE_NAME_VALUE),
+            (SOFTWARE_VERSION, 0) => string(SOFTWARE_VERSION_VALUE),
+            (EMCY_COB_ID, 0) => constant(U32, EMCY as u32 + node_id),
+            (HEARTBEAT_TIME, 0) => variable(U16, ReadWrite, HEARTBEAT_TIME_SLOT),
+            (IDENTITY, 0) => constant(U8, IDENTITY_VALUE.len() as u32),
+            (IDENTITY, 1..=3) => constant(U32, IDENTITY_VALUE[sub as usize - 1]),
+            (0x1400..=0x1403, 0) => constant(U8, 2),
+            (0x1400..=0x1403, PDO_COB_ID) => {
+                variable(U32, ReadWrite, pdo_slot(RPDO_SLOT, index, 0))
+            }
+            (0x1400..=0x1403, PDO_TRANSMISSION) => {
+                variable(U8, ReadWrite, pdo_slot(RPDO_SLOT, index, 1))
+            }
+            (0x1600..=0x1603, 0) => variable(U8, ReadWrite, pdo_slot(RPDO_SLOT, index, PDO_MAPPED)),
+            (0x1600..=0x1603, 1..=8) => variable(
+                U32,
+                ReadWrite,
+                pdo_slot(RPDO_SLOT, index, PDO_MAPPED + sub as usize),
+            ),
+            (0x1800..=0x1803, 0) => constant(U8, PDO_EVENT_TIMER as u32),
+            (0x1800..=0x1803, PDO_COB_ID) => {
+                variable(U32, ReadWrite, pdo_slot(TPDO_SLOT, index, 0))
+            }
+            (0x1800..=0x1803, PDO_TRANSMISSION) => {
+                variable(U8, ReadWrite, pdo_slot(TPDO_SLOT, index, 1))
+            }
+            (0x1800..=0x1803, PDO_INHIBIT_TIME) => {
+                variable(U16, ReadWrite, pdo_slot(TPDO_SLOT, index, 2))
+            }
+            (0x1800..=0x1803, PDO_EVENT_TIMER) => {
+                variable(U16, ReadWrite, pdo_slot(TPDO_SLOT, index, 3))
+            }
+            (0x1A00..=0x1A03, 0) => variable(U8, ReadWrite, pdo_slot(TPDO_SLOT, index, PDO_MAPPED)),
+            (0x1A00..=0x1A03, 1..=8) => variable(
+                U32,
+                ReadWrite,
+                pdo_slot(TPDO_SLOT, index, PDO_MAPPED + sub as usize),
+            ),
+            (GAINS, 0) => constant(U8, 3),
+            (GAINS, 1..=3) => variable(F32, ReadWrite, GAINS_SLOT + sub as usize - 1),
+            (ERROR_CODE, 0) => mappable(U16, ReadOnly, DRIVE_SLOT),
+            (CONTROLWORD, 0) => mappable(U16, ReadWrite, DRIVE_SLOT + 1),
+            (STATUSWORD, 0) => mappable(U16, ReadOnly, DRIVE_SLOT + 2),
+            (MODES_OF_OPERATION, 0) => mappable(I8, ReadWrite, DRIVE_SLOT + 3),
+            (MODES_OF_OPERATION_DISPLAY, 0) => mappable(I8, ReadOnly, DRIVE_SLOT + 4),
+            (POSITION_ACTUAL, 0) => mappable(I32, ReadOnly, DRIVE_SLOT + 5),
+            (POSITION_WINDOW, 0) => variable(U32, ReadWrite, DRIVE_SLOT + 6),
+            (VELOCITY_ACTUAL, 0) => mappable(I32, ReadOnly, DRIVE_SLOT + 7),
+            (TARGET_TORQUE, 0) => mappable(I16, ReadWrite, DRIVE_SLOT + 8),
+            (RATED_CURRENT, 0) => variable(U32, ReadWrite, DRIVE_SLOT + 9),
+            (TARGET_POSITION, 0) => mappable(I32, ReadWrite, DRIVE_SLOT + 10),
+            (TARGET_VELOCITY, 0) => mappable(I32, ReadWrite, DRIVE_SLOT + 11),
+            (SUPPORTED_MODES, 0) => constant(U32, SUPPORTED_MODES_VALUE),
+            _ if sub != 0 && self.describe(index, 0).is_ok() => return Err(Abort::NoSubIndex),
+            _ => return Err(Abort::NoObject),
+        })
+    }
+
+    // For the rest of the node, which knows what it's asking for: zero for anything that isn't
+    // there or is a string.
+    pub fn get(&self, index: u16, sub: u8) -> u32 {
+        match self.describe(index, sub).map(|object| object.value) {
+            Ok(Value::Const(value)) => value,
+            Ok(Value::Var(slot)) => self.values[slot],
+            _ => 0,
+        }
+    }
+
+    pub fn get_i32(&self, index: u16, sub: u8) -> i32 {
+        let value = self.get(index, sub);
+        match self.describe(index, sub).map(|object| object.kind) {
+            Ok(Kind::I8) => value as u8 as i8 as i32,
+            Ok(Kind::I16) => value as u16 as i16 as i32,
+            _ => value as i32,
+        }
+    }
+
+    pub fn get_f32(&self, index: u16, sub: u8) -> f32 {
+        f32::from_bits(self.get(index, sub))
+    }
+
+    // Also for the rest of the node, so it skips all the checks, including whether the master's
+    // allowed to write it. Anything that doesn't fit is cut off.
+    pub fn set(&mut self, index: u16, sub: u8, value: u32) {
+        if let Ok(Object {
+            kind,
+            value: Value::Var(slot),
+            ..
+        }) = self.describe(index, sub)
+        {
+            self.values[slot] = match kind.size() {
+                1 => value & 0xFF,
+                2 => value & 0xFFFF,
+                _ => value,
+            };
+        }
+    }
+
+    pub fn set_i32(&mut self, index: u16, sub: u8, value: i32) {
+        self.set(index, sub, value as u32)
+    }
+
+    // What a master gets back from an upload.
+    pub fn read(&self, index: u16, sub: u8) -> Result<Data, Abort> {
+        let object = self.describe(index, sub)?;
+        Ok(match object.value {
+            Value::Str(value) => Data::new(value.as_bytes()),
+            _ => Data::new(&self.get(index, sub).to_le_bytes()[..object.kind.size()]),
+        })
+    }
+
+    // A download from a master, which has to be exactly the right size.
+    pub fn write(&mut self, index: u16, sub: u8, bytes: &[u8]) -> Result<(), Abort> {
+        let object = self.describe(index, sub)?;
+        if object.access != Access::ReadWrite {
+            return Err(Abort::ReadOnly);
+        }
+        if bytes.len() != object.kind.size() {
+            return Err(Abort::Length);
+        }
+        let mut value = [0; 4];
+        value[..bytes.len()].copy_from_slice(bytes);
+        let value = u32::from_le_bytes(value);
+        self.check(index, sub, value)?;
+        self.set(index, sub, value);
+        Ok(())
+    }
+
+    // Whether the PDO a communication or mapping index belongs to is in use.
+    fn pdo_valid(&self, index: u16) -> bool {
+        let communication = match index {
+            0x1600..=0x1603 | 0x1A00..=0x1A03 => index - 0x200,
+            _ => index,
+        };
+        self.get(communication, PDO_COB_ID) & PDO_INVALID == 0
+    }
+
+    fn check(&self, index: u16, sub: u8, value: u32) -> Result<(), Abort> {
+        let ok = |valid: bool| {
+            if valid {
+                Ok(())
+            } else {
+                Err(Abort::ValueRange)
+            }
+        };
+        match (index, sub) {
+            // 11 bit IDs only, and one that's in use has to be taken out of use before it can
+            // move.
+            (0x1400..=0x1403 | 0x1800..=0x1803, PDO_COB_ID) => ok(value & PDO_EXTENDED == 0
+                && (value & PDO_INVALID != 0
+                    || !self.pdo_valid(index)
+                    || value == self.get(index, sub))),
+            // No RTR-only ones.
+            (0x1400..=0x1403 | 0x1800..=0x1803, PDO_TRANSMISSION) => {
+                ok(matches!(value, 0..=240 | 254 | 255))
+            }
+            // Mapping can only change while the PDO's not in use, and with the number of mapped
+            // objects at zero while the entries go in.
+            (0x1600..=0x1603 | 0x1A00..=0x1A03, _) if self.pdo_valid(index) => {
+                Err(Abort::DeviceState)
+            }
+            (0x1600..=0x1603 | 0x1A00..=0x1A03, 0) => {
+                if value as usize > MAX_MAPPED {
+                    return Err(Abort::ValueRange);
+                }
+                let mut bits = 0;
+                for sub in 1..=value as u8 {
+                    let entry = self.get(index, sub);
+                    self.check_mapping(index, entry)?;
+                    bits += unmap(entry).2;
+                }
+                if bits > 64 {
+                    return Err(Abort::MappingLength);
+                }
+                Ok(())
+            }
+            (0x1600..=0x1603 | 0x1A00..=0x1A03, _) if self.get(index, 0) != 0 => {
+                Err(Abort::DeviceState)
+            }
+            (0x1600..=0x1603 | 0x1A00..=0x1A03, _) if value != 0 => {
+                self.check_mapping(index, value)
+            }
+            // Same limits as `SetPosVelCmd` and `TorqueControlCmd` get over the native protocol.
+            // NaNs fail the comparisons, and so never make it in.
+            (GAINS, 1) => ok(within(f32::from_bits(value), 0., MAX_STIFFNESS)),
+            (GAINS, 2) => ok(within(f32::from_bits(value), 0., MAX_DAMPING)),
+            (GAINS, 3) => ok(within(
+                f32::from_bits(value),
+                MIN_TORQUE_CONSTANT,
+                MAX_TORQUE_CONSTANT,
+            )),
+            (MODES_OF_OPERATION, 0) => ok(Mode::from_raw(value as u8 as i8).is_some()),
+            // In mA.
+            (RATED_CURRENT, 0) => ok(value > 0 && value as f32 <= MAX_CURRENT * 1000.),
+            _ => Ok(()),
+        }
+    }
+
+    // Entries have to point at something that can be mapped, with its full size. Received ones
+    // also have to be something the master can write.
+    fn check_mapping(&self, index: u16, entry: u32) -> Result<(), Abort> {
+        let (mapped, sub, bits) = unmap(entry);
+        match self.describe(mapped, sub) {
+            Ok(object)
+                if object.mappable
+                    && bits == object.kind.size() * 8
+                    && (index >= TPDO_MAPPING || object.access == Access::ReadWrite) =>
+            {
+                Ok(())
+            }
+            _ => Err(Abort::NotMappable),
+        }
+    }
+}
+
+fn within(value: f32, min: f32, max: f32) -> bool {
+    value >= min && value <= max
+}
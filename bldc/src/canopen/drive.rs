@@ -0,0 +1,288 @@
+use super::dictionary::{
+    Dictionary, CONTROLWORD, ERROR_CODE, GAINS, MODES_OF_OPERATION, MODES_OF_OPERATION_DISPLAY,
+    POSITION_ACTUAL, POSITION_WINDOW, RATED_CURRENT, STATUSWORD, TARGET_POSITION, TARGET_TORQUE,
+    TARGET_VELOCITY, VELOCITY_ACTUAL,
+};
+use super::emcy;
+use super::{Action, Outputs};
+use crate::comms::messages::MAX_CURRENT;
+use crate::control_loops::pos_vel_control::PosVelState;
+use crate::fault::Faults;
+use third_party::ang::{AbsoluteDist, Angle};
+
+// The CiA 402 drive state machine. The master walks it through Switch On Disabled → Ready To
+// Switch On → Switched On → Operation Enabled with the controlword, and only in Operation Enabled
+// does anything get sent to the control loops. Which loop, and with what, is down to the mode of
+// operation (0x6060).
+//
+// Quick stop just disables the loop (i.e. quick stop option code 2), and we go on to Switch On
+// Disabled the next time around rather than waiting for the motor to stop: there's nothing we can
+// do to stop it any quicker anyway.
+
+// Controlword bits.
+const SWITCH_ON: u16 = 1 << 0;
+const ENABLE_VOLTAGE: u16 = 1 << 1;
+// Active low.
+const QUICK_STOP: u16 = 1 << 2;
+const ENABLE_OPERATION: u16 = 1 << 3;
+const NEW_SETPOINT: u16 = 1 << 4;
+const FAULT_RESET: u16 = 1 << 7;
+
+// Statusword bits, on top of the ones that say which state we're in.
+const VOLTAGE_ENABLED: u16 = 1 << 4;
+const WARNING: u16 = 1 << 7;
+const REMOTE: u16 = 1 << 9;
+const TARGET_REACHED: u16 = 1 << 10;
+const SETPOINT_ACKNOWLEDGE: u16 = 1 << 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DriveState {
+    NotReadyToSwitchOn,
+    SwitchOnDisabled,
+    ReadyToSwitchOn,
+    SwitchedOn,
+    OperationEnabled,
+    QuickStopActive,
+    FaultReactionActive,
+    Fault,
+}
+
+impl DriveState {
+    fn statusword(&self) -> u16 {
+        match self {
+            DriveState::NotReadyToSwitchOn => 0x00,
+            DriveState::SwitchOnDisabled => 0x40,
+            DriveState::ReadyToSwitchOn => 0x21 | VOLTAGE_ENABLED,
+            DriveState::SwitchedOn => 0x23 | VOLTAGE_ENABLED,
+            DriveState::OperationEnabled => 0x27 | VOLTAGE_ENABLED,
+            DriveState::QuickStopActive => 0x07 | VOLTAGE_ENABLED,
+            DriveState::FaultReactionActive => 0x0F,
+            DriveState::Fault => 0x08,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    None = 0,
+    ProfilePosition = 1,
+    ProfileVelocity = 3,
+    ProfileTorque = 4,
+}
+
+impl Mode {
+    pub fn from_raw(mode: i8) -> Option<Mode> {
+        match mode {
+            0 => Some(Mode::None),
+            1 => Some(Mode::ProfilePosition),
+            3 => Some(Mode::ProfileVelocity),
+            4 => Some(Mode::ProfileTorque),
+            _ => None,
+        }
+    }
+}
+
+// What the controlword's asking for, as far as the state machine's concerned.
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Shutdown,
+    SwitchOn,
+    EnableOperation,
+    DisableVoltage,
+    QuickStop,
+}
+
+fn command(controlword: u16) -> Command {
+    if controlword & ENABLE_VOLTAGE == 0 {
+        Command::DisableVoltage
+    } else if controlword & QUICK_STOP == 0 {
+        Command::QuickStop
+    } else if controlword & SWITCH_ON == 0 {
+        Command::Shutdown
+    } else if controlword & ENABLE_OPERATION == 0 {
+        Command::SwitchOn
+    } else {
+        Command::EnableOperation
+    }
+}
+
+// Where the motor is, in the same units the control loops use: radians at the output over a single
+// turn, and radians per second at the encoder.
+#[derive(Clone, Copy, Debug)]
+pub struct Feedback {
+    pub position: f32,
+    pub velocity: f32,
+    pub faults: Faults,
+}
+
+pub struct Drive {
+    state: DriveState,
+    // The last controlword, for the bits that only do something on a rising edge.
+    controlword: u16,
+    mode: Mode,
+    // The last thing the control loops were told, so they only hear about it when it changes.
+    commanded: Option<Action>,
+    // Profile position's target, in radians. It's where we were until the master sends one.
+    position: f32,
+}
+
+impl Drive {
+    pub fn new() -> Drive {
+        Drive {
+            state: DriveState::NotReadyToSwitchOn,
+            controlword: 0,
+            mode: Mode::None,
+            commanded: None,
+            position: 0.,
+        }
+    }
+
+    pub fn state(&self) -> DriveState {
+        self.state
+    }
+
+    // The master's gone (i.e. NMT took us out of Operational), so stop. Same as abort connection
+    // option code 2: it has to go through the state machine again to get going.
+    pub fn abort_connection(&mut self) {
+        if matches!(
+            self.state,
+            DriveState::ReadyToSwitchOn
+                | DriveState::SwitchedOn
+                | DriveState::OperationEnabled
+                | DriveState::QuickStopActive
+        ) {
+            self.state = DriveState::SwitchOnDisabled;
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        dictionary: &mut Dictionary,
+        feedback: &Feedback,
+        outputs: &mut impl Outputs,
+    ) {
+        let controlword = dictionary.get(CONTROLWORD, 0) as u16;
+        let rising = controlword & !self.controlword;
+        self.controlword = controlword;
+        let critical = feedback.faults.critical();
+        let previous = self.state;
+
+        self.state = if self.state == DriveState::Fault && rising & FAULT_RESET != 0 {
+            // The feedback's from before the faults were cleared, so it's not looked at again
+            // until next time. If they come straight back, so do we.
+            outputs.act(Action::ClearFaults);
+            dictionary.set(ERROR_CODE, 0, 0);
+            DriveState::SwitchOnDisabled
+        } else if let Some(fault) = critical.iter().next() {
+            // The loop's already been stopped by whatever tripped, so there's no reaction to wait
+            // for and Fault Reaction Active never lasts long enough to be seen.
+            if self.state != DriveState::Fault {
+                dictionary.set(ERROR_CODE, 0, emcy::code(fault) as u32);
+            }
+            DriveState::Fault
+        } else {
+            transition(self.state, command(controlword))
+        };
+
+        let mode =
+            Mode::from_raw(dictionary.get_i32(MODES_OF_OPERATION, 0) as i8).unwrap_or(Mode::None);
+        let enabled = self.state == DriveState::OperationEnabled;
+        if enabled && (previous != DriveState::OperationEnabled || mode != self.mode) {
+            // Hold where we are until there's a new setpoint.
+            self.position = feedback.position;
+        }
+        self.mode = mode;
+        if enabled && mode == Mode::ProfilePosition && rising & NEW_SETPOINT != 0 {
+            self.position = dictionary.get_i32(TARGET_POSITION, 0) as f32 / 1000.;
+        }
+
+        let desired = if enabled {
+            self.setpoint(dictionary)
+        } else {
+            None
+        };
+        match desired {
+            Some(action) if self.commanded != desired => outputs.act(action),
+            None if self.commanded.is_some() => outputs.act(Action::Disable),
+            _ => (),
+        }
+        self.commanded = desired;
+
+        let mut statusword = self.state.statusword() | REMOTE;
+        if feedback.faults.bits() & !critical.bits() != 0 {
+            statusword |= WARNING;
+        }
+        if enabled && mode == Mode::ProfilePosition {
+            let window = dictionary.get(POSITION_WINDOW, 0) as f32 / 1000.;
+            let error = Angle::Radians(self.position)
+                .abs_dist(Angle::Radians(feedback.position))
+                .in_radians();
+            if error.abs() <= window {
+                statusword |= TARGET_REACHED;
+            }
+            if controlword & NEW_SETPOINT != 0 {
+                statusword |= SETPOINT_ACKNOWLEDGE;
+            }
+        }
+        dictionary.set(STATUSWORD, 0, statusword as u32);
+        dictionary.set_i32(MODES_OF_OPERATION_DISPLAY, 0, mode as i32);
+        dictionary.set_i32(POSITION_ACTUAL, 0, (feedback.position * 1000.) as i32);
+        dictionary.set_i32(VELOCITY_ACTUAL, 0, (feedback.velocity * 1000.) as i32);
+    }
+
+    fn setpoint(&self, dictionary: &Dictionary) -> Option<Action> {
+        let stiffness_gain = dictionary.get_f32(GAINS, 1);
+        let damping_gain = dictionary.get_f32(GAINS, 2);
+        let torque_constant = dictionary.get_f32(GAINS, 3);
+        match self.mode {
+            Mode::None => None,
+            Mode::ProfilePosition => Some(Action::PosVel(PosVelState {
+                position: self.position,
+                velocity: 0.,
+                stiffness_gain,
+                damping_gain,
+                torque_constant,
+            })),
+            Mode::ProfileVelocity => Some(Action::PosVel(PosVelState {
+                position: 0.,
+                velocity: dictionary.get_i32(TARGET_VELOCITY, 0) as f32 / 1000.,
+                stiffness_gain: 0.,
+                damping_gain,
+                torque_constant,
+            })),
+            Mode::ProfileTorque => {
+                let rated = dictionary.get(RATED_CURRENT, 0) as f32 / 1000.;
+                let torque = dictionary.get_i32(TARGET_TORQUE, 0) as f32 / 1000.;
+                // Even at the rated current, 0x6071 can ask for 32x it.
+                Some(Action::Torque(
+                    (torque * rated).clamp(-MAX_CURRENT, MAX_CURRENT),
+                ))
+            }
+        }
+    }
+}
+
+impl Default for Drive {
+    fn default() -> Self {
+        Drive::new()
+    }
+}
+
+fn transition(state: DriveState, command: Command) -> DriveState {
+    use Command::*;
+    use DriveState::*;
+    match (state, command) {
+        (NotReadyToSwitchOn, _) => SwitchOnDisabled,
+        (SwitchOnDisabled, Shutdown) => ReadyToSwitchOn,
+        (ReadyToSwitchOn | SwitchedOn | OperationEnabled, DisableVoltage) => SwitchOnDisabled,
+        (ReadyToSwitchOn | SwitchedOn, QuickStop) => SwitchOnDisabled,
+        (OperationEnabled, QuickStop) => QuickStopActive,
+        (ReadyToSwitchOn, SwitchOn) => SwitchedOn,
+        (ReadyToSwitchOn | SwitchedOn, EnableOperation) => OperationEnabled,
+        (SwitchedOn | OperationEnabled, Shutdown) => ReadyToSwitchOn,
+        (OperationEnabled, SwitchOn) => SwitchedOn,
+        // The loop was stopped on the way in.
+        (QuickStopActive, _) => SwitchOnDisabled,
+        (state, _) => state,
+    }
+}
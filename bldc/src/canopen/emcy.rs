@@ -0,0 +1,67 @@
+use crate::fault::{Fault, Faults};
+
+// Emergency messages: one goes out for each fault as it's raised, and an "error reset" once they've
+// all been cleared. The first three bytes are the emergency code and the error register (0x1001),
+// as CiA 301 says. The other five are ours: the fault's code, or `NO_FAULT`, then every fault
+// that's active, same as `Faults::bits`.
+
+pub const ERROR_RESET: u16 = 0x0000;
+// An RPDO was too short for its mapping.
+pub const PDO_LENGTH: u16 = 0x8210;
+pub const NO_FAULT: u8 = 0xFF;
+
+// Error register bits.
+const GENERIC: u8 = 1 << 0;
+const CURRENT: u8 = 1 << 1;
+const VOLTAGE: u8 = 1 << 2;
+const TEMPERATURE: u8 = 1 << 3;
+const COMMUNICATION: u8 = 1 << 4;
+
+pub fn code(fault: Fault) -> u16 {
+    match fault {
+        Fault::ObserverGains | Fault::LoopParameters => 0x6320,
+        Fault::LockContention | Fault::LoopOverrun => 0x6100,
+        Fault::WatchdogReset | Fault::PanicReset => 0x6000,
+        Fault::FdcanTx => 0x8100,
+        Fault::FdcanRxOverflow => 0x8110,
+        Fault::FdcanErrorPassive => 0x8120,
+        Fault::CommandTimeout => 0x8130,
+        Fault::FdcanBusOff => 0x8140,
+        Fault::DrvOvercurrent | Fault::Overcurrent => 0x2300,
+        Fault::BusOvervoltage => 0x3210,
+        Fault::DrvUndervoltage | Fault::BusUndervoltage => 0x3220,
+        Fault::DrvOvertemperature | Fault::DrvOvertemperatureWarning => 0x4210,
+        Fault::DrvGateDrive | Fault::DrvRetried => 0x5000,
+        Fault::HallSensor | Fault::EncoderReadout => 0x7300,
+    }
+}
+
+pub fn error_register(faults: Faults) -> u8 {
+    faults.iter().fold(0, |register, fault| {
+        // The top digit of the code is the error class.
+        register
+            | GENERIC
+            | match code(fault) >> 12 {
+                0x2 => CURRENT,
+                0x3 => VOLTAGE,
+                0x4 => TEMPERATURE,
+                0x8 => COMMUNICATION,
+                _ => 0,
+            }
+    })
+}
+
+pub fn message(code: u16, fault: u8, active: Faults) -> [u8; 8] {
+    let code = code.to_le_bytes();
+    let bits = active.bits().to_le_bytes();
+    [
+        code[0],
+        code[1],
+        error_register(active),
+        fault,
+        bits[0],
+        bits[1],
+        bits[2],
+        bits[3],
+    ]
+}
@@ -0,0 +1,224 @@
+use crate::comms::fdcan::{self, Fdcan, Running};
+use crate::comms::handlers::faults::clear_faults;
+use crate::config;
+use crate::control_loops::command_timeout;
+use crate::control_loops::controller::SENSOR_STATE;
+use crate::control_loops::pos_vel_control::{PosVelState, PositionVelocity};
+use crate::control_loops::timing::CORE_CLOCK_HZ;
+use crate::control_loops::torque_control::TorqueControl;
+use crate::control_loops::{Controller, LoopMode};
+use crate::fault::{self, Faults};
+use crate::foc::DQCurrents;
+use cortex_m::peripheral::{DWT, SCB};
+use third_party::ang::Angle;
+
+// CANopen, for masters that don't speak our own protocol: CiA 301 (NMT, heartbeat, SDO, PDO and
+// EMCY) with the CiA 402 drive profile on top. It shares the FDCAN with everything else. Our own
+// protocol only uses extended IDs and CANopen only uses standard ones, so they stay out of each
+// other's way, and the host tools keep working on a node that's also on a CANopen network.
+//
+// Everything in here apart from `CanOpen` only deals in frames and `Action`s, so it can be run on
+// the host; `CanOpen` wires it up to the real FDCAN and control loops.
+//
+// Units, where the profile leaves them up to us:
+// - Positions are milliradians at the output (i.e. after `gear_ratio`), over a single turn, since
+//   that's what `PositionVelocity` works in.
+// - Velocities are milliradians per second at the encoder, same as `PositionVelocity`'s damping.
+// - Torques are thousandths of the motor's rated current (0x6075), since `TorqueControl` only knows
+//   about current.
+//
+// There's no trajectory generation: profile position jumps straight to the new target, and it's
+// up to the gains in 0x2000 how hard it goes about it.
+
+pub mod dictionary;
+pub mod drive;
+pub mod emcy;
+pub mod nmt;
+pub mod node;
+pub mod pdo;
+pub mod sdo;
+
+pub use dictionary::Dictionary;
+pub use drive::{Drive, DriveState, Feedback, Mode};
+pub use nmt::{NmtCommand, NmtState};
+pub use node::Node;
+
+// Function codes, i.e. the top four bits of a COB-ID. The node ID goes in the bottom seven.
+pub const NMT: u16 = 0x000;
+pub const SYNC: u16 = 0x080;
+pub const EMCY: u16 = 0x080;
+pub const TPDO: [u16; 4] = [0x180, 0x280, 0x380, 0x480];
+pub const RPDO: [u16; 4] = [0x200, 0x300, 0x400, 0x500];
+pub const SDO_TX: u16 = 0x580;
+pub const SDO_RX: u16 = 0x600;
+pub const HEARTBEAT: u16 = 0x700;
+
+// A classic CAN frame: CANopen never uses anything longer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CanFrame {
+    pub cob_id: u16,
+    pub len: u8,
+    pub data: [u8; 8],
+}
+
+impl CanFrame {
+    // Anything past eight bytes is dropped.
+    pub fn new(cob_id: u16, data: &[u8]) -> CanFrame {
+        let len = data.len().min(8);
+        let mut frame = CanFrame {
+            cob_id,
+            len: len as u8,
+            data: [0; 8],
+        };
+        frame.data[..len].copy_from_slice(&data[..len]);
+        frame
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+// What the node wants done to the rest of the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // Stop whatever loop's running.
+    Disable,
+    // Hold a q-axis current, in amps.
+    Torque(f32),
+    // Follow a position/velocity target, starting `PositionVelocity` if it isn't already running.
+    PosVel(PosVelState),
+    // Fault reset in the 402 state machine.
+    ClearFaults,
+    // Setpoints are still coming in, even if they haven't changed. See `command_timeout`.
+    Refresh,
+    // The master's still there (i.e. there's been a SYNC), but hasn't sent anything new.
+    Heartbeat,
+    // NMT reset node.
+    Reset,
+}
+
+// Everything the node does to the outside world.
+pub trait Outputs {
+    fn send(&mut self, frame: CanFrame);
+    fn act(&mut self, action: Action);
+}
+
+// CANopen node IDs only go up to 127, and 0 means everyone.
+pub fn node_id(id: u32) -> u8 {
+    id.clamp(1, 127) as u8
+}
+
+const CYCLES_PER_MS: u32 = CORE_CLOCK_HZ / 1000;
+
+// Milliseconds since we started, off the cycle counter. That wraps every 25s, so this has to be
+// looked at more often than that, which `listen` does.
+struct Clock {
+    last: u32,
+    ms: u32,
+}
+
+impl Clock {
+    fn now(&mut self) -> u32 {
+        let elapsed = DWT::cycle_count().wrapping_sub(self.last) / CYCLES_PER_MS;
+        self.last = self.last.wrapping_add(elapsed * CYCLES_PER_MS);
+        self.ms = self.ms.wrapping_add(elapsed);
+        self.ms
+    }
+}
+
+struct Hardware<'a> {
+    controller: &'a Controller,
+}
+
+impl Outputs for Hardware<'_> {
+    fn send(&mut self, frame: CanFrame) {
+        fdcan::send_standard(frame.cob_id, frame.data());
+    }
+
+    // Starting a loop is refused while there are critical faults latched, but the drive picks
+    // those up itself on its next update and goes to Fault.
+    fn act(&mut self, action: Action) {
+        let controller = self.controller;
+        match action {
+            Action::Disable => controller.disable_loop(),
+            Action::Torque(q) => {
+                let currents = DQCurrents { q, d: 0. };
+                // Restarting the loop would throw away the PI state on every new 0x6071.
+                if controller.mode() != LoopMode::TorqueControl || !controller.is_enabled() {
+                    controller
+                        .set_loop(TorqueControl::unbounded(currents))
+                        .ok();
+                } else {
+                    TorqueControl::command(currents);
+                }
+            }
+            Action::PosVel(state) => {
+                if controller.mode() != LoopMode::PositionVelocity || !controller.is_enabled() {
+                    controller.set_loop(PositionVelocity::new()).ok();
+                }
+                PositionVelocity::command(state);
+            }
+            Action::ClearFaults => clear_faults(controller, Faults::ALL),
+            Action::Refresh => command_timeout::command(),
+            Action::Heartbeat => command_timeout::heartbeat(),
+            Action::Reset => {
+                controller.disable_loop();
+                SCB::sys_reset();
+            }
+        }
+    }
+}
+
+fn feedback() -> Feedback {
+    let gear_ratio = config::current().gear_ratio;
+    let (position, velocity) = SENSOR_STATE.read().map_or((0., 0.), |sensors| {
+        let encoder = sensors.encoder_state;
+        let output = Angle::Radians(encoder.angle_multiturn.in_radians() / gear_ratio);
+        (
+            output.normalized().in_radians(),
+            encoder.velocity.in_radians(),
+        )
+    });
+    Feedback {
+        position,
+        velocity,
+        faults: fault::active(),
+    }
+}
+
+pub struct CanOpen {
+    node: Node,
+    clock: Clock,
+    started: bool,
+}
+
+impl CanOpen {
+    pub fn new(id: u8) -> CanOpen {
+        CanOpen {
+            node: Node::new(id),
+            clock: Clock {
+                last: DWT::cycle_count(),
+                ms: 0,
+            },
+            started: false,
+        }
+    }
+
+    // Called from `listen` every time around.
+    pub fn service(&mut self, controller: &Controller, fdcan: &Fdcan<Running>) {
+        let now = self.clock.now();
+        let feedback = feedback();
+        let mut hardware = Hardware { controller };
+        if !self.started {
+            self.started = true;
+            self.node.start(now, &mut hardware);
+        }
+        while let Some(message) = fdcan.pending_standard() {
+            let bytes = message.bytes();
+            let frame = CanFrame::new(message.id as u16, &bytes[..message.size as usize]);
+            self.node.receive(&frame, now, &feedback, &mut hardware);
+        }
+        self.node.poll(now, &feedback, &mut hardware);
+    }
+}
@@ -0,0 +1,49 @@
+// Network management: the master starts and stops nodes with NMT commands, and each node says what
+// state it's in with a heartbeat every 0x1017 milliseconds.
+
+// Values are what goes out in the heartbeat.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NmtState {
+    // Only until the boot-up message has gone out.
+    Initialising = 0x00,
+    // Only NMT and heartbeats.
+    Stopped = 0x04,
+    // Everything.
+    Operational = 0x05,
+    // Everything but PDOs.
+    PreOperational = 0x7F,
+}
+
+impl NmtState {
+    // SDO and EMCY.
+    pub fn sdo_allowed(&self) -> bool {
+        matches!(self, NmtState::Operational | NmtState::PreOperational)
+    }
+
+    // PDO and SYNC.
+    pub fn pdo_allowed(&self) -> bool {
+        *self == NmtState::Operational
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NmtCommand {
+    Start = 0x01,
+    Stop = 0x02,
+    EnterPreOperational = 0x80,
+    ResetNode = 0x81,
+    ResetCommunication = 0x82,
+}
+
+impl NmtCommand {
+    pub fn from_raw(command: u8) -> Option<NmtCommand> {
+        match command {
+            0x01 => Some(NmtCommand::Start),
+            0x02 => Some(NmtCommand::Stop),
+            0x80 => Some(NmtCommand::EnterPreOperational),
+            0x81 => Some(NmtCommand::ResetNode),
+            0x82 => Some(NmtCommand::ResetCommunication),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use super::dictionary::{
+    Dictionary, ERROR_REGISTER, HEARTBEAT_TIME, PDOS, PDO_COB_ID, PDO_EVENT_TIMER,
+    PDO_INHIBIT_TIME, PDO_INVALID, PDO_TRANSMISSION, RPDO_COMMUNICATION, RPDO_MAPPING,
+    TPDO_COMMUNICATION, TPDO_MAPPING,
+};
+use super::drive::{Drive, DriveState, Feedback};
+use super::emcy;
+use super::nmt::{NmtCommand, NmtState};
+use super::pdo;
+use super::sdo::SdoServer;
+use super::{Action, CanFrame, Outputs, EMCY, HEARTBEAT, NMT, SDO_RX, SDO_TX, SYNC};
+use crate::fault::Faults;
+
+// A CANopen node: everything that happens on the bus, and what it means for the drive. Time is in
+// milliseconds, from wherever the caller likes, as long as it wraps at `u32::MAX`.
+//
+// PDO transmission types:
+// - 0: synchronous, but only if it's changed (TPDO) or applied on the next SYNC (RPDO).
+// - 1 to 240: synchronous, every that many SYNCs (TPDO) or applied on the next SYNC (RPDO).
+// - 254 and 255: whenever it changes, no more often than the inhibit time and at least every event
+//   timer, if set (TPDO), or applied straight away (RPDO).
+
+// Last SYNC'd or sent TPDO state.
+#[derive(Clone, Copy, Default)]
+struct Tpdo {
+    last: Option<CanFrame>,
+    sent_at: u32,
+    syncs: u32,
+}
+
+pub struct Node {
+    dictionary: Dictionary,
+    nmt: NmtState,
+    sdo: SdoServer,
+    drive: Drive,
+    tpdos: [Tpdo; PDOS],
+    // Synchronous RPDOs wait here for the next SYNC.
+    rpdos: [Option<CanFrame>; PDOS],
+    last_heartbeat: u32,
+    // Faults as of the last EMCY.
+    faults: Faults,
+}
+
+impl Node {
+    pub fn new(id: u8) -> Node {
+        Node {
+            dictionary: Dictionary::new(id),
+            nmt: NmtState::Initialising,
+            sdo: SdoServer::new(),
+            drive: Drive::new(),
+            tpdos: [Tpdo::default(); PDOS],
+            rpdos: [None; PDOS],
+            last_heartbeat: 0,
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.dictionary.node_id()
+    }
+
+    pub fn state(&self) -> NmtState {
+        self.nmt
+    }
+
+    pub fn drive_state(&self) -> DriveState {
+        self.drive.state()
+    }
+
+    pub fn dictionary(&self) -> &Dictionary {
+        &self.dictionary
+    }
+
+    pub fn dictionary_mut(&mut self) -> &mut Dictionary {
+        &mut self.dictionary
+    }
+
+    // Sends the boot-up message and goes pre-operational.
+    pub fn start(&mut self, now: u32, outputs: &mut impl Outputs) {
+        outputs.send(CanFrame::new(HEARTBEAT + self.id() as u16, &[0]));
+        self.nmt = NmtState::PreOperational;
+        self.last_heartbeat = now;
+    }
+
+    pub fn receive(
+        &mut self,
+        frame: &CanFrame,
+        now: u32,
+        feedback: &Feedback,
+        outputs: &mut impl Outputs,
+    ) {
+        let id = self.id() as u16;
+        match frame.cob_id {
+            NMT => {
+                let data = frame.data();
+                if data.len() < 2 || (data[1] != 0 && data[1] != id as u8) {
+                    return;
+                }
+                if let Some(command) = NmtCommand::from_raw(data[0]) {
+                    self.nmt(command, now, feedback, outputs);
+                }
+            }
+            SYNC if self.nmt.pdo_allowed() => self.sync(now, feedback, outputs),
+            cob_id if cob_id == SDO_RX + id && self.nmt.sdo_allowed() => {
+                if let Some(reply) = self.sdo.receive(&mut self.dictionary, frame.data(), now) {
+                    outputs.send(CanFrame::new(SDO_TX + id, &reply));
+                }
+                self.drive.update(&mut self.dictionary, feedback, outputs);
+            }
+            cob_id if self.nmt.pdo_allowed() => {
+                let n = match self.rpdo(cob_id) {
+                    Some(n) => n,
+                    None => return,
+                };
+                let transmission = self
+                    .dictionary
+                    .get(RPDO_COMMUNICATION + n as u16, PDO_TRANSMISSION);
+                if transmission <= 240 {
+                    self.rpdos[n] = Some(*frame);
+                } else {
+                    self.apply(n, frame, feedback, outputs);
+                    self.drive.update(&mut self.dictionary, feedback, outputs);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Everything that happens on a timer. Call it often; every millisecond or so is plenty.
+    pub fn poll(&mut self, now: u32, feedback: &Feedback, outputs: &mut impl Outputs) {
+        let id = self.id() as u16;
+        if let Some(abort) = self.sdo.poll(now) {
+            outputs.send(CanFrame::new(SDO_TX + id, &abort));
+        }
+        self.drive.update(&mut self.dictionary, feedback, outputs);
+        self.emergencies(feedback.faults, outputs);
+
+        let period = self.dictionary.get(HEARTBEAT_TIME, 0);
+        if self.nmt != NmtState::Initialising
+            && period != 0
+            && now.wrapping_sub(self.last_heartbeat) >= period
+        {
+            outputs.send(CanFrame::new(HEARTBEAT + id, &[self.nmt as u8]));
+            self.last_heartbeat = now;
+        }
+
+        if !self.nmt.pdo_allowed() {
+            return;
+        }
+        for n in 0..PDOS {
+            let communication = TPDO_COMMUNICATION + n as u16;
+            if !self.tpdo_valid(n) || self.dictionary.get(communication, PDO_TRANSMISSION) < 254 {
+                continue;
+            }
+            // Inhibit time's in 100µs, so round it up to the next millisecond.
+            let inhibit = self
+                .dictionary
+                .get(communication, PDO_INHIBIT_TIME)
+                .div_ceil(10);
+            let event_timer = self.dictionary.get(communication, PDO_EVENT_TIMER);
+            let frame = self.tpdo(n);
+            let tpdo = &self.tpdos[n];
+            let elapsed = now.wrapping_sub(tpdo.sent_at);
+            let changed = tpdo.last != Some(frame) && elapsed >= inhibit;
+            let due = event_timer != 0 && elapsed >= event_timer;
+            if changed || due {
+                self.send_tpdo(n, frame, now, outputs);
+            }
+        }
+    }
+
+    fn nmt(
+        &mut self,
+        command: NmtCommand,
+        now: u32,
+        feedback: &Feedback,
+        outputs: &mut impl Outputs,
+    ) {
+        let previous = self.nmt;
+        match command {
+            NmtCommand::Start => self.nmt = NmtState::Operational,
+            NmtCommand::Stop => self.nmt = NmtState::Stopped,
+            NmtCommand::EnterPreOperational => self.nmt = NmtState::PreOperational,
+            NmtCommand::ResetNode => {
+                // Doesn't come back on the real thing, but the simulator carries on as if it had
+                // just booted.
+                outputs.act(Action::Reset);
+                *self = Node::new(self.id());
+                self.start(now, outputs);
+            }
+            NmtCommand::ResetCommunication => {
+                self.dictionary.reset_communication();
+                self.sdo.reset();
+                self.tpdos = [Tpdo::default(); PDOS];
+                self.start(now, outputs);
+            }
+        }
+        if self.nmt != NmtState::Operational {
+            self.rpdos = [None; PDOS];
+            if previous == NmtState::Operational {
+                self.drive.abort_connection();
+                self.drive.update(&mut self.dictionary, feedback, outputs);
+            }
+        }
+    }
+
+    fn sync(&mut self, now: u32, feedback: &Feedback, outputs: &mut impl Outputs) {
+        outputs.act(Action::Heartbeat);
+        for n in 0..PDOS {
+            if let Some(frame) = self.rpdos[n].take() {
+                self.apply(n, &frame, feedback, outputs);
+            }
+        }
+        self.drive.update(&mut self.dictionary, feedback, outputs);
+
+        for n in 0..PDOS {
+            let transmission = self
+                .dictionary
+                .get(TPDO_COMMUNICATION + n as u16, PDO_TRANSMISSION);
+            if !self.tpdo_valid(n) || transmission > 240 {
+                continue;
+            }
+            let frame = self.tpdo(n);
+            let tpdo = &mut self.tpdos[n];
+            let send = if transmission == 0 {
+                tpdo.last != Some(frame)
+            } else {
+                tpdo.syncs += 1;
+                tpdo.syncs >= transmission
+            };
+            if send {
+                self.tpdos[n].syncs = 0;
+                self.send_tpdo(n, frame, now, outputs);
+            }
+        }
+    }
+
+    // Writes an RPDO out to the dictionary. The drive's updated by whoever called this.
+    fn apply(
+        &mut self,
+        n: usize,
+        frame: &CanFrame,
+        feedback: &Feedback,
+        outputs: &mut impl Outputs,
+    ) {
+        match pdo::unpack(&mut self.dictionary, RPDO_MAPPING + n as u16, frame.data()) {
+            Ok(()) => {
+                if self.drive.state() == DriveState::OperationEnabled {
+                    outputs.act(Action::Refresh);
+                }
+            }
+            Err(_) => self.emergency(emcy::PDO_LENGTH, emcy::NO_FAULT, feedback.faults, outputs),
+        }
+    }
+
+    fn rpdo(&self, cob_id: u16) -> Option<usize> {
+        (0..PDOS).find(|n| {
+            let configured = self
+                .dictionary
+                .get(RPDO_COMMUNICATION + *n as u16, PDO_COB_ID);
+            configured & PDO_INVALID == 0 && configured & 0x7FF == cob_id as u32
+        })
+    }
+
+    fn tpdo_valid(&self, n: usize) -> bool {
+        self.dictionary
+            .get(TPDO_COMMUNICATION + n as u16, PDO_COB_ID)
+            & PDO_INVALID
+            == 0
+    }
+
+    fn tpdo(&self, n: usize) -> CanFrame {
+        let cob_id = self
+            .dictionary
+            .get(TPDO_COMMUNICATION + n as u16, PDO_COB_ID)
+            & 0x7FF;
+        let (data, len) = pdo::pack(&self.dictionary, TPDO_MAPPING + n as u16);
+        CanFrame::new(cob_id as u16, &data[..len])
+    }
+
+    fn send_tpdo(&mut self, n: usize, frame: CanFrame, now: u32, outputs: &mut impl Outputs) {
+        outputs.send(frame);
+        self.tpdos[n].last = Some(frame);
+        self.tpdos[n].sent_at = now;
+    }
+
+    // One EMCY for every fault that's been raised since last time, and an error reset once they're
+    // all gone.
+    fn emergencies(&mut self, faults: Faults, outputs: &mut impl Outputs) {
+        self.dictionary
+            .set(ERROR_REGISTER, 0, emcy::error_register(faults) as u32);
+        if faults == self.faults {
+            return;
+        }
+        let raised = Faults::from_bits(faults.bits() & !self.faults.bits());
+        self.faults = faults;
+        for fault in raised.iter() {
+            self.emergency(emcy::code(fault), fault as u8, faults, outputs);
+        }
+        if faults.is_empty() {
+            self.emergency(emcy::ERROR_RESET, emcy::NO_FAULT, faults, outputs);
+        }
+    }
+
+    fn emergency(&self, code: u16, fault: u8, faults: Faults, outputs: &mut impl Outputs) {
+        if !self.nmt.sdo_allowed() {
+            return;
+        }
+        let message = emcy::message(code, fault, faults);
+        outputs.send(CanFrame::new(EMCY + self.id() as u16, &message));
+    }
+}
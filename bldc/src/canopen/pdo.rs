@@ -0,0 +1,45 @@
+use super::dictionary::{unmap, Dictionary};
+use super::sdo::Abort;
+
+// Moving mapped objects in and out of PDOs. Mapping's checked as it's written (see
+// `Dictionary::write`), so by the time we get here every entry's a whole number of bytes, points at
+// something that exists, and they all fit in eight bytes.
+
+// A TPDO's worth of data, and how long it is.
+pub fn pack(dictionary: &Dictionary, mapping: u16) -> ([u8; 8], usize) {
+    let mut data = [0; 8];
+    let mut len = 0;
+    for sub in 1..=dictionary.get(mapping, 0) as u8 {
+        let (index, object_sub, bits) = unmap(dictionary.get(mapping, sub));
+        let bytes = bits / 8;
+        if len + bytes > data.len() {
+            break;
+        }
+        let value = dictionary.get(index, object_sub).to_le_bytes();
+        data[len..len + bytes].copy_from_slice(&value[..bytes]);
+        len += bytes;
+    }
+    (data, len)
+}
+
+// Writes an RPDO out to its mapped objects. One that's too short for its mapping is dropped
+// entirely; anything past the end of the mapping is ignored.
+pub fn unpack(dictionary: &mut Dictionary, mapping: u16, data: &[u8]) -> Result<(), Abort> {
+    let count = dictionary.get(mapping, 0) as u8;
+    let needed: usize = (1..=count)
+        .map(|sub| unmap(dictionary.get(mapping, sub)).2 / 8)
+        .sum();
+    if data.len() < needed {
+        return Err(Abort::Length);
+    }
+    let mut offset = 0;
+    for sub in 1..=count {
+        let (index, object_sub, bits) = unmap(dictionary.get(mapping, sub));
+        let bytes = bits / 8;
+        let mut value = [0; 4];
+        value[..bytes].copy_from_slice(&data[offset..offset + bytes]);
+        dictionary.set(index, object_sub, u32::from_le_bytes(value));
+        offset += bytes;
+    }
+    Ok(())
+}
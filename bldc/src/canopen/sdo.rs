@@ -0,0 +1,290 @@
+use super::dictionary::{Access, Data, Dictionary, MAX_SIZE};
+
+// SDO server: expedited and segmented transfers, one at a time. Block transfers aren't supported,
+// and get aborted like any other command we don't understand. A segmented transfer that's started
+// and then abandoned by the master is aborted after `TIMEOUT_MS`.
+
+const TIMEOUT_MS: u32 = 1000;
+
+// Client command specifiers, in the top three bits of the first byte.
+const DOWNLOAD_SEGMENT: u8 = 0;
+const INITIATE_DOWNLOAD: u8 = 1;
+const INITIATE_UPLOAD: u8 = 2;
+const UPLOAD_SEGMENT: u8 = 3;
+const ABORT: u8 = 4;
+
+const TOGGLE: u8 = 1 << 4;
+const EXPEDITED: u8 = 1 << 1;
+const SIZE_INDICATED: u8 = 1 << 0;
+// Last segment.
+const COMPLETE: u8 = 1 << 0;
+
+// Why a transfer was aborted. Codes are straight out of CiA 301.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Abort {
+    Toggle = 0x0503_0000,
+    Timeout = 0x0504_0000,
+    Command = 0x0504_0001,
+    ReadOnly = 0x0601_0002,
+    NoObject = 0x0602_0000,
+    NotMappable = 0x0604_0041,
+    MappingLength = 0x0604_0042,
+    Length = 0x0607_0010,
+    NoSubIndex = 0x0609_0011,
+    ValueRange = 0x0609_0030,
+    DeviceState = 0x0800_0022,
+}
+
+enum Transfer {
+    Idle,
+    Download {
+        index: u16,
+        sub: u8,
+        toggle: bool,
+        // How much the master said it'd send, if it did.
+        size: Option<usize>,
+        buffer: [u8; MAX_SIZE],
+        len: usize,
+    },
+    Upload {
+        index: u16,
+        sub: u8,
+        toggle: bool,
+        data: Data,
+        sent: usize,
+    },
+}
+
+pub struct SdoServer {
+    transfer: Transfer,
+    // When we last heard from the master about the transfer in progress.
+    last: u32,
+}
+
+fn response(command: u8, index: u16, sub: u8, data: [u8; 4]) -> [u8; 8] {
+    let index = index.to_le_bytes();
+    [
+        command, index[0], index[1], sub, data[0], data[1], data[2], data[3],
+    ]
+}
+
+fn abort(index: u16, sub: u8, abort: Abort) -> [u8; 8] {
+    response(ABORT << 5, index, sub, (abort as u32).to_le_bytes())
+}
+
+impl SdoServer {
+    pub fn new() -> SdoServer {
+        SdoServer {
+            transfer: Transfer::Idle,
+            last: 0,
+        }
+    }
+
+    // Handles a request from the master, returning whatever should go back. Aborts from the master
+    // don't get a response.
+    pub fn receive(
+        &mut self,
+        dictionary: &mut Dictionary,
+        request: &[u8],
+        now: u32,
+    ) -> Option<[u8; 8]> {
+        let mut request_bytes = [0; 8];
+        let len = request.len().min(8);
+        request_bytes[..len].copy_from_slice(&request[..len]);
+        let request = request_bytes;
+        let command = request[0];
+        let index = u16::from_le_bytes([request[1], request[2]]);
+        let sub = request[3];
+        self.last = now;
+        let reply = match command >> 5 {
+            INITIATE_DOWNLOAD => self.initiate_download(dictionary, index, sub, &request),
+            DOWNLOAD_SEGMENT => self.download_segment(dictionary, &request),
+            INITIATE_UPLOAD => self.initiate_upload(dictionary, index, sub),
+            UPLOAD_SEGMENT => self.upload_segment(command),
+            ABORT => {
+                self.transfer = Transfer::Idle;
+                return None;
+            }
+            _ => Err((index, sub, Abort::Command)),
+        };
+        Some(reply.unwrap_or_else(|(index, sub, code)| {
+            self.transfer = Transfer::Idle;
+            abort(index, sub, code)
+        }))
+    }
+
+    // Gives up on a segmented transfer the master's gone quiet on.
+    pub fn poll(&mut self, now: u32) -> Option<[u8; 8]> {
+        let (index, sub) = match self.transfer {
+            Transfer::Idle => return None,
+            Transfer::Download { index, sub, .. } | Transfer::Upload { index, sub, .. } => {
+                (index, sub)
+            }
+        };
+        if now.wrapping_sub(self.last) < TIMEOUT_MS {
+            return None;
+        }
+        self.transfer = Transfer::Idle;
+        Some(abort(index, sub, Abort::Timeout))
+    }
+
+    pub fn reset(&mut self) {
+        self.transfer = Transfer::Idle;
+    }
+
+    fn initiate_download(
+        &mut self,
+        dictionary: &mut Dictionary,
+        index: u16,
+        sub: u8,
+        request: &[u8; 8],
+    ) -> Result<[u8; 8], (u16, u8, Abort)> {
+        let command = request[0];
+        let fail = |code| (index, sub, code);
+        self.transfer = Transfer::Idle;
+        if command & EXPEDITED != 0 {
+            // Without a size it's however big the object is.
+            let len = match command & SIZE_INDICATED {
+                0 => dictionary.describe(index, sub).map_err(fail)?.kind.size(),
+                _ => 4 - (command as usize >> 2 & 0b11),
+            };
+            dictionary
+                .write(index, sub, &request[4..4 + len.min(4)])
+                .map_err(fail)?;
+        } else {
+            // Catch anything we'd refuse up front, rather than after it's all been sent.
+            let object = dictionary.describe(index, sub).map_err(fail)?;
+            if object.access != Access::ReadWrite {
+                return Err(fail(Abort::ReadOnly));
+            }
+            let size = match command & SIZE_INDICATED {
+                0 => None,
+                _ => Some(
+                    u32::from_le_bytes([request[4], request[5], request[6], request[7]]) as usize,
+                ),
+            };
+            if size.is_some_and(|size| size > MAX_SIZE) {
+                return Err(fail(Abort::Length));
+            }
+            self.transfer = Transfer::Download {
+                index,
+                sub,
+                toggle: false,
+                size,
+                buffer: [0; MAX_SIZE],
+                len: 0,
+            };
+        }
+        Ok(response(0x60, index, sub, [0; 4]))
+    }
+
+    fn download_segment(
+        &mut self,
+        dictionary: &mut Dictionary,
+        request: &[u8; 8],
+    ) -> Result<[u8; 8], (u16, u8, Abort)> {
+        let command = request[0];
+        let (index, sub, toggle, size, buffer, len) = match &mut self.transfer {
+            Transfer::Download {
+                index,
+                sub,
+                toggle,
+                size,
+                buffer,
+                len,
+            } => (*index, *sub, toggle, *size, buffer, len),
+            _ => return Err((0, 0, Abort::Command)),
+        };
+        let fail = |code| (index, sub, code);
+        if (command & TOGGLE != 0) != *toggle {
+            return Err(fail(Abort::Toggle));
+        }
+        let segment = 7 - (command as usize >> 1 & 0b111);
+        if *len + segment > MAX_SIZE {
+            return Err(fail(Abort::Length));
+        }
+        buffer[*len..*len + segment].copy_from_slice(&request[1..1 + segment]);
+        *len += segment;
+        let reply = [0x20 | (command & TOGGLE), 0, 0, 0, 0, 0, 0, 0];
+        *toggle = !*toggle;
+        if command & COMPLETE == 0 {
+            return Ok(reply);
+        }
+
+        let data = Data::new(&buffer[..*len]);
+        self.transfer = Transfer::Idle;
+        if size.is_some_and(|size| size != data.as_slice().len()) {
+            return Err(fail(Abort::Length));
+        }
+        dictionary
+            .write(index, sub, data.as_slice())
+            .map_err(fail)?;
+        Ok(reply)
+    }
+
+    fn initiate_upload(
+        &mut self,
+        dictionary: &Dictionary,
+        index: u16,
+        sub: u8,
+    ) -> Result<[u8; 8], (u16, u8, Abort)> {
+        self.transfer = Transfer::Idle;
+        let data = dictionary
+            .read(index, sub)
+            .map_err(|code| (index, sub, code))?;
+        let bytes = data.as_slice();
+        if bytes.len() <= 4 {
+            let mut expedited = [0; 4];
+            expedited[..bytes.len()].copy_from_slice(bytes);
+            let command = 0x40 | ((4 - bytes.len() as u8) << 2) | EXPEDITED | SIZE_INDICATED;
+            return Ok(response(command, index, sub, expedited));
+        }
+        self.transfer = Transfer::Upload {
+            index,
+            sub,
+            toggle: false,
+            data,
+            sent: 0,
+        };
+        Ok(response(
+            0x40 | SIZE_INDICATED,
+            index,
+            sub,
+            (bytes.len() as u32).to_le_bytes(),
+        ))
+    }
+
+    fn upload_segment(&mut self, command: u8) -> Result<[u8; 8], (u16, u8, Abort)> {
+        let (index, sub, toggle, data, sent) = match &mut self.transfer {
+            Transfer::Upload {
+                index,
+                sub,
+                toggle,
+                data,
+                sent,
+            } => (*index, *sub, toggle, data, sent),
+            _ => return Err((0, 0, Abort::Command)),
+        };
+        if (command & TOGGLE != 0) != *toggle {
+            return Err((index, sub, Abort::Toggle));
+        }
+        let remaining = &data.as_slice()[*sent..];
+        let segment = remaining.len().min(7);
+        let mut reply = [0; 8];
+        reply[1..1 + segment].copy_from_slice(&remaining[..segment]);
+        reply[0] = (command & TOGGLE) | ((7 - segment as u8) << 1);
+        *sent += segment;
+        *toggle = !*toggle;
+        if segment == remaining.len() {
+            reply[0] |= COMPLETE;
+            self.transfer = Transfer::Idle;
+        }
+        Ok(reply)
+    }
+}
+
+impl Default for SdoServer {
+    fn default() -> Self {
+        SdoServer::new()
+    }
+}
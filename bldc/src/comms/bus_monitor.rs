@@ -0,0 +1,204 @@
+use protocol::bus::{BusState, ProtocolError};
+use protocol::messages::BusStatusMsg;
+
+use crate::fault::{self, Fault};
+
+// Keeps an eye on the FDCAN's error state. PSR and ECR are polled from the TIM2 scheduler (see
+// `fdcan::poll`) and fed through `BusMonitor`, which counts everything up for `BusStatusMsg` and
+// raises faults as things get worse.
+//
+// Going bus-off is the big one. The peripheral takes itself off the bus and sets INIT, and won't
+// come back until we clear it, after which it waits out 128 idle periods on the bus before joining
+// in again. We clear it after a backoff, doubling each time it goes bus-off again soon after, so a
+// node on a harness that's shorted somewhere doesn't spend all its time flapping. `FdcanBusOff` is
+// critical regardless, so the bridge is off until the host's back and has had a look.
+
+// Polls (1kHz) to wait before the first restart, and the most we'll ever wait.
+const MIN_BACKOFF: u32 = 10;
+const MAX_BACKOFF: u32 = 1000;
+// Polls without going bus-off before the backoff goes back to the minimum.
+const BACKOFF_RESET_POLLS: u32 = 1000;
+
+// Fields of PSR and ECR; see RM0440 44.4.16 and 44.4.15.
+const PSR_LEC: u32 = 0b111;
+const PSR_EP: u32 = 1 << 5;
+const PSR_EW: u32 = 1 << 6;
+const PSR_BO: u32 = 1 << 7;
+const PSR_DLEC_SHIFT: u32 = 8;
+const ECR_REC_SHIFT: u32 = 8;
+const ECR_REC: u32 = 0x7F;
+const ECR_CEL_SHIFT: u32 = 16;
+
+// Everything one poll picks up. Reading PSR resets the error codes, and reading ECR resets CEL, so
+// these have to come from a single read of each.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BusSample {
+    pub psr: u32,
+    pub ecr: u32,
+    // The hardware's Rx FIFO overflowed since the last poll.
+    pub fifo_lost: bool,
+    // Frames dropped in software and while sending, so far.
+    pub rx_dropped: u32,
+    pub tx_dropped: u32,
+}
+
+impl BusSample {
+    pub fn state(&self) -> BusState {
+        if self.psr & PSR_BO != 0 {
+            BusState::BusOff
+        } else if self.psr & PSR_EP != 0 {
+            BusState::Passive
+        } else if self.psr & PSR_EW != 0 {
+            BusState::Warning
+        } else {
+            BusState::Active
+        }
+    }
+
+    pub fn last_error(&self) -> ProtocolError {
+        ProtocolError::from_raw(self.psr & PSR_LEC).unwrap_or(ProtocolError::None)
+    }
+
+    pub fn last_data_error(&self) -> ProtocolError {
+        ProtocolError::from_raw((self.psr >> PSR_DLEC_SHIFT) & PSR_LEC)
+            .unwrap_or(ProtocolError::None)
+    }
+
+    pub fn tx_errors(&self) -> u32 {
+        self.ecr & 0xFF
+    }
+
+    pub fn rx_errors(&self) -> u32 {
+        (self.ecr >> ECR_REC_SHIFT) & ECR_REC
+    }
+
+    // Protocol errors since the last read, saturating at 255.
+    pub fn logged_errors(&self) -> u32 {
+        (self.ecr >> ECR_CEL_SHIFT) & 0xFF
+    }
+}
+
+pub struct BusMonitor {
+    state: BusState,
+    tx_errors: u32,
+    rx_errors: u32,
+    // Most recent error codes that weren't `None`, so they're still around when the host asks.
+    last_error: ProtocolError,
+    last_data_error: ProtocolError,
+    bus_offs: u32,
+    protocol_errors: u32,
+    rx_dropped: u32,
+    rx_lost: u32,
+    tx_dropped: u32,
+    // Polls to wait before the next restart, and until then.
+    backoff: u32,
+    until_restart: u32,
+    clean_polls: u32,
+}
+
+impl BusMonitor {
+    pub fn new() -> BusMonitor {
+        BusMonitor {
+            state: BusState::Active,
+            tx_errors: 0,
+            rx_errors: 0,
+            last_error: ProtocolError::None,
+            last_data_error: ProtocolError::None,
+            bus_offs: 0,
+            protocol_errors: 0,
+            rx_dropped: 0,
+            rx_lost: 0,
+            tx_dropped: 0,
+            backoff: MIN_BACKOFF,
+            until_restart: 0,
+            clean_polls: 0,
+        }
+    }
+
+    pub fn state(&self) -> BusState {
+        self.state
+    }
+
+    // How long the next restart will wait, in polls.
+    pub fn backoff(&self) -> u32 {
+        self.backoff
+    }
+
+    // Feed in the latest sample, raising any faults. Returns whether the peripheral should be
+    // restarted to recover from bus-off.
+    pub fn update(&mut self, sample: BusSample) -> bool {
+        let state = sample.state();
+        let tx_errors = sample.tx_errors();
+        // TEC only goes up when something we sent didn't make it.
+        if tx_errors > self.tx_errors {
+            fault::raise(Fault::FdcanTx);
+        }
+        self.tx_errors = tx_errors;
+        self.rx_errors = sample.rx_errors();
+        if sample.last_error() != ProtocolError::None {
+            self.last_error = sample.last_error();
+        }
+        if sample.last_data_error() != ProtocolError::None {
+            self.last_data_error = sample.last_data_error();
+        }
+        self.protocol_errors = self.protocol_errors.wrapping_add(sample.logged_errors());
+        if sample.fifo_lost {
+            self.rx_lost = self.rx_lost.wrapping_add(1);
+        }
+        if sample.fifo_lost || sample.rx_dropped != self.rx_dropped {
+            fault::raise(Fault::FdcanRxOverflow);
+        }
+        self.rx_dropped = sample.rx_dropped;
+        self.tx_dropped = sample.tx_dropped;
+
+        let previous = core::mem::replace(&mut self.state, state);
+        if state == BusState::Passive && previous != BusState::Passive {
+            fault::raise(Fault::FdcanErrorPassive);
+        }
+        if state != BusState::BusOff {
+            if self.clean_polls < BACKOFF_RESET_POLLS {
+                self.clean_polls += 1;
+            } else {
+                self.backoff = MIN_BACKOFF;
+            }
+            return false;
+        }
+
+        self.clean_polls = 0;
+        if previous != BusState::BusOff {
+            self.bus_offs = self.bus_offs.wrapping_add(1);
+            fault::raise(Fault::FdcanBusOff);
+            self.until_restart = self.backoff;
+        }
+        self.until_restart = self.until_restart.saturating_sub(1);
+        if self.until_restart > 0 {
+            return false;
+        }
+        // Still bus-off after this one, e.g. because the bus is stuck dominant and recovery can
+        // never finish, means we'll try again, but less often.
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.until_restart = self.backoff;
+        true
+    }
+
+    pub fn status(&self) -> BusStatusMsg {
+        BusStatusMsg {
+            state: self.state as u32,
+            last_error: self.last_error as u32,
+            last_data_error: self.last_data_error as u32,
+            tx_errors: self.tx_errors,
+            rx_errors: self.rx_errors,
+            bus_offs: self.bus_offs,
+            protocol_errors: self.protocol_errors,
+            rx_dropped: self.rx_dropped,
+            rx_lost: self.rx_lost,
+            tx_dropped: self.tx_dropped,
+        }
+    }
+}
+
+impl Default for BusMonitor {
+    fn default() -> Self {
+        BusMonitor::new()
+    }
+}
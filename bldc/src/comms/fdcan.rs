@@ -1,12 +1,19 @@
 //! FDCAN implementation
+use super::bus_monitor::{BusMonitor, BusSample};
+use super::id::{CanId, NodeAddress};
+use super::messages::{BusStatusMsg, MessageID};
+use crate::control_loops::sync as bus_sync;
 use crate::util::interrupts::block_interrupts;
 use crate::{block_until, block_while};
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::peripheral::DWT;
+pub use protocol::frame::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame};
 
 use extended_filter::{ExtendedFilterMode, ExtendedFilterType};
+use ringbuffer::RingBuffer;
 use ringbuffer::RingBufferRead;
 use ringbuffer::RingBufferWrite;
 use static_assertions::const_assert;
@@ -21,14 +28,43 @@ pub mod tx_event;
 pub mod tx_fifo;
 
 const RECEIVE_BUFFER_SIZE: usize = 16;
+// How long `send_message` waits for room in the Tx FIFO before giving up on a frame: 1ms at 170MHz.
+// Nothing goes out while we're bus-off, or error passive and alone on the bus, and we'd rather drop
+// a frame than sit here until the watchdog bites.
+const TX_TIMEOUT_CYCLES: u32 = 170_000;
 
-type ReceiveBuffer = ringbuffer::ConstGenericRingBuffer<FdcanMessage, RECEIVE_BUFFER_SIZE>;
+// A frame from the host. The message's ID is cut down to just the command, which is all the
+// handlers care about, and the transaction's kept alongside for the ack; see `protocol::ack`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub transaction: u8,
+    pub message: FdcanMessage,
+}
+
+type ReceiveBuffer = ringbuffer::ConstGenericRingBuffer<Request, RECEIVE_BUFFER_SIZE>;
 pub static FDCAN_RECEIVE_BUF: SpinLock<Option<&'static mut ReceiveBuffer>> = SpinLock::new(None);
+// Standard frames, which are all CANopen's. Kept apart so a busy CANopen bus can't push out frames
+// for our own protocol, or the other way around.
+#[cfg(feature = "canopen")]
+type StandardBuffer = ringbuffer::ConstGenericRingBuffer<FdcanMessage, RECEIVE_BUFFER_SIZE>;
+#[cfg(feature = "canopen")]
+static STANDARD_RECEIVE_BUF: SpinLock<Option<StandardBuffer>> = SpinLock::new(None);
 static SHARED_DEVICE: SpinLock<Option<FdcanDevice>> = SpinLock::new(None);
+// Frames that didn't fit in `FDCAN_RECEIVE_BUF`, and that never went out; see `BusStatusMsg`.
+static RX_DROPPED: AtomicU32 = AtomicU32::new(0);
+static TX_DROPPED: AtomicU32 = AtomicU32::new(0);
+static BUS_STATE_CHANGED: AtomicBool = AtomicBool::new(false);
 pub const FDCAN_INTERRUPTS: [device::Interrupt; 2] = [
     device::interrupt::FDCAN1_INTR0_IT,
     device::interrupt::FDCAN1_INTR1_IT,
 ];
+// Telemetry goes out from TIM2 (see `telemetry`), so anything holding `SHARED_DEVICE` from the main
+// thread has to keep that out too.
+const DEVICE_INTERRUPTS: [device::Interrupt; 3] = [
+    device::interrupt::FDCAN1_INTR0_IT,
+    device::interrupt::FDCAN1_INTR1_IT,
+    device::interrupt::TIM2,
+];
 
 #[repr(C)]
 pub struct SramBlock {
@@ -93,11 +129,14 @@ impl DerefMut for Sram {
 struct FdcanDevice {
     sram: Sram,
     fdcan: device::FDCAN1,
+    node: NodeAddress,
+    monitor: BusMonitor,
 }
 
 pub struct Init {
     sram: Sram,
     fdcan: device::FDCAN1,
+    node: NodeAddress,
 }
 pub struct Running;
 
@@ -107,6 +146,10 @@ pub struct Fdcan<S> {
 
 pub fn take<'a>(fdcan: device::FDCAN1) -> Fdcan<Init> {
     *FDCAN_RECEIVE_BUF.try_lock().unwrap() = Some(init_buffer());
+    #[cfg(feature = "canopen")]
+    {
+        *STANDARD_RECEIVE_BUF.try_lock().unwrap() = Some(StandardBuffer::new());
+    }
     // Enter init mode.
     fdcan.cccr.modify(|_, w| w.init().init());
     // Block until we know we're in init mode.
@@ -117,6 +160,7 @@ pub fn take<'a>(fdcan: device::FDCAN1) -> Fdcan<Init> {
         mode_state: Init {
             sram: Sram::get(),
             fdcan,
+            node: NodeAddress::new(1, 0),
         },
     }
 }
@@ -140,6 +184,54 @@ impl Fdcan<Init> {
         self
     }
 
+    // Only accept frames addressed to this node, its group, or everyone; see `comms::id`. Anything
+    // else is dropped by the hardware before it ever gets to us.
+    pub fn set_node(mut self, node: NodeAddress) -> Self {
+        let filters = super::id::filters(&node);
+        for (i, filter) in filters.iter().enumerate() {
+            self = self.set_extended_filter(
+                i,
+                ExtendedFilterMode::StoreRxFIFO0,
+                ExtendedFilterType::Classic,
+                filter.id,
+                filter.mask,
+            );
+        }
+        // CANopen needs every standard frame: NMT, SYNC and other nodes' EMCYs aren't addressed
+        // to anyone in particular.
+        #[cfg(feature = "canopen")]
+        let standard_filters = {
+            self.mode_state.sram.standard_filters[0].update(|_, w| {
+                w.filter_type()
+                    .variant(standard_filter::FilterType::Range)
+                    .config()
+                    .variant(standard_filter::Action::StoreRxFIFO0)
+                    .id1()
+                    .set(0)
+                    .id2()
+                    .set(0x7FF)
+            });
+            1u32
+        };
+        #[cfg(not(feature = "canopen"))]
+        let standard_filters = 0u32;
+        // Safety: the global filter fields aren't broken out in stm32-rs, so set the whole
+        // register: LSE = number of extended filters, LSS = number of standard filters,
+        // non-matching standard and extended frames (ANFS/ANFE = 0b10) and all remote frames
+        // (RRFS/RRFE) are rejected.
+        self.mode_state.fdcan.rxgfc.write(|w| unsafe {
+            w.bits(
+                (filters.len() as u32) << 24
+                    | standard_filters << 16
+                    | 0b10 << 4
+                    | 0b10 << 2
+                    | 0b11,
+            )
+        });
+        self.mode_state.node = node;
+        self
+    }
+
     pub fn configure_protocol(self) -> Self {
         self.mode_state.fdcan.cccr.modify(|_, w| {
             w // Enable TX pause
@@ -265,12 +357,17 @@ impl Fdcan<Init> {
     pub fn start(self) -> Fdcan<Running> {
         // Enable interrupts.
         self.enable_interrupts();
-        let Init { fdcan, sram } = self.mode_state;
+        let Init { fdcan, sram, node } = self.mode_state;
         // Donate the device and SRAM to the interrupts,
-        *SHARED_DEVICE.lock() = Some(FdcanDevice { fdcan, sram });
+        *SHARED_DEVICE.lock() = Some(FdcanDevice {
+            fdcan,
+            sram,
+            node,
+            monitor: BusMonitor::new(),
+        });
         // We needx access to the resources we just donated to enable the device, so we block the
         // interrupts while we start to make sure the device is fully ready.
-        block_interrupts(FDCAN_INTERRUPTS, &SHARED_DEVICE, |shared| {
+        block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |shared| {
             // Enter run mode.
             shared.fdcan.cccr.modify(|_, w| w.init().run());
             // Block until we know we're running.
@@ -287,11 +384,37 @@ pub fn send_message(message: &impl OutgoingFdcanFrame) {
 }
 
 fn send_serialized_message(message: FdcanMessage) {
+    queue(|tx_buffer, node| {
+        tx_buffer.assign(&FdcanMessage {
+            id: node.reply(message.id as u8),
+            ..message
+        })
+    });
+}
+
+// A classic frame with a standard ID, i.e. CANopen. `id` goes out as-is.
+#[cfg(feature = "canopen")]
+pub fn send_standard(id: u16, data: &[u8]) {
+    let message = FdcanMessage::from_bytes(id as u32, data);
+    queue(|tx_buffer, _| tx_buffer.assign_standard(&message));
+}
+
+fn queue(assign: impl FnOnce(&mut tx_fifo::TxFifo, NodeAddress)) {
     // Block interrupts, acquiring the shared hardware.
-    block_interrupts(FDCAN_INTERRUPTS, &SHARED_DEVICE, |mut shared| {
+    block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |mut shared| {
         // TODO(blakely): Move to an actual TxFifo struct/impl
+        // Replies can go out several at a time (e.g. listing params), so wait for the hardware to
+        // make room rather than clobbering a frame that hasn't been sent yet. Within reason.
+        let start = DWT::cycle_count();
+        while shared.fdcan.txfqs.read().tfqf().bit_is_set() {
+            if DWT::cycle_count().wrapping_sub(start) >= TX_TIMEOUT_CYCLES {
+                TX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
         let tx_idx = shared.fdcan.txfqs.read().tfqpi().bits() as usize;
-        shared.sram.tx_buffers[tx_idx].assign(&message);
+        let node = shared.node;
+        assign(&mut shared.sram.tx_buffers[tx_idx], node);
         // Safety: No enum associated with this in stm32-rs. Bit field corresponds
         // to which tx buffer is being used.
         shared
@@ -301,8 +424,57 @@ fn send_serialized_message(message: FdcanMessage) {
     });
 }
 
+// For anything that would rather hold on to a frame than wait for room to send it.
+pub fn tx_fifo_full() -> bool {
+    block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |shared| {
+        shared.fdcan.txfqs.read().tfqf().bit_is_set()
+    })
+}
+
+// Where we are on the bus, as set up by `set_node`.
+pub fn node() -> NodeAddress {
+    block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |shared| shared.node)
+}
+
+// Scheduled from TIM2, same as `gate_driver::poll`. Restarts the peripheral if it's been bus-off
+// long enough; see `bus_monitor`.
+pub fn poll() {
+    block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |mut shared| {
+        let fifo_lost = shared.fdcan.ir.read().rf0l().bit_is_set();
+        if fifo_lost {
+            shared.fdcan.ir.write(|w| w.rf0l().set_bit());
+        }
+        let sample = BusSample {
+            psr: shared.fdcan.psr.read().bits(),
+            ecr: shared.fdcan.ecr.read().bits(),
+            fifo_lost,
+            rx_dropped: RX_DROPPED.load(Ordering::Relaxed),
+            tx_dropped: TX_DROPPED.load(Ordering::Relaxed),
+        };
+        let previous = shared.monitor.state();
+        if shared.monitor.update(sample) {
+            // Bus-off leaves us in init mode. Dropping out of it starts the recovery sequence.
+            shared.fdcan.cccr.modify(|_, w| w.init().run());
+        }
+        if shared.monitor.state() != previous {
+            BUS_STATE_CHANGED.store(true, Ordering::Release);
+        }
+    });
+}
+
+pub fn bus_status() -> BusStatusMsg {
+    block_interrupts(DEVICE_INTERRUPTS, &SHARED_DEVICE, |shared| {
+        shared.monitor.status()
+    })
+}
+
+// Whether the bus state's changed since the last time this was called.
+pub fn take_bus_state_changed() -> bool {
+    BUS_STATE_CHANGED.swap(false, Ordering::AcqRel)
+}
+
 impl Fdcan<Running> {
-    pub fn pending_message(&self) -> Option<FdcanMessage> {
+    pub fn pending_message(&self) -> Option<Request> {
         // Not only do we lock the receive buffer, but we prevent the FDCAN_INTR1 (Rx) from
         // firing - the only other interrupt that shares this particular buffer - ensuring
         // we aren't preempted when reading from it. This is fine in general since the
@@ -314,6 +486,16 @@ impl Fdcan<Running> {
             |mut buf| buf.dequeue(),
         )
     }
+
+    // Same as `pending_message`, but for standard frames. The ID's the whole 11-bit COB-ID.
+    #[cfg(feature = "canopen")]
+    pub fn pending_standard(&self) -> Option<FdcanMessage> {
+        crate::util::interrupts::block_interrupts(
+            FDCAN_INTERRUPTS,
+            &STANDARD_RECEIVE_BUF,
+            |mut buf| buf.dequeue(),
+        )
+    }
 }
 
 fn fdcan1_tx_isr() {
@@ -323,7 +505,9 @@ fn fdcan1_tx_isr() {
     // value retrieved from the get index it's fine.
     fdcan.txefa.modify(|_, w| unsafe { w.efai().bits(get_idx) });
 
-    // TODO(blakely): Actually check for Tx errors
+    // Errors are picked up by `poll`, which has to be the only thing reading PSR since that resets
+    // the error codes.
+
     // Ack the Tx interrupts
     fdcan.ir.modify(|_, w| w.tfe().set_bit().tefn().set_bit());
 }
@@ -343,11 +527,30 @@ fn fdcan1_rx_isr() {
             .as_mut()
             .expect("FDCAN RX ISR handled prior to populating buffer");
         let rx_buffer = &shared.sram.rx_fifo0[get_idx as usize];
-        (*receive_buf).push(FdcanMessage {
-            id: rx_buffer.id(),
-            data: *rx_buffer.data(),
-            size: rx_buffer.len(),
-        });
+        // The filters should only be letting through frames from the host, but just in case.
+        let host = CanId::decode(rx_buffer.id()).filter(|id| rx_buffer.extended() && !id.from_node);
+        match host {
+            // Syncs can't wait for the main loop to get around to them.
+            Some(id) if id.command == MessageID::Sync as u8 => bus_sync::sync(),
+            // The main loop's fallen behind. Still pushed, so the oldest is the one that goes.
+            Some(id) => {
+                if receive_buf.is_full() {
+                    RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                (*receive_buf).push(Request {
+                    transaction: id.transaction,
+                    message: FdcanMessage {
+                        id: id.command as u32,
+                        data: *rx_buffer.data(),
+                        size: rx_buffer.len(),
+                    },
+                })
+            }
+            // Everything with a standard ID is CANopen's.
+            #[cfg(feature = "canopen")]
+            None if !rx_buffer.extended() => receive_standard(rx_buffer),
+            None => (),
+        }
     }
     // Acknowledge the peripheral that we've read the message.
     // Safety: Upstream: not restricted to enum or range in stm32-rs. But since we're using the
@@ -360,25 +563,20 @@ fn fdcan1_rx_isr() {
     shared.fdcan.ir.modify(|_, w| w.rf0n().set_bit());
 }
 
-#[derive(Debug, Clone)]
-pub struct FdcanMessage {
-    pub id: u32,
-    pub data: [u32; 16],
-    pub size: u8,
-}
-
-impl FdcanMessage {
-    pub fn new<const T: usize>(message: u32, data: &[u32; T]) -> FdcanMessage {
-        // There's no real idiomatic way to zero-initialize-and-fill-in-up-to-length in Rust as of
-        // Aug '21.
-        let mut message = FdcanMessage {
-            id: message,
-            data: [0; 16],
-            size: T as u8,
-        };
-        let len = T.min(16);
-        message.data[..len].copy_from_slice(&data[..len]);
-        message
+#[cfg(feature = "canopen")]
+fn receive_standard(rx_buffer: &rx_fifo::RxFifo) {
+    let mut guard = STANDARD_RECEIVE_BUF
+        .try_lock()
+        .expect("FDCAN rx ISR can't lock standard receive buffer");
+    if let Some(receive_buf) = guard.as_mut() {
+        if receive_buf.is_full() {
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        receive_buf.push(FdcanMessage {
+            id: rx_buffer.id(),
+            data: *rx_buffer.data(),
+            size: rx_buffer.len(),
+        });
     }
 }
 
@@ -399,17 +597,6 @@ fn init_buffer() -> &'static mut ReceiveBuffer {
     unsafe { &mut *UNINIT_BUFFER.as_mut_ptr() }
 }
 
-pub trait IncomingFdcanFrame {
-    // Unpack the message from a buffer.
-    fn unpack(message: FdcanMessage) -> Self;
-}
-
-pub trait OutgoingFdcanFrame {
-    // Pack the message into a buffer of up to 64 bytes, returning the number of bytes that were
-    // packed.
-    fn pack(&self) -> FdcanMessage;
-}
-
 #[interrupt]
 fn FDCAN1_INTR0_IT() {
     clear_pending_irq(device::Interrupt::FDCAN1_INTR0_IT);
@@ -63,6 +63,9 @@ impl RxFifo {
     pub fn data(&self) -> &[u32; 16] {
         &self.data
     }
+    pub fn extended(&self) -> bool {
+        self.r0.read().extended().bits() != 0
+    }
     pub fn id(&self) -> u32 {
         let r0 = self.r0.read();
         match r0.extended().bits() {
@@ -76,7 +79,7 @@ impl RxFifo {
             // Standard range is 0-8 if DLC is <= 8, otherwise it's always 8
             0 => len.min(8),
             _ => match len {
-                x if x <= 8 => 8,
+                x if x <= 8 => x,
                 x if x == 9 => 12,
                 x if x == 10 => 16,
                 x if x == 11 => 20,
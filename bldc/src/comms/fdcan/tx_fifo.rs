@@ -96,7 +96,7 @@ impl TxFifo {
                 .set(frame.id)
         });
         self.data.copy_from_slice(&frame.data);
-        let frame_size_bytes = match frame.size * 4 {
+        let frame_size_bytes = match frame.size {
             x if x <= 8 => x,
             x if x <= 12 => 9,
             x if x <= 16 => 10,
@@ -120,4 +120,31 @@ impl TxFifo {
                 .set(frame_size_bytes)
         });
     }
+    // A classic frame with a standard ID, for CANopen. Anything past eight bytes is dropped.
+    #[cfg(feature = "canopen")]
+    pub fn assign_standard(&mut self, frame: &FdcanMessage) {
+        self.t0.update(|_, w| {
+            w.error_state()
+                .clear_bit()
+                .extended()
+                .clear_bit()
+                .remote_transmission()
+                .clear_bit()
+                .standard_id()
+                .set(frame.id & 0x7FF)
+        });
+        self.data.copy_from_slice(&frame.data);
+        self.t1.update(|_, w| {
+            w.message_marker()
+                .set(123)
+                .store_fifo()
+                .set_bit()
+                .fdcan_frame()
+                .clear_bit()
+                .bit_rate_switch()
+                .clear_bit()
+                .data_length()
+                .set(frame.size.min(8))
+        });
+    }
 }
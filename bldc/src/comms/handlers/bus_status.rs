@@ -0,0 +1,28 @@
+use crate::{
+    comms::{
+        fdcan,
+        messages::{FdcanID, GetBusStatusCmd, MessageID},
+    },
+    control_loops::Controller,
+};
+
+use super::{HandlesMessage, Nack};
+
+pub struct GetBusStatus {}
+
+impl GetBusStatus {
+    pub fn new() -> Self {
+        GetBusStatus {}
+    }
+}
+
+impl HandlesMessage<GetBusStatusCmd> for GetBusStatus {
+    fn handle(&self, _: &mut Controller, _: GetBusStatusCmd) -> Result<(), Nack> {
+        fdcan::send_message(&fdcan::bus_status());
+        Ok(())
+    }
+}
+
+impl FdcanID for GetBusStatus {
+    const ID: MessageID = MessageID::GetBusStatus;
+}
@@ -0,0 +1,85 @@
+use stm32g4::stm32g474 as device;
+use third_party::m4vga_rs::util::{spin_lock::SpinLock, sync::acquire_hw};
+
+use crate::{
+    comms::{
+        fdcan,
+        messages::{CalibrateHallsCmd, FdcanID, HallCalibrationMsg, MessageID},
+    },
+    config,
+    control_loops::calibrate_halls::{CalibrateHalls, HallCalibrationResult},
+    encoder::RotorSensorKind,
+    hall::{HallCalibrationStatus, HALL_SECTORS},
+    util::interrupts::block_interrupts,
+};
+
+use super::faults::fault_status;
+use super::{HandlesMessage, Nack};
+use crate::control_loops::Controller;
+
+// The loop finishes inside the control interrupt, so it just leaves the result here and main picks
+// it up through `take_result`. Applying it means touching the config, which we don't want to be
+// doing from the interrupt.
+static RESULT: SpinLock<Option<Option<HallCalibrationResult>>> = SpinLock::new(Some(None));
+
+fn finished(result: &HallCalibrationResult) {
+    *acquire_hw(&RESULT) = Some(*result);
+}
+
+fn calibration_msg(
+    status: HallCalibrationStatus,
+    angles: [f32; HALL_SECTORS],
+) -> HallCalibrationMsg {
+    HallCalibrationMsg {
+        status: status as u32,
+        angles,
+    }
+}
+
+// If a calibration's finished since last time, apply it and let the host know how it went.
+pub fn take_result() -> Option<HallCalibrationMsg> {
+    let result = block_interrupts([device::interrupt::ADC1_2], &RESULT, |mut result| {
+        result.take()
+    })?;
+    Some(match result {
+        Ok(calibration) => {
+            let mut updated = config::current();
+            updated.hall_angles = calibration.angles;
+            config::set(updated);
+            calibration_msg(HallCalibrationStatus::Ok, calibration.angles)
+        }
+        Err(status) => calibration_msg(status, [0.; HALL_SECTORS]),
+    })
+}
+
+pub struct StartHallCalibration {}
+
+impl StartHallCalibration {
+    pub fn new() -> Self {
+        StartHallCalibration {}
+    }
+}
+
+impl HandlesMessage<CalibrateHallsCmd> for StartHallCalibration {
+    fn handle(&self, controller: &mut Controller, cmd: CalibrateHallsCmd) -> Result<(), Nack> {
+        // The sensor's picked at boot, so this is what's actually running unless someone's changed
+        // the param without resetting. Close enough.
+        if RotorSensorKind::from_bits(config::current().rotor_sensor) != RotorSensorKind::Halls {
+            fdcan::send_message(&calibration_msg(
+                HallCalibrationStatus::NotHalls,
+                [0.; HALL_SECTORS],
+            ));
+            return Err(Nack::Rejected);
+        }
+        controller
+            .set_loop(CalibrateHalls::new(cmd.current, finished))
+            .map_err(|faults| {
+                fdcan::send_message(&fault_status(faults));
+                Nack::Faulted(faults.bits())
+            })
+    }
+}
+
+impl FdcanID for StartHallCalibration {
+    const ID: MessageID = MessageID::CalibrateHalls;
+}
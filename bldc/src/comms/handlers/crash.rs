@@ -0,0 +1,42 @@
+use crate::{
+    comms::{
+        fdcan::{self, FdcanMessage},
+        messages::{CrashLocationMsg, CrashMessageMsg, FdcanID, GetCrashRecordCmd, MessageID},
+    },
+    control_loops::Controller,
+    crash,
+};
+
+use super::{HandlesMessage, Nack};
+
+pub struct GetCrashRecord {}
+
+impl GetCrashRecord {
+    pub fn new() -> Self {
+        GetCrashRecord {}
+    }
+}
+
+impl HandlesMessage<GetCrashRecordCmd> for GetCrashRecord {
+    fn handle(&self, _: &mut Controller, _: GetCrashRecordCmd) -> Result<(), Nack> {
+        match crash::previous() {
+            // Nothing to report; let the host know that rather than leave it waiting.
+            None => fdcan::send_message(&FdcanMessage::new(MessageID::CrashLocation.into(), &[])),
+            Some(record) => {
+                fdcan::send_message(&CrashLocationMsg {
+                    line: record.line,
+                    column: record.column,
+                    file: record.file_bytes(),
+                });
+                fdcan::send_message(&CrashMessageMsg {
+                    message: record.message_bytes(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FdcanID for GetCrashRecord {
+    const ID: MessageID = MessageID::GetCrashRecord;
+}
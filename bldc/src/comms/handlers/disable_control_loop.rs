@@ -1,19 +1,8 @@
-use crate::comms::{
-    fdcan::FdcanMessage,
-    messages::{FdcanID, MessageID},
-};
+use crate::comms::messages::{DisableControlLoopCmd, FdcanID, MessageID};
 
-use super::HandlesMessage;
+use super::{HandlesMessage, Nack};
 use crate::control_loops::Controller;
 
-pub struct Cmd {}
-
-impl From<FdcanMessage> for Cmd {
-    fn from(_: FdcanMessage) -> Self {
-        Cmd {}
-    }
-}
-
 pub struct DisableControlLoop {}
 
 impl DisableControlLoop {
@@ -22,9 +11,10 @@ impl DisableControlLoop {
     }
 }
 
-impl HandlesMessage<Cmd> for DisableControlLoop {
-    fn handle(&self, controller: &mut Controller, _cmd: Cmd) {
+impl HandlesMessage<DisableControlLoopCmd> for DisableControlLoop {
+    fn handle(&self, controller: &mut Controller, _cmd: DisableControlLoopCmd) -> Result<(), Nack> {
         controller.disable_loop();
+        Ok(())
     }
 }
 
@@ -0,0 +1,39 @@
+use crate::{
+    comms::{
+        fdcan,
+        messages::{DrvRegistersMsg, DrvStatusMsg, DumpDrvRegistersCmd, FdcanID, MessageID},
+    },
+    control_loops::Controller,
+    gate_driver::{self, DrvStatus},
+};
+
+use super::{HandlesMessage, Nack};
+
+// Sent from `listen` whenever the DRV's fault status changes.
+pub fn drv_status(status: DrvStatus) -> DrvStatusMsg {
+    DrvStatusMsg {
+        fault_status_1: status.fault_status_1 as u32,
+        fault_status_2: status.fault_status_2 as u32,
+    }
+}
+
+pub struct DumpDrvRegisters {}
+
+impl DumpDrvRegisters {
+    pub fn new() -> Self {
+        DumpDrvRegisters {}
+    }
+}
+
+impl HandlesMessage<DumpDrvRegistersCmd> for DumpDrvRegisters {
+    fn handle(&self, _: &mut Controller, _: DumpDrvRegistersCmd) -> Result<(), Nack> {
+        fdcan::send_message(&DrvRegistersMsg {
+            registers: gate_driver::dump_registers().map(|register| register as u32),
+        });
+        Ok(())
+    }
+}
+
+impl FdcanID for DumpDrvRegisters {
+    const ID: MessageID = MessageID::DumpDrvRegisters;
+}
@@ -0,0 +1,94 @@
+use crate::{
+    comms::{
+        fdcan::{self, FdcanMessage},
+        messages::{ClearFaultsCmd, FaultStatusMsg, FdcanID, GetFaultsCmd, MessageID},
+    },
+    control_loops::Controller,
+    fault::{self, Faults},
+    gate_driver,
+};
+use protocol::DecodeError;
+
+use super::{HandlesMessage, Nack};
+
+// Querying and clearing latched faults. Clearing the last critical fault puts the loop interrupt
+// back to idling, same as at the start of `listen`; it's still up to the host to start a control
+// loop again. The gate driver's own latched faults get cleared along with ours, otherwise they'd
+// just get raised again on the next poll.
+
+// Also used by CANopen's fault reset.
+pub fn clear_faults(controller: &Controller, faults: Faults) {
+    let was_tripped = fault::tripped();
+    gate_driver::clear_faults();
+    fault::clear(faults);
+    if was_tripped {
+        controller.enable_loop().ok();
+    }
+}
+
+// `raised` is whatever's been raised since the last report.
+pub fn fault_status(raised: Faults) -> FaultStatusMsg {
+    let active = fault::active();
+    FaultStatusMsg {
+        active: active.bits(),
+        critical: active.critical().bits(),
+        raised: raised.bits(),
+    }
+}
+
+pub struct GetFaults {}
+
+impl GetFaults {
+    pub fn new() -> Self {
+        GetFaults {}
+    }
+}
+
+impl HandlesMessage<GetFaultsCmd> for GetFaults {
+    fn handle(&self, _: &mut Controller, _: GetFaultsCmd) -> Result<(), Nack> {
+        fdcan::send_message(&fault_status(Faults::default()));
+        Ok(())
+    }
+}
+
+impl FdcanID for GetFaults {
+    const ID: MessageID = MessageID::GetFaults;
+}
+
+// An empty message clears everything, so this one needs to know how long the frame was.
+pub struct Cmd {
+    pub faults: Faults,
+}
+
+impl TryFrom<FdcanMessage> for Cmd {
+    type Error = DecodeError;
+
+    fn try_from(message: FdcanMessage) -> Result<Self, Self::Error> {
+        Ok(Cmd {
+            faults: match message.size {
+                0 => Faults::ALL,
+                _ => Faults::from_bits(ClearFaultsCmd::try_from(message)?.faults),
+            },
+        })
+    }
+}
+
+pub struct ClearFaults {}
+
+impl ClearFaults {
+    pub fn new() -> Self {
+        ClearFaults {}
+    }
+}
+
+impl HandlesMessage<Cmd> for ClearFaults {
+    fn handle(&self, controller: &mut Controller, cmd: Cmd) -> Result<(), Nack> {
+        clear_faults(controller, cmd.faults);
+        fdcan::send_message(&fault_status(Faults::default()));
+        Ok(())
+    }
+}
+
+impl FdcanID for ClearFaults {
+    const ID: MessageID = MessageID::ClearFaults;
+}
@@ -0,0 +1,32 @@
+use crate::{
+    comms::{
+        fdcan,
+        group::GroupSetpoints,
+        messages::{FdcanID, MessageID},
+    },
+    control_loops::{pos_vel_control::PositionVelocity, Controller},
+};
+
+use super::{HandlesMessage, Nack};
+
+pub struct GroupSetPosVel {}
+
+impl GroupSetPosVel {
+    pub fn new() -> Self {
+        GroupSetPosVel {}
+    }
+}
+
+impl HandlesMessage<GroupSetpoints> for GroupSetPosVel {
+    fn handle(&self, _: &mut Controller, cmd: GroupSetpoints) -> Result<(), Nack> {
+        // Most of the frame is for someone else.
+        if let Some(setpoint) = cmd.find(fdcan::node().node) {
+            PositionVelocity::stage(setpoint.position, setpoint.velocity);
+        }
+        Ok(())
+    }
+}
+
+impl FdcanID for GroupSetPosVel {
+    const ID: MessageID = MessageID::GroupSetPosVel;
+}
@@ -0,0 +1,38 @@
+use crate::{
+    comms::messages::{FdcanID, HeartbeatCmd, MessageID, StatusMsg},
+    control_loops::{command_timeout, Controller},
+    fault,
+};
+
+use super::{HandlesMessage, Nack};
+
+// Our half of the heartbeat. Sent from `listen` at `heartbeat_rate`.
+pub fn status(controller: &Controller) -> StatusMsg {
+    let faults = fault::active();
+    StatusMsg {
+        mode: controller.mode() as u32,
+        state: controller.state() as u32,
+        active: faults.bits(),
+        critical: faults.critical().bits(),
+    }
+}
+
+// The host's half. Carries nothing; it just has to keep showing up.
+pub struct Heartbeat {}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {}
+    }
+}
+
+impl HandlesMessage<HeartbeatCmd> for Heartbeat {
+    fn handle(&self, _: &mut Controller, _: HeartbeatCmd) -> Result<(), Nack> {
+        command_timeout::heartbeat();
+        Ok(())
+    }
+}
+
+impl FdcanID for Heartbeat {
+    const ID: MessageID = MessageID::Heartbeat;
+}
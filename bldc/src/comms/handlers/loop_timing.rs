@@ -0,0 +1,46 @@
+use crate::{
+    comms::{
+        fdcan,
+        messages::{FdcanID, GetLoopTimingCmd, LoopTimingMsg, MessageID},
+    },
+    control_loops::{timing, Controller},
+};
+
+use super::{HandlesMessage, Nack};
+
+// How long the loop interrupt's been taking.
+pub fn loop_timing() -> LoopTimingMsg {
+    let timing = timing::current();
+    LoopTimingMsg {
+        samples: timing.samples,
+        overruns: timing.overruns,
+        min: match timing.samples {
+            0 => 0.,
+            _ => timing::cycles_to_us(timing.min),
+        },
+        average: timing::cycles_to_us(timing.average()),
+        max: timing::cycles_to_us(timing.max),
+    }
+}
+
+pub struct GetLoopTiming {}
+
+impl GetLoopTiming {
+    pub fn new() -> Self {
+        GetLoopTiming {}
+    }
+}
+
+impl HandlesMessage<GetLoopTimingCmd> for GetLoopTiming {
+    fn handle(&self, _: &mut Controller, cmd: GetLoopTimingCmd) -> Result<(), Nack> {
+        fdcan::send_message(&loop_timing());
+        if cmd.reset != 0 {
+            timing::reset();
+        }
+        Ok(())
+    }
+}
+
+impl FdcanID for GetLoopTiming {
+    const ID: MessageID = MessageID::GetLoopTiming;
+}
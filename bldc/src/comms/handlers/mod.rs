@@ -1,22 +1,53 @@
+pub mod bus_status;
+pub mod calibrate_halls;
+pub mod crash;
 pub mod disable_control_loop;
+pub mod drv;
+pub mod faults;
+pub mod group_set_pos_vel;
+pub mod heartbeat;
+pub mod loop_timing;
+pub mod params;
 pub mod pos_vel_control;
+pub mod reboot;
+pub mod scope;
 pub mod set_pos_vel;
+pub mod telemetry;
 pub mod torque_control;
 
 use crate::control_loops::Controller;
+use protocol::messages::AckMsg;
+use protocol::DecodeError;
 
-use super::fdcan::FdcanMessage;
+use super::fdcan::{FdcanMessage, Request};
 
+pub use protocol::ack::{reply, Nack};
+
+use bus_status::GetBusStatus;
+use calibrate_halls::StartHallCalibration;
+use crash::GetCrashRecord;
 use disable_control_loop::DisableControlLoop;
+use drv::DumpDrvRegisters;
+use faults::{ClearFaults, GetFaults};
+use group_set_pos_vel::GroupSetPosVel;
+use heartbeat::Heartbeat;
+use loop_timing::GetLoopTiming;
+use params::{GetParam, ListParams, RestoreDefaultParams, SaveParams, SetParam};
 use pos_vel_control::EnterPosVelControl;
+use reboot::RebootToBootloader;
+use scope::{ArmScope, GetScopeStatus, ReadScope, TriggerScope};
 use set_pos_vel::SetPosVel;
+use telemetry::{StartTelemetry, StopTelemetry};
 use torque_control::EnterTorqueControl;
 
+// Anything that isn't `Ok` goes back to the host in the command's ack, if it asked for one. Frames
+// that don't decode (too short, NaNs, out of range) never make it as far as `handle`; see
+// `process`.
 trait HandlesMessage<T>
 where
-    T: From<FdcanMessage>,
+    T: TryFrom<FdcanMessage, Error = DecodeError>,
 {
-    fn handle(&self, controller: &mut Controller, msg: T);
+    fn handle(&self, controller: &mut Controller, msg: T) -> Result<(), Nack>;
 }
 
 // This implements effectively the same thing as the `enum_dispatch` crate. However, it currently
@@ -35,11 +66,20 @@ macro_rules! dispatchable_enum {
         $( from_impl!($n { $x }); )*
 
         impl $n {
-            pub fn process(&self, controller: &mut Controller, msg: FdcanMessage) {
+            // Anything that doesn't decode is dropped, and reported back whether or not the host
+            // asked for an ack.
+            pub fn process(&self, controller: &mut Controller, request: Request) -> Option<AckMsg> {
                 use $n::*;
-                match self {
-                    $( $x(inner) => inner.handle(controller, msg.into()), )*
-                }
+                let command = request.message.id;
+                let result = match self {
+                    $(
+                        $x(inner) => match request.message.try_into() {
+                            Ok(msg) => inner.handle(controller, msg),
+                            Err(error) => Err(Nack::Malformed(error)),
+                        },
+                    )*
+                };
+                reply(command, request.transaction, result)
             }
         }
     };
@@ -63,4 +103,25 @@ dispatchable_enum!(MessageHandler {
     EnterPosVelControl,
     SetPosVel,
     DisableControlLoop,
+    GetParam,
+    SetParam,
+    ListParams,
+    SaveParams,
+    RestoreDefaultParams,
+    GetFaults,
+    ClearFaults,
+    DumpDrvRegisters,
+    GetLoopTiming,
+    GetCrashRecord,
+    Heartbeat,
+    GroupSetPosVel,
+    StartTelemetry,
+    StopTelemetry,
+    ArmScope,
+    TriggerScope,
+    GetScopeStatus,
+    ReadScope,
+    RebootToBootloader,
+    GetBusStatus,
+    StartHallCalibration,
 });
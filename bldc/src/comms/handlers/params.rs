@@ -0,0 +1,236 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    comms::{
+        fdcan,
+        messages::{
+            FdcanID, GetParamCmd, ListParamsCmd, MessageID, ParamAckMsg, ParamInfoMsg,
+            ParamValueMsg, RestoreDefaultParamsCmd, SaveParamsCmd, SetParamCmd, PARAM_NAME_BYTES,
+        },
+    },
+    config::{
+        self,
+        params::{self, Param, ParamError, ParamValue, PARAMS},
+        Config, FlashError,
+    },
+    control_loops::Controller,
+};
+
+use super::{HandlesMessage, Nack};
+
+// Get/set/list parameters, plus saving them to flash or going back to the defaults. Every request
+// gets a reply so the host can tell it went through.
+//
+// Sets take effect immediately in RAM. The current loop gains and gear ratio are picked up by a
+// running loop on its next iteration; everything else (pole pairs, sensing gains, ...) is only read
+// while configuring the peripherals and needs a save and reset. Nothing is persisted until a
+// `SaveParams`.
+
+pub use protocol::params::ParamStatus;
+
+impl From<ParamError> for ParamStatus {
+    fn from(error: ParamError) -> Self {
+        match error {
+            ParamError::UnknownParam => ParamStatus::UnknownParam,
+            ParamError::WrongType => ParamStatus::WrongType,
+            ParamError::OutOfRange => ParamStatus::OutOfRange,
+        }
+    }
+}
+
+impl From<FlashError> for ParamStatus {
+    fn from(_: FlashError) -> Self {
+        ParamStatus::FlashError
+    }
+}
+
+// Reply to `GetParam`/`SetParam`, with the value the parameter has after the request.
+fn param_value(id: u16, status: ParamStatus, value: Option<ParamValue>) -> ParamValueMsg {
+    let (kind, bits) = match value {
+        Some(value) => (value.kind() as u8, value.to_bits()),
+        None => (0, 0),
+    };
+    ParamValueMsg {
+        id,
+        kind,
+        status: status as u8,
+        bits,
+    }
+}
+
+// One of these is sent per parameter in reply to `ListParams`. Names that don't fit get cut short.
+fn param_info(index: usize, param: &Param, config: &Config) -> ParamInfoMsg {
+    let mut name = [0; PARAM_NAME_BYTES];
+    for (byte, c) in name.iter_mut().zip(param.info.name.bytes()) {
+        *byte = c;
+    }
+    ParamInfoMsg {
+        id: param.info.id,
+        kind: param.info.kind as u16,
+        index: index as u16,
+        count: PARAMS.len() as u16,
+        min: param.info.min.to_bits(),
+        max: param.info.max.to_bits(),
+        value: param.get(config).to_bits(),
+        name,
+    }
+}
+
+// Reply to `SaveParams`/`RestoreDefaultParams`.
+fn param_ack(request: MessageID, status: ParamStatus) -> ParamAckMsg {
+    ParamAckMsg {
+        request: request as u32,
+        status: status as u32,
+    }
+}
+
+// The reply says what went wrong; the ack only needs to say that something did.
+fn result(status: ParamStatus) -> Result<(), Nack> {
+    match status {
+        ParamStatus::Ok => Ok(()),
+        _ => Err(Nack::Rejected),
+    }
+}
+
+pub struct GetParam {}
+
+impl GetParam {
+    pub fn new() -> Self {
+        GetParam {}
+    }
+}
+
+impl HandlesMessage<GetParamCmd> for GetParam {
+    fn handle(&self, _: &mut Controller, cmd: GetParamCmd) -> Result<(), Nack> {
+        let (status, value) = match params::get(&config::current(), cmd.id) {
+            Ok(value) => (ParamStatus::Ok, Some(value)),
+            Err(error) => (error.into(), None),
+        };
+        fdcan::send_message(&param_value(cmd.id, status, value));
+        result(status)
+    }
+}
+
+impl FdcanID for GetParam {
+    const ID: MessageID = MessageID::GetParam;
+}
+
+pub struct SetParam {}
+
+impl SetParam {
+    pub fn new() -> Self {
+        SetParam {}
+    }
+}
+
+impl HandlesMessage<SetParamCmd> for SetParam {
+    fn handle(&self, _: &mut Controller, cmd: SetParamCmd) -> Result<(), Nack> {
+        let mut config = config::current();
+        let set = params::find(cmd.id)
+            .ok_or(ParamError::UnknownParam)
+            .and_then(|param| {
+                param.set(&mut config, ParamValue::from_bits(param.info.kind, cmd.bits))?;
+                Ok(param.get(&config))
+            });
+        let (status, value) = match set {
+            Ok(value) => {
+                config::set(config);
+                (ParamStatus::Ok, Some(value))
+            }
+            Err(error) => (error.into(), params::get(&config, cmd.id).ok()),
+        };
+        fdcan::send_message(&param_value(cmd.id, status, value));
+        result(status)
+    }
+}
+
+impl FdcanID for SetParam {
+    const ID: MessageID = MessageID::SetParam;
+}
+
+pub struct ListParams {}
+
+impl ListParams {
+    pub fn new() -> Self {
+        ListParams {}
+    }
+}
+
+impl HandlesMessage<ListParamsCmd> for ListParams {
+    fn handle(&self, _: &mut Controller, _: ListParamsCmd) -> Result<(), Nack> {
+        let config = config::current();
+        for (index, param) in PARAMS.iter().enumerate() {
+            fdcan::send_message(&param_info(index, param, &config));
+        }
+        Ok(())
+    }
+}
+
+impl FdcanID for ListParams {
+    const ID: MessageID = MessageID::ListParams;
+}
+
+// Erasing a flash page holds up the main loop for tens of ms, which is a good chunk of the
+// watchdog's window. So `SaveParams` only asks for a save, and main does it right after servicing
+// the watchdog.
+static SAVE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn take_save_request() -> bool {
+    SAVE_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+// Persist the current config, and let the host know how it went.
+pub fn save() -> ParamAckMsg {
+    let status = match config::save() {
+        Ok(()) => ParamStatus::Ok,
+        Err(error) => error.into(),
+    };
+    param_ack(MessageID::SaveParams, status)
+}
+
+pub struct SaveParams {}
+
+impl SaveParams {
+    pub fn new() -> Self {
+        SaveParams {}
+    }
+}
+
+impl HandlesMessage<SaveParamsCmd> for SaveParams {
+    // The ack only says the save's been taken on; the `ParamAck` after it says how it went.
+    fn handle(&self, _: &mut Controller, _: SaveParamsCmd) -> Result<(), Nack> {
+        SAVE_REQUESTED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl FdcanID for SaveParams {
+    const ID: MessageID = MessageID::SaveParams;
+}
+
+pub struct RestoreDefaultParams {}
+
+impl RestoreDefaultParams {
+    pub fn new() -> Self {
+        RestoreDefaultParams {}
+    }
+}
+
+impl HandlesMessage<RestoreDefaultParamsCmd> for RestoreDefaultParams {
+    fn handle(&self, _: &mut Controller, _: RestoreDefaultParamsCmd) -> Result<(), Nack> {
+        // Keep the node's address, or the next reset would take it off the bus (or onto someone
+        // else's ID) with no way of finding it again.
+        let current = config::current();
+        config::set(Config {
+            node_id: current.node_id,
+            node_group: current.node_group,
+            ..Config::DEFAULT
+        });
+        fdcan::send_message(&param_ack(MessageID::RestoreDefaultParams, ParamStatus::Ok));
+        Ok(())
+    }
+}
+
+impl FdcanID for RestoreDefaultParams {
+    const ID: MessageID = MessageID::RestoreDefaultParams;
+}
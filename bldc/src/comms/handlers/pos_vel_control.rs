@@ -1,18 +1,11 @@
-use super::HandlesMessage;
-use crate::comms::fdcan::FdcanMessage;
+use super::faults::fault_status;
+use super::{HandlesMessage, Nack};
+use crate::comms::fdcan;
 
-use crate::comms::messages::{FdcanID, MessageID};
+use crate::comms::messages::{EnterPosVelControlCmd, FdcanID, MessageID};
 use crate::control_loops::pos_vel_control::PositionVelocity;
 use crate::control_loops::Controller;
 
-pub struct Cmd {}
-
-impl From<FdcanMessage> for Cmd {
-    fn from(_message: FdcanMessage) -> Self {
-        Cmd {}
-    }
-}
-
 pub struct EnterPosVelControl {}
 
 impl EnterPosVelControl {
@@ -21,9 +14,14 @@ impl EnterPosVelControl {
     }
 }
 
-impl HandlesMessage<Cmd> for EnterPosVelControl {
-    fn handle(&self, controller: &mut Controller, _cmd: Cmd) {
-        controller.set_loop(PositionVelocity::new());
+impl HandlesMessage<EnterPosVelControlCmd> for EnterPosVelControl {
+    fn handle(&self, controller: &mut Controller, _cmd: EnterPosVelControlCmd) -> Result<(), Nack> {
+        controller
+            .set_loop(PositionVelocity::new())
+            .map_err(|faults| {
+                fdcan::send_message(&fault_status(faults));
+                Nack::Faulted(faults.bits())
+            })
     }
 }
 
@@ -0,0 +1,27 @@
+use crate::boot;
+use crate::comms::fdcan;
+use crate::comms::messages::{FdcanID, MessageID, RebootToBootloaderCmd};
+
+use super::{HandlesMessage, Nack};
+use crate::control_loops::Controller;
+
+pub struct RebootToBootloader {}
+
+impl RebootToBootloader {
+    pub fn new() -> Self {
+        RebootToBootloader {}
+    }
+}
+
+impl HandlesMessage<RebootToBootloaderCmd> for RebootToBootloader {
+    fn handle(&self, controller: &mut Controller, _cmd: RebootToBootloaderCmd) -> Result<(), Nack> {
+        controller.disable_loop();
+        // The bootloader announces itself once it's up.
+        boot::reboot_to_bootloader(fdcan::node());
+        Ok(())
+    }
+}
+
+impl FdcanID for RebootToBootloader {
+    const ID: MessageID = MessageID::RebootToBootloader;
+}
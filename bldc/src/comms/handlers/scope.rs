@@ -0,0 +1,111 @@
+use crate::{
+    comms::{
+        fdcan,
+        messages::{
+            ArmScopeCmd, FdcanID, GetScopeStatusCmd, MessageID, ReadScopeCmd, ScopeDataMsg,
+            ScopeStatusMsg, TriggerScopeCmd,
+        },
+    },
+    control_loops::Controller,
+    scope,
+};
+use protocol::scope::{ScopeConfig, SCOPE_CHUNK_VALUES};
+
+use super::{HandlesMessage, Nack};
+
+// Scope captures; see `protocol::scope`. Arming and asking for the status both get a status back,
+// and one goes out by itself when a capture finishes (see `listen`).
+
+pub fn scope_status() -> ScopeStatusMsg {
+    scope::with_capture(|capture| capture.status())
+}
+
+pub struct ArmScope {}
+
+impl ArmScope {
+    pub fn new() -> Self {
+        ArmScope {}
+    }
+}
+
+impl HandlesMessage<ArmScopeCmd> for ArmScope {
+    fn handle(&self, _: &mut Controller, cmd: ArmScopeCmd) -> Result<(), Nack> {
+        // Anything we can't make sense of leaves the last capture be, which the status shows.
+        let armed = match ScopeConfig::from_cmd(&cmd) {
+            Some(config) => {
+                scope::with_capture(|capture| capture.arm(config));
+                Ok(())
+            }
+            None => Err(Nack::Rejected),
+        };
+        fdcan::send_message(&scope_status());
+        armed
+    }
+}
+
+impl FdcanID for ArmScope {
+    const ID: MessageID = MessageID::ArmScope;
+}
+
+pub struct TriggerScope {}
+
+impl TriggerScope {
+    pub fn new() -> Self {
+        TriggerScope {}
+    }
+}
+
+impl HandlesMessage<TriggerScopeCmd> for TriggerScope {
+    fn handle(&self, _: &mut Controller, _: TriggerScopeCmd) -> Result<(), Nack> {
+        scope::with_capture(|capture| capture.force());
+        Ok(())
+    }
+}
+
+impl FdcanID for TriggerScope {
+    const ID: MessageID = MessageID::TriggerScope;
+}
+
+pub struct GetScopeStatus {}
+
+impl GetScopeStatus {
+    pub fn new() -> Self {
+        GetScopeStatus {}
+    }
+}
+
+impl HandlesMessage<GetScopeStatusCmd> for GetScopeStatus {
+    fn handle(&self, _: &mut Controller, _: GetScopeStatusCmd) -> Result<(), Nack> {
+        fdcan::send_message(&scope_status());
+        Ok(())
+    }
+}
+
+impl FdcanID for GetScopeStatus {
+    const ID: MessageID = MessageID::GetScopeStatus;
+}
+
+pub struct ReadScope {}
+
+impl ReadScope {
+    pub fn new() -> Self {
+        ReadScope {}
+    }
+}
+
+impl HandlesMessage<ReadScopeCmd> for ReadScope {
+    fn handle(&self, _: &mut Controller, cmd: ReadScopeCmd) -> Result<(), Nack> {
+        let mut values = [0.; SCOPE_CHUNK_VALUES];
+        let count = scope::with_capture(|capture| capture.read(cmd.offset as usize, &mut values));
+        fdcan::send_message(&ScopeDataMsg {
+            offset: cmd.offset,
+            count: count as u32,
+            values,
+        });
+        Ok(())
+    }
+}
+
+impl FdcanID for ReadScope {
+    const ID: MessageID = MessageID::ReadScope;
+}
@@ -1,45 +1,21 @@
 use crate::{
-    comms::{
-        fdcan::FdcanMessage,
-        messages::{FdcanID, MessageID},
-    },
+    comms::messages::{FdcanID, MessageID, SetPosVelCmd},
     control_loops::{
         pos_vel_control::{PosVelState, PositionVelocity},
-        Controller,
+        Controller, LoopMode,
     },
 };
 
-use super::HandlesMessage;
-
-pub struct Cmd {
-    pub position: f32,
-    pub velocity: f32,
-    pub stiffness_gain: f32,
-    pub damping_gain: f32,
-    pub torque_constant: f32,
-}
-
-impl From<FdcanMessage> for Cmd {
-    fn from(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        Cmd {
-            position: f32::from_bits(buffer[0]),
-            velocity: f32::from_bits(buffer[1]),
-            stiffness_gain: f32::from_bits(buffer[2]),
-            damping_gain: f32::from_bits(buffer[3]),
-            torque_constant: f32::from_bits(buffer[4]),
-        }
-    }
-}
+use super::{HandlesMessage, Nack};
 
-impl Into<PosVelState> for Cmd {
-    fn into(self) -> PosVelState {
+impl From<SetPosVelCmd> for PosVelState {
+    fn from(cmd: SetPosVelCmd) -> Self {
         PosVelState {
-            position: self.position,
-            velocity: self.velocity,
-            stiffness_gain: self.stiffness_gain,
-            damping_gain: self.damping_gain,
-            torque_constant: self.torque_constant,
+            position: cmd.position,
+            velocity: cmd.velocity,
+            stiffness_gain: cmd.stiffness_gain,
+            damping_gain: cmd.damping_gain,
+            torque_constant: cmd.torque_constant,
         }
     }
 }
@@ -52,9 +28,14 @@ impl SetPosVel {
     }
 }
 
-impl HandlesMessage<Cmd> for SetPosVel {
-    fn handle(&self, _: &mut Controller, cmd: Cmd) {
+impl HandlesMessage<SetPosVelCmd> for SetPosVel {
+    fn handle(&self, controller: &mut Controller, cmd: SetPosVelCmd) -> Result<(), Nack> {
+        // Nothing to follow it. It'd be thrown away when the loop starts anyway.
+        if controller.mode() != LoopMode::PositionVelocity {
+            return Err(Nack::Rejected);
+        }
         PositionVelocity::command(cmd.into());
+        Ok(())
     }
 }
 
@@ -0,0 +1,43 @@
+use crate::comms::messages::{FdcanID, MessageID, StartTelemetryCmd, StopTelemetryCmd};
+use crate::control_loops::Controller;
+use crate::telemetry;
+
+use super::{HandlesMessage, Nack};
+
+pub struct StartTelemetry {}
+
+impl StartTelemetry {
+    pub fn new() -> Self {
+        StartTelemetry {}
+    }
+}
+
+impl HandlesMessage<StartTelemetryCmd> for StartTelemetry {
+    fn handle(&self, _: &mut Controller, cmd: StartTelemetryCmd) -> Result<(), Nack> {
+        telemetry::start(cmd.signals, cmd.decimation);
+        Ok(())
+    }
+}
+
+impl FdcanID for StartTelemetry {
+    const ID: MessageID = MessageID::StartTelemetry;
+}
+
+pub struct StopTelemetry {}
+
+impl StopTelemetry {
+    pub fn new() -> Self {
+        StopTelemetry {}
+    }
+}
+
+impl HandlesMessage<StopTelemetryCmd> for StopTelemetry {
+    fn handle(&self, _: &mut Controller, _: StopTelemetryCmd) -> Result<(), Nack> {
+        telemetry::stop();
+        Ok(())
+    }
+}
+
+impl FdcanID for StopTelemetry {
+    const ID: MessageID = MessageID::StopTelemetry;
+}
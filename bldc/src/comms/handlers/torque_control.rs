@@ -1,33 +1,16 @@
 use crate::{
     comms::{
-        fdcan::FdcanMessage,
-        messages::{FdcanID, MessageID},
+        fdcan,
+        messages::{FdcanID, MessageID, TorqueControlCmd},
     },
     control_loops::torque_control::TorqueControl,
     foc::DQCurrents,
 };
 
-use super::HandlesMessage;
+use super::faults::fault_status;
+use super::{HandlesMessage, Nack};
 use crate::control_loops::Controller;
 
-pub struct Cmd {
-    pub duration: f32,
-    pub currents: DQCurrents,
-}
-
-impl From<FdcanMessage> for Cmd {
-    fn from(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        Cmd {
-            duration: f32::from_bits(buffer[0]),
-            currents: DQCurrents {
-                q: f32::from_bits(buffer[1]),
-                d: f32::from_bits(buffer[2]),
-            },
-        }
-    }
-}
-
 pub struct EnterTorqueControl {}
 
 impl EnterTorqueControl {
@@ -36,9 +19,16 @@ impl EnterTorqueControl {
     }
 }
 
-impl HandlesMessage<Cmd> for EnterTorqueControl {
-    fn handle(&self, controller: &mut Controller, cmd: Cmd) {
-        controller.set_loop(TorqueControl::new(cmd.duration, cmd.currents));
+impl HandlesMessage<TorqueControlCmd> for EnterTorqueControl {
+    fn handle(&self, controller: &mut Controller, cmd: TorqueControlCmd) -> Result<(), Nack> {
+        let currents = DQCurrents { q: cmd.q, d: cmd.d };
+        // Let the host know why nothing's happening, even if it didn't ask for an ack.
+        controller
+            .set_loop(TorqueControl::new(cmd.duration, currents))
+            .map_err(|faults| {
+                fdcan::send_message(&fault_status(faults));
+                Nack::Faulted(faults.bits())
+            })
     }
 }
 
@@ -1,5 +1,7 @@
+pub mod bus_monitor;
 pub mod fdcan;
 pub mod handlers;
 pub mod messages;
 
 pub use handlers::MessageHandler;
+pub use protocol::{group, id};
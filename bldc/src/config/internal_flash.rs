@@ -0,0 +1,113 @@
+use super::storage::{Flash, FlashError};
+use crate::block_while;
+use stm32g4::stm32g474 as device;
+
+// Two pages of bank 1 just past the bootloader and boot record, assuming the default dual-bank
+// layout (DBANK=1, 2K pages). memory.x keeps the linker out of here. Images only ever run from bank
+// 2 (see `protocol::update`), so the control loop keeps fetching instructions while we're
+// erasing/programming.
+const CONFIG_ADDRESS: u32 = 0x0800_9000;
+const FIRST_PAGE: u8 = 18;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+pub struct InternalFlash {
+    flash: device::FLASH,
+}
+
+impl InternalFlash {
+    pub fn new(flash: device::FLASH) -> InternalFlash {
+        InternalFlash { flash }
+    }
+
+    fn unlock(&self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            // Safety: the key sequence is straight out of RM0440 3.3.5.
+            self.flash.keyr.write(|w| unsafe { w.keyr().bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.keyr().bits(KEY2) });
+        }
+    }
+
+    fn lock(&self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    // Wait for the current operation to finish, then check (and clear) any errors it raised.
+    fn finish(&self) -> Result<(), FlashError> {
+        block_while! { self.flash.sr.read().bsy().bit_is_set() }
+        let sr = self.flash.sr.read();
+        let result = if sr.wrperr().bit_is_set() {
+            Err(FlashError::WriteProtected)
+        } else if sr.progerr().bit_is_set()
+            || sr.pgaerr().bit_is_set()
+            || sr.sizerr().bit_is_set()
+            || sr.pgserr().bit_is_set()
+            || sr.operr().bit_is_set()
+        {
+            Err(FlashError::Programming)
+        } else {
+            Ok(())
+        };
+        // Safety: all of the status flags are write-1-to-clear.
+        self.flash.sr.write(|w| unsafe { w.bits(sr.bits()) });
+        result
+    }
+}
+
+impl Flash for InternalFlash {
+    const PAGE_SIZE: u32 = 2048;
+
+    fn read(&self, offset: u32, buffer: &mut [u32]) {
+        for (i, word) in buffer.iter_mut().enumerate() {
+            // Safety: the config pages are always mapped and readable.
+            *word = unsafe {
+                core::ptr::read_volatile((CONFIG_ADDRESS + offset + i as u32 * 4) as *const u32)
+            };
+        }
+    }
+
+    fn erase_page(&mut self, page: u32) -> Result<(), FlashError> {
+        self.unlock();
+        // Clear anything left over from a previous operation.
+        self.finish().ok();
+        // Safety: PNB is 7 bits, and we only ever touch our two pages.
+        self.flash.cr.modify(|_, w| unsafe {
+            w.per()
+                .set_bit()
+                .bker()
+                .clear_bit()
+                .pnb()
+                .bits(FIRST_PAGE + page as u8)
+        });
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.finish();
+        self.flash.cr.modify(|_, w| w.per().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn program(&mut self, offset: u32, data: &[u32]) -> Result<(), FlashError> {
+        self.unlock();
+        self.finish().ok();
+        self.flash.cr.modify(|_, w| w.pg().set_bit());
+        let mut result = Ok(());
+        let mut address = CONFIG_ADDRESS + offset;
+        for double_word in data.chunks(2) {
+            // Safety: programming happens one double word at a time, with the two halves written
+            // back to back. The address is double word aligned as per the `Flash` contract.
+            unsafe {
+                core::ptr::write_volatile(address as *mut u32, double_word[0]);
+                core::ptr::write_volatile((address + 4) as *mut u32, double_word[1]);
+            }
+            result = self.finish();
+            if result.is_err() {
+                break;
+            }
+            address += 8;
+        }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result
+    }
+}
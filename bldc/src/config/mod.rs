@@ -0,0 +1,301 @@
+use crate::control_loops::command_timeout::TimeoutAction;
+use crate::foc::Modulation;
+use crate::hall::HALL_SECTORS;
+use crate::util::{
+    buffered_state::{BufferedState, StateReader, StateWriter},
+    seq_lock::SeqLock,
+};
+use lazy_static::lazy_static;
+use protocol::params::{default_f32, default_u32};
+use third_party::m4vga_rs::util::spin_lock::SpinLock;
+
+mod internal_flash;
+pub mod params;
+mod storage;
+
+pub use internal_flash::InternalFlash;
+pub use storage::{ConfigStore, Flash, FlashError, LoadError};
+
+// Everything about the board and motor that used to be a hard-coded constant. Loaded from flash at
+// startup by `driver::take_hardware`, and read by whoever needs it via `config::current()`. Control
+// loops can't safely read the `SeqLock` from inside the interrupt (they'd spin forever if they
+// preempted a writer), so they get the parts they care about through `loop_config()` instead.
+
+// Bump whenever fields are added. Fields are serialized one word each, in order, and must only ever
+// be added to the end: records written by older firmware can still be read, with whatever fields
+// they're missing taking their defaults.
+pub const CONFIG_VERSION: u16 = 6;
+pub const CONFIG_WORDS: usize = 29;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    // Current loop PI gains and output clamp, shared by the d and q axes.
+    pub current_kp: f32,
+    pub current_ki: f32,
+    pub current_v_clamp: f32,
+    // Motor revolutions per output revolution.
+    pub gear_ratio: f32,
+    pub pole_pairs: u8,
+    pub velocity_observer_bandwidth: f32,
+    // Ratio of the v_bus divider.
+    pub v_bus_gain: f32,
+    // Amps per volt at the ADC, i.e. 1 / (CSA gain * shunt resistance).
+    pub sense_gain: f32,
+    // Hard limits checked every cycle while a control loop is running; see `protection`. Going past
+    // any of them for `protection_debounce` cycles in a row trips the bridge.
+    pub max_phase_current: f32,
+    pub max_v_bus: f32,
+    pub min_v_bus: f32,
+    pub protection_debounce: u32,
+    // Seconds a control loop can go without a fresh command or heartbeat before it gives up; see
+    // `command_timeout`. Zero disables it.
+    pub pos_vel_timeout: f32,
+    pub torque_timeout: f32,
+    // What to do once it's given up, as a `TimeoutAction`.
+    pub timeout_action: u32,
+    pub timeout_damping: f32,
+    // Rate of the `Status` heartbeat, in Hz. Zero disables it.
+    pub heartbeat_rate: f32,
+    // Where we sit on the CAN bus; see `comms::id`. Only read at startup.
+    pub node_id: u32,
+    pub node_group: u32,
+    // Where the rotor position comes from, as a `RotorSensorKind`. Only read at startup.
+    pub rotor_sensor: u32,
+    // Electrical angle at the middle of each hall sector; see `hall`. Only used with halls.
+    pub hall_angles: [f32; HALL_SECTORS],
+    // How the current loop's output is turned into duty cycles, as a `Modulation`.
+    pub modulation: u32,
+    // How far past the modulation's linear limit the voltage vector can go; 1 stays linear.
+    pub overmodulation: f32,
+    // Largest duty cycle any phase gets.
+    pub max_duty: f32,
+}
+
+impl Config {
+    // Straight out of `protocol::params`, so the host knows them too.
+    pub const DEFAULT: Config = Config {
+        current_kp: default_f32(0x01),
+        current_ki: default_f32(0x02),
+        current_v_clamp: default_f32(0x03),
+        gear_ratio: default_f32(0x04),
+        pole_pairs: default_u32(0x05) as u8,
+        velocity_observer_bandwidth: default_f32(0x06),
+        v_bus_gain: default_f32(0x07),
+        sense_gain: default_f32(0x08),
+        max_phase_current: default_f32(0x09),
+        max_v_bus: default_f32(0x0A),
+        min_v_bus: default_f32(0x0B),
+        protection_debounce: default_u32(0x0C),
+        pos_vel_timeout: default_f32(0x0D),
+        torque_timeout: default_f32(0x0E),
+        timeout_action: default_u32(0x0F),
+        timeout_damping: default_f32(0x10),
+        heartbeat_rate: default_f32(0x11),
+        node_id: default_u32(0x12),
+        node_group: default_u32(0x13),
+        rotor_sensor: default_u32(0x14),
+        hall_angles: [
+            default_f32(0x15),
+            default_f32(0x16),
+            default_f32(0x17),
+            default_f32(0x18),
+            default_f32(0x19),
+            default_f32(0x1A),
+        ],
+        modulation: default_u32(0x1B),
+        overmodulation: default_f32(0x1C),
+        max_duty: default_f32(0x1D),
+    };
+
+    // Anything that depends on more than one field, which the ranges in `params` can't catch on
+    // their own.
+    pub fn is_consistent(&self) -> bool {
+        self.min_v_bus <= self.max_v_bus
+    }
+
+    // Whether this is a config the params could have been set to, one at a time. Anything coming
+    // out of flash has to pass this before it gets used.
+    pub fn is_valid(&self) -> bool {
+        params::in_range(self) && self.is_consistent()
+    }
+
+    pub fn to_words(&self) -> [u32; CONFIG_WORDS] {
+        let hall = |sector: usize| self.hall_angles[sector].to_bits();
+        [
+            self.current_kp.to_bits(),
+            self.current_ki.to_bits(),
+            self.current_v_clamp.to_bits(),
+            self.gear_ratio.to_bits(),
+            self.pole_pairs as u32,
+            self.velocity_observer_bandwidth.to_bits(),
+            self.v_bus_gain.to_bits(),
+            self.sense_gain.to_bits(),
+            self.max_phase_current.to_bits(),
+            self.max_v_bus.to_bits(),
+            self.min_v_bus.to_bits(),
+            self.protection_debounce,
+            self.pos_vel_timeout.to_bits(),
+            self.torque_timeout.to_bits(),
+            self.timeout_action,
+            self.timeout_damping.to_bits(),
+            self.heartbeat_rate.to_bits(),
+            self.node_id,
+            self.node_group,
+            self.rotor_sensor,
+            hall(0),
+            hall(1),
+            hall(2),
+            hall(3),
+            hall(4),
+            hall(5),
+            self.modulation,
+            self.overmodulation.to_bits(),
+            self.max_duty.to_bits(),
+        ]
+    }
+
+    // Inverse of `to_words`. Any trailing fields missing from `words` get their default value, and
+    // any extra words (from newer firmware) are ignored.
+    pub fn from_words(words: &[u32]) -> Config {
+        let default = Config::DEFAULT;
+        let float =
+            |index: usize, default: f32| words.get(index).map_or(default, |w| f32::from_bits(*w));
+        Config {
+            current_kp: float(0, default.current_kp),
+            current_ki: float(1, default.current_ki),
+            current_v_clamp: float(2, default.current_v_clamp),
+            gear_ratio: float(3, default.gear_ratio),
+            pole_pairs: words.get(4).map_or(default.pole_pairs, |w| *w as u8),
+            velocity_observer_bandwidth: float(5, default.velocity_observer_bandwidth),
+            v_bus_gain: float(6, default.v_bus_gain),
+            sense_gain: float(7, default.sense_gain),
+            max_phase_current: float(8, default.max_phase_current),
+            max_v_bus: float(9, default.max_v_bus),
+            min_v_bus: float(10, default.min_v_bus),
+            protection_debounce: *words.get(11).unwrap_or(&default.protection_debounce),
+            pos_vel_timeout: float(12, default.pos_vel_timeout),
+            torque_timeout: float(13, default.torque_timeout),
+            timeout_action: *words.get(14).unwrap_or(&default.timeout_action),
+            timeout_damping: float(15, default.timeout_damping),
+            heartbeat_rate: float(16, default.heartbeat_rate),
+            node_id: *words.get(17).unwrap_or(&default.node_id),
+            node_group: *words.get(18).unwrap_or(&default.node_group),
+            rotor_sensor: *words.get(19).unwrap_or(&default.rotor_sensor),
+            hall_angles: core::array::from_fn(|sector| {
+                float(20 + sector, default.hall_angles[sector])
+            }),
+            modulation: *words.get(26).unwrap_or(&default.modulation),
+            overmodulation: float(27, default.overmodulation),
+            max_duty: float(28, default.max_duty),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::DEFAULT
+    }
+}
+
+// The parts of the config that a running control loop (or the loop interrupt itself) picks up on the
+// fly.
+#[derive(Clone, Copy)]
+pub struct LoopConfig {
+    pub current_kp: f32,
+    pub current_ki: f32,
+    pub current_v_clamp: f32,
+    pub gear_ratio: f32,
+    pub max_phase_current: f32,
+    pub max_v_bus: f32,
+    pub min_v_bus: f32,
+    pub protection_debounce: u32,
+    pub pos_vel_timeout: f32,
+    pub torque_timeout: f32,
+    pub timeout_action: TimeoutAction,
+    pub timeout_damping: f32,
+    pub hall_angles: [f32; HALL_SECTORS],
+    pub modulation: Modulation,
+    pub overmodulation: f32,
+    pub max_duty: f32,
+}
+
+impl From<&Config> for LoopConfig {
+    fn from(config: &Config) -> Self {
+        LoopConfig {
+            current_kp: config.current_kp,
+            current_ki: config.current_ki,
+            current_v_clamp: config.current_v_clamp,
+            gear_ratio: config.gear_ratio,
+            max_phase_current: config.max_phase_current,
+            max_v_bus: config.max_v_bus,
+            min_v_bus: config.min_v_bus,
+            protection_debounce: config.protection_debounce,
+            pos_vel_timeout: config.pos_vel_timeout,
+            torque_timeout: config.torque_timeout,
+            timeout_action: TimeoutAction::from_bits(config.timeout_action),
+            timeout_damping: config.timeout_damping,
+            hall_angles: config.hall_angles,
+            modulation: Modulation::from_bits(config.modulation),
+            overmodulation: config.overmodulation,
+            max_duty: config.max_duty,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: SeqLock<Config> = SeqLock::new(Config::DEFAULT);
+}
+
+static LOOP_CONFIG_BUFFER: SpinLock<Option<BufferedState<LoopConfig>>> = SpinLock::new(None);
+static LOOP_CONFIG: SpinLock<Option<(StateReader<LoopConfig>, StateWriter<LoopConfig>)>> =
+    SpinLock::new(None);
+static STORE: SpinLock<Option<ConfigStore<InternalFlash>>> = SpinLock::new(None);
+
+pub fn current() -> Config {
+    CONFIG.read()
+}
+
+// Replace the config. Control loops see the change on their next iteration.
+pub fn set(config: Config) {
+    *CONFIG.lock_write() = config;
+    with_loop_config(|(_, writer)| *writer.update() = LoopConfig::from(&config));
+}
+
+// A reader for the loop-critical parts of the config. Must be called outside of the control loop
+// interrupt; the reader itself is then safe to use inside it.
+pub fn loop_config() -> StateReader<LoopConfig> {
+    with_loop_config(|(reader, _)| reader.clone())
+}
+
+fn with_loop_config<T>(
+    f: impl FnOnce(&mut (StateReader<LoopConfig>, StateWriter<LoopConfig>)) -> T,
+) -> T {
+    let mut loop_config = LOOP_CONFIG.lock();
+    let loop_config = loop_config.get_or_insert_with(|| {
+        let mut buffer = LOOP_CONFIG_BUFFER.lock();
+        *buffer = Some(BufferedState::new(LoopConfig::from(&current())));
+        buffer
+            .as_mut()
+            .expect("No loop config buffer to split")
+            .split()
+    });
+    f(loop_config)
+}
+
+// Load the config out of flash (or fall back to the defaults), and hang on to the store so that
+// `save` can write to it later.
+pub fn donate_store(store: ConfigStore<InternalFlash>) -> Result<Config, LoadError> {
+    let loaded = store.load();
+    set(loaded.unwrap_or_default());
+    *STORE.lock() = Some(store);
+    loaded
+}
+
+// Persist the current config.
+pub fn save() -> Result<(), FlashError> {
+    STORE
+        .lock()
+        .as_mut()
+        .expect("Config store not donated")
+        .save(&current())
+}
@@ -0,0 +1,117 @@
+use super::Config;
+
+// Registry of the tunable parts of `Config`, so they can be inspected and changed over FDCAN by ID
+// instead of reflashing. The IDs, names, ranges and defaults all live in `protocol::params`; this
+// just hooks each one up to its field.
+
+pub use protocol::params::{ParamInfo, ParamStatus, ParamType, ParamValue};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamError {
+    UnknownParam,
+    WrongType,
+    OutOfRange,
+}
+
+pub struct Param {
+    pub info: &'static ParamInfo,
+    get: fn(&Config) -> ParamValue,
+    set: fn(&mut Config, ParamValue),
+}
+
+impl Param {
+    pub fn get(&self, config: &Config) -> ParamValue {
+        (self.get)(config)
+    }
+
+    pub fn set(&self, config: &mut Config, value: ParamValue) -> Result<(), ParamError> {
+        self.check(value)?;
+        // e.g. `min_v_bus` can't go past `max_v_bus`, whichever one's being set.
+        let mut updated = *config;
+        (self.set)(&mut updated, value);
+        if !updated.is_consistent() {
+            return Err(ParamError::OutOfRange);
+        }
+        *config = updated;
+        Ok(())
+    }
+
+    fn check(&self, value: ParamValue) -> Result<(), ParamError> {
+        self.info.check(value).map_err(|status| match status {
+            ParamStatus::WrongType => ParamError::WrongType,
+            _ => ParamError::OutOfRange,
+        })
+    }
+}
+
+// Array fields get one param per element, e.g. `hall_angles[2]` is `hall_angles_2`.
+macro_rules! params {
+    ( $( $id:literal => $field:ident $([$index:literal])?: $kind:ident, )* ) => {
+        pub static PARAMS: &[Param] = &[
+            $(
+                Param {
+                    info: protocol::params::info($id),
+                    get: |config| ParamValue::$kind(config.$field $([$index])? as _),
+                    set: |config, value| {
+                        if let ParamValue::$kind(value) = value {
+                            config.$field $([$index])? = value as _;
+                        }
+                    },
+                },
+            )*
+        ];
+    };
+}
+
+params! {
+    0x01 => current_kp: F32,
+    0x02 => current_ki: F32,
+    0x03 => current_v_clamp: F32,
+    0x04 => gear_ratio: F32,
+    0x05 => pole_pairs: U32,
+    0x06 => velocity_observer_bandwidth: F32,
+    0x07 => v_bus_gain: F32,
+    0x08 => sense_gain: F32,
+    0x09 => max_phase_current: F32,
+    0x0A => max_v_bus: F32,
+    0x0B => min_v_bus: F32,
+    0x0C => protection_debounce: U32,
+    0x0D => pos_vel_timeout: F32,
+    0x0E => torque_timeout: F32,
+    0x0F => timeout_action: U32,
+    0x10 => timeout_damping: F32,
+    0x11 => heartbeat_rate: F32,
+    0x12 => node_id: U32,
+    0x13 => node_group: U32,
+    0x14 => rotor_sensor: U32,
+    0x15 => hall_angles[0]: F32,
+    0x16 => hall_angles[1]: F32,
+    0x17 => hall_angles[2]: F32,
+    0x18 => hall_angles[3]: F32,
+    0x19 => hall_angles[4]: F32,
+    0x1A => hall_angles[5]: F32,
+    0x1B => modulation: U32,
+    0x1C => overmodulation: F32,
+    0x1D => max_duty: F32,
+}
+
+// Whether every param in `config` is somewhere `set` would have let it go.
+pub fn in_range(config: &Config) -> bool {
+    PARAMS
+        .iter()
+        .all(|param| param.check(param.get(config)).is_ok())
+}
+
+pub fn find(id: u16) -> Option<&'static Param> {
+    PARAMS.iter().find(|param| param.info.id == id)
+}
+
+pub fn get(config: &Config, id: u16) -> Result<ParamValue, ParamError> {
+    find(id)
+        .map(|param| param.get(config))
+        .ok_or(ParamError::UnknownParam)
+}
+
+pub fn set(config: &mut Config, id: u16, value: ParamValue) -> Result<(), ParamError> {
+    find(id).ok_or(ParamError::UnknownParam)?.set(config, value)
+}
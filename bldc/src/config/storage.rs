@@ -0,0 +1,217 @@
+use super::{Config, CONFIG_VERSION, CONFIG_WORDS};
+use crate::util::crc::crc32;
+
+// Config records, double-buffered across two flash pages.
+//
+// Each save appends a new record after the last one in the active page, so a page is only erased
+// once every few saves. When the active page fills up, the other page is erased and the record goes
+// at its start. The page holding the previous record is never touched while writing, so if power
+// is lost partway through a write or erase there's still a complete record to fall back to. On
+// load, both pages are scanned and the newest record with a good CRC wins.
+//
+// Record layout, in words:
+//   0: MAGIC
+//   1: CONFIG_VERSION | payload length << 16
+//   2: sequence number
+//   3..: payload (`Config::to_words`)
+//   then a CRC32 of everything before it, padded out to a whole double word.
+
+const MAGIC: u32 = 0x5049_4E4F;
+const HEADER_WORDS: usize = 3;
+// Upper bound on the payload of any record we're willing to read, including ones from newer
+// firmware with more fields than we know about.
+const MAX_PAYLOAD_WORDS: usize = 64;
+const ERASED: u32 = 0xFFFF_FFFF;
+const RECORD_WORDS: usize = record_words(CONFIG_WORDS);
+
+// The G4 programs a double word at a time, so records are padded out to an even number of words.
+const fn record_words(payload_words: usize) -> usize {
+    (HEADER_WORDS + payload_words + 1 + 1) & !1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlashError {
+    WriteProtected,
+    Programming,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadError {
+    // Nothing has ever been saved.
+    Empty,
+    // There's something in flash, but none of it is a valid record.
+    Corrupted,
+}
+
+// Access to the two pages set aside for config. Offsets are in bytes from the start of the first
+// page. `program` is only ever called with whole double words at double word aligned offsets, and
+// only on erased flash.
+pub trait Flash {
+    const PAGE_SIZE: u32;
+
+    fn read(&self, offset: u32, buffer: &mut [u32]);
+    fn erase_page(&mut self, page: u32) -> Result<(), FlashError>;
+    fn program(&mut self, offset: u32, data: &[u32]) -> Result<(), FlashError>;
+}
+
+struct Record {
+    sequence: u32,
+    config: Config,
+}
+
+struct PageScan {
+    newest: Option<Record>,
+    // Offset of the first free byte after the last record.
+    end: u32,
+    corrupted: bool,
+}
+
+pub struct ConfigStore<F: Flash> {
+    flash: F,
+    // Where the next record goes.
+    page: u32,
+    offset: u32,
+    sequence: u32,
+    loaded: Result<Config, LoadError>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    pub fn new(flash: F) -> ConfigStore<F> {
+        let mut store = ConfigStore {
+            flash,
+            page: 0,
+            offset: 0,
+            sequence: 0,
+            loaded: Err(LoadError::Empty),
+        };
+        let scans = [store.scan_page(0), store.scan_page(1)];
+
+        let mut newest: Option<(u32, &Record)> = None;
+        for (page, scan) in scans.iter().enumerate() {
+            if let Some(record) = &scan.newest {
+                match newest {
+                    Some((_, best)) if !is_newer(record.sequence, best.sequence) => (),
+                    _ => newest = Some((page as u32, record)),
+                }
+            }
+        }
+        match newest {
+            Some((page, record)) => {
+                store.page = page;
+                store.offset = scans[page as usize].end;
+                store.sequence = record.sequence;
+                store.loaded = Ok(record.config);
+            }
+            None => {
+                store.offset = scans[0].end;
+                if scans.iter().any(|scan| scan.corrupted) {
+                    store.loaded = Err(LoadError::Corrupted);
+                }
+            }
+        }
+        store
+    }
+
+    // The most recently saved config, or why there isn't one.
+    pub fn load(&self) -> Result<Config, LoadError> {
+        self.loaded
+    }
+
+    pub fn save(&mut self, config: &Config) -> Result<(), FlashError> {
+        let sequence = self.sequence.wrapping_add(1);
+        let mut record = [ERASED; RECORD_WORDS];
+        record[0] = MAGIC;
+        record[1] = CONFIG_VERSION as u32 | (CONFIG_WORDS as u32) << 16;
+        record[2] = sequence;
+        record[HEADER_WORDS..HEADER_WORDS + CONFIG_WORDS].copy_from_slice(&config.to_words());
+        record[HEADER_WORDS + CONFIG_WORDS] = crc32(&record[..HEADER_WORDS + CONFIG_WORDS]);
+
+        let length = (RECORD_WORDS * 4) as u32;
+        if self.offset + length > F::PAGE_SIZE || !self.is_erased(self.offset, length) {
+            // Out of room (or something's been scribbled where we wanted to write). Move over to the
+            // other page, leaving this one intact until the new record is safely down.
+            let page = 1 - self.page;
+            self.flash.erase_page(page)?;
+            self.page = page;
+            self.offset = 0;
+        }
+        let offset = self.page * F::PAGE_SIZE + self.offset;
+        // Even if programming fails partway, the space is no longer usable.
+        self.offset += length;
+        self.flash.program(offset, &record)?;
+
+        self.sequence = sequence;
+        self.loaded = Ok(*config);
+        Ok(())
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn is_erased(&self, offset: u32, length: u32) -> bool {
+        let base = self.page * F::PAGE_SIZE + offset;
+        let mut word = [0u32];
+        (0..length / 4).all(|i| {
+            self.flash.read(base + i * 4, &mut word);
+            word[0] == ERASED
+        })
+    }
+
+    fn scan_page(&self, page: u32) -> PageScan {
+        let base = page * F::PAGE_SIZE;
+        let mut scan = PageScan {
+            newest: None,
+            end: 0,
+            corrupted: false,
+        };
+        let mut buffer = [0u32; HEADER_WORDS + MAX_PAYLOAD_WORDS + 1];
+        while scan.end + (HEADER_WORDS * 4) as u32 <= F::PAGE_SIZE {
+            let header = &mut buffer[..HEADER_WORDS];
+            self.flash.read(base + scan.end, header);
+            if header[0] == ERASED {
+                break;
+            }
+            let payload_words = (header[1] >> 16) as usize;
+            let length = (record_words(payload_words) * 4) as u32;
+            if header[0] != MAGIC
+                || payload_words > MAX_PAYLOAD_WORDS
+                || scan.end + length > F::PAGE_SIZE
+            {
+                // No way of telling where the next record starts, so give up on the rest of the
+                // page.
+                scan.corrupted = true;
+                scan.end = F::PAGE_SIZE;
+                break;
+            }
+
+            let checked = HEADER_WORDS + payload_words;
+            self.flash.read(
+                base + scan.end + (HEADER_WORDS * 4) as u32,
+                &mut buffer[HEADER_WORDS..checked + 1],
+            );
+            let config = Config::from_words(&buffer[HEADER_WORDS..checked]);
+            if crc32(&buffer[..checked]) != buffer[checked] {
+                // Most likely a write that was interrupted. The header's intact, so we can still
+                // skip over it.
+                scan.corrupted = true;
+            } else if !config.is_valid() {
+                // Intact, but nothing we'd ever let be set now (or saved by firmware that did).
+                scan.corrupted = true;
+            } else {
+                let sequence = buffer[2];
+                match &scan.newest {
+                    Some(newest) if !is_newer(sequence, newest.sequence) => (),
+                    _ => scan.newest = Some(Record { sequence, config }),
+                }
+            }
+            scan.end += length;
+        }
+        scan
+    }
+}
+
+// Sequence numbers are allowed to wrap.
+fn is_newer(sequence: u32, than: u32) -> bool {
+    (sequence.wrapping_sub(than) as i32) > 0
+}
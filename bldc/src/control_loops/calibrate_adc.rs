@@ -1,15 +1,11 @@
 use crate::{
-    comms::fdcan::{FdcanMessage, IncomingFdcanFrame},
     current_sensing::PhaseCurrents,
+    hal::{Peripherals, PhaseCurrentSource},
 };
 
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 
 // Calibrate ADC values.
-pub struct CalibrateADCCmd {
-    pub duration: f32,
-}
-
 pub struct CalibrateADC {
     total_counts: u32,
     loop_count: u32,
@@ -28,12 +24,12 @@ impl CalibrateADC {
     }
 }
 
-impl Commutate for CalibrateADC {
+impl<P: Peripherals> Commutate<P> for CalibrateADC {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         self.loop_count += 1;
         let current_sensor = &mut hardware.current_sensor;
@@ -57,12 +53,3 @@ impl Commutate for CalibrateADC {
         (self.callback)(&self.sample);
     }
 }
-
-impl IncomingFdcanFrame for CalibrateADCCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        CalibrateADCCmd {
-            duration: f32::from_bits(buffer[0]),
-        }
-    }
-}
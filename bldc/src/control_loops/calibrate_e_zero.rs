@@ -1,7 +1,9 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 use crate::comms::messages::EZeroMsg;
 use crate::{
+    config,
     foc::{DQCurrents, FieldOrientedControlImpl},
+    hal::{Peripherals, RotorPositionSensor, ThreePhaseBridge},
     led::Led,
     pi_controller::PIController,
 };
@@ -31,10 +33,14 @@ impl CalibrateEZero {
         currents: DQCurrents,
         callback: for<'r> fn(&'r EZeroMsg),
     ) -> CalibrateEZero {
-        let q_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
-        let d_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
+        let gains = *config::loop_config().read();
+        let q_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
+        let d_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
 
         let mut foc = FieldOrientedControlImpl::new(q_controller, d_controller);
+        foc.configure(&gains);
         foc.q_current(currents.q);
         foc.d_current(currents.d);
 
@@ -56,12 +62,12 @@ impl CalibrateEZero {
     }
 }
 
-impl Commutate for CalibrateEZero {
+impl<P: Peripherals> Commutate<P> for CalibrateEZero {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         Led::<crate::led::Red>::on_while(|| {
             let ControlHardware {
@@ -75,7 +81,13 @@ impl Commutate for CalibrateEZero {
                 Some(state) => state,
             };
             // Calculate the required PWM values via field oriented control.
-            let phase_voltages = self.foc.update(current_sensor, encoder_state, cordic, DT);
+            let phase_voltages = self.foc.update(
+                current_sensor,
+                encoder_state,
+                cordic,
+                sensor_state.v_bus,
+                DT,
+            );
             hardware
                 .pwm
                 .set_voltages(sensor_state.v_bus, phase_voltages);
@@ -0,0 +1,136 @@
+use super::{Commutate, ControlHardware, LoopState, SensorState};
+use crate::{
+    config::{self, LoopConfig},
+    encoder::EncoderState,
+    foc::FieldOrientedControlImpl,
+    hal::{BusVoltageSource, Peripherals, RotorPositionSensor, ThreePhaseBridge},
+    hall::{HallCalibration, HallCalibrationStatus, HallCalibrator},
+    pi_controller::PIController,
+    util::buffered_state::StateReader,
+};
+use core::f32::consts::PI;
+use third_party::ang::Angle;
+
+// Finds where the hall sectors are. Holds `current` on the d axis at an electrical angle that we
+// turn ourselves, so the rotor gets dragged along with it, and notes the angle every time the halls
+// change. Goes forwards a couple of turns and then back again; see `HallCalibrator` for why.
+//
+// Only the halls are read from the sensor; the angle the currents are driven at is ours, so it
+// works before anything's calibrated.
+
+const DT: f32 = 1. / 40_000.;
+// Electrical radians per second. Slow enough for the rotor to keep up, even with a bit of load.
+const SWEEP_VELOCITY: f32 = 4. * PI;
+// Each way.
+const SWEEP_TURNS: f32 = 2.;
+// Seconds to let the rotor settle onto the starting angle before anything's noted.
+const SETTLE_TIME: f32 = 0.25;
+
+pub type HallCalibrationResult = Result<HallCalibration, HallCalibrationStatus>;
+
+pub struct CalibrateHalls {
+    foc: FieldOrientedControlImpl,
+    config: StateReader<LoopConfig>,
+    calibrator: HallCalibrator,
+    loop_count: u32,
+    settle_counts: u32,
+    sweep_counts: u32,
+    callback: for<'r> fn(&'r HallCalibrationResult),
+}
+
+impl CalibrateHalls {
+    pub fn new(current: f32, callback: for<'r> fn(&'r HallCalibrationResult)) -> CalibrateHalls {
+        let config = config::loop_config();
+        let gains = *config.read();
+        let q_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
+        let d_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
+        let mut foc = FieldOrientedControlImpl::new(q_controller, d_controller);
+        foc.d_current(current);
+
+        CalibrateHalls {
+            foc,
+            config,
+            calibrator: HallCalibrator::new(),
+            loop_count: 0,
+            settle_counts: (SETTLE_TIME / DT) as u32,
+            sweep_counts: (SWEEP_TURNS * 2. * PI / SWEEP_VELOCITY / DT) as u32,
+            callback,
+        }
+    }
+
+    pub fn foc(&self) -> &FieldOrientedControlImpl {
+        &self.foc
+    }
+
+    fn done(&self) -> bool {
+        self.loop_count >= self.settle_counts + 2 * self.sweep_counts
+    }
+
+    // Where we're dragging the rotor to, and how fast, `count` iterations in.
+    fn drive(&self, count: u32) -> (f32, f32) {
+        let sweep = count.saturating_sub(self.settle_counts);
+        if count < self.settle_counts {
+            (0., 0.)
+        } else if sweep < self.sweep_counts {
+            (sweep as f32 * DT * SWEEP_VELOCITY, SWEEP_VELOCITY)
+        } else {
+            let back = sweep - self.sweep_counts;
+            let turned = (self.sweep_counts as f32 - back as f32) * DT * SWEEP_VELOCITY;
+            (turned, -SWEEP_VELOCITY)
+        }
+    }
+}
+
+impl<P: Peripherals> Commutate<P> for CalibrateHalls {
+    fn commutate(
+        &mut self,
+        loop_state: LoopState,
+        _sensor_state: &SensorState,
+        hardware: &mut ControlHardware<P>,
+    ) -> LoopState {
+        let encoder_state = match hardware.encoder.state() {
+            None => return LoopState::Running,
+            Some(state) => *state,
+        };
+        if matches!(loop_state, LoopState::Shutdown) || self.done() {
+            return LoopState::Idle;
+        }
+
+        let config = *self.config.read();
+        self.foc.configure(&config);
+        hardware.pwm.set_max_duty(config.max_duty);
+
+        let (angle, velocity) = self.drive(self.loop_count);
+        if self.loop_count >= self.settle_counts {
+            self.calibrator.add(angle, encoder_state.raw_encoder as u8);
+        }
+        let driven = EncoderState {
+            electrical_angle: Angle::Radians(angle).normalized(),
+            electrical_velocity: Angle::Radians(velocity),
+            ..encoder_state
+        };
+        let v_bus = hardware.current_sensor.v_bus();
+        let phase_voltages = self.foc.update(
+            &hardware.current_sensor,
+            &driven,
+            &mut hardware.cordic,
+            v_bus,
+            DT,
+        );
+        hardware.pwm.set_voltages(v_bus, phase_voltages);
+
+        self.loop_count += 1;
+        LoopState::Running
+    }
+
+    fn finished(&mut self) {
+        let result = if self.done() {
+            self.calibrator.finish()
+        } else {
+            Err(HallCalibrationStatus::Aborted)
+        };
+        (self.callback)(&result);
+    }
+}
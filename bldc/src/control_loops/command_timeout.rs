@@ -0,0 +1,109 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::fault::{self, Fault};
+
+// Deadman for control loops that follow a stream of commands from the host. If the host goes away
+// mid-move, a loop would otherwise keep chasing its last setpoint forever. Fresh commands and
+// `Heartbeat` frames both count as the host still being there; once a loop's gone too long without
+// either it raises `CommandTimeout` and stops following its commands, and it's up to the loop what
+// it does instead (see `TimeoutAction`).
+//
+// A heartbeat only keeps a loop from timing out. Once it has, it takes a fresh command to pick back
+// up: a host that's come back up still sending heartbeats shouldn't have the joint jump to wherever
+// it was told to go before it went down.
+
+// How long `RampDown` takes to get to zero.
+const RAMP_TIME: f32 = 0.1;
+
+// Counters rather than flags, so that whichever loop's running can tell whether there's been
+// anything new since it last looked without having to clear anything.
+static COMMANDS: AtomicU32 = AtomicU32::new(0);
+static HEARTBEATS: AtomicU32 = AtomicU32::new(0);
+
+pub fn command() {
+    COMMANDS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn heartbeat() {
+    HEARTBEATS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeoutAction {
+    // Ramp whatever the loop was asking for down to zero over `RAMP_TIME`.
+    RampDown = 0,
+    // Resist motion with `timeout_damping`, and nothing else.
+    Damp = 1,
+}
+
+impl TimeoutAction {
+    pub fn from_bits(bits: u32) -> TimeoutAction {
+        match bits {
+            1 => TimeoutAction::Damp,
+            _ => TimeoutAction::RampDown,
+        }
+    }
+}
+
+pub struct CommandTimeout {
+    commands: u32,
+    heartbeats: u32,
+    idle_cycles: u32,
+    timed_out_cycles: Option<u32>,
+    // From the last `update`.
+    loop_frequency: f32,
+}
+
+impl CommandTimeout {
+    // Starting the loop counts as a fresh command.
+    pub fn new() -> CommandTimeout {
+        CommandTimeout {
+            commands: COMMANDS.load(Ordering::Relaxed),
+            heartbeats: HEARTBEATS.load(Ordering::Relaxed),
+            idle_cycles: 0,
+            timed_out_cycles: None,
+            loop_frequency: 0.,
+        }
+    }
+
+    // Call once per loop iteration, along with how often that is (see
+    // `ThreePhaseBridge::loop_frequency`). `timeout` is in seconds; zero disables it.
+    pub fn update(&mut self, timeout: f32, loop_frequency: f32) {
+        self.loop_frequency = loop_frequency;
+        let commands = COMMANDS.load(Ordering::Relaxed);
+        let heartbeats = HEARTBEATS.load(Ordering::Relaxed);
+        if commands != self.commands {
+            self.idle_cycles = 0;
+            self.timed_out_cycles = None;
+        } else if heartbeats != self.heartbeats {
+            self.idle_cycles = 0;
+        } else {
+            self.idle_cycles = self.idle_cycles.saturating_add(1);
+        }
+        self.commands = commands;
+        self.heartbeats = heartbeats;
+
+        match &mut self.timed_out_cycles {
+            Some(cycles) => *cycles = cycles.saturating_add(1),
+            None => {
+                if timeout > 0. && self.idle_cycles as f32 >= timeout * loop_frequency {
+                    self.timed_out_cycles = Some(0);
+                    fault::raise(Fault::CommandTimeout);
+                }
+            }
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out_cycles.is_some()
+    }
+
+    // Scale for whatever the loop was doing when it timed out: 1 until then, falling to 0 over
+    // `RAMP_TIME`.
+    pub fn ramp(&self) -> f32 {
+        match self.timed_out_cycles {
+            None => 1.,
+            Some(cycles) => (1. - cycles as f32 / (RAMP_TIME * self.loop_frequency)).max(0.),
+        }
+    }
+}
@@ -1,38 +1,113 @@
 use super::calibrate_adc::CalibrateADC;
+use super::calibrate_halls::CalibrateHalls;
 use super::pos_vel_control::PositionVelocity;
 use super::torque_control::TorqueControl;
 use super::{ControlHardware, SensorState};
-use crate::util::interrupts::block_interrupt;
+use crate::fault::{self, Faults};
+use crate::foc::FocState;
+use crate::hal::{g474::G474, Peripherals, ThreePhaseBridge};
+use crate::protection::Protection;
+use crate::util::interrupts::{block_interrupt, block_interrupts};
 use crate::util::seq_lock::SeqLock;
-use enum_dispatch::enum_dispatch;
 use lazy_static::lazy_static;
 use stm32g4::stm32g474 as device;
 use third_party::m4vga_rs::util::armv7m::{disable_irq, enable_irq};
 use third_party::m4vga_rs::util::spin_lock::SpinLock;
 
+// Same story as `dispatchable_enum` in comms::handlers: `enum_dispatch` can't dispatch a trait
+// that's generic over the peripherals, so we roll our own.
+macro_rules! control_loop_enum {
+    ( $n: ident { $( $x: ident ),* $(,)? }) => {
+        pub enum $n {
+            $(
+                $x($x),
+            )*
+        }
+
+        $(
+            impl From<$x> for $n {
+                fn from(inner: $x) -> Self {
+                    $n::$x(inner)
+                }
+            }
+        )*
+
+        impl<P: Peripherals> Commutate<P> for $n {
+            fn commutate(
+                &mut self,
+                loop_state: LoopState,
+                sensor_state: &SensorState,
+                hardware: &mut ControlHardware<P>,
+            ) -> LoopState {
+                match self {
+                    $( $n::$x(inner) => inner.commutate(loop_state, sensor_state, hardware), )*
+                }
+            }
+
+            fn finished(&mut self) {
+                match self {
+                    $( $n::$x(inner) => Commutate::<P>::finished(inner), )*
+                }
+            }
+        }
+    };
+}
+
 // TODO(blakely): move to mod.rs
-#[enum_dispatch(Commutate)]
-pub enum ControlLoop {
+control_loop_enum!(ControlLoop {
     CalibrateADC,
     TorqueControl,
     PositionVelocity,
+    CalibrateHalls,
+});
+
+// Which loop is running, as reported in the `Status` heartbeat. Part of the wire protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopMode {
+    Idle = 0,
+    CalibrateADC = 1,
+    TorqueControl = 2,
+    PositionVelocity = 3,
+    CalibrateHalls = 4,
 }
 
-// Trait that any control loops need to implement.
-#[enum_dispatch]
-pub trait Commutate: Send {
+impl ControlLoop {
+    pub fn mode(&self) -> LoopMode {
+        match self {
+            ControlLoop::CalibrateADC(_) => LoopMode::CalibrateADC,
+            ControlLoop::TorqueControl(_) => LoopMode::TorqueControl,
+            ControlLoop::PositionVelocity(_) => LoopMode::PositionVelocity,
+            ControlLoop::CalibrateHalls(_) => LoopMode::CalibrateHalls,
+        }
+    }
+
+    // How the current loop's doing, for loops that run one.
+    pub fn foc_state(&self) -> Option<FocState> {
+        match self {
+            ControlLoop::CalibrateADC(_) => None,
+            ControlLoop::TorqueControl(inner) => Some(inner.foc().state()),
+            ControlLoop::PositionVelocity(inner) => Some(inner.foc().state()),
+            ControlLoop::CalibrateHalls(inner) => Some(inner.foc().state()),
+        }
+    }
+}
+
+// Trait that any control loops need to implement. Generic over the peripherals so that the same
+// loop can be run on any board (or in simulation).
+pub trait Commutate<P: Peripherals>: Send {
     fn commutate(
         &mut self,
         loop_state: LoopState,
         sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState;
     fn finished(&mut self);
 }
 
 pub struct InterruptData {
     pub control_loop: Option<ControlLoop>,
-    pub hw: ControlHardware,
+    pub hw: ControlHardware<G474>,
+    pub protection: Protection,
 }
 
 pub static INTERRUPT_SHARED: SpinLock<Option<InterruptData>> = SpinLock::new(None);
@@ -57,18 +132,22 @@ impl Controller {
         Controller {}
     }
 
-    pub fn set_loop<C>(&self, control_loop: C)
+    // Refuses to start anything while there are critical faults latched; they have to be cleared
+    // first.
+    pub fn set_loop<C>(&self, control_loop: C) -> Result<(), Faults>
     where
         C: Into<ControlLoop>,
     {
+        check_faults()?;
         block_interrupt(device::interrupt::ADC1_2, &INTERRUPT_SHARED, |mut vars| {
             vars.control_loop = Some(control_loop.into());
         });
 
-        self.enable_loop();
+        self.enable_loop()
     }
 
-    pub fn enable_loop(&self) {
+    pub fn enable_loop(&self) -> Result<(), Faults> {
+        check_faults()?;
         block_interrupt(
             device::interrupt::ADC1_2,
             &INTERRUPT_SHARED,
@@ -77,6 +156,7 @@ impl Controller {
                 control_vars.hw.pwm.enable_loop();
             },
         );
+        Ok(())
     }
 
     pub fn is_enabled(&self) -> bool {
@@ -86,6 +166,15 @@ impl Controller {
         }
     }
 
+    pub fn mode(&self) -> LoopMode {
+        block_interrupts([device::interrupt::ADC1_2], &INTERRUPT_SHARED, |shared| {
+            shared
+                .control_loop
+                .as_ref()
+                .map_or(LoopMode::Idle, ControlLoop::mode)
+        })
+    }
+
     pub fn disable_loop(&self) {
         // If we're not enabled, don't do anything.
         if !self.is_enabled() {
@@ -118,12 +207,22 @@ impl Controller {
         );
     }
 
-    pub fn donate_hardware(&self, hw: ControlHardware) {
+    pub fn donate_hardware(&self, hw: ControlHardware<G474>) {
         *INTERRUPT_SHARED
             .try_lock()
             .expect("Lock held while trying to donate hardware") = Some(InterruptData {
             control_loop: None,
             hw,
+            protection: Protection::new(),
         });
     }
 }
+
+fn check_faults() -> Result<(), Faults> {
+    let critical = fault::active().critical();
+    if critical.is_empty() {
+        Ok(())
+    } else {
+        Err(critical)
+    }
+}
@@ -1,21 +1,8 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
-use crate::comms::fdcan::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame};
+use crate::hal::{Peripherals, PhaseCurrentSource};
 
 // Sample current one one phase for a period of time, building a histogram of currents.
 
-// Build a 16-bin distribution of current, one phase at a time.
-pub struct IdleCurrentDistributionCmd {
-    pub duration: f32,
-    pub center_current: f32,
-    pub current_range: f32,
-    pub phase: u8,
-}
-
-// Response to IdleCurrentDistributionCmd
-pub struct CurrentDistribution<'a> {
-    pub bins: &'a [u32; 16],
-}
-
 enum Phase {
     A,
     B,
@@ -59,12 +46,12 @@ impl IdleCurrentDistribution {
     }
 }
 
-impl Commutate for IdleCurrentDistribution {
+impl<P: Peripherals> Commutate<P> for IdleCurrentDistribution {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         self.loop_count += 1;
         let current_sensor = &hardware.current_sensor;
@@ -90,21 +77,3 @@ impl Commutate for IdleCurrentDistribution {
         (self.callback)(&self.bins);
     }
 }
-
-impl IncomingFdcanFrame for IdleCurrentDistributionCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        IdleCurrentDistributionCmd {
-            duration: f32::from_bits(buffer[0]),
-            center_current: f32::from_bits(buffer[1]),
-            current_range: f32::from_bits(buffer[2]),
-            phase: buffer[3] as u8 & 0xFF,
-        }
-    }
-}
-
-impl<'a> OutgoingFdcanFrame for CurrentDistribution<'a> {
-    fn pack(&self) -> FdcanMessage {
-        FdcanMessage::new(0x10, self.bins)
-    }
-}
@@ -1,14 +1,9 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 use crate::{
-    comms::fdcan::{FdcanMessage, IncomingFdcanFrame},
     current_sensing::PhaseCurrents,
+    hal::{Peripherals, PhaseCurrentSource},
 };
 
-// Current sense for a given duration.
-pub struct IdleCurrentSenseCmd {
-    pub duration: f32,
-}
-
 // During commutation, no PWM is performed. The current is sampled once at each loop for a given
 // duration then averaged across all samples.
 pub struct IdleCurrentSensor {
@@ -29,12 +24,12 @@ impl IdleCurrentSensor {
     }
 }
 
-impl Commutate for IdleCurrentSensor {
+impl<P: Peripherals> Commutate<P> for IdleCurrentSensor {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         self.loop_count += 1;
         let current_sensor = &hardware.current_sensor;
@@ -51,12 +46,3 @@ impl Commutate for IdleCurrentSensor {
         (self.callback)(&self.sample);
     }
 }
-
-impl IncomingFdcanFrame for IdleCurrentSenseCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        IdleCurrentSenseCmd {
-            duration: f32::from_bits(buffer[0]),
-        }
-    }
-}
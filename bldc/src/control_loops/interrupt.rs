@@ -1,13 +1,22 @@
+use cortex_m::peripheral::DWT;
 use stm32g4::stm32g474::{self as device, interrupt};
 use third_party::m4vga_rs::util::armv7m::clear_pending_irq;
 use third_party::m4vga_rs::util::sync::acquire_hw;
 
+use crate::fault;
+use crate::foc::FocState;
+use crate::hal::{
+    g474::G474, BusVoltageSource, PhaseCurrentSource, RotorPositionSensor, ThreePhaseBridge,
+};
 use crate::led::{self, Led};
+use crate::scope;
+use crate::telemetry::{self, Snapshot};
+use crate::watchdog;
 
 use super::controller::{
     Commutate, ControlLoop, InterruptData, INTERRUPT_SHARED, LOOP_STATE, SENSOR_STATE,
 };
-use super::{ControlHardware, LoopState, SensorState};
+use super::{timing, ControlHardware, LoopState, SensorState};
 
 // Interrupt handler triggered by TIM1[CH4]'s tim_trgo2. Under normal circumstances this function
 // will be called continuously, regardless of the control loop in place. Note that the control loop
@@ -20,15 +29,26 @@ fn ADC1_2() {
         // Clear the IRQ so it doesn't immediately fire again.
         clear_pending_irq(device::Interrupt::ADC1_2);
 
-        commutate();
+        let start = DWT::cycle_count();
+        let foc = commutate();
+        // The sampling below counts towards the loop time too, so the snapshot can only ever have
+        // the previous period's.
+        if let Some(snapshot) = Snapshot::new(foc, timing::current().last) {
+            telemetry::sample(&snapshot);
+            scope::sample(&snapshot);
+        }
+        watchdog::loop_checkin();
+        timing::record(DWT::cycle_count().wrapping_sub(start));
     });
 }
 
-fn commutate() {
+// Returns the current loop's state for telemetry, if there's a loop running one.
+fn commutate() -> Option<FocState> {
     let shared = &mut *acquire_hw(&INTERRUPT_SHARED);
     let InterruptData {
         ref mut control_loop,
         ref mut hw,
+        ref mut protection,
     } = shared;
 
     // Identify current state of the BLDC.
@@ -57,9 +77,37 @@ fn commutate() {
     // Update the state
     *SENSOR_STATE.lock_write() = Some(SensorState::new(&encoder_state, &phase_currents, v_bus));
 
+    // Check the hard limits before the loop gets a say. Nothing's being driven when there's no loop,
+    // so there's nothing to protect.
+    match control_loop {
+        Some(_) => {
+            if let Some(fault) = protection.check(&phase_currents, v_bus) {
+                fault::raise(fault);
+            }
+        }
+        None => protection.reset(),
+    }
+
+    // A critical fault anywhere means the bridge has to go to a safe state, regardless of what the
+    // loop wants. Disabling stops TIM1, so this is the last time we'll get called until someone
+    // clears the faults and re-enables things.
+    if fault::tripped() {
+        let pwm = &mut hw.pwm;
+        pwm.zero_phases();
+        pwm.reset_current_sample();
+        pwm.reset_deadtime();
+        pwm.disable_loop();
+        if let Some(control_loop) = control_loop {
+            Commutate::<G474>::finished(control_loop);
+        }
+        shared.control_loop = None;
+        *LOOP_STATE.lock_write() = LoopState::Idle;
+        return None;
+    }
+
     // If there's a control callback, call it. Otherwise just idle.
     let control_loop: &mut ControlLoop = match control_loop {
-        None => return,
+        None => return None,
         Some(ref mut x) => x,
     };
 
@@ -67,7 +115,9 @@ fn commutate() {
     // nothing should be able to preempt us between when we set it above and now.
     let sensor_state = &SENSOR_STATE.read().unwrap();
 
-    let new_loop_state = match control_loop.commutate(LOOP_STATE.read(), sensor_state, hw) {
+    let loop_state = control_loop.commutate(LOOP_STATE.read(), sensor_state, hw);
+    let foc = control_loop.foc_state();
+    let new_loop_state = match loop_state {
         LoopState::Idle => {
             let pwm = &mut hw.pwm;
             // Make sure we pull all phases low in case the control loops didn't. Better safe than
@@ -77,11 +127,12 @@ fn commutate() {
             // Reset the current sampling to be between PWM pulses.
             pwm.reset_current_sample();
             pwm.reset_deadtime();
-            control_loop.finished();
+            Commutate::<G474>::finished(control_loop);
             shared.control_loop = None;
             LoopState::Idle
         }
         x => x,
     };
     *LOOP_STATE.lock_write() = new_loop_state;
+    foc
 }
@@ -1,7 +1,8 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 use crate::{
-    comms::fdcan::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame},
     current_sensing::PhaseCurrents,
+    fault::{self, Fault},
+    hal::{BusVoltageSource, Peripherals, PhaseCurrentSource, SamplingPeriod, ThreePhaseBridge},
     pwm::PwmDuty,
 };
 
@@ -18,18 +19,6 @@ use crate::{
 const MAX_PWM_DUTY_CYCLE: f32 = 0.15;
 const MIN_SQUARE_WAVE_FREQ: u32 = 5000;
 
-// Measure the inductance of the windings
-pub struct MeasureInductanceCmd {
-    pub duration: f32,
-    pub frequency: u32,
-    pub pwm_duty: f32,
-    pub sample_pwm_percent: f32,
-}
-// Return value for inductances
-pub struct Inductances<'a> {
-    pub inductances: &'a [f32; 3],
-}
-
 enum Direction {
     Up,
     Down,
@@ -47,7 +36,7 @@ pub struct MeasureInductance {
     remainder: f32,
     last_sample: Option<PhaseCurrents>,
     pwm_duty: f32,
-    sample_pwm_ccr: u16,
+    sample_pwm_percent: f32,
 
     callback: fn([f32; 3]),
 
@@ -63,12 +52,12 @@ impl MeasureInductance {
         callback: fn([f32; 3]),
     ) -> MeasureInductance {
         let square_wave_freq = square_wave_freq.min(20_000).max(MIN_SQUARE_WAVE_FREQ);
-        let pwm_duty = pwm_duty.max(0.).min(MAX_PWM_DUTY_CYCLE);
-        if pwm_duty > MAX_PWM_DUTY_CYCLE {
-            // TODO(blakely): This isn't a panic; this should be checked during `listen`.
-            panic!("Max PWM duty cycle too high for inductance calibration")
+        // Asking for more than the max duty cycle means whoever set this up got it wrong, so don't
+        // just silently clamp it.
+        if pwm_duty > MAX_PWM_DUTY_CYCLE || pwm_duty.is_nan() {
+            fault::raise(Fault::LoopParameters);
         }
-        let pwm_ccr = (pwm_duty * 2125f32) as u16;
+        let pwm_duty = pwm_duty.max(0.).min(MAX_PWM_DUTY_CYCLE);
         MeasureInductance {
             total_counts: (40_000 as f32 * duration) as u32,
             loop_count: 0,
@@ -81,7 +70,7 @@ impl MeasureInductance {
             remainder: 0.,
             last_sample: None,
             pwm_duty,
-            sample_pwm_ccr: (((2125 - pwm_ccr) as f32 * sample_pwm_percent) as u16 + 1).max(2124),
+            sample_pwm_percent,
 
             callback,
 
@@ -90,15 +79,15 @@ impl MeasureInductance {
     }
 }
 
-impl Commutate for MeasureInductance {
+impl<P: Peripherals> Commutate<P> for MeasureInductance {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         let current_sensor = &mut hardware.current_sensor;
-        current_sensor.sampling_period_long();
+        current_sensor.set_sampling_period(SamplingPeriod::Long);
 
         self.v_bus += current_sensor.v_bus();
         let sample = current_sensor.sample();
@@ -119,27 +108,34 @@ impl Commutate for MeasureInductance {
         let count_and_remainder: f32 = self.switch_count as f32 + self.remainder;
         let pwm = &mut hardware.pwm;
 
-        pwm.set_sample_ccr(self.sample_pwm_ccr);
+        // One count past however far into the off time was asked for, but no later than the default
+        // sample point just before the peak.
+        let arr = pwm.arr();
+        let off_counts = (1. - self.pwm_duty) * arr as f32;
+        let sample_ccr = ((off_counts * self.sample_pwm_percent) as u16 + 1).min(arr - 1);
+        pwm.set_sample_point(sample_ccr as f32 / arr as f32);
 
         if count_and_remainder >= self.loops_per_switch {
             self.switch_count = 0;
             self.switches += 1;
             self.remainder = count_and_remainder - self.loops_per_switch;
+            // `direction` is which way phase A's current is heading from here on. The next sample
+            // is the first one to see the new duties, so it has to be switched along with them.
             self.direction = match self.direction {
                 Direction::Up => {
                     pwm.set_pwm_duty_cycles(PwmDuty {
-                        a: self.pwm_duty,
-                        b: 0.,
-                        c: 0.,
+                        a: 0.,
+                        b: self.pwm_duty,
+                        c: self.pwm_duty,
                     });
                     self.switches += 1;
                     Direction::Down
                 }
                 Direction::Down => {
                     pwm.set_pwm_duty_cycles(PwmDuty {
-                        a: 0.,
-                        b: self.pwm_duty,
-                        c: self.pwm_duty,
+                        a: self.pwm_duty,
+                        b: 0.,
+                        c: 0.,
                     });
                     self.switches += 1;
                     Direction::Up
@@ -164,36 +160,13 @@ impl Commutate for MeasureInductance {
         let v_ref = v_bus * self.pwm_duty;
         let dt = loop_count / 40_000f32;
 
+        // Phase A is in series with B and C in parallel, so it only sees two thirds of the
+        // voltage, and B and C a third each, the other way around.
         let inductances = [
-            v_ref / (self.sample.phase_a / dt),
-            v_ref / (self.sample.phase_b / dt),
-            v_ref / (self.sample.phase_c / dt),
+            2. / 3. * v_ref / (self.sample.phase_a / dt),
+            -1. / 3. * v_ref / (self.sample.phase_b / dt),
+            -1. / 3. * v_ref / (self.sample.phase_c / dt),
         ];
         (self.callback)(inductances);
     }
 }
-
-impl IncomingFdcanFrame for MeasureInductanceCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        MeasureInductanceCmd {
-            duration: f32::from_bits(buffer[0]),
-            frequency: buffer[1],
-            pwm_duty: f32::from_bits(buffer[2]),
-            sample_pwm_percent: f32::from_bits(buffer[3]),
-        }
-    }
-}
-
-impl<'a> OutgoingFdcanFrame for Inductances<'a> {
-    fn pack(&self) -> FdcanMessage {
-        FdcanMessage::new(
-            0x14,
-            &[
-                self.inductances[0].to_bits(),
-                self.inductances[1].to_bits(),
-                self.inductances[2].to_bits(),
-            ],
-        )
-    }
-}
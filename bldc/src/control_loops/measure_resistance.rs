@@ -1,8 +1,8 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 
 use crate::{
-    comms::fdcan::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame},
     current_sensing::PhaseCurrents,
+    hal::{BusVoltageSource, Peripherals, PhaseCurrentSource, SamplingPeriod, ThreePhaseBridge},
     pwm::PwmDuty,
 };
 
@@ -15,13 +15,6 @@ use crate::{
 // but I ***really*** don't want to fry anything right now :)
 const MAX_PWM_DUTY_CYCLE: f32 = 0.08;
 
-// Measure the resistance of the windings.
-pub struct MeasureResistanceCmd {
-    pub duration: f32,
-    pub target_voltage: f32,
-    pub phase: crate::control_loops::measure_resistance::Phase,
-}
-
 pub enum Phase {
     A,
     B,
@@ -63,18 +56,18 @@ impl MeasureResistance {
 }
 
 pub struct Resistance {
-    resistance: f32,
+    pub resistance: f32,
 }
 
-impl Commutate for MeasureResistance {
+impl<P: Peripherals> Commutate<P> for MeasureResistance {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         let current_sensor = &mut hardware.current_sensor;
-        current_sensor.sampling_period_fast();
+        current_sensor.set_sampling_period(SamplingPeriod::Fast);
 
         self.current += current_sensor.sample();
         let v_bus = current_sensor.v_bus();
@@ -118,7 +111,9 @@ impl Commutate for MeasureResistance {
         self.pwm_duty /= self.loop_count as f32;
         self.v_bus /= self.loop_count as f32;
         self.current /= self.loop_count;
-        let resistance = (self.v_bus * self.pwm_duty)
+        // The current goes out through the driven phase and back through the other two in
+        // parallel, so only two thirds of the voltage is across the one we're measuring.
+        let resistance = (2. / 3. * self.v_bus * self.pwm_duty)
             / match self.phase {
                 Phase::A => self.current.phase_a,
                 Phase::B => self.current.phase_b,
@@ -127,25 +122,3 @@ impl Commutate for MeasureResistance {
         (self.callback)(&Resistance { resistance });
     }
 }
-
-impl IncomingFdcanFrame for MeasureResistanceCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        MeasureResistanceCmd {
-            duration: f32::from_bits(buffer[0]),
-            target_voltage: f32::from_bits(buffer[1]),
-
-            phase: match buffer[2] & 0xFFu32 {
-                0 => crate::control_loops::measure_resistance::Phase::A,
-                1 => crate::control_loops::measure_resistance::Phase::B,
-                _ => crate::control_loops::measure_resistance::Phase::C,
-            },
-        }
-    }
-}
-
-impl OutgoingFdcanFrame for Resistance {
-    fn pack(&self) -> crate::comms::fdcan::FdcanMessage {
-        FdcanMessage::new(0x12, &[self.resistance.to_bits()])
-    }
-}
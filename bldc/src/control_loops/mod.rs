@@ -1,13 +1,9 @@
-use crate::{
-    comms::fdcan::{FdcanMessage, OutgoingFdcanFrame},
-    cordic::Cordic,
-    current_sensing::{self, CurrentSensor, PhaseCurrents},
-    encoder::{Encoder, EncoderState},
-    pwm::PwmOutput,
-};
+use crate::{current_sensing::PhaseCurrents, encoder::EncoderState, hal::Peripherals};
 
 pub mod calibrate_adc;
 pub mod calibrate_e_zero;
+pub mod calibrate_halls;
+pub mod command_timeout;
 pub mod controller;
 pub mod idle_current_distribution;
 pub mod idle_current_sensor;
@@ -17,19 +13,21 @@ pub mod measure_resistance;
 pub mod phase_current;
 pub mod pos_vel_control;
 pub mod read_encoder;
+pub mod sync;
+pub mod timing;
 pub mod torque_control;
 
-pub use controller::{Commutate, Controller, LoopState};
+pub use controller::{Commutate, Controller, LoopMode, LoopState};
 
 // TODO(blakely): This is probably bad form...
 pub use idle_current_distribution::*;
 pub use idle_current_sensor::*;
 
-pub struct ControlHardware {
-    pub current_sensor: CurrentSensor<current_sensing::Ready>,
-    pub pwm: PwmOutput,
-    pub encoder: Encoder,
-    pub cordic: Cordic,
+pub struct ControlHardware<P: Peripherals> {
+    pub current_sensor: P::CurrentSensor,
+    pub pwm: P::Bridge,
+    pub encoder: P::Encoder,
+    pub cordic: P::SinCos,
 }
 
 // TODO(blakely): don't require these to be Copy/Clone; use references instead.
@@ -49,21 +47,3 @@ impl SensorState {
         }
     }
 }
-
-impl OutgoingFdcanFrame for SensorState {
-    fn pack(&self) -> crate::comms::fdcan::FdcanMessage {
-        FdcanMessage::new(
-            0x1B,
-            &[
-                self.encoder_state.angle.in_radians().to_bits(),
-                self.encoder_state.angle_multiturn.in_radians().to_bits(),
-                self.encoder_state.velocity.in_radians().to_bits(),
-                self.encoder_state.electrical_angle.in_radians().to_bits(),
-                self.encoder_state
-                    .electrical_velocity
-                    .in_radians()
-                    .to_bits(),
-            ],
-        )
-    }
-}
@@ -1,8 +1,7 @@
 use super::{Commutate, ControlHardware, LoopState, SensorState};
 
 use crate::{
-    comms::fdcan::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame},
-    current_sensing::PhaseCurrents,
+    hal::{BusVoltageSource, Peripherals, PhaseCurrentSource, SamplingPeriod, ThreePhaseBridge},
     pi_controller::PIController,
     pwm::PwmDuty,
 };
@@ -17,31 +16,6 @@ pub enum Phase {
     C,
 }
 
-pub struct PhaseCurrentCmd {
-    pub target_current: f32,
-    pub duration: f32,
-    pub k: f32,
-    pub ki: f32,
-    pub phase: Phase,
-}
-
-impl IncomingFdcanFrame for PhaseCurrentCmd {
-    fn unpack(message: FdcanMessage) -> Self {
-        let buffer = message.data;
-        PhaseCurrentCmd {
-            duration: f32::from_bits(buffer[0]),
-            target_current: f32::from_bits(buffer[1]),
-            k: f32::from_bits(buffer[2]),
-            ki: f32::from_bits(buffer[3]),
-            phase: match buffer[4] & 0xFFu32 {
-                0 => Phase::A,
-                1 => Phase::B,
-                _ => Phase::C,
-            },
-        }
-    }
-}
-
 pub struct PhaseCurrent {
     total_counts: u32,
     loop_count: u32,
@@ -64,15 +38,15 @@ impl<'a> PhaseCurrent {
     }
 }
 
-impl Commutate for PhaseCurrent {
+impl<P: Peripherals> Commutate<P> for PhaseCurrent {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         let current_sensor = &mut hardware.current_sensor;
-        current_sensor.sampling_period_fast();
+        current_sensor.set_sampling_period(SamplingPeriod::Fast);
 
         let pwm = &mut hardware.pwm;
 
@@ -128,16 +102,3 @@ impl Commutate for PhaseCurrent {
         let _asdf = self.loop_count;
     }
 }
-
-impl OutgoingFdcanFrame for PhaseCurrents {
-    fn pack(&self) -> FdcanMessage {
-        FdcanMessage::new(
-            0xD,
-            &[
-                self.phase_a.to_bits(),
-                self.phase_b.to_bits(),
-                self.phase_c.to_bits(),
-            ],
-        )
-    }
-}
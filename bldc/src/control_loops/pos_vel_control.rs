@@ -1,25 +1,51 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use third_party::{
     ang::{AbsoluteDist, Angle},
     m4vga_rs::util::spin_lock::SpinLock,
 };
 
 use crate::{
+    config::{self, LoopConfig},
+    fault::{self, Fault},
     foc::FieldOrientedControlImpl,
+    hal::{BusVoltageSource, Peripherals, RotorPositionSensor, ThreePhaseBridge},
     pi_controller::PIController,
     util::buffered_state::{BufferedState, StateReader, StateWriter},
 };
 
-use super::{Commutate, LoopState, SensorState};
+use super::{
+    command_timeout::{self, CommandTimeout, TimeoutAction},
+    sync, Commutate, LoopState, SensorState,
+};
 
-const GEAR_RATIO: f32 = 6.0;
 const DT: f32 = 1. / 40_000.;
 
-// Position and velocity control using FoC wrapped in torque control.
+// Position and velocity control using FoC wrapped in torque control. Expects a steady stream of
+// commands (or heartbeats) from the host; see `command_timeout`.
+//
+// Commands come in two flavours. `command` takes effect on the next cycle, same as it always has.
+// `stage` only updates the position and velocity targets, and holds on to them until the next bus
+// `sync`, so that several joints can be moved in lockstep. Staged targets keep whatever gains were
+// last sent with a full `command`.
 
 static COMMAND_BUFFER: SpinLock<Option<BufferedState<PosVelState>>> = SpinLock::new(None);
 static COMMAND: SpinLock<Option<StateWriter<PosVelState>>> = SpinLock::new(None);
+static STAGED_BUFFER: SpinLock<Option<BufferedState<PosVelState>>> = SpinLock::new(None);
+static STAGED: SpinLock<Option<StateWriter<PosVelState>>> = SpinLock::new(None);
+// Bumped after each write to the buffers above, so the loop knows when there's something new.
+static COMMANDS: AtomicU32 = AtomicU32::new(0);
+static STAGES: AtomicU32 = AtomicU32::new(0);
+
+const IDLE_COMMAND: PosVelState = PosVelState {
+    position: 0.,
+    velocity: 0.,
+    stiffness_gain: 0.,
+    damping_gain: 0.,
+    torque_constant: 1.,
+};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PosVelState {
     pub position: f32,
     pub velocity: f32,
@@ -31,74 +57,179 @@ pub struct PosVelState {
 pub struct PositionVelocity {
     foc: FieldOrientedControlImpl,
     commands: StateReader<PosVelState>,
+    staged: StateReader<PosVelState>,
+    // Whichever of the above we're currently following.
+    target: PosVelState,
+    commands_seen: u32,
+    stages_seen: u32,
+    syncs_seen: u32,
+    config: StateReader<LoopConfig>,
+    timeout: CommandTimeout,
+    // Last torque asked for before timing out, for `TimeoutAction::RampDown`.
+    torque: f32,
 }
 
 impl PositionVelocity {
     pub fn new() -> PositionVelocity {
-        // TODO(blakely): Don't hard-code these; instead pull from either global config,
-        // calibration, or FDCAN command.
-        let q_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
-        let d_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
+        // TODO(blakely): Pull these from calibration.
+        let config = config::loop_config();
+        let gains = *config.read();
+        let q_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
+        let d_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
         let foc = FieldOrientedControlImpl::new(q_controller, d_controller);
 
         let mut command_buffer = COMMAND_BUFFER.lock();
-        *command_buffer = Some(BufferedState::new(PosVelState {
-            position: 0.,
-            velocity: 0.,
-            stiffness_gain: 0.,
-            damping_gain: 0.,
-            torque_constant: 1.,
-        }));
-
+        *command_buffer = Some(BufferedState::new(IDLE_COMMAND));
         let (reader, writer) = command_buffer
             .as_mut()
             .expect("No command buffer to split")
             .split();
-
         *COMMAND.lock() = Some(writer);
 
+        let mut staged_buffer = STAGED_BUFFER.lock();
+        *staged_buffer = Some(BufferedState::new(IDLE_COMMAND));
+        let (staged, staged_writer) = staged_buffer
+            .as_mut()
+            .expect("No staged buffer to split")
+            .split();
+        *STAGED.lock() = Some(staged_writer);
+
         PositionVelocity {
             foc,
             commands: reader,
+            staged,
+            target: IDLE_COMMAND,
+            // Anything from before the loop started doesn't count.
+            commands_seen: COMMANDS.load(Ordering::Acquire),
+            stages_seen: STAGES.load(Ordering::Acquire),
+            syncs_seen: sync::syncs(),
+            config,
+            timeout: CommandTimeout::new(),
+            torque: 0.,
         }
     }
 
     pub fn command<'a>(command: PosVelState) {
-        if let Some(state) = &mut *COMMAND.try_lock().expect("Lock held when writing command") {
-            *state.update() = command;
+        // Only ever written from the main thread, so the lock should never be held here. If it is,
+        // drop the command rather than risk a partial update.
+        match COMMAND.try_lock() {
+            Ok(mut writer) => {
+                if let Some(state) = &mut *writer {
+                    *state.update() = command;
+                    COMMANDS.fetch_add(1, Ordering::Release);
+                }
+            }
+            Err(_) => fault::raise(Fault::LockContention),
+        }
+        // Anything staged from here on goes out with the new gains.
+        match STAGED.try_lock() {
+            Ok(mut writer) => {
+                if let Some(state) = &mut *writer {
+                    let mut staged = state.update();
+                    *staged = PosVelState {
+                        stiffness_gain: command.stiffness_gain,
+                        damping_gain: command.damping_gain,
+                        torque_constant: command.torque_constant,
+                        ..*staged.other()
+                    };
+                }
+            }
+            Err(_) => fault::raise(Fault::LockContention),
+        }
+        command_timeout::command();
+    }
+
+    // Hold on to a new target until the next `sync`. Also only ever called from the main thread.
+    pub fn stage(position: f32, velocity: f32) {
+        match STAGED.try_lock() {
+            Ok(mut writer) => {
+                if let Some(state) = &mut *writer {
+                    let mut staged = state.update();
+                    *staged = PosVelState {
+                        position,
+                        velocity,
+                        ..*staged.other()
+                    };
+                    drop(staged);
+                    STAGES.fetch_add(1, Ordering::Release);
+                }
+            }
+            Err(_) => fault::raise(Fault::LockContention),
+        }
+    }
+
+    pub fn foc(&self) -> &FieldOrientedControlImpl {
+        &self.foc
+    }
+
+    fn update_target(&mut self) {
+        let commands = COMMANDS.load(Ordering::Acquire);
+        if commands != self.commands_seen {
+            self.commands_seen = commands;
+            self.target = *self.commands.read();
+        }
+        let syncs = sync::syncs();
+        if syncs == self.syncs_seen {
+            return;
+        }
+        self.syncs_seen = syncs;
+        // A sync with nothing new staged leaves us be, otherwise a sync meant for the rest of the
+        // bus would drag us back to a stale target.
+        let stages = STAGES.load(Ordering::Acquire);
+        if stages != self.stages_seen {
+            self.stages_seen = stages;
+            self.target = *self.staged.read();
+            // The host's clearly still there.
+            command_timeout::command();
         }
     }
 }
 
-impl Commutate for PositionVelocity {
+impl<P: Peripherals> Commutate<P> for PositionVelocity {
     fn commutate(
         &mut self,
         loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut super::ControlHardware,
+        hardware: &mut super::ControlHardware<P>,
     ) -> LoopState {
         let encoder_state = match hardware.encoder.state() {
             None => return LoopState::Running,
             Some(state) => state,
         };
+        // Pick up any changes to the gains, modulation or gearing.
+        let config = *self.config.read();
+        self.foc.configure(&config);
+        hardware.pwm.set_max_duty(config.max_duty);
+
         let mech_angle =
-            Angle::Radians(encoder_state.angle_multiturn.in_radians() / GEAR_RATIO).normalized();
+            Angle::Radians(encoder_state.angle_multiturn.in_radians() / config.gear_ratio)
+                .normalized();
         let mech_velocity = encoder_state.velocity.in_radians();
 
-        let commands = self.commands.read();
+        self.update_target();
+        let commands = self.target;
 
         let theta_diff = Angle::Radians(commands.position)
             .normalized()
             .abs_dist(mech_angle);
 
+        self.timeout
+            .update(config.pos_vel_timeout, hardware.pwm.loop_frequency());
         let torque_desired = match loop_state {
             LoopState::Shutdown => 0.,
+            _ if self.timeout.timed_out() => match config.timeout_action {
+                TimeoutAction::RampDown => self.torque * self.timeout.ramp(),
+                TimeoutAction::Damp => -config.timeout_damping * mech_velocity,
+            },
             _ => {
-                commands.stiffness_gain * (theta_diff.in_radians())
-                    + commands.damping_gain * (commands.velocity - mech_velocity)
+                self.torque = commands.stiffness_gain * (theta_diff.in_radians())
+                    + commands.damping_gain * (commands.velocity - mech_velocity);
+                self.torque
             }
         };
-        let q_current = torque_desired / (commands.torque_constant * GEAR_RATIO);
+        let q_current = torque_desired / (commands.torque_constant * config.gear_ratio);
         self.foc.q_current(q_current);
 
         // Get the current rail voltage.
@@ -108,6 +239,7 @@ impl Commutate for PositionVelocity {
             &hardware.current_sensor,
             &encoder_state,
             &mut hardware.cordic,
+            v_bus,
             DT,
         );
         hardware.pwm.set_voltages(v_bus, phase_voltages);
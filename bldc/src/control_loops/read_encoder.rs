@@ -1,14 +1,13 @@
-use crate::comms::fdcan::{FdcanMessage, IncomingFdcanFrame, OutgoingFdcanFrame};
-
 use super::{Commutate, ControlHardware, LoopState, SensorState};
+use crate::hal::{PendingCosSin, Peripherals, RotorPositionSensor, SinCos};
 
 pub struct EncoderResults {
-    angle: f32,
-    velocity: f32,
-    e_angle: f32,
-    e_velocity: f32,
-    a_cos: f32,
-    a_sin: f32,
+    pub angle: f32,
+    pub velocity: f32,
+    pub e_angle: f32,
+    pub e_velocity: f32,
+    pub a_cos: f32,
+    pub a_sin: f32,
 }
 
 impl EncoderResults {
@@ -39,12 +38,12 @@ impl ReadEncoder {
     }
 }
 
-impl Commutate for ReadEncoder {
+impl<P: Peripherals> Commutate<P> for ReadEncoder {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         let results = &mut self.encoder_results;
 
@@ -68,27 +67,3 @@ impl Commutate for ReadEncoder {
         (self.callback)(&self.encoder_results);
     }
 }
-
-pub struct ReadEncoderMsg {}
-
-impl IncomingFdcanFrame for ReadEncoderMsg {
-    fn unpack(_: FdcanMessage) -> Self {
-        ReadEncoderMsg {}
-    }
-}
-
-impl OutgoingFdcanFrame for EncoderResults {
-    fn pack(&self) -> FdcanMessage {
-        FdcanMessage::new(
-            0x13,
-            &[
-                self.angle.to_bits(),
-                self.velocity.to_bits(),
-                self.e_angle.to_bits(),
-                self.e_velocity.to_bits(),
-                self.a_cos.to_bits(),
-                self.a_sin.to_bits(),
-            ],
-        )
-    }
-}
@@ -0,0 +1,18 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Bus-wide sync point. Every node on the bus sees a `Sync` frame at the same instant, so rather than
+// wait for the main loop to get around to it (which depends on whatever else it's busy with), the
+// FDCAN Rx interrupt bumps this directly and the control loops latch whatever they've had staged on
+// their next cycle. That way every axis applies its new targets within one control cycle of the
+// others.
+//
+// A counter rather than a flag, same as `command_timeout`.
+static SYNCS: AtomicU32 = AtomicU32::new(0);
+
+pub fn sync() {
+    SYNCS.fetch_add(1, Ordering::Release);
+}
+
+pub fn syncs() -> u32 {
+    SYNCS.load(Ordering::Acquire)
+}
@@ -0,0 +1,114 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    fault::{self, Fault},
+    util::seq_lock::SeqLock,
+};
+
+// Execution time of the loop interrupt, as measured by the DWT cycle counter. Anything longer than
+// the PWM period is an overrun: the next ADC trigger has already fired and we're falling behind.
+// The odd overrun is survivable (and worth knowing about), but a string of them means the loop's
+// effectively stopped being a 40kHz loop, so that trips a fault.
+
+pub const CORE_CLOCK_HZ: u32 = 170_000_000;
+// 25us at 170MHz.
+pub const PERIOD_CYCLES: u32 = CORE_CLOCK_HZ / 40_000;
+// 1ms worth of back to back overruns.
+const MAX_CONSECUTIVE_OVERRUNS: u32 = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopTiming {
+    pub samples: u32,
+    pub overruns: u32,
+    // In cycles.
+    pub min: u32,
+    pub max: u32,
+    // The most recent one, for telemetry.
+    pub last: u32,
+    total: u64,
+    consecutive_overruns: u32,
+}
+
+impl LoopTiming {
+    pub const fn new() -> LoopTiming {
+        LoopTiming {
+            samples: 0,
+            overruns: 0,
+            min: u32::MAX,
+            max: 0,
+            last: 0,
+            total: 0,
+            consecutive_overruns: 0,
+        }
+    }
+
+    // Returns a fault if overruns have gone on for too long.
+    pub fn record(&mut self, cycles: u32) -> Option<Fault> {
+        // `samples` runs out a bit over a day in at 40kHz. Freeze the average there rather than
+        // let it wrap and divide the whole total by a handful of samples; `reset` starts it over.
+        if self.samples < u32::MAX {
+            self.samples += 1;
+            self.total += cycles as u64;
+        }
+        self.last = cycles;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        if cycles <= PERIOD_CYCLES {
+            self.consecutive_overruns = 0;
+            return None;
+        }
+        self.overruns = self.overruns.saturating_add(1);
+        self.consecutive_overruns += 1;
+        match self.consecutive_overruns >= MAX_CONSECUTIVE_OVERRUNS {
+            true => Some(Fault::LoopOverrun),
+            false => None,
+        }
+    }
+
+    // In cycles.
+    pub fn average(&self) -> u32 {
+        match self.samples {
+            0 => 0,
+            samples => (self.total / samples as u64) as u32,
+        }
+    }
+}
+
+impl Default for LoopTiming {
+    fn default() -> Self {
+        LoopTiming::new()
+    }
+}
+
+// Written from the loop interrupt only; see `config` for why main mustn't write to a `SeqLock` the
+// interrupt also writes. Resets are requested through `RESET` instead.
+lazy_static! {
+    static ref TIMING: SeqLock<LoopTiming> = SeqLock::new(LoopTiming::new());
+}
+static RESET: AtomicBool = AtomicBool::new(false);
+
+pub fn cycles_to_us(cycles: u32) -> f32 {
+    cycles as f32 / (CORE_CLOCK_HZ / 1_000_000) as f32
+}
+
+// Called by the loop interrupt with however long it took this time around.
+pub fn record(cycles: u32) {
+    let mut timing = TIMING.lock_write();
+    if RESET.swap(false, Ordering::AcqRel) {
+        *timing = LoopTiming::new();
+    }
+    if let Some(fault) = timing.record(cycles) {
+        fault::raise(fault);
+    }
+}
+
+pub fn current() -> LoopTiming {
+    TIMING.read()
+}
+
+// Start the stats over. Takes effect the next time the loop runs.
+pub fn reset() {
+    RESET.store(true, Ordering::Release);
+}
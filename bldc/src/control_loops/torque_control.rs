@@ -1,11 +1,31 @@
-use super::{Commutate, ControlHardware, LoopState, SensorState};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use third_party::m4vga_rs::util::spin_lock::SpinLock;
+
+use super::{
+    command_timeout::{self, CommandTimeout},
+    Commutate, ControlHardware, LoopState, SensorState,
+};
 use crate::{
+    config::{self, LoopConfig},
+    fault::{self, Fault},
     foc::{DQCurrents, FieldOrientedControlImpl},
+    hal::{BusVoltageSource, Peripherals, RotorPositionSensor, ThreePhaseBridge},
     led::Led,
     pi_controller::PIController,
+    util::buffered_state::{BufferedState, StateReader, StateWriter},
 };
 
 // Simple torque control using FoC.
+//
+// The currents can be changed while the loop's running with `command`, same as
+// `PositionVelocity::command`. Starting a new loop just to change the setpoint resets the PI
+// integrators, which shows up as a kick every time the host moves the target.
+
+static COMMAND_BUFFER: SpinLock<Option<BufferedState<DQCurrents>>> = SpinLock::new(None);
+static COMMAND: SpinLock<Option<StateWriter<DQCurrents>>> = SpinLock::new(None);
+// Bumped after each write to the buffer above, so the loop knows when there's something new.
+static COMMANDS: AtomicU32 = AtomicU32::new(0);
 
 // TODO(blakely): Hardcoded here
 const DT: f32 = 1. / 40_000.;
@@ -14,35 +34,93 @@ const _MAX_PWM_VALUE: f32 = 2125.;
 
 pub struct TorqueControl {
     foc: FieldOrientedControlImpl,
+    config: StateReader<LoopConfig>,
     loop_count: u32,
-    total_counts: u32,
+    // `None` runs until the loop's replaced or disabled.
+    total_counts: Option<u32>,
+    currents: DQCurrents,
+    commands: StateReader<DQCurrents>,
+    commands_seen: u32,
+    timeout: CommandTimeout,
 }
 
 impl TorqueControl {
     pub fn new(duration: f32, currents: DQCurrents) -> TorqueControl {
-        // TODO(blakely): Don't hard-code these; instead pull from either global config,
-        // calibration, or FDCAN command.
-        let q_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
-        let d_controller = PIController::new(1.421142407046769, 0.055681818, 24.);
+        // TODO(blakely): Don't hard code this
+        TorqueControl::with_counts(Some((40_000 as f32 * duration) as u32), currents)
+    }
+
+    // Keeps going for as long as it's left running, for masters (i.e. CANopen) that expect a
+    // torque to stay put until they say otherwise. The command timeout still applies.
+    pub fn unbounded(currents: DQCurrents) -> TorqueControl {
+        TorqueControl::with_counts(None, currents)
+    }
+
+    fn with_counts(total_counts: Option<u32>, currents: DQCurrents) -> TorqueControl {
+        // TODO(blakely): Pull these from calibration.
+        let config = config::loop_config();
+        let gains = *config.read();
+        let q_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
+        let d_controller =
+            PIController::new(gains.current_kp, gains.current_ki, gains.current_v_clamp);
 
         let mut foc = FieldOrientedControlImpl::new(q_controller, d_controller);
         foc.q_current(currents.q);
         foc.d_current(currents.d);
+
+        let mut command_buffer = COMMAND_BUFFER.lock();
+        *command_buffer = Some(BufferedState::new(currents));
+        let (reader, writer) = command_buffer
+            .as_mut()
+            .expect("No command buffer to split")
+            .split();
+        *COMMAND.lock() = Some(writer);
+
         TorqueControl {
             foc,
+            config,
             loop_count: 0,
-            // TODO(blakely): Don't hard code this
-            total_counts: (40_000 as f32 * duration) as u32,
+            total_counts,
+            currents,
+            commands: reader,
+            // Anything from before the loop started doesn't count.
+            commands_seen: COMMANDS.load(Ordering::Acquire),
+            timeout: CommandTimeout::new(),
         }
     }
+
+    // New currents for the running loop, picked up on the next cycle. Only ever called from the
+    // main thread. Doesn't restart the duration; that's still counted from `new`.
+    pub fn command(currents: DQCurrents) {
+        match COMMAND.try_lock() {
+            Ok(mut writer) => {
+                if let Some(state) = &mut *writer {
+                    *state.update() = currents;
+                    COMMANDS.fetch_add(1, Ordering::Release);
+                }
+            }
+            Err(_) => fault::raise(Fault::LockContention),
+        }
+        command_timeout::command();
+    }
+
+    pub fn foc(&self) -> &FieldOrientedControlImpl {
+        &self.foc
+    }
+
+    fn expired(&self) -> bool {
+        self.total_counts
+            .is_some_and(|total_counts| self.loop_count >= total_counts)
+    }
 }
 
-impl Commutate for TorqueControl {
+impl<P: Peripherals> Commutate<P> for TorqueControl {
     fn commutate(
         &mut self,
         _loop_state: LoopState,
         _sensor_state: &SensorState,
-        hardware: &mut ControlHardware,
+        hardware: &mut ControlHardware<P>,
     ) -> LoopState {
         Led::<crate::led::Red>::on_while(|| {
             let encoder_state = match hardware.encoder.state() {
@@ -52,18 +130,44 @@ impl Commutate for TorqueControl {
             // Get the current rail voltage.
             let v_bus = hardware.current_sensor.v_bus();
 
+            // Pick up any changes to the gains or modulation.
+            let config = *self.config.read();
+            self.foc.configure(&config);
+            hardware.pwm.set_max_duty(config.max_duty);
+
+            let commands = COMMANDS.load(Ordering::Acquire);
+            if commands != self.commands_seen {
+                self.commands_seen = commands;
+                self.currents = *self.commands.read();
+                if !self.expired() {
+                    self.foc.q_current(self.currents.q);
+                    self.foc.d_current(self.currents.d);
+                }
+            }
+
+            // There's no velocity to current mapping in here to damp with, so whatever the
+            // configured `TimeoutAction`, a timeout just ramps the currents down.
+            self.timeout
+                .update(config.torque_timeout, hardware.pwm.loop_frequency());
+            if self.timeout.timed_out() && !self.expired() {
+                let ramp = self.timeout.ramp();
+                self.foc.q_current(self.currents.q * ramp);
+                self.foc.d_current(self.currents.d * ramp);
+            }
+
             // Calculate the required PWM values via field oriented control.
             let phase_voltages = self.foc.update(
                 &hardware.current_sensor,
                 &encoder_state,
                 &mut hardware.cordic,
+                v_bus,
                 DT,
             );
             hardware.pwm.set_voltages(v_bus, phase_voltages);
 
-            self.loop_count += 1;
-            match self.loop_count {
-                x if x >= self.total_counts => {
+            self.loop_count = self.loop_count.saturating_add(1);
+            match self.expired() {
+                true => {
                     self.foc.q_current(0.);
                     self.foc.d_current(0.);
                     // if dq_currents.q < 0.1 && dq_currents.d < 0.1 {
@@ -74,7 +178,7 @@ impl Commutate for TorqueControl {
                     // }
                     LoopState::Running
                 }
-                _ => LoopState::Running,
+                false => LoopState::Running,
             }
         })
     }
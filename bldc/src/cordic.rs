@@ -1,4 +1,7 @@
-use crate::{block_until, block_while};
+use crate::{
+    block_until, block_while,
+    hal::{PendingCosSin, SinCos},
+};
 use core::f32::consts::PI;
 use core::marker::PhantomData;
 use fixed::types::I1F31;
@@ -30,6 +33,12 @@ impl<'a, const N: usize> CordicProcessing<'a, N> {
     }
 }
 
+impl<'a> PendingCosSin for CordicProcessing<'a, 2> {
+    fn get_result(self) -> [f32; 2] {
+        CordicProcessing::get_result(self)
+    }
+}
+
 fn to_q1_31(theta: Angle) -> I1F31 {
     // Normalize to [-1,1)
     let linearized = theta.normalized().in_radians() / PI - 1.;
@@ -55,8 +64,12 @@ impl Cordic {
             device: SpinLock::new(cordic),
         }
     }
+}
+
+impl SinCos for Cordic {
+    type Pending<'a> = CordicProcessing<'a, 2>;
 
-    pub fn cos_sin<'a>(&'a mut self, theta: Angle) -> CordicProcessing<'a, 2> {
+    fn cos_sin(&mut self, theta: Angle) -> CordicProcessing<'_, 2> {
         let cordic = self
             .device
             .try_lock()
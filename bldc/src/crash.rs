@@ -0,0 +1,213 @@
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+
+use third_party::m4vga_rs::util::spin_lock::SpinLock;
+
+use crate::{
+    comms::messages::{CRASH_FILE_BYTES, CRASH_MESSAGE_BYTES},
+    fault::{self, Fault},
+    util::crc::crc32,
+};
+
+// What happens when we panic. `panic-itm` and `panic-halt` both just stop, which is the last thing
+// you want when the panic was in the loop interrupt: TIM1 keeps happily generating whatever duty
+// cycle it had last. So instead we pull the power stage's plug ourselves - TIM1's main output
+// enable off, DRV_ENABLE low - leave a note about where it happened in a bit of RAM that isn't
+// cleared on startup, and reset.
+//
+// On the way back up `take_previous` picks the note up and raises `PanicReset`, and the record can
+// be read out over FDCAN with `GetCrashRecord`.
+
+// Sized to fit a frame each; see `CrashLocationMsg` and `CrashMessageMsg`.
+const FILE_BYTES: usize = CRASH_FILE_BYTES;
+const MESSAGE_BYTES: usize = CRASH_MESSAGE_BYTES;
+const MAGIC: u32 = 0x5041_4E43;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrashRecord {
+    pub line: u32,
+    pub column: u32,
+    // Both NUL-padded. Long file names keep their end, long messages keep their start.
+    file: [u8; FILE_BYTES],
+    message: [u8; MESSAGE_BYTES],
+}
+
+impl CrashRecord {
+    pub fn new(file: &str, line: u32, column: u32, message: &dyn fmt::Display) -> CrashRecord {
+        let mut record = CrashRecord {
+            line,
+            column,
+            file: [0; FILE_BYTES],
+            message: [0; MESSAGE_BYTES],
+        };
+        let mut start = file.len().saturating_sub(FILE_BYTES);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let file = &file.as_bytes()[start..];
+        record.file[..file.len()].copy_from_slice(file);
+        // Running out of room isn't an error worth reporting; we keep what fits.
+        write!(Truncating::new(&mut record.message), "{}", message).ok();
+        record
+    }
+
+    pub fn file(&self) -> &str {
+        text(&self.file)
+    }
+
+    pub fn message(&self) -> &str {
+        text(&self.message)
+    }
+
+    // Raw, for sending on to the host.
+    pub fn file_bytes(&self) -> [u8; FILE_BYTES] {
+        self.file
+    }
+
+    pub fn message_bytes(&self) -> [u8; MESSAGE_BYTES] {
+        self.message
+    }
+
+    pub fn file_words(&self) -> [u32; FILE_BYTES / 4] {
+        words(&self.file)
+    }
+
+    pub fn message_words(&self) -> [u32; MESSAGE_BYTES / 4] {
+        words(&self.message)
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut buffer = [0u32; 2 + (FILE_BYTES + MESSAGE_BYTES) / 4];
+        buffer[0] = self.line;
+        buffer[1] = self.column;
+        buffer[2..2 + FILE_BYTES / 4].copy_from_slice(&self.file_words());
+        buffer[2 + FILE_BYTES / 4..].copy_from_slice(&self.message_words());
+        crc32(&buffer)
+    }
+}
+
+// How a record sits in RAM across the reset. RAM comes up full of garbage after a power cycle, so
+// the record only counts if both the magic and CRC check out.
+#[derive(Clone, Copy)]
+pub struct SavedCrash {
+    magic: u32,
+    record: CrashRecord,
+    crc: u32,
+}
+
+impl SavedCrash {
+    pub fn new(record: CrashRecord) -> SavedCrash {
+        SavedCrash {
+            magic: MAGIC,
+            record,
+            crc: record.checksum(),
+        }
+    }
+
+    pub fn record(&self) -> Option<CrashRecord> {
+        match self.magic == MAGIC && self.crc == self.record.checksum() {
+            true => Some(self.record),
+            false => None,
+        }
+    }
+
+    pub fn invalidate(&mut self) {
+        self.magic = 0;
+    }
+}
+
+// cortex-m-rt leaves `.uninit` alone on startup, so this survives anything but a power cycle.
+#[cfg_attr(not(feature = "host"), link_section = ".uninit.CRASH")]
+static mut SAVED: MaybeUninit<SavedCrash> = MaybeUninit::uninit();
+
+static PREVIOUS: SpinLock<Option<CrashRecord>> = SpinLock::new(None);
+
+// Called once at startup, before anything's had a chance to panic again.
+pub fn take_previous() {
+    // Safety: only called from `driver::init`, before interrupts are enabled, and the only other
+    // access is the panic handler. Every bit pattern is a valid `SavedCrash`, so reading whatever
+    // was left in RAM is fine; `record` decides whether it means anything.
+    let saved = unsafe { (*core::ptr::addr_of_mut!(SAVED)).assume_init_mut() };
+    if let Some(record) = saved.record() {
+        fault::raise(Fault::PanicReset);
+        *PREVIOUS.lock() = Some(record);
+    }
+    saved.invalidate();
+}
+
+// The record left by the last panic, if the last reset was one.
+pub fn previous() -> Option<CrashRecord> {
+    *PREVIOUS.lock()
+}
+
+// Everything the binary's `#[panic_handler]` needs to do.
+pub fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    safe_power_stage();
+
+    let (file, line, column) = match info.location() {
+        Some(location) => (location.file(), location.line(), location.column()),
+        None => ("", 0, 0),
+    };
+    let record = CrashRecord::new(file, line, column, &info.message());
+    // Safety: interrupts are off and we're never coming back, so nothing else is looking.
+    unsafe {
+        core::ptr::addr_of_mut!(SAVED).write_volatile(MaybeUninit::new(SavedCrash::new(record)));
+    }
+
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+pub fn safe_power_stage() {
+    // Safety: we're going down, so whoever owned TIM1 and GPIOC isn't getting them back. Both are
+    // single writes that don't care what state the peripheral was left in.
+    #[cfg(not(feature = "host"))]
+    unsafe {
+        use stm32g4::stm32g474::{GPIOC, TIM1};
+        (*TIM1::ptr()).bdtr.modify(|_, w| w.moe().clear_bit());
+        // DRV_ENABLE.
+        (*GPIOC::ptr()).bsrr.write(|w| w.br6().set_bit());
+    };
+}
+
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Truncating<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Truncating { buffer, len: 0 }
+    }
+}
+
+impl<'a> Write for Truncating<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buffer.len() - self.len;
+        let bytes = &s.as_bytes()[..s.len().min(room)];
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        match bytes.len() == s.len() {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+// Up to the first NUL, or whatever's valid UTF-8 if truncation split a character.
+fn text(bytes: &[u8]) -> &str {
+    let bytes = &bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())];
+    match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    words
+}
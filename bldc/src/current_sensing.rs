@@ -3,7 +3,11 @@ use core::ops;
 
 use stm32g4::stm32g474 as device;
 
-use crate::{block_until, block_while, util::stm32::blocking_sleep_us};
+use crate::{
+    block_until, block_while,
+    hal::{BusVoltageSource, PhaseCurrentSource, SamplingPeriod},
+    util::stm32::blocking_sleep_us,
+};
 
 // TODO(blakely): Generalize this with HAL
 pub struct CurrentSensor<T: CurrentSensorState> {
@@ -64,8 +68,8 @@ pub fn new(
         v_refint,
         from_v_refint,
 
-        // TODO(blakely): These should be configurable.
-        sense_gain: 1. / (40. * 0.001),
+        sense_gain: 1.0,
+        // TODO(blakely): This should be configurable.
         sense_v_ref: 3.3,
 
         _marker: PhantomData,
@@ -94,7 +98,8 @@ impl<T: CurrentSensorState> CurrentSensor<T> {
 
 impl CurrentSensor<Configuring> {
     // TODO(blakely): Make this configurable after HAL is ready.
-    pub fn configure_phase_sensing(mut self) -> Self {
+    pub fn configure_phase_sensing(mut self, sense_gain: f32) -> Self {
+        self.sense_gain = sense_gain;
         let adc1 = &self.phase_a;
         let adc2 = &self.phase_b;
         let adc3 = &self.phase_c;
@@ -537,24 +542,34 @@ impl PhaseCurrents {
     }
 }
 
-impl CurrentSensor<Ready> {
+impl PhaseCurrentSource for CurrentSensor<Ready> {
     // Sample ADC values and correct for offset.
-    pub fn sample(&self) -> PhaseCurrents {
+    fn sample(&self) -> PhaseCurrents {
         sample(self)
     }
 
     // Sample raw ADC values (no offset correction).
-    pub fn sample_raw(&self) -> PhaseCurrents {
+    fn sample_raw(&self) -> PhaseCurrents {
         sample_raw(self)
     }
 
-    pub fn set_calibration(&mut self, phase_a: f32, phase_b: f32, phase_c: f32) {
+    fn set_calibration(&mut self, phase_a: f32, phase_b: f32, phase_c: f32) {
         self.phase_a_offset = phase_a;
         self.phase_b_offset = phase_b;
         self.phase_c_offset = phase_c;
     }
 
-    pub fn v_bus(&self) -> f32 {
+    fn set_sampling_period(&mut self, period: SamplingPeriod) {
+        match period {
+            SamplingPeriod::Fast => self.sampling_period_fast(),
+            SamplingPeriod::Normal => self.sampling_period_normal(),
+            SamplingPeriod::Long => self.sampling_period_long(),
+        }
+    }
+}
+
+impl BusVoltageSource for CurrentSensor<Ready> {
+    fn v_bus(&self) -> f32 {
         let v_refint = self.v_refint.dr.read().bits() as u16;
         (self.from_v_refint)(v_refint, self.v_bus.dr.read().bits() as u16) * self.v_bus_gain
     }
@@ -1,33 +1,48 @@
+use crate::boot;
+#[cfg(feature = "canopen")]
+use crate::canopen::{self, CanOpen};
 use crate::comms::fdcan::{self, Fdcan, Running};
+use crate::comms::handlers::calibrate_halls;
+use crate::comms::handlers::drv::drv_status;
+use crate::comms::handlers::faults::fault_status;
+use crate::comms::handlers::heartbeat::status;
+use crate::comms::handlers::params;
+use crate::comms::handlers::scope::scope_status;
+use crate::comms::handlers::{self, Nack};
+use crate::comms::id::NodeAddress;
 use crate::comms::messages::FdcanID;
 use crate::comms::MessageHandler;
+use crate::config::{self, Config, ConfigStore, InternalFlash};
 use crate::control_loops::calibrate_adc::CalibrateADC;
-use crate::control_loops::{ControlHardware, Controller};
+use crate::control_loops::{timing, ControlHardware, Controller};
 use crate::cordic::Cordic;
-use crate::encoder::Encoder;
-use crate::pwm::PwmOutput;
+use crate::crash;
+use crate::encoder::{Encoder, RotorSensor, RotorSensorKind};
+use crate::fault;
+use crate::gate_driver;
+use crate::hall::Halls;
+use crate::ic::{angle_sensor::AngleSensor, drv8323rs, hall, spi_encoder};
+use crate::pwm::{PwmOutput, PWM_ARR};
+use crate::scope;
+use crate::telemetry;
 use crate::timer::TimerConfig;
 use crate::util::stm32::{
     clock_setup, clocks::G4_CLOCK_SETUP, disable_dead_battery_pd, donate_systick,
 };
+use crate::watchdog::Watchdog;
 use crate::{current_sensing, timer};
-use crate::{ic::drv8323rs, ic::ma702};
-use cortex_m::peripheral as cm;
-use drv8323rs::Drv8323rs;
+use cortex_m::peripheral::{self as cm, DWT};
 use heapless::FnvIndexMap;
 use stm32g4::stm32g474 as device;
 use third_party::m4vga_rs::util::armv7m::{disable_irq, enable_irq};
 
-const V_BUS_GAIN: f32 = 16.0; // 24v with a 150k/10k voltage divider.
-
 pub struct Driver<S> {
     pub mode_state: S,
-    message_handlers: FnvIndexMap<u32, MessageHandler, 16>,
+    message_handlers: FnvIndexMap<u32, MessageHandler, 32>,
     controller: Controller,
 }
 
 pub struct DriverHardware {
-    pub drv: Drv8323rs<drv8323rs::Ready>,
     pub gpioa: device::GPIOA,
     pub fdcan: Fdcan<Running>,
 }
@@ -52,23 +67,46 @@ pub struct Init {
     pub adc4: device::ADC4,
     pub adc5: device::ADC5,
     pub cordic: device::CORDIC,
+    pub iwdg: device::IWDG,
 }
 
 pub struct Calibrating {
     pub hardware: DriverHardware,
+    pub iwdg: device::IWDG,
 }
 
 pub struct Ready {
     pub hardware: DriverHardware,
+    pub watchdog: Watchdog,
+    // Cycle count the last time `send_status` looked, and how many cycles it's been since the last
+    // `Status` went out.
+    last_cycles: u32,
+    since_status: u64,
+    // Whether we've told the bootloader this image is a keeper yet.
+    healthy: bool,
+    #[cfg(feature = "canopen")]
+    canopen: CanOpen,
+}
+
+// Everything that runs off TIM2. There's only the one callback, and it's the lowest priority
+// interrupt, so anything in here can take its time.
+fn periodic() {
+    gate_driver::poll();
+    fdcan::poll();
+    telemetry::send();
 }
 
 pub fn take_hardware() -> Driver<Init> {
-    let cp = cm::Peripherals::take().unwrap();
+    let mut cp = cm::Peripherals::take().unwrap();
     let p = device::Peripherals::take().unwrap();
 
     // Donate the SYST peripheral to the blocking sleep handler so it's available anywhere.
     donate_systick(cp.SYST);
 
+    // The loop interrupt times itself with the cycle counter.
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
     init(
         cp.NVIC,
         p.RCC,
@@ -93,6 +131,7 @@ pub fn take_hardware() -> Driver<Init> {
         p.ADC4,
         p.ADC5,
         p.CORDIC,
+        p.IWDG,
     )
 }
 
@@ -120,9 +159,20 @@ fn init(
     adc4: device::ADC4,
     adc5: device::ADC5,
     cordic: device::CORDIC,
+    iwdg: device::IWDG,
 ) -> Driver<Init> {
     disable_dead_battery_pd(&pwr);
 
+    // Pick up anything the panic handler left behind before it gets overwritten.
+    crash::take_previous();
+
+    // If the watchdog's what got us here, say so. The reset flags are sticky, so clear them for
+    // next time.
+    if rcc.csr.read().iwdgrstf().bit_is_set() {
+        fault::raise(fault::Fault::WatchdogReset);
+    }
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+
     // Make sure we don't receive any interrupts before we're ready.
     disable_irq(device::Interrupt::ADC1_2);
     disable_irq(device::Interrupt::DMA1_CH2);
@@ -137,6 +187,10 @@ fn init(
         .acr
         .modify(|_, w| w.dcen().enabled().icen().enabled().prften().enabled());
 
+    // Pull the config out of flash before anything gets configured. If there's nothing there (or
+    // it's been corrupted), fall back to the defaults.
+    config::donate_store(ConfigStore::new(InternalFlash::new(flash))).ok();
+
     // FDCAN configuration
     // Turn on PLLQ so that we can use that for FDCAN
     // TODO(blakely): Is this necessary? Can't we just use the PCLK1? CubeMX seems to think so...
@@ -211,12 +265,31 @@ fn init(
             adc4,
             adc5,
             cordic,
+            iwdg,
         },
         message_handlers: FnvIndexMap::new(),
         controller: Controller::new(),
     }
 }
 
+// Start streaming from whichever angle sensor's on SPI1.
+fn spi_encoder<A: AngleSensor>(
+    spi1: device::SPI1,
+    tim3: device::TIM3,
+    dma1: device::DMA1,
+    dmamux: &device::DMAMUX,
+    config: &Config,
+) -> Encoder<A> {
+    let sensor = spi_encoder::new::<A>(spi1, tim3)
+        .configure_spi()
+        .begin_stream_polling(dma1, dmamux);
+    Encoder::new(
+        sensor,
+        config.pole_pairs,
+        config.velocity_observer_bandwidth,
+    )
+}
+
 impl Driver<Init> {
     // TODO(blakely): Move into a device-specific, feature-guarded trait
     fn configure_gpio(&self) {
@@ -224,9 +297,10 @@ impl Driver<Init> {
         // Configure GPIOA pins
         // PA0 - ADC2_IN1 - SENSE_B
         // PA1 - ADC1_IN2 - SENSE_A
-        // PA4 - SPI1 - ENC_CS - AF5
+        // PA4 - TIM3_CH2 - ENC_CS - AF2
         // PA5 - SPI1 - ENC_SCK - AF5
         // PA6 - SPI1 - ENC_MISO - AF5
+        //   (or HALL_A-HALL_C if `rotor_sensor` says halls; see `ic::hall`)
         // PA7 - SPI1 - ENC_MOSI - AF5
         // PA7 - TIM1 - INH_A - AF6
         // PA8 - TIM1 - INH_B - AF6
@@ -300,7 +374,7 @@ impl Driver<Init> {
         // Alternate function settings
         gpioa
             .afrl
-            .modify(|_, w| w.afrl4().af5().afrl5().af5().afrl6().af5().afrl7().af5());
+            .modify(|_, w| w.afrl4().af2().afrl5().af5().afrl6().af5().afrl7().af5());
         gpioa.afrh.modify(|_, w| {
             w.afrh8()
                 .af6()
@@ -441,29 +515,58 @@ impl Driver<Init> {
     }
 
     pub fn configure_peripherals<'a>(self) -> Driver<Calibrating> {
+        let config = config::current();
         self.configure_gpio();
         let pwm = PwmOutput::new(self.mode_state.tim1, true).configure(TimerConfig {
             prescalar: 1,
-            arr: 2125,
+            arr: PWM_ARR,
         });
 
-        let ma702 = ma702::new(self.mode_state.spi1, self.mode_state.tim3)
-            .configure_spi()
-            .begin_stream_polling(self.mode_state.dma1, &self.mode_state.dmamux);
-
-        let encoder = Encoder::new(ma702, 21, 200.);
+        // Changing the rotor sensor takes a save and reset, same as the node ID.
+        let encoder = match RotorSensorKind::from_bits(config.rotor_sensor) {
+            RotorSensorKind::Ma702 => RotorSensor::Ma702(spi_encoder(
+                self.mode_state.spi1,
+                self.mode_state.tim3,
+                self.mode_state.dma1,
+                &self.mode_state.dmamux,
+                &config,
+            )),
+            RotorSensorKind::Ma730 => RotorSensor::Ma730(spi_encoder(
+                self.mode_state.spi1,
+                self.mode_state.tim3,
+                self.mode_state.dma1,
+                &self.mode_state.dmamux,
+                &config,
+            )),
+            RotorSensorKind::As5047p => RotorSensor::As5047p(spi_encoder(
+                self.mode_state.spi1,
+                self.mode_state.tim3,
+                self.mode_state.dma1,
+                &self.mode_state.dmamux,
+                &config,
+            )),
+            RotorSensorKind::Mt6835 => RotorSensor::Mt6835(spi_encoder(
+                self.mode_state.spi1,
+                self.mode_state.tim3,
+                self.mode_state.dma1,
+                &self.mode_state.dmamux,
+                &config,
+            )),
+            RotorSensorKind::Halls => RotorSensor::Halls(Halls::new(
+                hall::configure(&self.mode_state.gpioa),
+                config.pole_pairs,
+                config.velocity_observer_bandwidth,
+            )),
+        };
 
         let gpioc = &self.mode_state.gpioc;
         let drv = drv8323rs::new(self.mode_state.spi3)
             .enable(|| gpioc.bsrr.write(|w| w.bs6().set_bit()))
             .calibrate();
+        gate_driver::donate_hardware(drv);
 
         timer::donate_hardware_for_scheduler(self.mode_state.tim2);
 
-        timer::periodic_callback(1000., 0.001, || {
-            let _asdf = 1;
-        });
-
         // Set up current sensing.
         // Set up the ADC clocks. We're assuming we're running on a 170MHz AHB bus, so div=4 gives
         // us 42.5MHz (below max freq of 60MHz for single or 52MHz for multiple channels).
@@ -485,21 +588,15 @@ impl Driver<Init> {
             self.mode_state.adc4,
             self.mode_state.adc5,
         )
-        .configure_phase_sensing()
+        .configure_phase_sensing(config.sense_gain)
         .configure_v_refint()
-        .configure_v_bus(V_BUS_GAIN)
+        .configure_v_bus(config.v_bus_gain)
         .ready();
 
         // Configure FDCAN
+        // Changes to the node ID or group take effect after a save and reset.
         let fdcan = fdcan::take(self.mode_state.fdcan)
-            // TODO(blakely): clean up this API.
-            .set_extended_filter(
-                0,
-                fdcan::extended_filter::ExtendedFilterMode::StoreRxFIFO0,
-                fdcan::extended_filter::ExtendedFilterType::Classic,
-                0x1,
-                0xFFF_FFFF,
-            )
+            .set_node(NodeAddress::new(config.node_id, config.node_group))
             .configure_interrupts()
             .configure_protocol()
             .configure_timing()
@@ -510,6 +607,9 @@ impl Driver<Init> {
         enable_irq(device::Interrupt::FDCAN1_INTR0_IT);
         // Rx IRQ
         enable_irq(device::Interrupt::FDCAN1_INTR1_IT);
+        // The loop interrupt feeds these whether or not anyone's asked for anything.
+        telemetry::init();
+        scope::init();
         // ADC1 IRQ
         enable_irq(device::Interrupt::ADC1_2);
 
@@ -538,13 +638,18 @@ impl Driver<Init> {
             cordic: Cordic::new(cordic, 20),
         });
 
+        // Start watching the DRV and sending telemetry. This has to wait until everything else is
+        // configured: polling uses `blocking_sleep_us`, which isn't reentrant, and a lot of the
+        // setup above sleeps.
+        timer::periodic_callback(1000., 0.001, periodic);
+
         Driver {
             mode_state: Calibrating {
                 hardware: DriverHardware {
-                    drv,
                     gpioa: self.mode_state.gpioa,
                     fdcan,
                 },
+                iwdg: self.mode_state.iwdg,
             },
             message_handlers: self.message_handlers,
             controller: self.controller,
@@ -555,14 +660,27 @@ impl Driver<Init> {
 impl Driver<Calibrating> {
     pub fn calibrate(self) -> Driver<Ready> {
         let controller = &self.controller;
-        controller.enable_loop();
-        controller.set_loop(CalibrateADC::new(2., move |_| {}));
-        while controller.is_enabled() {}
+        // Calibration takes a couple of seconds, but it runs off the loop interrupt like anything
+        // else, so it checks in every cycle and the usual window's plenty. That way a stall here
+        // gets caught too.
+        let watchdog = Watchdog::start(self.mode_state.iwdg);
+        // If anything's already faulted there's no point calibrating; the loop won't be enabled
+        // and we'll fall straight through.
+        controller.set_loop(CalibrateADC::new(2., move |_| {})).ok();
+        while controller.is_enabled() {
+            watchdog.service(true);
+        }
         controller.disable_loop();
 
         Driver {
             mode_state: Ready {
                 hardware: self.mode_state.hardware,
+                watchdog,
+                last_cycles: DWT::cycle_count(),
+                since_status: 0,
+                healthy: false,
+                #[cfg(feature = "canopen")]
+                canopen: CanOpen::new(canopen::node_id(config::current().node_id)),
             },
             message_handlers: self.message_handlers,
             controller: self.controller,
@@ -572,14 +690,80 @@ impl Driver<Calibrating> {
 
 impl Driver<Ready> {
     pub fn listen(mut self) -> ! {
-        self.controller.enable_loop();
+        self.controller.enable_loop().ok();
 
         loop {
-            while let Some(message) = self.mode_state.hardware.fdcan.pending_message() {
-                if let Some(handler) = self.message_handlers.get(&message.id) {
-                    handler.process(&mut self.controller, message);
+            self.mode_state
+                .watchdog
+                .service(self.controller.is_enabled());
+
+            while let Some(request) = self.mode_state.hardware.fdcan.pending_message() {
+                let command = request.message.id;
+                // Coming up isn't enough for this image to be a keeper; it also has to be able to
+                // hear the host, or it could never be told to go back to the bootloader.
+                if !self.mode_state.healthy && self.message_handlers.contains_key(&command) {
+                    self.mode_state.healthy = true;
+                    boot::mark_healthy();
+                }
+                let reply = match self.message_handlers.get(&command) {
+                    Some(handler) => handler.process(&mut self.controller, request),
+                    None => handlers::reply(command, request.transaction, Err(Nack::Unsupported)),
+                };
+                if let Some(reply) = reply {
+                    fdcan::send_message(&reply);
                 }
             }
+
+            let raised = fault::take_unreported();
+            if !raised.is_empty() {
+                fdcan::send_message(&fault_status(raised));
+            }
+            if let Some(status) = gate_driver::take_changed() {
+                fdcan::send_message(&drv_status(status));
+            }
+            if scope::take_finished() {
+                fdcan::send_message(&scope_status());
+            }
+            if params::take_save_request() {
+                // Give the erase the watchdog's whole window.
+                self.mode_state
+                    .watchdog
+                    .service(self.controller.is_enabled());
+                fdcan::send_message(&params::save());
+            }
+            if let Some(calibration) = calibrate_halls::take_result() {
+                fdcan::send_message(&calibration);
+            }
+            if fdcan::take_bus_state_changed() {
+                fdcan::send_message(&fdcan::bus_status());
+            }
+            self.send_status();
+            #[cfg(feature = "canopen")]
+            self.mode_state
+                .canopen
+                .service(&self.controller, &self.mode_state.hardware.fdcan);
+        }
+    }
+
+    fn send_status(&mut self) {
+        // The cycle counter wraps every 25s, and rates slower than that are allowed, so keep a
+        // running total instead of comparing against when the last one went out. We're back here
+        // far more often than that.
+        let now = DWT::cycle_count();
+        let elapsed = now.wrapping_sub(self.mode_state.last_cycles);
+        self.mode_state.last_cycles = now;
+        self.mode_state.since_status += elapsed as u64;
+
+        // Zero turns it off.
+        let rate = config::current().heartbeat_rate;
+        if rate <= 0. {
+            return;
+        }
+        let period = (timing::CORE_CLOCK_HZ as f32 / rate) as u64;
+        if self.mode_state.since_status >= period {
+            self.mode_state.since_status = 0;
+            fdcan::send_message(&status(&self.controller));
+            fdcan::send_message(&fdcan::bus_status());
         }
     }
 
@@ -1,10 +1,19 @@
-use crate::ic::ma702::{Ma702, StreamingPolling};
-use core::f32::consts::PI;
+use crate::{
+    fault::{self, Fault},
+    hal::RotorPositionSensor,
+    hall::Halls,
+    ic::{
+        angle_sensor::{AngleReading, AngleSensor, SensorError},
+        as5047p::As5047p,
+        ma702::Ma702,
+        ma730::Ma730,
+        mt6835::Mt6835,
+        spi_encoder::{SpiEncoder, StreamingPolling},
+    },
+};
 use third_party::ang::{AbsoluteDist, Angle};
 
-const TWO_PI: f32 = PI * 2.;
-
-struct PllObserverRadians {
+pub(crate) struct PllObserverRadians {
     kp: f32,
     ki: f32,
     min_d_theta: Angle,
@@ -13,9 +22,9 @@ struct PllObserverRadians {
     d_theta: Angle,
 }
 
-struct PllObserverState {
-    angle: Angle,
-    velocity: Angle,
+pub(crate) struct PllObserverState {
+    pub angle: Angle,
+    pub velocity: Angle,
 }
 
 impl PllObserverRadians {
@@ -28,23 +37,22 @@ impl PllObserverRadians {
         // forum](https://discourse.odriverobotics.com/t/rotor-encoder-pll-and-velocity/224/4)
         let kp = 2.0 * bandwidth;
         let ki = 0.25 * (kp * kp);
-        // TODO(blakely): Don't panic here; return a `Result`.
-        if kp < 1. {
-            panic!(
-                "Observer bandwidth needs to be >= 0.5 due to discretization limitations. At {} \
-             rads/s kp={}",
-                bandwidth, kp
-            );
-        }
+        // Bandwidth needs to be >= 0.5 due to discretization limitations; `with_gains` takes care
+        // of flagging it if it isn't.
         PllObserverRadians::with_gains(kp, ki, min_d_theta)
     }
 
     // Allow instantiating with gains directly
     pub fn with_gains(kp: f32, ki: f32, min_d_theta: Angle) -> Self {
-        // TODO(blakely): Don't panic here; return a `Result`.
-        if kp < 1. {
-            panic!("Due to discretization kp must be >= 1.0. Got {}", kp);
-        }
+        // Due to discretization kp must be >= 1.0. Rather than bring down the whole board, latch a
+        // fault (which keeps the bridge off) and fall back to the slowest gains that are still
+        // stable so the position readout keeps working.
+        let (kp, ki) = if kp >= 1. {
+            (kp, ki)
+        } else {
+            fault::raise(Fault::ObserverGains);
+            (1., 0.25)
+        };
         PllObserverRadians {
             kp,
             ki,
@@ -105,7 +113,7 @@ impl PllObserverRadians {
 
 #[derive(Clone, Copy)]
 pub struct EncoderState {
-    pub raw_encoder: u16,
+    pub raw_encoder: u32,
     pub angle: Angle,
     pub velocity: Angle,
     pub angle_multiturn: Angle,
@@ -113,38 +121,31 @@ pub struct EncoderState {
     pub electrical_velocity: Angle,
 }
 
-pub struct Encoder {
-    ma702: Ma702<StreamingPolling>,
+// Turns raw angle readings into the filtered angle and velocity estimates the control loops use.
+// Doesn't care where the readings come from, so the simulator can drive it the same way the MA702
+// does.
+pub struct AngleTracker {
     pole_pairs: u8,
-    encoder_observer: PllObserverRadians,
+    observer: PllObserverRadians,
     state: Option<EncoderState>,
 }
 
-impl Encoder {
-    pub fn new(
-        ma702: Ma702<StreamingPolling>,
-        pole_pairs: u8,
-        velocity_observer_bandwidth: f32,
-    ) -> Encoder {
-        Encoder {
-            ma702,
+impl AngleTracker {
+    pub fn new(pole_pairs: u8, velocity_observer_bandwidth: f32, min_d_theta: Angle) -> Self {
+        AngleTracker {
             pole_pairs,
-            encoder_observer: PllObserverRadians::with_bandwidth(
-                velocity_observer_bandwidth,
-                Angle::Radians(TWO_PI / 4096.),
-            ),
+            observer: PllObserverRadians::with_bandwidth(velocity_observer_bandwidth, min_d_theta),
             state: None,
         }
     }
 
-    pub fn update(&mut self, delta_t: f32) -> EncoderState {
-        let angle_state = self.ma702.update(delta_t);
-        let pll_state = self.encoder_observer.update(delta_t, angle_state.angle);
-        let electrical_angle = (angle_state.angle * self.pole_pairs as f32).normalized();
+    pub fn update(&mut self, delta_t: f32, raw_angle: u32, angle: Angle) -> EncoderState {
+        let pll_state = self.observer.update(delta_t, angle);
+        let electrical_angle = (angle * self.pole_pairs as f32).normalized();
         let electrical_velocity = pll_state.velocity * self.pole_pairs as f32;
 
         let mut new_state = EncoderState {
-            raw_encoder: angle_state.raw_angle,
+            raw_encoder: raw_angle,
             angle: pll_state.angle,
             velocity: pll_state.velocity,
             angle_multiturn: pll_state.angle,
@@ -164,3 +165,109 @@ impl Encoder {
         &self.state
     }
 }
+
+// Bad readings in a row before we stop trusting the sensor. The odd one is expected, e.g. when a
+// multi-word reading's caught halfway through being streamed in.
+const READOUT_ERROR_LIMIT: u32 = 4;
+
+pub struct Encoder<A: AngleSensor> {
+    sensor: SpiEncoder<A, StreamingPolling>,
+    tracker: AngleTracker,
+    errors: u32,
+}
+
+impl<A: AngleSensor> Encoder<A> {
+    pub fn new(
+        sensor: SpiEncoder<A, StreamingPolling>,
+        pole_pairs: u8,
+        velocity_observer_bandwidth: f32,
+    ) -> Encoder<A> {
+        Encoder {
+            sensor,
+            // The observer only ever sees whole counts, so anything within one of them is as good
+            // as on it.
+            tracker: AngleTracker::new(pole_pairs, velocity_observer_bandwidth, A::resolution()),
+            errors: 0,
+        }
+    }
+}
+
+impl<A: AngleSensor> RotorPositionSensor for Encoder<A> {
+    fn update(&mut self, delta_t: f32) -> EncoderState {
+        // Carry on with the last good reading until there've been too many bad ones.
+        let reading: AngleReading = match self.sensor.update(delta_t) {
+            Ok(reading) => {
+                self.errors = 0;
+                reading
+            }
+            // Not a new error, just the sensor getting over the last one.
+            Err(SensorError::Recovering) => *self.sensor.state(),
+            Err(_) => {
+                self.errors += 1;
+                if self.errors >= READOUT_ERROR_LIMIT {
+                    fault::raise(Fault::EncoderReadout);
+                }
+                *self.sensor.state()
+            }
+        };
+        self.tracker
+            .update(delta_t, reading.raw_angle, reading.angle)
+    }
+
+    fn state(&self) -> &Option<EncoderState> {
+        self.tracker.state()
+    }
+}
+
+// Which of the above the board's got, as stored in the config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RotorSensorKind {
+    Ma702 = 0,
+    Halls = 1,
+    Ma730 = 2,
+    As5047p = 3,
+    Mt6835 = 4,
+}
+
+impl RotorSensorKind {
+    pub fn from_bits(bits: u32) -> RotorSensorKind {
+        match bits {
+            1 => RotorSensorKind::Halls,
+            2 => RotorSensorKind::Ma730,
+            3 => RotorSensorKind::As5047p,
+            4 => RotorSensorKind::Mt6835,
+            _ => RotorSensorKind::Ma702,
+        }
+    }
+}
+
+// Whichever sensor the board was set up with; see `driver::configure_peripherals`.
+pub enum RotorSensor {
+    Ma702(Encoder<Ma702>),
+    Halls(Halls),
+    Ma730(Encoder<Ma730>),
+    As5047p(Encoder<As5047p>),
+    Mt6835(Encoder<Mt6835>),
+}
+
+impl RotorPositionSensor for RotorSensor {
+    fn update(&mut self, delta_t: f32) -> EncoderState {
+        match self {
+            RotorSensor::Ma702(encoder) => encoder.update(delta_t),
+            RotorSensor::Halls(halls) => halls.update(delta_t),
+            RotorSensor::Ma730(encoder) => encoder.update(delta_t),
+            RotorSensor::As5047p(encoder) => encoder.update(delta_t),
+            RotorSensor::Mt6835(encoder) => encoder.update(delta_t),
+        }
+    }
+
+    fn state(&self) -> &Option<EncoderState> {
+        match self {
+            RotorSensor::Ma702(encoder) => encoder.state(),
+            RotorSensor::Halls(halls) => halls.state(),
+            RotorSensor::Ma730(encoder) => encoder.state(),
+            RotorSensor::As5047p(encoder) => encoder.state(),
+            RotorSensor::Mt6835(encoder) => encoder.state(),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Central fault tracking. Faults can be raised from anywhere - including the control loop
+// interrupt - since everything here is a single atomic. Once raised, a fault stays latched until
+// it's explicitly cleared, even if whatever caused it goes away.
+//
+// Critical faults trip the bridge: the control loop interrupt notices on its next iteration, zeroes
+// the phases and disables the outputs, and `Controller` refuses to start another loop until the
+// fault is cleared. Warnings are latched and reported, but otherwise don't stop anything.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+// Codes are part of the wire protocol (they're bit positions in `Faults`), so don't renumber them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    // Position/velocity observer gains are too low to be stable after discretization.
+    ObserverGains = 0,
+    // A control loop was asked to run with parameters outside what's safe.
+    LoopParameters = 1,
+    // Something was locked when it shouldn't have been, and the operation was dropped.
+    LockContention = 2,
+    // A frame failed to go out on the bus.
+    FdcanTx = 3,
+    // The FDCAN peripheral went bus-off.
+    FdcanBusOff = 4,
+    // The gate driver kept tripping on VDS or sense amp overcurrent, even after being retried.
+    DrvOvercurrent = 5,
+    // Same, but for VM or charge pump undervoltage.
+    DrvUndervoltage = 6,
+    // Gate driver saw a VGS fault on one of the FETs.
+    DrvGateDrive = 7,
+    // Gate driver shut down due to overtemperature.
+    DrvOvertemperature = 8,
+    // Gate driver is getting warm.
+    DrvOvertemperatureWarning = 9,
+    // Gate driver tripped on something recoverable and was cleared.
+    DrvRetried = 10,
+    // One of the limits checked by `protection` was exceeded.
+    Overcurrent = 11,
+    BusOvervoltage = 12,
+    BusUndervoltage = 13,
+    // The control loop interrupt kept running past its period.
+    LoopOverrun = 14,
+    // We came up after being reset by the watchdog.
+    WatchdogReset = 15,
+    // We came up after a panic. See `crash` for where.
+    PanicReset = 16,
+    // A control loop stopped hearing from the host. See `command_timeout`.
+    CommandTimeout = 17,
+    // The FDCAN peripheral went error passive. See `comms::bus_monitor`.
+    FdcanErrorPassive = 18,
+    // Frames from the host were dropped, either by us or by the hardware.
+    FdcanRxOverflow = 19,
+    // The hall sensors read all on or all off, which they never should. See `hall`.
+    HallSensor = 20,
+    // The angle sensor kept failing its parity or CRC check, or flagging an error of its own. See
+    // `encoder::Encoder`.
+    EncoderReadout = 21,
+}
+
+const ALL_FAULTS: [Fault; 22] = [
+    Fault::ObserverGains,
+    Fault::LoopParameters,
+    Fault::LockContention,
+    Fault::FdcanTx,
+    Fault::FdcanBusOff,
+    Fault::DrvOvercurrent,
+    Fault::DrvUndervoltage,
+    Fault::DrvGateDrive,
+    Fault::DrvOvertemperature,
+    Fault::DrvOvertemperatureWarning,
+    Fault::DrvRetried,
+    Fault::Overcurrent,
+    Fault::BusOvervoltage,
+    Fault::BusUndervoltage,
+    Fault::LoopOverrun,
+    Fault::WatchdogReset,
+    Fault::PanicReset,
+    Fault::CommandTimeout,
+    Fault::FdcanErrorPassive,
+    Fault::FdcanRxOverflow,
+    Fault::HallSensor,
+    Fault::EncoderReadout,
+];
+
+impl Fault {
+    pub const fn severity(&self) -> Severity {
+        match self {
+            Fault::ObserverGains
+            | Fault::LoopParameters
+            | Fault::FdcanBusOff
+            | Fault::DrvOvercurrent
+            | Fault::DrvUndervoltage
+            | Fault::DrvGateDrive
+            | Fault::DrvOvertemperature
+            | Fault::Overcurrent
+            | Fault::BusOvervoltage
+            | Fault::BusUndervoltage
+            | Fault::LoopOverrun
+            | Fault::HallSensor
+            | Fault::EncoderReadout => Severity::Critical,
+            Fault::LockContention
+            | Fault::FdcanTx
+            | Fault::DrvOvertemperatureWarning
+            | Fault::DrvRetried
+            | Fault::WatchdogReset
+            | Fault::PanicReset
+            | Fault::CommandTimeout
+            | Fault::FdcanErrorPassive
+            | Fault::FdcanRxOverflow => Severity::Warning,
+        }
+    }
+
+    const fn bit(&self) -> u32 {
+        1 << *self as u32
+    }
+}
+
+const CRITICAL_MASK: u32 = {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < ALL_FAULTS.len() {
+        if let Severity::Critical = ALL_FAULTS[i].severity() {
+            mask |= ALL_FAULTS[i].bit();
+        }
+        i += 1;
+    }
+    mask
+};
+
+// A set of faults.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults(u32);
+
+impl Faults {
+    pub const ALL: Faults = Faults(u32::MAX);
+
+    pub fn from_bits(bits: u32) -> Faults {
+        Faults(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, fault: Fault) -> bool {
+        self.0 & fault.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn critical(&self) -> Faults {
+        Faults(self.0 & CRITICAL_MASK)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Fault> {
+        let faults = *self;
+        ALL_FAULTS
+            .into_iter()
+            .filter(move |fault| faults.contains(*fault))
+    }
+}
+
+impl From<Fault> for Faults {
+    fn from(fault: Fault) -> Self {
+        Faults(fault.bit())
+    }
+}
+
+static ACTIVE: AtomicU32 = AtomicU32::new(0);
+// Faults that have been raised since the last call to `take_unreported`.
+static UNREPORTED: AtomicU32 = AtomicU32::new(0);
+
+// Raising a fault that's already latched isn't news, so it's only reported the first time. That
+// way things that poll (or errors that repeat) don't flood the bus.
+pub fn raise(fault: Fault) {
+    if ACTIVE.fetch_or(fault.bit(), Ordering::AcqRel) & fault.bit() == 0 {
+        UNREPORTED.fetch_or(fault.bit(), Ordering::AcqRel);
+    }
+}
+
+pub fn active() -> Faults {
+    Faults(ACTIVE.load(Ordering::Acquire))
+}
+
+// Whether any critical faults are latched, i.e. the bridge must be kept in a safe state.
+pub fn tripped() -> bool {
+    ACTIVE.load(Ordering::Acquire) & CRITICAL_MASK != 0
+}
+
+// Clear the given faults, returning whichever are still active afterwards.
+pub fn clear(faults: Faults) -> Faults {
+    Faults(ACTIVE.fetch_and(!faults.0, Ordering::AcqRel) & !faults.0)
+}
+
+pub fn take_unreported() -> Faults {
+    Faults(UNREPORTED.swap(0, Ordering::AcqRel))
+}
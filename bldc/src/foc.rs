@@ -1,17 +1,20 @@
 use crate::{
-    cordic::Cordic,
-    current_sensing::{CurrentSensor, PhaseCurrents, Ready},
+    config::LoopConfig,
+    current_sensing::PhaseCurrents,
     encoder::EncoderState,
+    hal::{PendingCosSin, PhaseCurrentSource, SinCos},
     pi_controller::PIController,
     pwm::PhaseVoltages,
 };
+use num_traits::Float;
 
-// Field-oriented control. Very basic Park/Clark forward and inverse. Currently no SVM is performed,
-// and only a single i_q/i_d value is accepted.
+// Field-oriented control. Very basic Park/Clark forward and inverse followed by a selectable
+// modulation strategy, and only a single i_q/i_d value is accepted.
 
 const TWO_THIRDS: f32 = 0.6666666666666;
 const SQRT_3: f32 = 1.73205080757;
 const FRAC_SQRT_3_2: f32 = SQRT_3 / 2.;
+pub use protocol::params::MAX_OVERMODULATION;
 
 pub struct PhaseDuty {
     pub a: f32,
@@ -19,14 +22,16 @@ pub struct PhaseDuty {
     pub c: f32,
 }
 
+#[derive(Clone, Copy)]
 pub struct DQCurrents {
     pub q: f32,
     pub d: f32,
 }
 
-struct DQVoltages {
-    q: f32,
-    d: f32,
+#[derive(Clone, Copy)]
+pub struct DQVoltages {
+    pub q: f32,
+    pub d: f32,
 }
 
 fn forward_park_clark(phase_currents: PhaseCurrents, cos: f32, sin: f32) -> DQCurrents {
@@ -60,28 +65,82 @@ fn inverse_park_clark(dq_voltages: DQVoltages, cos: f32, sin: f32) -> PhaseVolta
     PhaseVoltages { a, b, c }
 }
 
-// fn _space_vector_modulation(v_ref: f32, phase_voltages: PhaseVoltages) -> PhaseDuty {
-//     let PhaseVoltages {
-//         a: a_raw,
-//         b: b_raw,
-//         c: c_raw,
-//     } = phase_voltages;
-//     let v_min = a_raw.min(b_raw).min(c_raw);
-//     let v_max = a_raw.max(b_raw).max(c_raw);
-//     let v_offset = (v_min + v_max) / 2.;
-//     // const PWM_OFFSET: f32 = (0.0 + 0.94) / 2.;
-//     let pwm_offset: f32 = (0.0 + 0.94) / 2.;
-//     let a = (0.5 * (a_raw - v_offset) / v_ref + pwm_offset)
-//         .max(0.)
-//         .min(0.94);
-//     let b = (0.5 * (b_raw - v_offset) / v_ref + pwm_offset)
-//         .max(0.)
-//         .min(0.94);
-//     let c = (0.5 * (c_raw - v_offset) / v_ref + pwm_offset)
-//         .max(0.)
-//         .min(0.94);
-//     PhaseDuty { a, b, c }
-// }
+// Limit the dq voltage vector to a circle of radius `v_max`, giving priority to the d axis: v_d
+// keeps as much of the available voltage as it asks for and v_q gets whatever is left over.
+pub fn limit_voltage_vector(voltages: DQVoltages, v_max: f32) -> DQVoltages {
+    let d = voltages.d.max(-v_max).min(v_max);
+    let q_max = (v_max * v_max - d * d).max(0.).sqrt();
+    let q = voltages.q.max(-q_max).min(q_max);
+    DQVoltages { q, d }
+}
+
+// How the phase voltages coming out of the inverse Park/Clark transform are turned into PWM duties.
+// All strategies produce identical line-to-line voltages; they only differ in the common-mode
+// (zero-sequence) voltage injected into all three phases.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Modulation {
+    // Plain sine-triangle PWM. Each phase is centered on v_bus/2, limiting the linear range to a
+    // phase voltage of v_bus/2.
+    Sinusoidal = 0,
+    // Min-max injection, which is equivalent to centered space vector modulation. Extends the
+    // linear range to v_bus/sqrt(3): ~15% more than sinusoidal.
+    SpaceVector = 1,
+    // 60° discontinuous PWM (DPWM1). The phase with the largest magnitude is clamped to its rail so
+    // that each phase stops switching for a third of the electrical cycle. Same linear range as
+    // SVPWM with fewer switching losses, at the cost of more ripple at low modulation indices.
+    Discontinuous = 2,
+}
+
+impl Modulation {
+    pub fn from_bits(bits: u32) -> Modulation {
+        match bits {
+            0 => Modulation::Sinusoidal,
+            2 => Modulation::Discontinuous,
+            _ => Modulation::SpaceVector,
+        }
+    }
+
+    // Largest phase voltage magnitude that can be produced while staying in the linear region.
+    pub fn max_voltage(&self, v_bus: f32) -> f32 {
+        match self {
+            Modulation::Sinusoidal => v_bus / 2.,
+            Modulation::SpaceVector | Modulation::Discontinuous => v_bus / SQRT_3,
+        }
+    }
+}
+
+// Inject the common-mode offset for the given modulation strategy. Input voltages are expected to
+// be zero-sequence free (i.e. straight out of `inverse_park_clark`).
+pub fn modulate(voltages: PhaseVoltages, v_bus: f32, modulation: Modulation) -> PhaseVoltages {
+    let PhaseVoltages { a, b, c } = voltages;
+    let v_max = a.max(b).max(c);
+    let v_min = a.min(b).min(c);
+    let offset = match modulation {
+        Modulation::Sinusoidal => 0.,
+        Modulation::SpaceVector => -(v_max + v_min) / 2.,
+        // Clamp whichever peak is furthest from zero to its respective rail.
+        Modulation::Discontinuous => match v_max + v_min {
+            x if x >= 0. => v_bus / 2. - v_max,
+            _ => -v_bus / 2. - v_min,
+        },
+    };
+    PhaseVoltages {
+        a: a + offset,
+        b: b + offset,
+        c: c + offset,
+    }
+}
+
+// What the current loop saw and did on its last update. For telemetry; nothing reads it back.
+#[derive(Clone, Copy, Debug)]
+pub struct FocState {
+    pub current_d: f32,
+    pub current_q: f32,
+    pub voltage_d: f32,
+    pub voltage_q: f32,
+    pub integrator_d: f32,
+    pub integrator_q: f32,
+}
 
 pub struct FieldOrientedControlImpl {
     q_controller: PIController,
@@ -89,6 +148,18 @@ pub struct FieldOrientedControlImpl {
 
     q_current_target: f32,
     d_current_target: f32,
+
+    modulation: Modulation,
+    // Radius of the voltage limit circle relative to the linear limit of the modulation strategy.
+    // Anything above 1.0 enters overmodulation, where the duty clamp distorts the output.
+    overmodulation: f32,
+    // `current_v_clamp` from the config. The PIs are clamped to whichever's lower out of this and
+    // what the modulation can actually produce from the bus right now.
+    v_clamp: f32,
+
+    // From the last update.
+    dq_currents: DQCurrents,
+    dq_voltages: DQVoltages,
 }
 
 impl FieldOrientedControlImpl {
@@ -98,9 +169,42 @@ impl FieldOrientedControlImpl {
             d_controller,
             q_current_target: 0.,
             d_current_target: 0.,
+            modulation: Modulation::SpaceVector,
+            overmodulation: 1.,
+            v_clamp: f32::MAX,
+            dq_currents: DQCurrents { q: 0., d: 0. },
+            dq_voltages: DQVoltages { q: 0., d: 0. },
         }
     }
 
+    // Switch modulation strategies. Safe to call between updates.
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.modulation = modulation;
+    }
+
+    pub fn modulation(&self) -> Modulation {
+        self.modulation
+    }
+
+    // Scale the voltage limit circle past the linear range of the modulator, up to six-step.
+    pub fn set_overmodulation(&mut self, ratio: f32) {
+        self.overmodulation = ratio.max(0.).min(MAX_OVERMODULATION);
+    }
+
+    // Pick up the gains and modulation from the config. Also safe to call between updates.
+    pub fn configure(&mut self, config: &LoopConfig) {
+        self.set_current_gains(config.current_kp, config.current_ki, config.current_v_clamp);
+        self.set_modulation(config.modulation);
+        self.set_overmodulation(config.overmodulation);
+    }
+
+    // Update the gains of both current controllers.
+    pub fn set_current_gains(&mut self, k: f32, ki: f32, v_clamp: f32) {
+        self.q_controller.set_gains(k, ki, v_clamp);
+        self.d_controller.set_gains(k, ki, v_clamp);
+        self.v_clamp = v_clamp;
+    }
+
     pub fn q_current(&mut self, current: f32) {
         self.q_current_target = current;
     }
@@ -109,11 +213,23 @@ impl FieldOrientedControlImpl {
         self.d_current_target = current;
     }
 
-    pub fn update(
+    pub fn state(&self) -> FocState {
+        FocState {
+            current_d: self.dq_currents.d,
+            current_q: self.dq_currents.q,
+            voltage_d: self.dq_voltages.d,
+            voltage_q: self.dq_voltages.q,
+            integrator_d: self.d_controller.integral(),
+            integrator_q: self.q_controller.integral(),
+        }
+    }
+
+    pub fn update<C: PhaseCurrentSource, S: SinCos>(
         &mut self,
-        current_sensor: &CurrentSensor<Ready>,
+        current_sensor: &C,
         encoder_state: &EncoderState,
-        cordic: &mut Cordic,
+        cordic: &mut S,
+        v_bus: f32,
         dt: f32,
     ) -> PhaseVoltages {
         // Kick off CORDIC conversion
@@ -130,23 +246,34 @@ impl FieldOrientedControlImpl {
         let new_electrical_theta =
             encoder_state.electrical_angle + 1.5f32 * dt * encoder_state.electrical_velocity;
         let pending_cos_sin = cordic.cos_sin(new_electrical_theta);
-        // In the meantime, update the controllers for d and q axes
+        // In the meantime, update the controllers for d and q axes. Neither axis can ever get more
+        // than the whole vector, so there's no sense letting either integrator wind up past it.
+        let v_max = self.modulation.max_voltage(v_bus) * self.overmodulation;
+        let v_clamp = self.v_clamp.min(v_max);
+        self.q_controller.set_v_clamp(v_clamp);
+        self.d_controller.set_v_clamp(v_clamp);
         let new_q_voltage = self
             .q_controller
             .update(dq_currents.q, self.q_current_target);
         let new_d_voltage = self
             .d_controller
             .update(dq_currents.d, self.d_current_target);
-        // Get the result of the new theta.
-        let [cos, sin] = pending_cos_sin.get_result();
-        let new_voltages = inverse_park_clark(
+        // The bus can only produce so much voltage, so limit the combined vector and let both
+        // integrators know how much of their output was actually applied.
+        let dq_voltages = limit_voltage_vector(
             DQVoltages {
                 q: new_q_voltage,
                 d: new_d_voltage,
             },
-            cos,
-            sin,
+            v_max,
         );
-        new_voltages
+        self.q_controller.saturate(dq_voltages.q);
+        self.d_controller.saturate(dq_voltages.d);
+        // Get the result of the new theta.
+        let [cos, sin] = pending_cos_sin.get_result();
+        self.dq_currents = dq_currents;
+        self.dq_voltages = dq_voltages;
+        let new_voltages = inverse_park_clark(dq_voltages, cos, sin);
+        modulate(new_voltages, v_bus, self.modulation)
     }
 }
@@ -0,0 +1,266 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use stm32g4::stm32g474 as device;
+use third_party::m4vga_rs::util::{spin_lock::SpinLock, sync::acquire_hw};
+
+use crate::{
+    fault::{self, Fault},
+    ic::drv8323rs::{self, Drv8323rs},
+    util::{interrupts::block_interrupts, seq_lock::SeqLock},
+};
+
+// Keeps an eye on the DRV8323RS while we're running. The fault status registers are polled from the
+// TIM2 scheduler and decoded into `DrvFault`s, which get raised with the fault manager.
+//
+// Overcurrent and undervoltage are things the DRV can recover from on its own (its OCP_MODE even
+// has an auto-retry option), so those get cleared and retried a few times before we give up and
+// trip. Gate drive faults and overtemperature shutdown latch immediately. Clearing faults via
+// `ClearFaults` also clears the DRV.
+//
+// Polling, rather than waiting on nFAULT, since nFAULT isn't broken out to anything on this board.
+
+// How many times a recoverable fault gets cleared before it trips the bridge.
+const MAX_RETRIES: u8 = 3;
+// Number of consecutive clean polls before the retries reset. One second at the 1kHz poll rate.
+const RETRY_RESET_POLLS: u16 = 1000;
+
+// Fault status register bits; see section 8.6.1 of the datasheet. The low six bits of both
+// registers are per-FET, in the order `FETS` below.
+const FS1_UVLO: u16 = 1 << 7;
+const FS1_OTSD: u16 = 1 << 6;
+const FS2_SA_OC: u16 = 1 << 10;
+const FS2_OTW: u16 = 1 << 7;
+const FS2_CPUV: u16 = 1 << 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    A,
+    B,
+    C,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Switch {
+    High,
+    Low,
+}
+
+const FETS: [(Phase, Switch); 6] = [
+    (Phase::A, Switch::High),
+    (Phase::A, Switch::Low),
+    (Phase::B, Switch::High),
+    (Phase::B, Switch::Low),
+    (Phase::C, Switch::High),
+    (Phase::C, Switch::Low),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrvFault {
+    VdsOvercurrent(Phase, Switch),
+    SenseOvercurrent(Phase),
+    GateDrive(Phase, Switch),
+    Undervoltage,
+    ChargePumpUndervoltage,
+    Overtemperature,
+    OvertemperatureWarning,
+}
+
+impl DrvFault {
+    // The fault that gets raised if this can't be (or has already been) retried.
+    pub fn fault(&self) -> Fault {
+        match self {
+            DrvFault::VdsOvercurrent(..) | DrvFault::SenseOvercurrent(_) => Fault::DrvOvercurrent,
+            DrvFault::Undervoltage | DrvFault::ChargePumpUndervoltage => Fault::DrvUndervoltage,
+            DrvFault::GateDrive(..) => Fault::DrvGateDrive,
+            DrvFault::Overtemperature => Fault::DrvOvertemperature,
+            DrvFault::OvertemperatureWarning => Fault::DrvOvertemperatureWarning,
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        matches!(self.fault(), Fault::DrvOvercurrent | Fault::DrvUndervoltage)
+    }
+}
+
+// Raw contents of the two fault status registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DrvStatus {
+    pub fault_status_1: u16,
+    pub fault_status_2: u16,
+}
+
+impl DrvStatus {
+    pub fn is_clear(&self) -> bool {
+        self.faults().next().is_none()
+    }
+
+    pub fn faults(&self) -> impl Iterator<Item = DrvFault> {
+        let DrvStatus {
+            fault_status_1: fs1,
+            fault_status_2: fs2,
+        } = *self;
+        let fets = FETS
+            .into_iter()
+            .enumerate()
+            .flat_map(move |(i, (phase, switch))| {
+                let bit = 1 << (5 - i);
+                let vds = (fs1 & bit != 0).then_some(DrvFault::VdsOvercurrent(phase, switch));
+                let vgs = (fs2 & bit != 0).then_some(DrvFault::GateDrive(phase, switch));
+                vds.into_iter().chain(vgs)
+            });
+        let sense = [Phase::A, Phase::B, Phase::C]
+            .into_iter()
+            .enumerate()
+            .filter(move |(i, _)| fs2 & (FS2_SA_OC >> i) != 0)
+            .map(|(_, phase)| DrvFault::SenseOvercurrent(phase));
+        let others = [
+            (fs1 & FS1_UVLO, DrvFault::Undervoltage),
+            (fs2 & FS2_CPUV, DrvFault::ChargePumpUndervoltage),
+            (fs1 & FS1_OTSD, DrvFault::Overtemperature),
+            (fs2 & FS2_OTW, DrvFault::OvertemperatureWarning),
+        ]
+        .into_iter()
+        .filter(|(bits, _)| *bits != 0)
+        .map(|(_, fault)| fault);
+        fets.chain(sense).chain(others)
+    }
+}
+
+// Decides what to do about whatever the DRV is reporting. Kept separate from the hardware so the
+// retry policy can be tested on the host.
+pub struct DrvMonitor {
+    status: DrvStatus,
+    retries: u8,
+    clean_polls: u16,
+}
+
+impl DrvMonitor {
+    pub fn new() -> DrvMonitor {
+        DrvMonitor {
+            status: DrvStatus::default(),
+            retries: 0,
+            clean_polls: 0,
+        }
+    }
+
+    pub fn status(&self) -> DrvStatus {
+        self.status
+    }
+
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    // Start over, e.g. after the faults have been cleared by hand.
+    pub fn reset(&mut self) {
+        *self = DrvMonitor::new();
+    }
+
+    // Feed in the latest status, raising any faults. Returns whether the DRV's latched faults
+    // should be cleared to retry.
+    pub fn update(&mut self, status: DrvStatus) -> bool {
+        self.status = status;
+        if status.is_clear() {
+            if self.clean_polls < RETRY_RESET_POLLS {
+                self.clean_polls += 1;
+            } else {
+                self.retries = 0;
+            }
+            return false;
+        }
+
+        self.clean_polls = 0;
+        let mut retry = false;
+        for drv_fault in status.faults() {
+            if drv_fault.retryable() && self.retries < MAX_RETRIES {
+                retry = true;
+            } else {
+                fault::raise(drv_fault.fault());
+            }
+        }
+        if retry {
+            self.retries += 1;
+            fault::raise(Fault::DrvRetried);
+        }
+        retry
+    }
+}
+
+struct Monitor {
+    drv: Drv8323rs<drv8323rs::Ready>,
+    state: DrvMonitor,
+}
+
+static MONITOR: SpinLock<Option<Monitor>> = SpinLock::new(None);
+lazy_static! {
+    static ref STATUS: SeqLock<DrvStatus> = SeqLock::new(DrvStatus::default());
+}
+static STATUS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub fn donate_hardware(drv: Drv8323rs<drv8323rs::Ready>) {
+    *MONITOR.lock() = Some(Monitor {
+        drv,
+        state: DrvMonitor::new(),
+    });
+}
+
+fn read_status(drv: &Drv8323rs<drv8323rs::Ready>) -> DrvStatus {
+    DrvStatus {
+        fault_status_1: drv.fault_status_1().read().bits(),
+        fault_status_2: drv.fault_status_2().read().bits(),
+    }
+}
+
+fn clear_drv(drv: &Drv8323rs<drv8323rs::Ready>) {
+    // CLR_FLT resets itself once the faults are cleared.
+    drv.control_register()
+        .update(|_, w| w.clear_latched_faults().set_bit());
+}
+
+// Scheduled from TIM2. Low priority, so it's fine to sit here for the couple dozen microseconds it
+// takes to go over SPI.
+pub fn poll() {
+    let monitor = &mut *acquire_hw(&MONITOR);
+    let status = read_status(&monitor.drv);
+    let previous = monitor.state.status();
+    if monitor.state.update(status) {
+        clear_drv(&monitor.drv);
+    }
+    if status != previous {
+        *STATUS.lock_write() = status;
+        STATUS_CHANGED.store(true, Ordering::Release);
+    }
+}
+
+// The latest status, if it's changed since the last time this was called.
+pub fn take_changed() -> Option<DrvStatus> {
+    if STATUS_CHANGED.swap(false, Ordering::AcqRel) {
+        Some(STATUS.read())
+    } else {
+        None
+    }
+}
+
+pub fn clear_faults() {
+    block_interrupts([device::Interrupt::TIM2], &MONITOR, |mut monitor| {
+        clear_drv(&monitor.drv);
+        monitor.state.reset();
+    });
+}
+
+// Every register, in address order. Handy for debugging.
+pub fn dump_registers() -> [u16; 7] {
+    block_interrupts([device::Interrupt::TIM2], &MONITOR, |monitor| {
+        let drv = &monitor.drv;
+        [
+            drv.fault_status_1().read().bits(),
+            drv.fault_status_2().read().bits(),
+            drv.control_register().read().bits(),
+            drv.gate_drive_hs().read().bits(),
+            drv.gate_drive_ls().read().bits(),
+            drv.over_current_protection().read().bits(),
+            drv.current_sense().read().bits(),
+        ]
+    })
+}
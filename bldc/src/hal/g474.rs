@@ -0,0 +1,19 @@
+use super::Peripherals;
+use crate::{
+    cordic::Cordic,
+    current_sensing::{CurrentSensor, Ready},
+    encoder::RotorSensor,
+    pwm::PwmOutput,
+};
+
+// The pino BLDC board: ADC1-5 for phase currents and v_bus, TIM1 driving the DRV8323RS, an angle
+// sensor on SPI (or three halls on the same connector) for rotor position, and the CORDIC
+// coprocessor for cos/sin.
+pub struct G474 {}
+
+impl Peripherals for G474 {
+    type CurrentSensor = CurrentSensor<Ready>;
+    type Bridge = PwmOutput;
+    type Encoder = RotorSensor;
+    type SinCos = Cordic;
+}
@@ -0,0 +1,94 @@
+use crate::{
+    current_sensing::PhaseCurrents,
+    encoder::EncoderState,
+    pwm::{PhaseVoltages, PwmDuty},
+};
+use third_party::ang::Angle;
+
+pub mod g474;
+
+// The pieces of hardware a control loop needs, as traits. The control loops only ever see a
+// `ControlHardware<P>`, so the same loop code can be run against the G474, the host-side simulator,
+// a mock, or whatever board comes next.
+
+// How long the ADCs sample for. Longer sampling gives less noise, but pushes out the end of the
+// conversion.
+#[derive(Clone, Copy)]
+pub enum SamplingPeriod {
+    Fast,
+    Normal,
+    Long,
+}
+
+pub trait PhaseCurrentSource {
+    // Sample phase currents and correct for offset.
+    fn sample(&self) -> PhaseCurrents;
+    // Sample phase currents without offset correction.
+    fn sample_raw(&self) -> PhaseCurrents;
+    fn set_calibration(&mut self, phase_a: f32, phase_b: f32, phase_c: f32);
+    // Not every ADC can change its sampling period, so the default is to ignore it.
+    fn set_sampling_period(&mut self, _period: SamplingPeriod) {}
+}
+
+pub trait BusVoltageSource {
+    fn v_bus(&self) -> f32;
+}
+
+pub trait ThreePhaseBridge {
+    fn set_pwm_duty_cycles(&mut self, pwms: PwmDuty);
+    // Set phase voltages relative to the v_bus/2 midpoint.
+    fn set_voltages(&mut self, v_bus: f32, voltages: PhaseVoltages);
+    // Limit the duty cycle `set_voltages` is allowed to command.
+    fn set_max_duty(&mut self, _max_duty: f32) {}
+    fn zero_phases(&mut self) {
+        self.set_pwm_duty_cycles(PwmDuty {
+            a: 0.,
+            b: 0.,
+            c: 0.,
+        });
+    }
+    // Start switching. Outputs must not be driven before this is called.
+    fn enable_loop(&mut self);
+    fn disable_loop(&mut self);
+    // The auto-reload value the PWM timer was configured with, i.e. how many counts there are to a
+    // period.
+    fn arr(&self) -> u16;
+    // How often the control loop runs, which is once per PWM period.
+    fn loop_frequency(&self) -> f32;
+    // Move the current sampling point within the PWM period, as a fraction of the period.
+    fn set_sample_point(&mut self, _fraction: f32) {}
+    // Put the sampling point and deadtime back the way they were before a control loop fiddled with
+    // them.
+    fn reset_current_sample(&mut self) {}
+    fn reset_deadtime(&mut self) {}
+}
+
+pub trait RotorPositionSensor {
+    // Take a new reading and update the angle/velocity estimates.
+    fn update(&mut self, delta_t: f32) -> EncoderState;
+    fn state(&self) -> &Option<EncoderState>;
+}
+
+// A cos/sin calculation that may still be in flight.
+pub trait PendingCosSin {
+    // Blocks until the result is ready. Returns `[cos, sin]`.
+    fn get_result(self) -> [f32; 2];
+}
+
+pub trait SinCos {
+    type Pending<'a>: PendingCosSin
+    where
+        Self: 'a;
+
+    // Kick off a cos/sin calculation. Hardware accelerators can run this in the background while
+    // the caller does something else.
+    fn cos_sin(&mut self, theta: Angle) -> Self::Pending<'_>;
+}
+
+// Everything a board needs to provide to run the control loops.
+pub trait Peripherals {
+    type CurrentSensor: PhaseCurrentSource + BusVoltageSource + Send;
+    type Bridge: ThreePhaseBridge + Send;
+    type Encoder: RotorPositionSensor + Send;
+    type SinCos: SinCos + Send;
+}
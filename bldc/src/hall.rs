@@ -0,0 +1,260 @@
+use crate::{
+    config::{self, LoopConfig},
+    encoder::{EncoderState, PllObserverRadians},
+    fault::{self, Fault},
+    hal::RotorPositionSensor,
+    ic::hall::HallInputs,
+    util::buffered_state::StateReader,
+};
+use core::f32::consts::PI;
+use num_traits::Float;
+use third_party::ang::{AbsoluteDist, Angle};
+
+pub use protocol::hall::{HallCalibrationStatus, HALL_SECTORS};
+
+// Rotor position from three hall sensors, for motors that don't have an encoder. The halls only say
+// which sixth of an electrical turn the rotor's in, so `PllObserverRadians` fills in between sector
+// changes, same as it does between the MA702's counts. There's no absolute mechanical position
+// either: the mechanical angle starts out at zero wherever the rotor was at power up, and counts
+// however far it's turned since.
+//
+// Sectors are numbered in the order the hall states come round (see `SEQUENCE`), and located by the
+// electrical angle at their middle; see `HallCalibration`.
+
+const TWO_PI: f32 = 2. * PI;
+const SECTOR_WIDTH: f32 = TWO_PI / HALL_SECTORS as f32;
+// Loop iterations in a row the sensors can read nonsense before we stop trusting them. A sensor can
+// glitch for a sample or two as it switches, but not for 100us.
+const INVALID_LIMIT: u32 = 4;
+
+// The sensors are 120° apart, so only one of them changes at a time, and all on or all off never
+// happens. Bit 0 is hall A.
+pub const SEQUENCE: [u8; HALL_SECTORS] = [0b001, 0b011, 0b010, 0b110, 0b100, 0b101];
+
+pub fn sector(state: u8) -> Option<usize> {
+    SEQUENCE.iter().position(|&sector| sector == state)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HallCalibration {
+    // Electrical angle at the middle of each sector, in radians.
+    pub angles: [f32; HALL_SECTORS],
+}
+
+impl HallCalibration {
+    // Sector 0 starting at zero, and all of them the same width.
+    pub const EVEN: HallCalibration = HallCalibration {
+        angles: [
+            0.5 * SECTOR_WIDTH,
+            1.5 * SECTOR_WIDTH,
+            2.5 * SECTOR_WIDTH,
+            3.5 * SECTOR_WIDTH,
+            4.5 * SECTOR_WIDTH,
+            5.5 * SECTOR_WIDTH,
+        ],
+    };
+}
+
+// Works out a `HallCalibration` from the rotor being dragged round by a known electrical angle.
+// Every time the sector changes, the angle it happened at is a sample of the boundary between the
+// two. The rotor lags behind whichever way it's being dragged, so it needs dragging both ways (and
+// just as far) to even that out. Samples are averaged as unit vectors so wrapping doesn't matter.
+//
+// Doesn't care which way round the sectors go, so the sensors can be wired to the connector in any
+// order.
+pub struct HallCalibrator {
+    // Sums of sin and cos for the boundary at the start of each sector, i.e. between it and the one
+    // before.
+    boundaries: [(f32, f32); HALL_SECTORS],
+    samples: [u32; HALL_SECTORS],
+    sector: Option<usize>,
+    steps: u32,
+    skips: u32,
+    invalid: bool,
+}
+
+impl HallCalibrator {
+    pub fn new() -> HallCalibrator {
+        HallCalibrator {
+            boundaries: [(0., 0.); HALL_SECTORS],
+            samples: [0; HALL_SECTORS],
+            sector: None,
+            steps: 0,
+            skips: 0,
+            invalid: false,
+        }
+    }
+
+    pub fn add(&mut self, electrical_angle: f32, state: u8) {
+        let sector = match sector(state) {
+            Some(sector) => sector,
+            None => {
+                self.invalid = true;
+                return;
+            }
+        };
+        let previous = match self.sector.replace(sector) {
+            Some(previous) if previous != sector => previous,
+            _ => return,
+        };
+        let boundary = if sector == (previous + 1) % HALL_SECTORS {
+            sector
+        } else if previous == (sector + 1) % HALL_SECTORS {
+            previous
+        } else {
+            self.skips += 1;
+            return;
+        };
+        self.steps += 1;
+        let (sin, cos) = electrical_angle.sin_cos();
+        self.boundaries[boundary].0 += sin;
+        self.boundaries[boundary].1 += cos;
+        self.samples[boundary] += 1;
+    }
+
+    pub fn finish(&self) -> Result<HallCalibration, HallCalibrationStatus> {
+        if self.invalid {
+            return Err(HallCalibrationStatus::InvalidState);
+        }
+        // The odd skip is a glitch, but more than one in ten and the rest can't be trusted either.
+        if self.skips * 10 > self.steps {
+            return Err(HallCalibrationStatus::Skipped);
+        }
+        if self.samples.contains(&0) {
+            return Err(HallCalibrationStatus::MissingSector);
+        }
+        let boundary = |n: usize| {
+            let (sin, cos) = self.boundaries[n % HALL_SECTORS];
+            Angle::Radians(sin.atan2(cos))
+        };
+        let mut angles = [0.; HALL_SECTORS];
+        for (sector, angle) in angles.iter_mut().enumerate() {
+            let start = boundary(sector);
+            let width = boundary(sector + 1).abs_dist(start);
+            *angle = (start + width * 0.5).normalized().in_radians();
+        }
+        Ok(HallCalibration { angles })
+    }
+}
+
+impl Default for HallCalibrator {
+    fn default() -> Self {
+        HallCalibrator::new()
+    }
+}
+
+// Turns hall states into the same filtered angle and velocity estimates `AngleTracker` gives the
+// control loops, so they don't need to know the difference.
+pub struct HallTracker {
+    pole_pairs: u8,
+    calibration: HallCalibration,
+    observer: PllObserverRadians,
+    // Last sector that made sense.
+    sector: Option<usize>,
+    invalid: u32,
+    state: Option<EncoderState>,
+}
+
+impl HallTracker {
+    pub fn new(
+        pole_pairs: u8,
+        velocity_observer_bandwidth: f32,
+        calibration: HallCalibration,
+    ) -> HallTracker {
+        HallTracker {
+            pole_pairs,
+            calibration,
+            // All the observer ever sees is the middle of a sector, so anywhere within half a
+            // sector of it is as good as on it.
+            observer: PllObserverRadians::with_bandwidth(
+                velocity_observer_bandwidth,
+                Angle::Radians(SECTOR_WIDTH / 2.),
+            ),
+            sector: None,
+            invalid: 0,
+            state: None,
+        }
+    }
+
+    pub fn set_calibration(&mut self, calibration: HallCalibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn update(&mut self, delta_t: f32, hall_state: u8) -> EncoderState {
+        match sector(hall_state) {
+            Some(sector) => {
+                self.sector = Some(sector);
+                self.invalid = 0;
+            }
+            None => {
+                self.invalid += 1;
+                if self.invalid >= INVALID_LIMIT {
+                    fault::raise(Fault::HallSensor);
+                }
+            }
+        }
+        let measured = self
+            .sector
+            .map_or(0., |sector| self.calibration.angles[sector]);
+        let pll_state = self.observer.update(delta_t, Angle::Radians(measured));
+        let pole_pairs = self.pole_pairs as f32;
+        let electrical_angle = pll_state.angle;
+        let electrical_velocity = pll_state.velocity;
+
+        let angle_multiturn = match self.state {
+            Some(previous) => {
+                let d_theta = electrical_angle.abs_dist(previous.electrical_angle);
+                previous.angle_multiturn + Angle::Radians(d_theta.in_radians() / pole_pairs)
+            }
+            None => Angle::Radians(0.),
+        };
+        let state = EncoderState {
+            raw_encoder: hall_state as u32,
+            angle: angle_multiturn.normalized(),
+            velocity: Angle::Radians(electrical_velocity.in_radians() / pole_pairs),
+            angle_multiturn,
+            electrical_angle,
+            electrical_velocity,
+        };
+        self.state = Some(state);
+        state
+    }
+
+    pub fn state(&self) -> &Option<EncoderState> {
+        &self.state
+    }
+}
+
+pub struct Halls {
+    inputs: HallInputs,
+    tracker: HallTracker,
+    config: StateReader<LoopConfig>,
+}
+
+impl Halls {
+    pub fn new(inputs: HallInputs, pole_pairs: u8, velocity_observer_bandwidth: f32) -> Halls {
+        let config = config::loop_config();
+        let calibration = HallCalibration {
+            angles: config.read().hall_angles,
+        };
+        Halls {
+            inputs,
+            tracker: HallTracker::new(pole_pairs, velocity_observer_bandwidth, calibration),
+            config,
+        }
+    }
+}
+
+impl RotorPositionSensor for Halls {
+    fn update(&mut self, delta_t: f32) -> EncoderState {
+        // Picks up a new calibration, whether it's from `CalibrateHalls` or the params being set.
+        self.tracker.set_calibration(HallCalibration {
+            angles: self.config.read().hall_angles,
+        });
+        self.tracker.update(delta_t, self.inputs.read())
+    }
+
+    fn state(&self) -> &Option<EncoderState> {
+        self.tracker.state()
+    }
+}
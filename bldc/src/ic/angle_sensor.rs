@@ -0,0 +1,154 @@
+//! What the SPI magnetic angle sensors have in common, so that `spi_encoder` can poll any of them
+//! the same way.
+
+use core::f32::consts::PI;
+use third_party::ang::Angle;
+
+const TWO_PI: f32 = 2. * PI;
+
+// Longest exchange any of the sensors needs, in 16-bit words.
+pub const MAX_FRAME_WORDS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SensorError {
+    // The sensor's parity bit doesn't match what it sent.
+    Parity,
+    // The sensor says something went wrong with an earlier command.
+    ErrorFlag,
+    // The CRC doesn't match. Also what a reading torn by the DMA halfway through a frame looks
+    // like.
+    Crc,
+    // The sensor flagged a problem with the magnet, its supply, or how fast it's turning; raw
+    // status bits.
+    Status(u8),
+    // Still clearing an earlier error, so there's nothing to read yet; see `ErrorRecovery`.
+    Recovering,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AngleReading {
+    pub raw_angle: u32,
+    pub angle: Angle,
+}
+
+impl AngleReading {
+    pub fn new() -> AngleReading {
+        AngleReading {
+            raw_angle: 0,
+            angle: Angle::Radians(0.),
+        }
+    }
+}
+
+impl Default for AngleReading {
+    fn default() -> Self {
+        AngleReading::new()
+    }
+}
+
+pub trait AngleSensor {
+    // Bits of angle per turn.
+    const BITS: u32;
+    // SPI clock polarity and phase, as in CPOL and CPHA.
+    const CPOL: bool;
+    const CPHA: bool;
+    // The fastest the sensor can be clocked.
+    const MAX_CLOCK_HZ: u32;
+    // Clocked out every reading, with chip select held low the whole way through. No more than
+    // `MAX_FRAME_WORDS` long.
+    const REQUEST: &'static [u16];
+    // Sent in place of `REQUEST` to clear an error the sensor's latched, for sensors that do that.
+    // Same length as `REQUEST`. Replies to it aren't angles.
+    const CLEAR_ERROR: Option<&'static [u16]> = None;
+
+    // Angle in counts, from what came back while `REQUEST` was going out.
+    fn decode(response: &[u16]) -> Result<u32, SensorError>;
+
+    fn counts() -> u32 {
+        1 << Self::BITS
+    }
+
+    // Smallest change in angle the sensor can report.
+    fn resolution() -> Angle {
+        Angle::Radians(TWO_PI / Self::counts() as f32)
+    }
+
+    fn read(response: &[u16]) -> Result<AngleReading, SensorError> {
+        let raw_angle = Self::decode(response)?;
+        Ok(AngleReading {
+            raw_angle,
+            angle: Self::resolution() * raw_angle as f32,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RecoveryState {
+    Streaming,
+    // `CLEAR_ERROR` is going out instead of `REQUEST`, for this long so far.
+    Clearing(f32),
+    // `REQUEST` is back, but what comes back is still the reply to `CLEAR_ERROR` until it's been
+    // out for a frame.
+    Settling(f32),
+}
+
+// Swaps in `CLEAR_ERROR` whenever a sensor flags an error, then throws away replies until they're
+// angles again. Frames are paced by a timer rather than whoever's reading them, so this goes by how
+// long it's been rather than counting frames, with a frame's worth of slack either way.
+pub struct ErrorRecovery {
+    request: &'static [u16],
+    clear: Option<&'static [u16]>,
+    frame_period: f32,
+    state: RecoveryState,
+}
+
+impl ErrorRecovery {
+    pub fn new<A: AngleSensor>(frame_period: f32) -> ErrorRecovery {
+        ErrorRecovery {
+            request: A::REQUEST,
+            clear: A::CLEAR_ERROR,
+            frame_period,
+            state: RecoveryState::Streaming,
+        }
+    }
+
+    // Filters the latest `reading`, taken `delta_t` after the last one. Also gives back what to
+    // start sending in place of whatever's going out now, if that needs to change.
+    pub fn update(
+        &mut self,
+        reading: Result<AngleReading, SensorError>,
+        delta_t: f32,
+    ) -> (Result<AngleReading, SensorError>, Option<&'static [u16]>) {
+        match self.state {
+            RecoveryState::Streaming => match (reading, self.clear) {
+                (Err(SensorError::ErrorFlag), Some(clear)) => {
+                    self.state = RecoveryState::Clearing(0.);
+                    (reading, Some(clear))
+                }
+                _ => (reading, None),
+            },
+            // Long enough for a whole frame to have gone out with the clear in it.
+            RecoveryState::Clearing(elapsed) => {
+                let elapsed = elapsed + delta_t;
+                if elapsed >= 2. * self.frame_period {
+                    self.state = RecoveryState::Settling(0.);
+                    (Err(SensorError::Recovering), Some(self.request))
+                } else {
+                    self.state = RecoveryState::Clearing(elapsed);
+                    (Err(SensorError::Recovering), None)
+                }
+            }
+            // And then one frame for the reply to the clear, and another for an angle.
+            RecoveryState::Settling(elapsed) => {
+                let elapsed = elapsed + delta_t;
+                if elapsed >= 3. * self.frame_period {
+                    self.state = RecoveryState::Streaming;
+                    self.update(reading, 0.)
+                } else {
+                    self.state = RecoveryState::Settling(elapsed);
+                    (Err(SensorError::Recovering), None)
+                }
+            }
+        }
+    }
+}
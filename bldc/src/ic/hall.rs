@@ -0,0 +1,33 @@
+//! Three hall sensors, on what's otherwise the angle sensor's connector.
+
+use stm32g4::stm32g474::{self as device, GPIOA};
+
+// PA4 - HALL_A (ENC_CS)
+// PA5 - HALL_B (ENC_SCK)
+// PA6 - HALL_C (ENC_MISO)
+//
+// TIM3's hall interface mode would be nicer, since it XORs the three together and timestamps the
+// edges, but PA5 isn't one of its channels. Reading the pins every loop iteration is plenty at
+// 40kHz anyway, and the observer does the rest.
+pub struct HallInputs {}
+
+// Takes the pins back from SPI1, so call it after `Driver::configure_gpio`.
+pub fn configure(gpioa: &device::GPIOA) -> HallInputs {
+    gpioa
+        .moder
+        .modify(|_, w| w.moder4().input().moder5().input().moder6().input());
+    // Most hall sensors are open drain.
+    gpioa
+        .pupdr
+        .modify(|_, w| w.pupdr4().pull_up().pupdr5().pull_up().pupdr6().pull_up());
+    HallInputs {}
+}
+
+impl HallInputs {
+    // Hall A in bit 0, B in bit 1, and C in bit 2.
+    pub fn read(&self) -> u8 {
+        // Safety: reading the input data register has no side effects.
+        let idr = unsafe { (*GPIOA::ptr()).idr.read().bits() };
+        ((idr >> 4) & 0b111) as u8
+    }
+}
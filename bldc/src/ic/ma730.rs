@@ -0,0 +1,17 @@
+//! The MA730, which is the MA702 with a couple more bits.
+
+use super::angle_sensor::{AngleSensor, SensorError};
+
+pub struct Ma730 {}
+
+impl AngleSensor for Ma730 {
+    const BITS: u32 = 14;
+    const CPOL: bool = false;
+    const CPHA: bool = false;
+    const MAX_CLOCK_HZ: u32 = 3_000_000;
+    const REQUEST: &'static [u16] = &[0];
+
+    fn decode(response: &[u16]) -> Result<u32, SensorError> {
+        Ok((response[0] >> 2) as u32)
+    }
+}
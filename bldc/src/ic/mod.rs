@@ -1,2 +1,8 @@
+pub mod angle_sensor;
+pub mod as5047p;
 pub mod drv8323rs;
+pub mod hall;
 pub mod ma702;
+pub mod ma730;
+pub mod mt6835;
+pub mod spi_encoder;
@@ -0,0 +1,35 @@
+//! The MT6835 21-bit angle sensor.
+
+use super::angle_sensor::{AngleSensor, SensorError};
+use crate::util::crc::crc8;
+
+// Burst read (0xA) starting from register 0x003, the top byte of the angle. The next four bytes
+// back are ANGLE[20:13], ANGLE[12:5], ANGLE[4:0] with three status bits underneath, and a CRC over
+// the first three.
+const BURST_READ_ANGLE: u16 = 0xA003;
+const STATUS_MASK: u8 = 0b111;
+
+pub struct Mt6835 {}
+
+impl AngleSensor for Mt6835 {
+    const BITS: u32 = 21;
+    const CPOL: bool = true;
+    const CPHA: bool = true;
+    const MAX_CLOCK_HZ: u32 = 16_000_000;
+    const REQUEST: &'static [u16] = &[BURST_READ_ANGLE, 0, 0];
+
+    fn decode(response: &[u16]) -> Result<u32, SensorError> {
+        // Nothing useful comes back while the command's going out.
+        let [high, middle] = response[1].to_be_bytes();
+        let [low, crc] = response[2].to_be_bytes();
+        if crc8(&[high, middle, low]) != crc {
+            return Err(SensorError::Crc);
+        }
+        // Overspeed, weak field and undervoltage, from the bottom up.
+        let status = low & STATUS_MASK;
+        if status != 0 {
+            return Err(SensorError::Status(status));
+        }
+        Ok(((high as u32) << 13) | ((middle as u32) << 5) | ((low as u32) >> 3))
+    }
+}
@@ -0,0 +1,449 @@
+//! Streams readings from whichever `AngleSensor` is on SPI1, without the CPU having to do anything.
+//!
+//! TIM3 paces everything at 50kHz. Its CH2 output on PA4 is the chip select: low for the start of
+//! each period, long enough to get the whole of `REQUEST` out, and high for the rest. The words
+//! themselves are written to `SPI1[DR]` by DMA off TIM3's compare events (CH1 for the first, then
+//! CH3 and CH4), spaced a word's worth of clocks apart. Another DMA channel copies every word that
+//! comes back into `RESPONSE`, wrapping around once the frame's done.
+//!
+//! Chip select can't be left to SPI1, since it only pulses NSS between words with CPHA clear, and
+//! then only between every word.
+
+use super::angle_sensor::{AngleReading, AngleSensor, ErrorRecovery, SensorError, MAX_FRAME_WORDS};
+use crate::block_until;
+use crate::block_while;
+use crate::util::buffered_state::{BufferedState, StateReader, StateWriter};
+use core::marker::PhantomData;
+use stm32g4::stm32g474::{self as device, interrupt};
+use third_party::m4vga_rs::util::armv7m::{clear_pending_irq, enable_irq};
+use third_party::m4vga_rs::util::spin_lock::SpinLock;
+use third_party::m4vga_rs::util::sync::acquire_hw;
+
+const CORE_CLOCK_HZ: u32 = 170_000_000;
+// TIM3 ticks at the core clock over `TIM3_PRESCALAR + 1`, and overflows every `TIM3_ARR` ticks.
+const TIM3_PRESCALAR: u16 = 3;
+const TIM3_ARR: u16 = 850;
+// Seconds between frames.
+const FRAME_PERIOD: f32 = (TIM3_PRESCALAR + 1) as f32 * TIM3_ARR as f32 / CORE_CLOCK_HZ as f32;
+// Chip select to first clock edge, and last clock edge to chip select, in TIM3 ticks. ~0.5us,
+// comfortably more than any of the sensors need.
+const CS_SETUP_TICKS: u16 = 20;
+
+// Static location in memory to stream the raw responses to. This has to be a) in a consistent
+// location and b) In RAM, not flash.
+pub static mut RESPONSE: [u16; MAX_FRAME_WORDS] = [0; MAX_FRAME_WORDS];
+// And the same for what's sent.
+static mut REQUEST: [u16; MAX_FRAME_WORDS] = [0; MAX_FRAME_WORDS];
+
+// SPI1's `BR`: the clock is the core clock over 2^(BR + 1). Picks the fastest the sensor can take.
+fn baud_rate_bits(max_clock_hz: u32) -> u8 {
+    (0..7u8)
+        .find(|bits| CORE_CLOCK_HZ >> (bits + 1) <= max_clock_hz)
+        .unwrap_or(7)
+}
+
+// How many TIM3 ticks it takes to clock out a word at `BR` = `bits`.
+fn word_ticks(bits: u8) -> u16 {
+    let core_clocks = 16u32 << (bits + 1);
+    (core_clocks / (TIM3_PRESCALAR as u32 + 1)) as u16 + 1
+}
+
+pub struct SpiEncoder<A: AngleSensor, S> {
+    spi: device::SPI1,
+    tim3: device::TIM3,
+
+    #[allow(dead_code)]
+    mode_state: S,
+    _sensor: PhantomData<A>,
+}
+
+pub struct Init {}
+pub struct Ready {}
+
+pub struct StreamingPolling {
+    state: AngleReading,
+    recovery: ErrorRecovery,
+}
+
+pub struct StreamingInterrupt {
+    state: StateReader<AngleReading>,
+}
+
+pub fn new<A: AngleSensor>(spi: device::SPI1, tim3: device::TIM3) -> SpiEncoder<A, Init> {
+    SpiEncoder {
+        spi,
+        tim3,
+        mode_state: Init {},
+        _sensor: PhantomData,
+    }
+}
+
+impl<A: AngleSensor> SpiEncoder<A, Init> {
+    pub fn configure_spi(self) -> SpiEncoder<A, Ready> {
+        // SPI config
+        let spi1 = self.spi;
+
+        // Disable SPI, if enabled.
+        spi1.cr1.modify(|_, w| w.spe().clear_bit());
+        block_until! { spi1.cr1.read().spe().bit_is_clear() }
+        // Chip select is TIM3's job, so NSS is left to software and held high to keep SPI1 in
+        // master mode.
+        spi1.cr1.modify(|_, w| unsafe {
+            w.cpha()
+                .bit(A::CPHA)
+                .cpol()
+                .bit(A::CPOL)
+                .mstr()
+                .set_bit()
+                // Safety: Upstream: This should be a 3-bit enum, and `baud_rate_bits` is at most 7.
+                .br()
+                .bits(baud_rate_bits(A::MAX_CLOCK_HZ))
+                .crcen()
+                .clear_bit()
+                .ssm()
+                .set_bit()
+                .ssi()
+                .set_bit()
+        });
+        // 16 bit transfers
+        spi1.cr2.modify(|_, w| {
+            w.ssoe()
+                .disabled()
+                .frf()
+                .clear_bit()
+                .ds()
+                .sixteen_bit()
+                .nssp()
+                .clear_bit()
+                // Enable RX DMA trigger
+                .rxdmaen()
+                .set_bit()
+        });
+
+        SpiEncoder {
+            spi: spi1,
+            tim3: self.tim3,
+            mode_state: Ready {},
+            _sensor: PhantomData,
+        }
+    }
+}
+
+impl<A: AngleSensor> SpiEncoder<A, Ready> {
+    fn configure_tx_streams(&self, dma: &device::DMA1, dmamux: &device::DMAMUX) {
+        // Configure DMA1 streams 1, 3 and 4 to each transfer one word of the request into
+        // `SPI1[DR]`, off TIM3's CH1, CH3 and CH4 compare events respectively. Sensors with shorter
+        // requests just don't use the later ones.
+
+        // Safety: The DMA's not running yet, so nothing else is looking at `REQUEST`.
+        let request = unsafe { &mut *core::ptr::addr_of_mut!(REQUEST) };
+        request[..A::REQUEST.len()].copy_from_slice(A::REQUEST);
+        let spi_dr = ((&self.spi.dr) as *const _) as u32;
+
+        // All three are configured the same way, so this saves repeating it.
+        macro_rules! tx_stream {
+            ($ccr: ident, $cndtr: ident, $cmar: ident, $cpar: ident, $word: expr) => {
+                // Disable DMA channel if it's enabled.
+                dma.$ccr.modify(|_, w| w.en().clear_bit());
+                block_until!(dma.$ccr.read().en().bit_is_clear());
+                // Configure for memory-to-peripheral mode @ 16-bit. Don't change address for
+                // either memory or peripheral.
+                dma.$ccr.modify(|_, w| unsafe {
+                    // Safety: Upstream: This should be a 2-bit enum. 0b01 = 16-bit
+                    w.msize()
+                        .bits(0b01)
+                        // Safety: Upstream: This should be a 2-bit enum. 0b01 = 16-bit
+                        .psize()
+                        .bits(0b01)
+                        .minc()
+                        .clear_bit()
+                        .pinc()
+                        .clear_bit()
+                        .circ()
+                        .set_bit()
+                        .dir()
+                        .set_bit()
+                });
+                // Just transfer a single value
+                // Safety: Upstream: This should have a proper range of 0-65535 in stm32-rs.
+                dma.$cndtr.write(|w| unsafe { w.ndt().bits(1) });
+                // Safety: This is the source of the DMA stream. We've configured it for 16-bit
+                // and the address we're taking is a `u16`
+                dma.$cmar
+                    .write(|w| unsafe { w.ma().bits((&request[$word] as *const _) as u32) });
+                // Safety: Erm... its not? XD We're asking the DMA to stream data to an arbitrary
+                // address, which is in no way shape or form safe. We've set it up so that it's a
+                // `u16` transfer from `REQUEST` to `SPI[DR]`. YOLO
+                dma.$cpar.write(|w| unsafe { w.pa().bits(spi_dr) });
+            };
+        }
+        tx_stream!(ccr1, cndtr1, cmar1, cpar1, 0);
+        tx_stream!(ccr3, cndtr3, cmar3, cpar3, 1);
+        tx_stream!(ccr4, cndtr4, cmar4, cpar4, 2);
+
+        // Now we wire up the DMA triggers to their respective streams
+        // Note: DMAMUX channels 0-7 connected to DMA1 channels 1-8, 8-15=DMA2 1-8
+        // TIM3_CH1 = 61, TIM3_CH3 = 63, TIM3_CH4 = 64
+        // Safety: Upstream: This should be an enum.
+        // TODO(blakely): Add enum values to `stm32-rs`
+        dmamux.c0cr.modify(|_, w| unsafe { w.dmareq_id().bits(61) });
+        dmamux.c2cr.modify(|_, w| unsafe { w.dmareq_id().bits(63) });
+        dmamux.c3cr.modify(|_, w| unsafe { w.dmareq_id().bits(64) });
+    }
+
+    fn configure_rx_stream(&self, dma: &device::DMA1, dmamux: &device::DMAMUX) {
+        // Configure DMA1 stream 2 to read from `SPI1[DR]` into `RESPONSE` whenever a word comes in.
+
+        // Disable DMA channel if it's enabled.
+        dma.ccr2.modify(|_, w| w.en().clear_bit());
+        block_until!(dma.ccr2.read().en().bit_is_clear());
+        // Configure for peripheral-to-memory mode @ 16-bit. Step through `RESPONSE`, but not the
+        // peripheral.
+        dma.ccr2.modify(|_, w| unsafe {
+            // Safety: Upstream: This should be a 2-bit enum. 0b01 = 16-bit
+            w.msize()
+                .bits(0b01)
+                // Safety: Upstream: This should be a 2-bit enum. 0b01 = 16-bit
+                .psize()
+                .bits(0b01)
+                .minc()
+                .set_bit()
+                .pinc()
+                .clear_bit()
+                .circ()
+                .set_bit()
+                // Peripheral-to-Memory this time.
+                .dir()
+                .clear_bit()
+        });
+        // A frame's worth, then back to the start.
+        // Safety: Upstream: This should have a proper range of 0-65535 in stm32-rs.
+        dma.cndtr2
+            .write(|w| unsafe { w.ndt().bits(A::REQUEST.len() as u16) });
+        // Target memory location
+        {
+            // Safety: This is the destination of the DMA stream. We've configured it for 16-bit
+            // and the address we're taking is a `[u16]` at least as long as the frame. We're also
+            // taking a reference to a `static mut` which is normally bad, but DMA writes _should_
+            // be atomic.
+            dma.cmar2
+                .write(|w| unsafe { w.ma().bits(core::ptr::addr_of!(RESPONSE) as u32) });
+        }
+        // Target peripheral location
+        {
+            let spi = &self.spi;
+            // Safety: We're reading from an arbitrary location in memory: the data register of the
+            // SPI peripheral. It's configured to read 16 bits, the width of the packet we're
+            // requesting from the SPI peripheral.
+            dma.cpar2
+                .write(|w| unsafe { w.pa().bits(((&spi.dr) as *const _) as u32) });
+        }
+
+        // Now we wire up the DMA triggers to their respective streams
+        // Note: DMAMUX channels 0-7 connected to DMA1 channels 1-8, 8-15=DMA2 1-8
+        // SPI1 RX to the DMA stream2 - SPI1_RX = 10
+        // Safety: Upstream: This should be an enum.
+        // TODO(blakely): Add enum values to `stm32-rs`
+        dmamux.c1cr.modify(|_, w| unsafe { w.dmareq_id().bits(10) });
+    }
+
+    fn start_stream(&mut self, dma: &device::DMA1, dmamux: &device::DMAMUX) {
+        self.configure_tx_streams(dma, dmamux);
+        self.configure_rx_stream(dma, dmamux);
+
+        // Enable SPI.
+        self.spi.cr1.modify(|_, w| w.spe().set_bit());
+        block_until! { self.spi.cr1.read().spe().bit_is_set() }
+
+        // Enable DMA streams 1-4.
+        dma.ccr1.modify(|_, w| w.en().set_bit());
+        block_until! {  dma.ccr1.read().en().bit_is_set() }
+        dma.ccr2.modify(|_, w| w.en().set_bit());
+        block_until! {  dma.ccr2.read().en().bit_is_set() }
+        dma.ccr3.modify(|_, w| w.en().set_bit());
+        block_until! {  dma.ccr3.read().en().bit_is_set() }
+        dma.ccr4.modify(|_, w| w.en().set_bit());
+        block_until! {  dma.ccr4.read().en().bit_is_set() }
+
+        let tim3 = &self.tim3;
+        // Stop the timer if it's running for some reason.
+        tim3.cr1.modify(|_, w| w.cen().clear_bit());
+        block_until!(tim3.cr1.read().cen().bit_is_clear());
+        // Edge aligned mode, and up counting.
+        tim3.cr1.modify(|_, w| w.dir().up().cms().edge_aligned());
+        // Assuming 170MHz core clock, set prescalar to 4 and ARR to 850 for 170e6/850/4=50kHz.
+        // Why is the value actually 3 and not 4? The timer clock is set to `core_clk / (PSC[PSC] +
+        // 1)`. If it were to use the value directly it'd divide the clock by zero on reset, which
+        // would be A Bad Thing.
+        // Safety: Upstream: This should have a proper range of 0-65535 in stm32-rs. 3 is well
+        // within range.
+        tim3.psc.write(|w| w.psc().bits(TIM3_PRESCALAR));
+        tim3.arr.write(|w| w.arr().bits(TIM3_ARR));
+
+        // One word goes out every `word` ticks after chip select drops, and chip select comes back
+        // up once they're all done. Compare events for words past the end of the request just
+        // don't get a DMA request.
+        let word = word_ticks(baud_rate_bits(A::MAX_CLOCK_HZ));
+        let words = A::REQUEST.len() as u16;
+        tim3.ccr1.write(|w| w.ccr().bits(CS_SETUP_TICKS));
+        tim3.ccr3.write(|w| w.ccr().bits(CS_SETUP_TICKS + word));
+        tim3.ccr4.write(|w| w.ccr().bits(CS_SETUP_TICKS + 2 * word));
+        tim3.ccr2
+            .write(|w| w.ccr().bits(2 * CS_SETUP_TICKS + words * word));
+        // CH2 as PWM mode 1, active low: low from the start of the period until `CCR2`. Same as
+        // TIM1, the fourth OCxM bit is split off from the rest.
+        tim3.ccmr1_output()
+            .modify(|_, w| w.cc2s().output().oc2m().pwm_mode1().oc2m_3().clear_bit());
+        tim3.ccer.modify(|_, w| w.cc2e().set_bit().cc2p().set_bit());
+        // Fire off a DMA on each word's compare event.
+        tim3.dier.modify(|_, w| {
+            w.cc1de()
+                .set_bit()
+                .cc3de()
+                .bit(words > 1)
+                .cc4de()
+                .bit(words > 2)
+        });
+        // Kick off tim3 to start the stream.
+        tim3.cr1.modify(|_, w| w.cen().set_bit());
+    }
+
+    pub fn begin_stream_polling(
+        mut self,
+        dma: device::DMA1,
+        dmamux: &device::DMAMUX,
+    ) -> SpiEncoder<A, StreamingPolling> {
+        self.start_stream(&dma, dmamux);
+
+        SpiEncoder {
+            spi: self.spi,
+            tim3: self.tim3,
+            mode_state: StreamingPolling {
+                state: AngleReading::new(),
+                recovery: ErrorRecovery::new::<A>(FRAME_PERIOD),
+            },
+            _sensor: PhantomData,
+        }
+    }
+
+    pub fn begin_stream_interrupt(
+        mut self,
+        dma: device::DMA1,
+        dmamux: &device::DMAMUX,
+    ) -> SpiEncoder<A, StreamingInterrupt> {
+        self.start_stream(&dma, dmamux);
+
+        let mut angle_state = ENCODER_STATE.lock();
+        *angle_state = Some(BufferedState::new(AngleReading::new()));
+
+        // Enable the DMA1[CH2] Transfer Complete interrupt so that the handler is called when the
+        // whole frame's been transferred from SPI to memory.
+        dma.ccr2.modify(|_, w| w.tcie().set_bit());
+
+        let (reader, writer) = angle_state
+            .as_mut()
+            .expect("Cannot acquire encoder state")
+            .split();
+
+        // Clear DMA IRQ flag.
+        dma.ifcr.write(|w| w.gif2().set_bit());
+
+        // Donate the DMA so that the interrupt handler can clear the interrupt flag, and the writer
+        // so that it can update the sensor's state.
+        *ENCODER_INTERRUPT_DATA.lock() = Some(InterruptData {
+            dma,
+            writer,
+            read: A::read,
+            recovery: ErrorRecovery::new::<A>(FRAME_PERIOD),
+        });
+
+        // Enable the DMA1[CH2] interrupt in NVIC.
+        enable_irq(device::Interrupt::DMA1_CH2);
+
+        SpiEncoder {
+            spi: self.spi,
+            tim3: self.tim3,
+            mode_state: StreamingInterrupt { state: reader },
+            _sensor: PhantomData,
+        }
+    }
+}
+
+impl<A: AngleSensor> SpiEncoder<A, StreamingInterrupt> {
+    pub fn state(&self) -> &AngleReading {
+        self.mode_state.state.read()
+    }
+}
+
+impl<A: AngleSensor> SpiEncoder<A, StreamingPolling> {
+    // The latest reading. On an error the last good one is kept, so the caller can carry on with
+    // that if it wants to.
+    pub fn update(&mut self, delta_t: f32) -> Result<AngleReading, SensorError> {
+        let reading = A::read(&read_response());
+        let (reading, request) = self.mode_state.recovery.update(reading, delta_t);
+        if let Some(request) = request {
+            write_request(request);
+        }
+        let new_state = reading?;
+        self.mode_state.state = new_state;
+        Ok(new_state)
+    }
+
+    pub fn state(&self) -> &AngleReading {
+        &self.mode_state.state
+    }
+}
+
+pub struct InterruptData {
+    dma: device::DMA1,
+    writer: StateWriter<AngleReading>,
+    read: fn(&[u16]) -> Result<AngleReading, SensorError>,
+    recovery: ErrorRecovery,
+}
+
+pub static ENCODER_STATE: SpinLock<Option<BufferedState<AngleReading>>> = SpinLock::new(None);
+pub static ENCODER_INTERRUPT_DATA: SpinLock<Option<InterruptData>> = SpinLock::new(None);
+
+// Copy out whatever the DMA's streamed in. For multi-word frames this can catch the DMA halfway
+// through, mixing words from two readings; that's what the sensors with more than one word have a
+// CRC for.
+fn read_response() -> [u16; MAX_FRAME_WORDS] {
+    // Safety: accessing global mutable values is inherently unsafe. Technically RESPONSE doesn't
+    // need to be mutable since no user code is mutating it, but it _is_ modified by the DMA
+    // controller, so better safe than sorry. Each word is read in a single instruction.
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!(RESPONSE)) }
+}
+
+// Swaps what's going out. The DMA picks up each word as it's sent, so the next frame gets it.
+fn write_request(request: &[u16]) {
+    for (i, word) in request.iter().enumerate() {
+        // Safety: the DMA only ever reads `REQUEST`, a word at a time, and each of these is a
+        // single write.
+        unsafe { core::ptr::write_volatile(core::ptr::addr_of_mut!(REQUEST[i]), *word) };
+    }
+}
+
+// This is the interrupt that fires when the transfer from the `SPI[DR]` register transaction is
+// complete.
+#[interrupt]
+fn DMA1_CH2() {
+    // Clear pending IRQ in NVIC.
+    clear_pending_irq(device::Interrupt::DMA1_CH2);
+
+    let InterruptData {
+        dma,
+        writer,
+        read,
+        recovery,
+    } = &mut *acquire_hw(&ENCODER_INTERRUPT_DATA);
+    // One frame per transfer. Bad readings are dropped; readers just see the last good one.
+    let (reading, request) = recovery.update(read(&read_response()), FRAME_PERIOD);
+    if let Some(request) = request {
+        write_request(request);
+    }
+    if let Ok(reading) = reading {
+        *writer.update() = reading;
+    }
+    // Clear DMA IRQ flag.
+    dma.ifcr.write(|w| w.gif2().set_bit());
+}
@@ -1,9 +1,11 @@
 use core::marker::PhantomData;
 
+#[cfg(not(feature = "host"))]
 use stm32g4::stm32g474::GPIOB;
 
 // Badly hacked LED control :(
 // TODO(blakely): TIM4 should be used for BLDCV1 if we ever want to PWM the LEDs.
+// There's no GPIOB on the host, so the LEDs are no-ops under the `host` feature.
 
 pub struct Red {}
 pub struct Green {}
@@ -34,9 +36,10 @@ impl<T> Led<T> {
         Self: LedBit,
     {
         // Safety: atomic write to bit set/reset regsiter with no side effects.
+        #[cfg(not(feature = "host"))]
         unsafe {
             (*GPIOB::ptr()).bsrr.write(|w| w.bits(1 << Self::bit()));
-        }
+        };
     }
 
     pub fn off()
@@ -44,11 +47,12 @@ impl<T> Led<T> {
         Self: LedBit,
     {
         // Safety: atomic write to bit set/reset regsiter with no side effects.
+        #[cfg(not(feature = "host"))]
         unsafe {
             (*GPIOB::ptr())
                 .bsrr
                 .write(|w| w.bits(1 << (Self::bit() + 16)));
-        }
+        };
     }
 }
 
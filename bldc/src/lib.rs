@@ -2,15 +2,30 @@
 
 pub mod util;
 
+pub mod boot;
+#[cfg(feature = "canopen")]
+pub mod canopen;
 pub mod comms;
+pub mod config;
 pub mod control_loops;
 pub mod cordic;
+pub mod crash;
 pub mod current_sensing;
 pub mod driver;
 pub mod encoder;
+pub mod fault;
 pub mod foc;
+pub mod gate_driver;
+pub mod hal;
+pub mod hall;
 pub mod ic;
 pub mod led;
 pub mod pi_controller;
+pub mod protection;
 pub mod pwm;
+pub mod scope;
+#[cfg(feature = "host")]
+pub mod sim;
+pub mod telemetry;
 pub mod timer;
+pub mod watchdog;
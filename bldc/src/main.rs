@@ -1,9 +1,23 @@
 #![cfg_attr(not(test), no_std)]
 #![no_main]
 
+use bldc::comms::handlers::bus_status::GetBusStatus;
+use bldc::comms::handlers::calibrate_halls::StartHallCalibration;
+use bldc::comms::handlers::crash::GetCrashRecord;
 use bldc::comms::handlers::disable_control_loop::DisableControlLoop;
+use bldc::comms::handlers::drv::DumpDrvRegisters;
+use bldc::comms::handlers::faults::{ClearFaults, GetFaults};
+use bldc::comms::handlers::group_set_pos_vel::GroupSetPosVel;
+use bldc::comms::handlers::heartbeat::Heartbeat;
+use bldc::comms::handlers::loop_timing::GetLoopTiming;
+use bldc::comms::handlers::params::{
+    GetParam, ListParams, RestoreDefaultParams, SaveParams, SetParam,
+};
 use bldc::comms::handlers::pos_vel_control::EnterPosVelControl;
+use bldc::comms::handlers::reboot::RebootToBootloader;
+use bldc::comms::handlers::scope::{ArmScope, GetScopeStatus, ReadScope, TriggerScope};
 use bldc::comms::handlers::set_pos_vel::SetPosVel;
+use bldc::comms::handlers::telemetry::{StartTelemetry, StopTelemetry};
 use bldc::comms::handlers::torque_control::EnterTorqueControl;
 use bldc::driver;
 
@@ -12,6 +26,12 @@ use panic_halt as _;
 #[cfg(feature = "panic-itm")]
 use panic_itm as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
 
+#[cfg(feature = "panic-safe")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    bldc::crash::panic(info)
+}
+
 // TODO(blakely): Comment on all the stuff that happens before we actually get here...
 #[cortex_m_rt::entry]
 fn main() -> ! {
@@ -33,17 +53,6 @@ fn main() -> ! {
     //     Message::PosVelCommand(cmd) => {
     //         PosVelControl::command(cmd);
     //     }
-    //     Message::BeginStateStream(cmd) => {
-    //         let frequency = cmd.frequency.max(1.);
-    //         timer::periodic_callback(frequency, frequency / 10_000., || {
-    //             if let Some(state) = SENSOR_STATE.read() {
-    //                 fdcan::send_message(&state);
-    //             };
-    //         });
-    //     }
-    //     Message::EndStateStream => {
-    //         timer::stop_periodic_callback();
-    //     }
     //     _ => (),
     // });
 
@@ -51,6 +60,27 @@ fn main() -> ! {
     driver.add_message_handler(EnterPosVelControl::new());
     driver.add_message_handler(SetPosVel::new());
     driver.add_message_handler(DisableControlLoop::new());
+    driver.add_message_handler(GetParam::new());
+    driver.add_message_handler(SetParam::new());
+    driver.add_message_handler(ListParams::new());
+    driver.add_message_handler(SaveParams::new());
+    driver.add_message_handler(RestoreDefaultParams::new());
+    driver.add_message_handler(GetFaults::new());
+    driver.add_message_handler(ClearFaults::new());
+    driver.add_message_handler(DumpDrvRegisters::new());
+    driver.add_message_handler(GetLoopTiming::new());
+    driver.add_message_handler(GetCrashRecord::new());
+    driver.add_message_handler(Heartbeat::new());
+    driver.add_message_handler(GroupSetPosVel::new());
+    driver.add_message_handler(StartTelemetry::new());
+    driver.add_message_handler(StopTelemetry::new());
+    driver.add_message_handler(ArmScope::new());
+    driver.add_message_handler(TriggerScope::new());
+    driver.add_message_handler(GetScopeStatus::new());
+    driver.add_message_handler(ReadScope::new());
+    driver.add_message_handler(RebootToBootloader::new());
+    driver.add_message_handler(GetBusStatus::new());
+    driver.add_message_handler(StartHallCalibration::new());
 
     driver.listen();
 }
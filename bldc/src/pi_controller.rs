@@ -3,6 +3,8 @@ pub struct PIController {
     ki: f32,
     ki_integral: f32,
     v_clamp: f32,
+    // Last output from `update`, before any saturation applied downstream.
+    output: f32,
 }
 
 impl PIController {
@@ -12,14 +14,40 @@ impl PIController {
             ki,
             ki_integral: 0.,
             v_clamp,
+            output: 0.,
         }
     }
 
+    // Change gains on the fly. The integrator is left alone so there's no bump in the output.
+    pub fn set_gains(&mut self, k: f32, ki: f32, v_clamp: f32) {
+        self.k = k;
+        self.ki = ki;
+        self.v_clamp = v_clamp;
+    }
+
+    pub fn set_v_clamp(&mut self, v_clamp: f32) {
+        self.v_clamp = v_clamp;
+    }
+
     pub fn update(&mut self, measurement: f32, target: f32) -> f32 {
         let error = target - measurement;
         let voltage = self.k * error + self.ki_integral;
         self.ki_integral += self.k * self.ki * error;
         self.ki_integral = self.ki_integral.clamp(-self.v_clamp, self.v_clamp);
-        voltage.clamp(-self.v_clamp, self.v_clamp)
+        self.output = voltage.clamp(-self.v_clamp, self.v_clamp);
+        self.output
+    }
+
+    // Report the value that was actually applied after the output of `update` was limited further
+    // downstream (e.g. voltage vector saturation). The difference is fed back into the integrator,
+    // with `ki` as the back-calculation gain so it settles at the saturated output instead of
+    // winding up past it.
+    pub fn saturate(&mut self, applied: f32) {
+        self.ki_integral += self.ki * (applied - self.output);
+        self.ki_integral = self.ki_integral.clamp(-self.v_clamp, self.v_clamp);
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.ki_integral
     }
 }
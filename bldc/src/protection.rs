@@ -0,0 +1,84 @@
+use crate::{
+    config::{self, LoopConfig},
+    current_sensing::PhaseCurrents,
+    fault::Fault,
+    util::buffered_state::StateReader,
+};
+
+// Hard limits on phase current and bus voltage. Checked by the loop interrupt every cycle that a
+// control loop is running, before the loop itself gets to run, so a loop that's gone off the rails
+// can't talk its way out of it. Limits come from the config and can be changed on the fly.
+//
+// Each limit has to be exceeded for `protection_debounce` cycles in a row before it trips, so a
+// single noisy ADC sample doesn't take the joint down.
+
+pub struct Protection {
+    config: StateReader<LoopConfig>,
+    overcurrent: u32,
+    overvoltage: u32,
+    undervoltage: u32,
+}
+
+impl Protection {
+    // Has to be called outside of the loop interrupt, same as `config::loop_config`.
+    pub fn new() -> Protection {
+        Protection {
+            config: config::loop_config(),
+            overcurrent: 0,
+            overvoltage: 0,
+            undervoltage: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.overcurrent = 0;
+        self.overvoltage = 0;
+        self.undervoltage = 0;
+    }
+
+    // Returns the fault to raise, if any limit has been exceeded for long enough.
+    pub fn check(&mut self, currents: &PhaseCurrents, v_bus: f32) -> Option<Fault> {
+        let limits = *self.config.read();
+        let peak = currents
+            .phase_a
+            .abs()
+            .max(currents.phase_b.abs())
+            .max(currents.phase_c.abs());
+
+        let overcurrent = debounce(
+            &mut self.overcurrent,
+            peak > limits.max_phase_current,
+            limits.protection_debounce,
+        );
+        let overvoltage = debounce(
+            &mut self.overvoltage,
+            v_bus > limits.max_v_bus,
+            limits.protection_debounce,
+        );
+        let undervoltage = debounce(
+            &mut self.undervoltage,
+            v_bus < limits.min_v_bus,
+            limits.protection_debounce,
+        );
+
+        if overcurrent {
+            Some(Fault::Overcurrent)
+        } else if overvoltage {
+            Some(Fault::BusOvervoltage)
+        } else if undervoltage {
+            Some(Fault::BusUndervoltage)
+        } else {
+            None
+        }
+    }
+}
+
+fn debounce(count: &mut u32, exceeded: bool, limit: u32) -> bool {
+    if exceeded {
+        *count = count.saturating_add(1);
+        *count >= limit
+    } else {
+        *count = 0;
+        false
+    }
+}
@@ -1,6 +1,9 @@
 use stm32g4::stm32g474 as device;
 
-use crate::{block_until, block_while, timer::TimerConfig};
+use crate::{
+    block_until, block_while, control_loops::timing::CORE_CLOCK_HZ, hal::ThreePhaseBridge,
+    timer::TimerConfig,
+};
 
 pub struct PwmDuty {
     pub a: f32,
@@ -14,19 +17,37 @@ pub struct PhaseVoltages {
     pub c: f32,
 }
 
+// TIM1's auto-reload value: 40kHz center-aligned at 170MHz. `PwmOutput` goes by whatever it was
+// actually configured with, this is just what the driver configures it with.
+pub const PWM_ARR: u16 = 2125;
+// Forced deadtime inserted by TIM1[CH5], which ANDs with channels 1-3, as the fraction of the
+// period it lets through (2083 / 2125 at the ARR above).
+const DEADTIME_DUTY: f32 = 2083. / 2125.;
+// Largest duty cycle we allow by default. Matches the deadtime above, which would cut it off there
+// anyway.
+pub const DEFAULT_MAX_DUTY: f32 = DEADTIME_DUTY;
+
+// TIM1[CH5]'s CCR for the forced deadtime, for a timer configured with `arr`.
+pub fn deadtime_ccr(arr: u16) -> u16 {
+    (DEADTIME_DUTY * arr as f32 + 0.5) as u16
+}
+
 impl PhaseVoltages {
-    pub fn as_pwm(&self, v_bus: f32, invert_pwm: bool) -> PwmDuty {
-        match invert_pwm {
-            false => PwmDuty {
-                a: self.a / v_bus * 0.5 + 0.5,
-                b: self.b / v_bus * 0.5 + 0.5,
-                c: self.c / v_bus * 0.5 + 0.5,
-            },
-            true => PwmDuty {
-                a: -self.a / v_bus * 0.5 + 0.5,
-                b: -self.b / v_bus * 0.5 + 0.5,
-                c: -self.c / v_bus * 0.5 + 0.5,
-            },
+    // Convert voltages relative to the v_bus/2 midpoint into duty cycles, clamped to [0, max_duty].
+    // A phase voltage of +v_bus/2 maps to a duty of 1.0, and -v_bus/2 to 0.0 (or the reverse if the
+    // PWM is inverted).
+    pub fn as_pwm(&self, v_bus: f32, invert_pwm: bool, max_duty: f32) -> PwmDuty {
+        let to_duty = |voltage: f32| -> f32 {
+            let duty = match invert_pwm {
+                false => 0.5 + voltage / v_bus,
+                true => 0.5 - voltage / v_bus,
+            };
+            duty.max(0.).min(max_duty)
+        };
+        PwmDuty {
+            a: to_duty(self.a),
+            b: to_duty(self.b),
+            c: to_duty(self.c),
         }
     }
 }
@@ -34,6 +55,10 @@ impl PhaseVoltages {
 pub struct PwmOutput {
     timer: device::TIM1,
     invert: bool,
+    max_duty: f32,
+    // From `configure`.
+    arr: u16,
+    prescalar: u16,
 }
 
 impl PwmOutput {
@@ -41,9 +66,16 @@ impl PwmOutput {
         PwmOutput {
             timer,
             invert: invert_pwm,
+            max_duty: DEFAULT_MAX_DUTY,
+            arr: PWM_ARR,
+            prescalar: 1,
         }
     }
 
+    pub fn max_duty(&self) -> f32 {
+        self.max_duty
+    }
+
     pub fn configure(mut self, config: TimerConfig) -> Self {
         // Configure TIM1 for control loop (actual timer frequency is double, since up + down = 1
         // full cycle).
@@ -134,6 +166,8 @@ impl PwmOutput {
         // Note: the prescalar is 0-indexed; psc=0 implies prescalar = 1.
         tim1.psc.write(|w| w.psc().bits(config.prescalar - 1));
         tim1.arr.write(|w| w.arr().bits(config.arr));
+        self.arr = config.arr;
+        self.prescalar = config.prescalar;
 
         // Set repetition counter to 1, since we only want update TIM1 events on only after the full
         // up/down count cycle.
@@ -159,7 +193,7 @@ impl PwmOutput {
                 .gc5c3()
                 .set_bit()
                 .ccr()
-                .bits(2083)
+                .bits(deadtime_ccr(self.arr))
         });
         // Set channel 4 to trigger _just_ before the midway point.
         self.reset_current_sample();
@@ -169,39 +203,65 @@ impl PwmOutput {
         self
     }
 
-    pub fn set_pwm_duty_cycles(&mut self, pwms: PwmDuty) {
+    // TODO(blakely): Don't expose ccr here.
+    pub fn set_sample_ccr(&mut self, ccr: u16) {
+        self.timer.ccr4.write(|w| w.ccr().bits(ccr));
+    }
+}
+
+impl ThreePhaseBridge for PwmOutput {
+    fn set_pwm_duty_cycles(&mut self, pwms: PwmDuty) {
         // Set PWM values
+        let arr = self.arr as f32;
         self.timer
             .ccr1
-            .write(|w| w.ccr().bits((pwms.a * 2125.) as u16));
+            .write(|w| w.ccr().bits((pwms.a * arr) as u16));
         self.timer
             .ccr2
-            .write(|w| w.ccr().bits((pwms.b * 2125.) as u16));
+            .write(|w| w.ccr().bits((pwms.b * arr) as u16));
         self.timer
             .ccr3
-            .write(|w| w.ccr().bits((pwms.c * 2125.) as u16));
+            .write(|w| w.ccr().bits((pwms.c * arr) as u16));
     }
 
-    pub fn set_voltages(&mut self, v_bus: f32, voltages: PhaseVoltages) {
-        self.set_pwm_duty_cycles(voltages.as_pwm(v_bus, self.invert));
+    fn set_voltages(&mut self, v_bus: f32, voltages: PhaseVoltages) {
+        self.set_pwm_duty_cycles(voltages.as_pwm(v_bus, self.invert, self.max_duty));
     }
 
-    // TODO(blakely): Don't expose ccr here.
-    pub fn set_sample_ccr(&mut self, ccr: u16) {
-        self.timer.ccr4.write(|w| w.ccr().bits(ccr));
+    fn set_max_duty(&mut self, max_duty: f32) {
+        self.max_duty = max_duty.max(0.).min(1.);
+    }
+
+    fn arr(&self) -> u16 {
+        self.arr
+    }
+
+    fn loop_frequency(&self) -> f32 {
+        // Center-aligned, so it takes counting up to `arr` and back down again for one period.
+        let config = TimerConfig {
+            prescalar: self.prescalar,
+            arr: self.arr,
+        };
+        config.frequency(CORE_CLOCK_HZ) / 2.
     }
 
-    pub fn reset_current_sample(&mut self) {
-        self.set_sample_ccr(2124);
+    fn set_sample_point(&mut self, fraction: f32) {
+        self.set_sample_ccr((fraction * self.arr as f32 + 0.5) as u16);
     }
 
-    pub fn reset_deadtime(&mut self) {
+    fn reset_current_sample(&mut self) {
+        self.set_sample_ccr(self.arr - 1);
+    }
+
+    fn reset_deadtime(&mut self) {
         // TODO(blakely): Set this CCR to a logical safe PWM duty (min deadtime 400ns = 98.4% duty
         // cycle at 40kHz)
-        self.timer.ccr5.write(|w| w.ccr().bits(2083));
+        self.timer
+            .ccr5
+            .write(|w| w.ccr().bits(deadtime_ccr(self.arr)));
     }
 
-    pub fn enable_loop(&mut self) {
+    fn enable_loop(&mut self) {
         // Kick off the timer.
         self.timer.cr1.modify(|_, w| w.cen().set_bit());
         // Now that the timer has started, enable the main output to allow current on the pins. If
@@ -211,18 +271,10 @@ impl PwmOutput {
         self.timer.bdtr.modify(|_, w| w.moe().set_bit());
     }
 
-    pub fn disable_loop(&mut self) {
+    fn disable_loop(&mut self) {
         // Disable main output.
         self.timer.bdtr.modify(|_, w| w.moe().clear_bit());
         // Disable the timer completely.
         self.timer.cr1.modify(|_, w| w.cen().clear_bit());
     }
-
-    pub fn zero_phases(&mut self) {
-        self.set_pwm_duty_cycles(PwmDuty {
-            a: 0.,
-            b: 0.,
-            c: 0.,
-        });
-    }
 }
@@ -0,0 +1,59 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use protocol::scope::{Capture, ScopeState};
+use stm32g4::stm32g474 as device;
+use third_party::m4vga_rs::util::{spin_lock::SpinLock, sync::acquire_hw};
+
+use crate::fault;
+use crate::telemetry::Snapshot;
+use crate::util::interrupts::block_interrupts;
+
+// Scope captures; see `protocol::scope`. The loop interrupt feeds every iteration into the capture,
+// and everything else happens from the main thread with the loop interrupt held off. Reads only
+// copy out a frame's worth at a time, so that's never for long.
+
+// 16kB. With four signals that's a little over 25ms at the full loop rate.
+const BUFFER_VALUES: usize = 4096;
+
+type Buffer = &'static mut [f32; BUFFER_VALUES];
+
+static SCOPE: SpinLock<Option<Capture<Buffer>>> = SpinLock::new(None);
+// Set when a capture finishes, so main can let the host know.
+static FINISHED: AtomicBool = AtomicBool::new(false);
+
+fn init_buffer() -> Buffer {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    static mut BUFFER: [f32; BUFFER_VALUES] = [0.; BUFFER_VALUES];
+
+    if TAKEN.swap(true, Ordering::AcqRel) {
+        panic!("Scope buffer acquired twice");
+    }
+    // Safety: only ever handed out once, per the above.
+    unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) }
+}
+
+pub fn init() {
+    *SCOPE.lock() = Some(Capture::new(init_buffer()));
+}
+
+// Called by the loop interrupt every iteration.
+pub fn sample(snapshot: &Snapshot) {
+    let capture = &mut *acquire_hw(&SCOPE);
+    let before = capture.state();
+    capture.sample(|signal| snapshot.value(signal), fault::active().bits());
+    if before != ScopeState::Done && capture.state() == ScopeState::Done {
+        FINISHED.store(true, Ordering::Release);
+    }
+}
+
+// Anything else goes through here, from the main thread.
+pub fn with_capture<R>(f: impl FnOnce(&mut Capture<Buffer>) -> R) -> R {
+    block_interrupts([device::interrupt::ADC1_2], &SCOPE, |mut capture| {
+        f(&mut capture)
+    })
+}
+
+// Whether a capture's finished since the last time this was called.
+pub fn take_finished() -> bool {
+    FINISHED.swap(false, Ordering::AcqRel)
+}
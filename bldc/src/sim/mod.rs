@@ -0,0 +1,197 @@
+use crate::{
+    control_loops::{Commutate, ControlHardware, LoopState, SensorState},
+    fault::{self, Faults},
+    hal::{
+        BusVoltageSource, Peripherals, PhaseCurrentSource, RotorPositionSensor, ThreePhaseBridge,
+    },
+    protection::Protection,
+    pwm::PhaseVoltages,
+};
+
+mod motor;
+mod peripherals;
+
+pub use motor::{Motor, MotorParameters};
+pub use peripherals::{SimCordic, SimCordicProcessing, SimCurrentSensor, SimEncoder, SimPwm};
+
+// Host-side simulation of the motor and the peripherals that drive it. The control loops run
+// unmodified against a `ControlHardware<SimPeripherals>`. `Simulator::step` does the job of the
+// ADC1_2 interrupt: latch the sensors, run the loop, then let the plant evolve for one PWM period
+// under whatever duties the loop commanded.
+
+// Control loop period, matching TIM1's 40kHz update rate.
+pub const DT: f32 = 1. / 40_000.;
+// Number of plant integration steps per control period.
+const SUBSTEPS: u32 = 20;
+// Same default as the board.
+const VELOCITY_OBSERVER_BANDWIDTH: f32 = 200.;
+
+pub struct SimPeripherals {}
+
+impl Peripherals for SimPeripherals {
+    type CurrentSensor = SimCurrentSensor;
+    type Bridge = SimPwm;
+    type Encoder = SimEncoder;
+    type SinCos = SimCordic;
+}
+
+pub struct Simulator {
+    pub motor: Motor,
+    pub hw: ControlHardware<SimPeripherals>,
+    v_bus: f32,
+    loop_state: LoopState,
+    protection: Protection,
+    ticks: u32,
+}
+
+impl Simulator {
+    pub fn new(params: MotorParameters, v_bus: f32) -> Simulator {
+        Simulator {
+            hw: ControlHardware {
+                current_sensor: SimCurrentSensor::new(v_bus),
+                pwm: SimPwm::new(false),
+                encoder: SimEncoder::new(params.pole_pairs, VELOCITY_OBSERVER_BANDWIDTH),
+                cordic: SimCordic::new(),
+            },
+            motor: Motor::new(params),
+            v_bus,
+            loop_state: LoopState::Idle,
+            protection: Protection::new(),
+            ticks: 0,
+        }
+    }
+
+    pub fn set_v_bus(&mut self, v_bus: f32) {
+        self.v_bus = v_bus;
+    }
+
+    pub fn time(&self) -> f32 {
+        self.ticks as f32 * DT
+    }
+
+    pub fn loop_state(&self) -> LoopState {
+        self.loop_state
+    }
+
+    // Equivalent of `Controller::enable_loop`, including refusing to run with critical faults.
+    pub fn enable_loop(&mut self) -> Result<(), Faults> {
+        let critical = fault::active().critical();
+        if !critical.is_empty() {
+            return Err(critical);
+        }
+        self.loop_state = LoopState::Running;
+        self.hw.pwm.enable_loop();
+        Ok(())
+    }
+
+    // Ask the loop to shut down, same as `Controller::disable_loop`. It's up to the loop to decide
+    // when it's actually idle.
+    pub fn shutdown(&mut self) {
+        if let LoopState::Running = self.loop_state {
+            self.loop_state = LoopState::Shutdown;
+        }
+    }
+
+    // Run a single control period. Mirrors `interrupt::commutate`, then advances the plant.
+    pub fn step<C: Commutate<SimPeripherals>>(&mut self, control_loop: &mut C) -> LoopState {
+        let ControlHardware {
+            ref mut current_sensor,
+            ref mut encoder,
+            ..
+        } = self.hw;
+        encoder.set_angle(self.motor.angle());
+        current_sensor.set_measurement(self.motor.phase_currents(), self.v_bus);
+
+        let encoder_state = encoder.update(DT);
+        let sensor_state = SensorState::new(
+            &encoder_state,
+            &current_sensor.sample(),
+            current_sensor.v_bus(),
+        );
+
+        match self.loop_state {
+            LoopState::Running | LoopState::Shutdown => {
+                if let Some(fault) = self
+                    .protection
+                    .check(&sensor_state.currents, sensor_state.v_bus)
+                {
+                    fault::raise(fault);
+                }
+            }
+            LoopState::Idle => self.protection.reset(),
+        }
+
+        if fault::tripped() {
+            let pwm = &mut self.hw.pwm;
+            pwm.zero_phases();
+            pwm.reset_current_sample();
+            pwm.reset_deadtime();
+            pwm.disable_loop();
+            if let LoopState::Running | LoopState::Shutdown = self.loop_state {
+                control_loop.finished();
+            }
+            self.loop_state = LoopState::Idle;
+        } else if let LoopState::Running | LoopState::Shutdown = self.loop_state {
+            self.loop_state =
+                match control_loop.commutate(self.loop_state, &sensor_state, &mut self.hw) {
+                    LoopState::Idle => {
+                        let pwm = &mut self.hw.pwm;
+                        pwm.zero_phases();
+                        pwm.reset_current_sample();
+                        pwm.reset_deadtime();
+                        control_loop.finished();
+                        LoopState::Idle
+                    }
+                    x => x,
+                };
+        }
+
+        self.advance();
+        self.loop_state
+    }
+
+    // Enable the loop and run it for `duration` seconds or until it goes idle, whichever comes
+    // first. `observe` is called after every control period.
+    pub fn run<C, F>(&mut self, control_loop: &mut C, duration: f32, mut observe: F) -> LoopState
+    where
+        C: Commutate<SimPeripherals>,
+        F: FnMut(&Simulator),
+    {
+        if self.enable_loop().is_err() {
+            return self.loop_state;
+        }
+        let periods = (duration / DT) as u32;
+        for _ in 0..periods {
+            let loop_state = self.step(control_loop);
+            observe(self);
+            if let LoopState::Idle = loop_state {
+                break;
+            }
+        }
+        self.loop_state
+    }
+
+    // Let the plant evolve for one PWM period. The duties are averaged over the period, so PWM
+    // ripple isn't modelled.
+    fn advance(&mut self) {
+        let dt = DT / SUBSTEPS as f32;
+        self.ticks += 1;
+        if !self.hw.pwm.is_enabled() {
+            for _ in 0..SUBSTEPS {
+                self.motor.step_open_circuit(dt);
+            }
+            return;
+        }
+        let [a, b, c] = self.hw.pwm.phase_duties();
+        // Wye-connected windings: the neutral floats to the average of the three phases.
+        let neutral = (a + b + c) / 3.;
+        let voltages = PhaseVoltages {
+            a: (a - neutral) * self.v_bus,
+            b: (b - neutral) * self.v_bus,
+            c: (c - neutral) * self.v_bus,
+        };
+        for _ in 0..SUBSTEPS {
+            self.motor.step(&voltages, dt);
+        }
+    }
+}
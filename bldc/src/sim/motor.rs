@@ -0,0 +1,167 @@
+use crate::{current_sensing::PhaseCurrents, foc::DQCurrents, pwm::PhaseVoltages};
+use core::f32::consts::PI;
+use num_traits::Float;
+
+// Lumped-parameter model of a PMSM in the rotor (dq) frame:
+//
+//   v_d = R i_d + L_d di_d/dt - ω_e L_q i_q
+//   v_q = R i_q + L_q di_q/dt + ω_e (L_d i_d + λ)
+//   T_e = 3/2 p (λ i_q + (L_d - L_q) i_d i_q)
+//   J dω/dt = T_e - B ω - T_load
+//
+// Uses the same amplitude-invariant Park/Clark convention as `foc`, so currents read back through
+// the simulated current sensor line up with what the FOC loop expects. No magnetic saturation,
+// cogging or iron losses; it's just enough to close the loop around the controllers.
+
+const TWO_PI: f32 = 2. * PI;
+const TWO_THIRDS_PI: f32 = 2. * PI / 3.;
+
+#[derive(Clone, Copy)]
+pub struct MotorParameters {
+    // Phase resistance, in ohms.
+    pub resistance: f32,
+    // d- and q-axis inductances, in henries. Identical for surface-mount magnets.
+    pub inductance_d: f32,
+    pub inductance_q: f32,
+    // Permanent magnet flux linkage, in webers.
+    pub flux_linkage: f32,
+    pub pole_pairs: u8,
+    // Rotor inertia, in kg*m^2.
+    pub inertia: f32,
+    // Viscous friction, in Nm/(rad/s).
+    pub friction: f32,
+    // Constant external torque pulling the rotor backwards (e.g. a weight hanging off an arm), in
+    // Nm.
+    pub load_torque: f32,
+}
+
+pub struct Motor {
+    params: MotorParameters,
+    i_d: f32,
+    i_q: f32,
+    // Mechanical angle (multiturn) and velocity of the rotor, in radians and radians/s.
+    angle: f32,
+    velocity: f32,
+}
+
+impl Motor {
+    pub fn new(params: MotorParameters) -> Motor {
+        Motor {
+            params,
+            i_d: 0.,
+            i_q: 0.,
+            angle: 0.,
+            velocity: 0.,
+        }
+    }
+
+    pub fn params(&self) -> &MotorParameters {
+        &self.params
+    }
+
+    pub fn set_load_torque(&mut self, load_torque: f32) {
+        self.params.load_torque = load_torque;
+    }
+
+    // Teleport the rotor. Mostly useful for setting up a test.
+    pub fn set_angle(&mut self, angle: f32) {
+        self.angle = angle;
+    }
+
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn electrical_angle(&self) -> f32 {
+        let angle = (self.angle * self.params.pole_pairs as f32) % TWO_PI;
+        match angle {
+            x if x < 0. => x + TWO_PI,
+            x => x,
+        }
+    }
+
+    pub fn dq_currents(&self) -> DQCurrents {
+        DQCurrents {
+            q: self.i_q,
+            d: self.i_d,
+        }
+    }
+
+    pub fn phase_currents(&self) -> PhaseCurrents {
+        let theta = self.electrical_angle();
+        let phase =
+            |offset: f32| self.i_d * (theta + offset).cos() - self.i_q * (theta + offset).sin();
+        PhaseCurrents {
+            phase_a: phase(0.),
+            phase_b: phase(-TWO_THIRDS_PI),
+            phase_c: phase(TWO_THIRDS_PI),
+        }
+    }
+
+    pub fn torque(&self) -> f32 {
+        let MotorParameters {
+            inductance_d,
+            inductance_q,
+            flux_linkage,
+            pole_pairs,
+            ..
+        } = self.params;
+        1.5 * pole_pairs as f32
+            * (flux_linkage * self.i_q + (inductance_d - inductance_q) * self.i_d * self.i_q)
+    }
+
+    // Advance the model by `dt` with the line-to-neutral `voltages` held across the windings.
+    pub fn step(&mut self, voltages: &PhaseVoltages, dt: f32) {
+        let theta = self.electrical_angle();
+        let park = |f: fn(f32) -> f32| {
+            2. / 3.
+                * (voltages.a * f(theta)
+                    + voltages.b * f(theta - TWO_THIRDS_PI)
+                    + voltages.c * f(theta + TWO_THIRDS_PI))
+        };
+        let v_d = park(|x| x.cos());
+        let v_q = -park(|x| x.sin());
+
+        let MotorParameters {
+            resistance,
+            inductance_d,
+            inductance_q,
+            flux_linkage,
+            pole_pairs,
+            ..
+        } = self.params;
+        let omega_e = self.velocity * pole_pairs as f32;
+        let di_d = (v_d - resistance * self.i_d + omega_e * inductance_q * self.i_q) / inductance_d;
+        let di_q =
+            (v_q - resistance * self.i_q - omega_e * (inductance_d * self.i_d + flux_linkage))
+                / inductance_q;
+        self.i_d += di_d * dt;
+        self.i_q += di_q * dt;
+
+        self.step_mechanics(self.torque(), dt);
+    }
+
+    // Advance the model by `dt` with the bridge switched off. Ignores conduction through the body
+    // diodes, so the windings are treated as open and the current drops straight to zero.
+    pub fn step_open_circuit(&mut self, dt: f32) {
+        self.i_d = 0.;
+        self.i_q = 0.;
+        self.step_mechanics(0., dt);
+    }
+
+    fn step_mechanics(&mut self, torque: f32, dt: f32) {
+        let MotorParameters {
+            inertia,
+            friction,
+            load_torque,
+            ..
+        } = self.params;
+        let acceleration = (torque - friction * self.velocity - load_torque) / inertia;
+        self.velocity += acceleration * dt;
+        self.angle += self.velocity * dt;
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{
+    current_sensing::PhaseCurrents,
+    encoder::{AngleTracker, EncoderState},
+    hal::{
+        BusVoltageSource, PendingCosSin, PhaseCurrentSource, RotorPositionSensor, SinCos,
+        ThreePhaseBridge,
+    },
+    hall::{self, HallCalibration, HallTracker, HALL_SECTORS},
+    pwm::{self, PhaseVoltages, PwmDuty, DEFAULT_MAX_DUTY},
+};
+use core::f32::consts::PI;
+use num_traits::Float;
+use third_party::ang::Angle;
+
+// Simulated implementations of the `hal` traits, plus a few extra methods for the `Simulator` to
+// push the plant state in and pull the commanded duties out.
+
+const TWO_PI: f32 = 2. * PI;
+// Matches the MA702's 12-bit output.
+const ENCODER_COUNTS: f32 = 4096.;
+// TIM1 ARR, as a float since that's all we ever use it as.
+const PWM_ARR: f32 = pwm::PWM_ARR as f32;
+const DEFAULT_SAMPLE_CCR: u16 = pwm::PWM_ARR - 1;
+
+pub struct SimCurrentSensor {
+    // What the ADCs would currently read, offsets included.
+    reading: PhaseCurrents,
+    // Offsets the simulated ADCs add on top of the true phase currents.
+    adc_offsets: PhaseCurrents,
+    calibration: PhaseCurrents,
+    v_bus: f32,
+}
+
+impl SimCurrentSensor {
+    pub fn new(v_bus: f32) -> SimCurrentSensor {
+        SimCurrentSensor {
+            reading: PhaseCurrents::new(),
+            adc_offsets: PhaseCurrents::new(),
+            calibration: PhaseCurrents::new(),
+            v_bus,
+        }
+    }
+
+    pub fn set_adc_offsets(&mut self, offsets: PhaseCurrents) {
+        self.adc_offsets = offsets;
+    }
+
+    // Latch the plant's phase currents and bus voltage, as if the ADCs had just been triggered.
+    pub fn set_measurement(&mut self, currents: PhaseCurrents, v_bus: f32) {
+        self.reading = currents + self.adc_offsets;
+        self.v_bus = v_bus;
+    }
+}
+
+impl PhaseCurrentSource for SimCurrentSensor {
+    fn sample(&self) -> PhaseCurrents {
+        self.reading - self.calibration
+    }
+
+    fn sample_raw(&self) -> PhaseCurrents {
+        self.reading
+    }
+
+    fn set_calibration(&mut self, phase_a: f32, phase_b: f32, phase_c: f32) {
+        self.calibration = PhaseCurrents {
+            phase_a,
+            phase_b,
+            phase_c,
+        };
+    }
+}
+
+impl BusVoltageSource for SimCurrentSensor {
+    fn v_bus(&self) -> f32 {
+        self.v_bus
+    }
+}
+
+pub struct SimPwm {
+    ccr: [u16; 3],
+    sample_ccr: u16,
+    deadtime_ccr: u16,
+    invert: bool,
+    max_duty: f32,
+    enabled: bool,
+}
+
+impl SimPwm {
+    pub fn new(invert_pwm: bool) -> SimPwm {
+        SimPwm {
+            ccr: [0; 3],
+            sample_ccr: DEFAULT_SAMPLE_CCR,
+            deadtime_ccr: pwm::deadtime_ccr(pwm::PWM_ARR),
+            invert: invert_pwm,
+            max_duty: DEFAULT_MAX_DUTY,
+            enabled: false,
+        }
+    }
+
+    // Duty cycle each phase's high side actually sees, after CCR quantization and the deadtime AND
+    // with channel 5. An inverted bridge switches the complement.
+    pub fn phase_duties(&self) -> [f32; 3] {
+        let deadtime = self.deadtime_ccr as f32 / PWM_ARR;
+        let mut duties = [0.; 3];
+        for (duty, ccr) in duties.iter_mut().zip(self.ccr.iter()) {
+            let high = (*ccr as f32 / PWM_ARR).min(deadtime);
+            *duty = match self.invert {
+                false => high,
+                true => 1. - high,
+            };
+        }
+        duties
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn sample_ccr(&self) -> u16 {
+        self.sample_ccr
+    }
+
+    pub fn max_duty(&self) -> f32 {
+        self.max_duty
+    }
+}
+
+impl ThreePhaseBridge for SimPwm {
+    fn set_pwm_duty_cycles(&mut self, pwms: PwmDuty) {
+        self.ccr = [
+            (pwms.a * PWM_ARR) as u16,
+            (pwms.b * PWM_ARR) as u16,
+            (pwms.c * PWM_ARR) as u16,
+        ];
+    }
+
+    fn set_voltages(&mut self, v_bus: f32, voltages: PhaseVoltages) {
+        self.set_pwm_duty_cycles(voltages.as_pwm(v_bus, self.invert, self.max_duty));
+    }
+
+    fn set_max_duty(&mut self, max_duty: f32) {
+        self.max_duty = max_duty.max(0.).min(1.);
+    }
+
+    fn arr(&self) -> u16 {
+        pwm::PWM_ARR
+    }
+
+    fn loop_frequency(&self) -> f32 {
+        1. / super::DT
+    }
+
+    fn set_sample_point(&mut self, fraction: f32) {
+        self.sample_ccr = (fraction * PWM_ARR + 0.5) as u16;
+    }
+
+    fn reset_current_sample(&mut self) {
+        self.sample_ccr = DEFAULT_SAMPLE_CCR;
+    }
+
+    fn reset_deadtime(&mut self) {
+        self.deadtime_ccr = pwm::deadtime_ccr(pwm::PWM_ARR);
+    }
+
+    fn enable_loop(&mut self) {
+        self.enabled = true;
+    }
+
+    fn disable_loop(&mut self) {
+        self.enabled = false;
+    }
+}
+
+pub struct SimEncoder {
+    tracker: AngleTracker,
+    raw_angle: u32,
+    halls: Option<SimHalls>,
+}
+
+// Halls instead of the MA702. `offset` is the electrical angle the first sector starts at, i.e.
+// where the sensors happen to have ended up relative to the magnets.
+struct SimHalls {
+    tracker: HallTracker,
+    pole_pairs: u8,
+    offset: f32,
+    state: u8,
+}
+
+impl SimEncoder {
+    pub fn new(pole_pairs: u8, velocity_observer_bandwidth: f32) -> SimEncoder {
+        SimEncoder {
+            tracker: AngleTracker::new(
+                pole_pairs,
+                velocity_observer_bandwidth,
+                Angle::Radians(TWO_PI / ENCODER_COUNTS),
+            ),
+            raw_angle: 0,
+            halls: None,
+        }
+    }
+
+    // Starts out with `HallCalibration::EVEN`, same as a fresh config.
+    pub fn halls(pole_pairs: u8, velocity_observer_bandwidth: f32, offset: f32) -> SimEncoder {
+        SimEncoder {
+            halls: Some(SimHalls {
+                tracker: HallTracker::new(
+                    pole_pairs,
+                    velocity_observer_bandwidth,
+                    HallCalibration::EVEN,
+                ),
+                pole_pairs,
+                offset,
+                state: hall::SEQUENCE[0],
+            }),
+            ..SimEncoder::new(pole_pairs, velocity_observer_bandwidth)
+        }
+    }
+
+    pub fn set_hall_calibration(&mut self, calibration: HallCalibration) {
+        if let Some(halls) = self.halls.as_mut() {
+            halls.tracker.set_calibration(calibration);
+        }
+    }
+
+    // Latch the rotor's mechanical angle, quantized the same way the MA702 would report it.
+    pub fn set_angle(&mut self, angle: f32) {
+        let turns = angle / TWO_PI;
+        let fraction = turns - turns.floor();
+        self.raw_angle = ((fraction * ENCODER_COUNTS) as u32) & 0xFFF;
+
+        if let Some(halls) = self.halls.as_mut() {
+            let electrical = (angle * halls.pole_pairs as f32 - halls.offset) / TWO_PI;
+            let sector = ((electrical - electrical.floor()) * HALL_SECTORS as f32) as usize;
+            halls.state = hall::SEQUENCE[sector.min(HALL_SECTORS - 1)];
+        }
+    }
+}
+
+impl RotorPositionSensor for SimEncoder {
+    fn update(&mut self, delta_t: f32) -> EncoderState {
+        if let Some(halls) = self.halls.as_mut() {
+            return halls.tracker.update(delta_t, halls.state);
+        }
+        let angle = Angle::Radians(self.raw_angle as f32 / ENCODER_COUNTS) * TWO_PI;
+        self.tracker.update(delta_t, self.raw_angle, angle)
+    }
+
+    fn state(&self) -> &Option<EncoderState> {
+        match &self.halls {
+            Some(halls) => halls.tracker.state(),
+            None => self.tracker.state(),
+        }
+    }
+}
+
+pub struct SimCordic {}
+
+// Nothing to wait on; the result is calculated up front.
+pub struct SimCordicProcessing {
+    result: [f32; 2],
+}
+
+impl PendingCosSin for SimCordicProcessing {
+    fn get_result(self) -> [f32; 2] {
+        self.result
+    }
+}
+
+impl SimCordic {
+    pub fn new() -> SimCordic {
+        SimCordic {}
+    }
+}
+
+impl SinCos for SimCordic {
+    type Pending<'a> = SimCordicProcessing;
+
+    fn cos_sin(&mut self, theta: Angle) -> SimCordicProcessing {
+        let theta = theta.in_radians();
+        SimCordicProcessing {
+            result: [theta.cos(), theta.sin()],
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use protocol::telemetry::{Signal, Signals, TelemetryMsg, TELEMETRY_VALUES};
+use ringbuffer::{
+    ConstGenericRingBuffer, RingBuffer, RingBufferExt, RingBufferRead, RingBufferWrite,
+};
+use stm32g4::stm32g474 as device;
+use third_party::m4vga_rs::util::{spin_lock::SpinLock, sync::acquire_hw};
+
+use crate::comms::fdcan;
+use crate::control_loops::{controller::SENSOR_STATE, timing, SensorState};
+use crate::foc::FocState;
+use crate::util::interrupts::block_interrupts;
+
+// High-rate telemetry; see `protocol::telemetry` for what goes out. The loop interrupt samples into
+// a ring buffer, and TIM2 drains it into frames every tick. TIM2's the lowest priority interrupt, so
+// sending from there never holds up the loop, and it means the main thread can sit in a handler
+// without the stream stalling.
+//
+// If the bus can't keep up (at full rate with only a couple of signals, it can't) the buffer fills
+// and new samples are dropped until there's room again.
+
+// A little over 1.5ms worth at the full loop rate, so a single TIM2 tick never loses anything by
+// itself.
+const BUFFER_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    sequence: u32,
+    values: [f32; TELEMETRY_VALUES],
+}
+
+struct Telemetry {
+    signals: Signals,
+    decimation: u16,
+    // Loop iterations until the next sample.
+    countdown: u16,
+    sequence: u32,
+    dropped: u32,
+    buffer: ConstGenericRingBuffer<Sample, BUFFER_SIZE>,
+}
+
+impl Telemetry {
+    fn new() -> Telemetry {
+        Telemetry {
+            signals: Signals::default(),
+            decimation: 1,
+            countdown: 1,
+            sequence: 0,
+            dropped: 0,
+            buffer: ConstGenericRingBuffer::new(),
+        }
+    }
+
+    // As many samples as will fit in a frame, as long as their sequence numbers are consecutive.
+    fn next_frame(&mut self) -> Option<TelemetryMsg> {
+        let first = self.buffer.peek()?.sequence;
+        let mut frame = TelemetryMsg::new(self.signals, self.decimation, first, self.dropped);
+        while let Some(sample) = self.buffer.peek().copied() {
+            if sample.sequence != frame.next_sequence() || !frame.push(&sample.values) {
+                break;
+            }
+            self.buffer.skip();
+        }
+        Some(frame)
+    }
+}
+
+// Shared between the loop interrupt and TIM2, and set up by main. Whoever's not the loop has to
+// keep it out while holding this.
+static TELEMETRY: SpinLock<Option<Telemetry>> = SpinLock::new(None);
+
+pub fn init() {
+    *TELEMETRY.lock() = Some(Telemetry::new());
+}
+
+pub fn start(signals: Signals, decimation: u32) {
+    let decimation = decimation.clamp(1, u16::MAX as u32) as u16;
+    block_interrupts(
+        [device::interrupt::ADC1_2, device::interrupt::TIM2],
+        &TELEMETRY,
+        |mut telemetry| {
+            *telemetry = Telemetry {
+                signals,
+                decimation,
+                ..Telemetry::new()
+            };
+        },
+    );
+}
+
+pub fn stop() {
+    start(Signals::default(), 1);
+}
+
+// Everything a `Signal` can be read from, as of the end of a loop iteration. Shared with `scope`.
+pub struct Snapshot {
+    sensors: SensorState,
+    foc: Option<FocState>,
+    cycles: u32,
+}
+
+impl Snapshot {
+    // Takes what the loop interrupt's just done, along with how long the last whole interrupt took.
+    // `None` if the sensors haven't been read yet.
+    pub fn new(foc: Option<FocState>, cycles: u32) -> Option<Snapshot> {
+        Some(Snapshot {
+            sensors: SENSOR_STATE.read()?,
+            foc,
+            cycles,
+        })
+    }
+
+    pub fn value(&self, signal: Signal) -> f32 {
+        let encoder = &self.sensors.encoder_state;
+        let currents = &self.sensors.currents;
+        // Only loops that run the current controllers have these. NaN rather than zero, so they
+        // can't be mistaken for real readings.
+        let current_loop = |f: fn(&FocState) -> f32| self.foc.as_ref().map_or(f32::NAN, f);
+        match signal {
+            Signal::Angle => encoder.angle.in_radians(),
+            Signal::Velocity => encoder.velocity.in_radians(),
+            Signal::AngleMultiturn => encoder.angle_multiturn.in_radians(),
+            Signal::CurrentD => current_loop(|foc| foc.current_d),
+            Signal::CurrentQ => current_loop(|foc| foc.current_q),
+            Signal::VoltageD => current_loop(|foc| foc.voltage_d),
+            Signal::VoltageQ => current_loop(|foc| foc.voltage_q),
+            Signal::PhaseA => currents.phase_a,
+            Signal::PhaseB => currents.phase_b,
+            Signal::PhaseC => currents.phase_c,
+            Signal::VBus => self.sensors.v_bus,
+            Signal::IntegratorD => current_loop(|foc| foc.integrator_d),
+            Signal::IntegratorQ => current_loop(|foc| foc.integrator_q),
+            Signal::LoopTime => timing::cycles_to_us(self.cycles),
+        }
+    }
+}
+
+// Called by the loop interrupt every iteration.
+pub fn sample(snapshot: &Snapshot) {
+    let telemetry = &mut *acquire_hw(&TELEMETRY);
+    if telemetry.signals.is_empty() {
+        return;
+    }
+    telemetry.countdown -= 1;
+    if telemetry.countdown > 0 {
+        return;
+    }
+    telemetry.countdown = telemetry.decimation;
+
+    let sequence = telemetry.sequence;
+    telemetry.sequence = sequence.wrapping_add(1);
+    if telemetry.buffer.is_full() {
+        telemetry.dropped = telemetry.dropped.wrapping_add(1);
+        return;
+    }
+    let mut values = [0.; TELEMETRY_VALUES];
+    for (slot, signal) in values.iter_mut().zip(telemetry.signals.iter()) {
+        *slot = snapshot.value(signal);
+    }
+    telemetry.buffer.push(Sample { sequence, values });
+}
+
+// Scheduled from TIM2. Sends as many frames as the Tx FIFO has room for and leaves the rest for the
+// next tick. Nothing that can preempt us sends anything, so the room's still there by the time we
+// get around to using it.
+pub fn send() {
+    while !fdcan::tx_fifo_full() {
+        let frame = block_interrupts([device::interrupt::ADC1_2], &TELEMETRY, |mut telemetry| {
+            telemetry.next_frame()
+        });
+        match frame {
+            Some(frame) => fdcan::send_message(&frame),
+            None => return,
+        }
+    }
+}
@@ -55,6 +55,14 @@ impl<T: Copy> StateReader<T> {
 
 unsafe impl<T: Copy> Send for StateReader<T> {}
 
+// Any number of readers can share the buffer, as long as none of them is preempted by the writer
+// mid-read.
+impl<T: Copy> Clone for StateReader<T> {
+    fn clone(&self) -> Self {
+        StateReader { state: self.state }
+    }
+}
+
 pub struct StateWriter<T: Copy> {
     state: NonNull<BufferedState<T>>,
 }
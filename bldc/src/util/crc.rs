@@ -0,0 +1,28 @@
+// CRC-32 (IEEE 802.3) over the little-endian bytes of `words`. Done in software rather than with
+// the CRC peripheral so that it's usable on the host.
+pub fn crc32(words: &[u32]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in words.iter().flat_map(|word| word.to_le_bytes()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// CRC-8 with polynomial x^8 + x^2 + x + 1 and a zero initial value, as used by the MT6835.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            };
+        }
+    }
+    crc
+}
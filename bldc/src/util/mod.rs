@@ -1,5 +1,6 @@
 pub mod bitfield;
 pub mod buffered_state;
+pub mod crc;
 pub mod interrupts;
 pub mod seq_lock;
 pub mod stm32;
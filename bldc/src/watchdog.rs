@@ -0,0 +1,67 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use stm32g4::stm32g474 as device;
+
+use crate::block_while;
+
+// The independent watchdog. If anything wedges - a `block_until!` waiting on a CORDIC or SPI flag
+// that never comes, a handler that never returns, the loop interrupt stalling out - we'd rather
+// reset than sit there with the bridge energized. The IWDG runs off the LSI, so it'll fire even if
+// the clocks are hosed.
+//
+// It's started just before the ADC calibration, and from then on only refreshed (from `calibrate`
+// and then `listen`) when *both* the main loop and the loop interrupt are making progress: the
+// loop interrupt checks in every cycle, and `service` only refreshes if it's seen a check-in since
+// the last time around. Whether the reset was caused by the watchdog gets picked up at startup and
+// raised as a `WatchdogReset` warning.
+
+// LSI is 32kHz, so /32 gives ~1ms ticks.
+const PRESCALER_DIV32: u32 = 0b011;
+const TIMEOUT_MS: u32 = 100;
+
+const KEY_START: u32 = 0xCCCC;
+const KEY_UNLOCK: u32 = 0x5555;
+const KEY_REFRESH: u32 = 0xAAAA;
+
+static LOOP_CHECKIN: AtomicBool = AtomicBool::new(false);
+
+// Called by the loop interrupt every cycle.
+pub fn loop_checkin() {
+    LOOP_CHECKIN.store(true, Ordering::Release);
+}
+
+pub struct Watchdog {
+    iwdg: device::IWDG,
+}
+
+impl Watchdog {
+    // Once started there's no stopping it, short of a reset.
+    pub fn start(iwdg: device::IWDG) -> Watchdog {
+        // Safety: the SVD doesn't have the key values enumerated. Prescaler and reload are within
+        // their 3- and 12-bit ranges.
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_START) });
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_UNLOCK) });
+        iwdg.pr.write(|w| unsafe { w.bits(PRESCALER_DIV32) });
+        iwdg.rlr.write(|w| unsafe { w.bits(TIMEOUT_MS) });
+        // Wait for the new prescaler and reload to make it across to the LSI domain.
+        block_while! { iwdg.sr.read().bits() != 0 }
+
+        let watchdog = Watchdog { iwdg };
+        watchdog.refresh();
+        watchdog
+    }
+
+    // Call this every time around the main loop. `loop_expected` is whether a control loop is
+    // supposed to be running. When it's idle (or been stopped after tripping on a fault) nothing's
+    // driving the bridge, so there's nothing to wait for.
+    pub fn service(&self, loop_expected: bool) {
+        if LOOP_CHECKIN.swap(false, Ordering::AcqRel) || !loop_expected {
+            self.refresh();
+        }
+    }
+
+    fn refresh(&self) {
+        // Safety: see above.
+        self.iwdg.kr.write(|w| unsafe { w.bits(KEY_REFRESH) });
+    }
+}
@@ -0,0 +1,9 @@
+MEMORY
+{
+  /* The bootloader gets the first 32K of bank 1. Everything after it is laid out in
+     protocol/src/update.rs. */
+  FLASH (rx)      : ORIGIN = 0x8000000, LENGTH = 32K
+  RAM (xrw)       : ORIGIN = 0x20000000, LENGTH = 96K
+}
+
+_stext = ORIGIN(FLASH) + 0x1D8;
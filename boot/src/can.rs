@@ -0,0 +1,193 @@
+use protocol::id::{self, CanId, NodeAddress};
+use protocol::{FdcanMessage, Frame};
+use stm32g4::stm32g474 as device;
+
+// Just enough FDCAN to get frames on and off the bus, polled. Timing and filtering are the same as
+// bldc's `comms::fdcan`, so the host can't tell which one it's talking to. The message RAM's poked
+// at directly rather than through bldc's element types, which would drag the rest of the firmware
+// in with them; layout's from RM0440 44.3.3.
+
+const SRAM: usize = 0x4000_A400;
+const SRAM_WORDS: usize = 212;
+const EXTENDED_FILTERS: usize = SRAM + 0x70;
+const RX_FIFO0: usize = SRAM + 0xB0;
+const TX_BUFFERS: usize = SRAM + 0x278;
+// Two header words and 64 bytes of data.
+const ELEMENT_WORDS: usize = 18;
+
+const EXTENDED: u32 = 1 << 30;
+const FD_FORMAT: u32 = 1 << 21;
+const BIT_RATE_SWITCH: u32 = 1 << 20;
+const DLC_SHIFT: u32 = 16;
+
+fn dlc_to_len(dlc: u32) -> u8 {
+    match dlc {
+        x if x <= 8 => x as u8,
+        9 => 12,
+        10 => 16,
+        11 => 20,
+        12 => 24,
+        13 => 32,
+        14 => 48,
+        _ => 64,
+    }
+}
+
+fn len_to_dlc(len: u8) -> u32 {
+    match len {
+        x if x <= 8 => x as u32,
+        x if x <= 12 => 9,
+        x if x <= 16 => 10,
+        x if x <= 20 => 11,
+        x if x <= 24 => 12,
+        x if x <= 32 => 13,
+        x if x <= 48 => 14,
+        _ => 15,
+    }
+}
+
+fn write_words(address: usize, words: &[u32]) {
+    for (i, word) in words.iter().enumerate() {
+        // Safety: only ever called with addresses in FDCAN1's message RAM, which we own.
+        unsafe { core::ptr::write_volatile((address as *mut u32).add(i), *word) };
+    }
+}
+
+fn read_words(address: usize, words: &mut [u32]) {
+    for (i, word) in words.iter_mut().enumerate() {
+        // Safety: as above.
+        *word = unsafe { core::ptr::read_volatile((address as *const u32).add(i)) };
+    }
+}
+
+pub struct Can {
+    fdcan: device::FDCAN1,
+    node: NodeAddress,
+}
+
+impl Can {
+    // Expects the PLL to be up already.
+    pub fn new(
+        fdcan: device::FDCAN1,
+        rcc: &device::RCC,
+        gpioa: &device::GPIOA,
+        node: NodeAddress,
+    ) -> Can {
+        // Kernel clock from PLLQ, same as the application.
+        rcc.pllcfgr
+            .modify(|_, w| w.pllqen().set_bit().pllq().div2());
+        rcc.ccipr.modify(|_, w| w.fdcansel().pllq());
+        rcc.apb1enr1.modify(|_, w| w.fdcanen().enabled());
+        rcc.ahb2enr.modify(|_, w| w.gpioaen().set_bit());
+
+        // PA11 - FDCAN_RX, PA12 - FDCAN_TX
+        gpioa.afrh.modify(|_, w| w.afrh11().af9().afrh12().af9());
+        gpioa.ospeedr.modify(|_, w| {
+            w.ospeedr11()
+                .very_high_speed()
+                .ospeedr12()
+                .very_high_speed()
+        });
+        gpioa
+            .moder
+            .modify(|_, w| w.moder11().alternate().moder12().alternate());
+
+        fdcan.cccr.modify(|_, w| w.init().init());
+        while fdcan.cccr.read().init().is_run() {}
+        fdcan.cccr.modify(|_, w| w.cce().readwrite());
+        write_words(SRAM, &[0; SRAM_WORDS]);
+
+        // Only frames for us, our group or everyone; see `id::filters`. F0 stores matches in Rx
+        // FIFO 0, and F1 makes it a classic ID/mask filter.
+        let filters = id::filters(&node);
+        for (i, filter) in filters.iter().enumerate() {
+            write_words(
+                EXTENDED_FILTERS + i * 8,
+                &[0b001 << 29 | filter.id, 0b10 << 30 | filter.mask],
+            );
+        }
+        // Safety: same as bldc's `set_node`: no standard filters, and reject anything that doesn't
+        // match and all remote frames.
+        fdcan.rxgfc.write(|w| unsafe {
+            w.bits((filters.len() as u32) << 24 | 0b10 << 4 | 0b10 << 2 | 0b11)
+        });
+
+        fdcan
+            .cccr
+            .modify(|_, w| w.brse().set_bit().fdoe().set_bit());
+        fdcan.ckdiv.modify(|_, w| w.pdiv().div1());
+        // Safety: the same known good values as bldc's `configure_timing`: 1MHz nominal, 5MHz data,
+        // off a 170MHz PLL.
+        fdcan.nbtp.modify(|_, w| unsafe {
+            w.nbrp()
+                .bits(4)
+                .ntseg1()
+                .bits(21)
+                .ntseg2()
+                .bits(10)
+                .nsjw()
+                .bits(5)
+        });
+        fdcan.dbtp.modify(|_, w| unsafe {
+            w.dbrp()
+                .bits(0)
+                .dtseg1()
+                .bits(21)
+                .dtseg2()
+                .bits(10)
+                .dsjw()
+                .bits(10)
+        });
+        fdcan.txbc.modify(|_, w| w.tfqm().fifo());
+
+        fdcan.cccr.modify(|_, w| w.init().run());
+        while !fdcan.cccr.read().init().is_run() {}
+        Can { fdcan, node }
+    }
+
+    // The next frame from the host, with its ID cut down to the bare command like the application
+    // does.
+    pub fn receive(&mut self) -> Option<FdcanMessage> {
+        loop {
+            let status = self.fdcan.rxf0s.read();
+            if status.f0fl().bits() == 0 {
+                return None;
+            }
+            let get = status.f0gi().bits();
+            let mut element = [0; ELEMENT_WORDS];
+            read_words(RX_FIFO0 + get as usize * ELEMENT_WORDS * 4, &mut element);
+            // Safety: acking the index we were just handed.
+            self.fdcan
+                .rxf0a
+                .modify(|_, w| unsafe { w.f0ai().bits(get) });
+
+            if element[0] & EXTENDED == 0 {
+                continue;
+            }
+            if let Some(id) = CanId::decode(element[0] & 0x1FFF_FFFF).filter(|id| !id.from_node) {
+                let mut data = [0; 16];
+                data.copy_from_slice(&element[2..]);
+                return Some(FdcanMessage {
+                    id: id.command as u32,
+                    data,
+                    size: dlc_to_len((element[1] >> DLC_SHIFT) & 0xF),
+                });
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &impl Frame) {
+        let frame = message.encode();
+        while self.fdcan.txfqs.read().tfqf().bit_is_set() {}
+        let put = self.fdcan.txfqs.read().tfqpi().bits();
+        let mut element = [0; ELEMENT_WORDS];
+        element[0] = EXTENDED | self.node.reply(frame.id as u8);
+        element[1] = FD_FORMAT | BIT_RATE_SWITCH | len_to_dlc(frame.size) << DLC_SHIFT;
+        element[2..].copy_from_slice(&frame.data);
+        write_words(TX_BUFFERS + put as usize * ELEMENT_WORDS * 4, &element);
+        // Safety: one bit per Tx buffer, and `put` is the one the hardware handed us.
+        self.fdcan
+            .txbar
+            .modify(|_, w| unsafe { w.ar().bits(1 << put) });
+    }
+}
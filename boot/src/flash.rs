@@ -0,0 +1,121 @@
+use protocol::update::{FlashError, SlotFlash, PAGE_SIZE};
+use stm32g4::stm32g474 as device;
+
+// Same as bldc's `config::internal_flash`, but for anywhere past the bootloader rather than just the
+// config pages. Assumes the default dual-bank layout (DBANK=1, 2K pages).
+
+const FLASH_START: u32 = 0x0800_0000;
+const BANK_SIZE: u32 = 256 * 1024;
+// Everything before this is us.
+const FIRST_WRITABLE: u32 = FLASH_START + 32 * 1024;
+const FLASH_END: u32 = FLASH_START + 2 * BANK_SIZE;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+pub struct InternalFlash<'a> {
+    flash: &'a device::FLASH,
+}
+
+impl<'a> InternalFlash<'a> {
+    pub fn new(flash: &'a device::FLASH) -> InternalFlash<'a> {
+        InternalFlash { flash }
+    }
+
+    fn unlock(&self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            // Safety: the key sequence is straight out of RM0440 3.3.5.
+            self.flash.keyr.write(|w| unsafe { w.keyr().bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.keyr().bits(KEY2) });
+        }
+    }
+
+    fn lock(&self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    // Wait for the current operation to finish, then check (and clear) any errors it raised.
+    fn finish(&self) -> Result<(), FlashError> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        let sr = self.flash.sr.read();
+        let failed = sr.wrperr().bit_is_set()
+            || sr.progerr().bit_is_set()
+            || sr.pgaerr().bit_is_set()
+            || sr.sizerr().bit_is_set()
+            || sr.pgserr().bit_is_set()
+            || sr.operr().bit_is_set();
+        // Safety: all of the status flags are write-1-to-clear.
+        self.flash.sr.write(|w| unsafe { w.bits(sr.bits()) });
+        match failed {
+            true => Err(FlashError),
+            false => Ok(()),
+        }
+    }
+
+    fn writable(address: u32, len: u32) -> Result<(), FlashError> {
+        match address >= FIRST_WRITABLE && address + len <= FLASH_END {
+            true => Ok(()),
+            false => Err(FlashError),
+        }
+    }
+}
+
+impl<'a> SlotFlash for InternalFlash<'a> {
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        Self::writable(address, PAGE_SIZE)?;
+        let offset = address - FLASH_START;
+        let bank2 = offset >= BANK_SIZE;
+        let page = (offset % BANK_SIZE) / PAGE_SIZE;
+        self.unlock();
+        // Clear anything left over from a previous operation.
+        self.finish().ok();
+        // Safety: PNB is 7 bits, and there are 128 pages to a bank.
+        self.flash
+            .cr
+            .modify(|_, w| unsafe { w.per().set_bit().bker().bit(bank2).pnb().bits(page as u8) });
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.finish();
+        self.flash
+            .cr
+            .modify(|_, w| w.per().clear_bit().bker().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        Self::writable(address, data.len() as u32)?;
+        self.unlock();
+        self.finish().ok();
+        self.flash.cr.modify(|_, w| w.pg().set_bit());
+        let mut result = Ok(());
+        let mut address = address;
+        for double_word in data.chunks_exact(8) {
+            let word = |i: usize| u32::from_le_bytes(double_word[i..i + 4].try_into().unwrap());
+            // Safety: programming happens one double word at a time, with the two halves written
+            // back to back. The address is double word aligned as per the `SlotFlash` contract.
+            unsafe {
+                core::ptr::write_volatile(address as *mut u32, word(0));
+                core::ptr::write_volatile((address + 4) as *mut u32, word(4));
+            }
+            result = self.finish();
+            if result.is_err() {
+                break;
+            }
+            address += 8;
+        }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn read(&self, address: u32, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            // Safety: all of flash is always mapped and readable. Anything outside it reads as
+            // erased rather than faulting.
+            *byte = match (FLASH_START..FLASH_END).contains(&(address + i as u32)) {
+                true => unsafe { core::ptr::read_volatile((address + i as u32) as *const u8) },
+                false => 0xFF,
+            };
+        }
+    }
+}
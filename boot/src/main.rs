@@ -0,0 +1,227 @@
+#![no_std]
+#![no_main]
+
+// The resident bootloader; see `protocol::update` for the big picture. It runs straight out of reset
+// with the clocks as the chip left them, decides what to boot, and if that's anything at all jumps
+// to it without touching another peripheral, so the application comes up exactly as it would have
+// without us. Only if it's staying does it bring up the PLL and FDCAN and wait for the host.
+//
+// No interrupts, no heap, nothing clever: if this is broken the board's a brick until someone gets a
+// probe on it.
+
+mod can;
+mod flash;
+
+use can::Can;
+use flash::InternalFlash;
+use protocol::id::NodeAddress;
+use protocol::messages::{BeginUpdateCmd, BootStatusMsg, MessageID, UpdateAckMsg, UpdateChunkCmd};
+use protocol::update::{
+    self, BootRecord, Handoff, ImageHeader, Slot, SlotFlash, UpdateStatus, Updater, HANDOFF_WORDS,
+    SLOT_SIZE,
+};
+use protocol::{FdcanMessage, Frame};
+use stm32g4::stm32g474 as device;
+
+// Where we are on the bus if the application's never told us.
+const DEFAULT_NODE: (u32, u32) = (1, 0);
+
+// Nothing to clean up after; just start over.
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+fn read_handoff(rcc: &device::RCC, pwr: &device::PWR, tamp: &device::TAMP) -> Handoff {
+    rcc.apb1enr1
+        .modify(|_, w| w.pwren().set_bit().rtcapben().set_bit());
+    pwr.cr1.modify(|_, w| w.dbp().set_bit());
+    Handoff::from_words(&[
+        tamp.bkp0r.read().bits(),
+        tamp.bkp1r.read().bits(),
+        tamp.bkp2r.read().bits(),
+    ])
+}
+
+fn write_handoff(tamp: &device::TAMP, handoff: &Handoff) {
+    let words: [u32; HANDOFF_WORDS] = handoff.to_words();
+    // Safety: they're just scratch registers.
+    tamp.bkp0r.write(|w| unsafe { w.bits(words[0]) });
+    tamp.bkp1r.write(|w| unsafe { w.bits(words[1]) });
+    tamp.bkp2r.write(|w| unsafe { w.bits(words[2]) });
+}
+
+// An image that was put in slot A with a probe rather than through us. We've no idea how long it
+// is, so the whole slot's taken as the image, which makes for a slow check on every boot until the
+// first real update. Only done when there's no record at all; once there is one, we're the only way
+// in.
+fn adopt(flash: &InternalFlash) -> BootRecord {
+    let mut record = BootRecord::empty();
+    let mut vectors = [0; 8];
+    flash.read(Slot::A.address(), &mut vectors);
+    let stack = u32::from_le_bytes([vectors[0], vectors[1], vectors[2], vectors[3]]);
+    if stack & 0xFFF0_0000 == 0x2000_0000 && Slot::for_image(&vectors) == Some(Slot::A) {
+        let mut crc = update::Crc32::new();
+        let mut buffer = [0; 256];
+        for offset in (0..SLOT_SIZE).step_by(buffer.len()) {
+            flash.read(Slot::A.address() + offset, &mut buffer);
+            crc.update(&buffer);
+        }
+        record.active = Some(Slot::A);
+        record.images[Slot::A as usize] = Some(ImageHeader {
+            address: Slot::A.address(),
+            length: SLOT_SIZE,
+            crc: crc.finish(),
+        });
+    }
+    record
+}
+
+// Same clocks as the application (see bldc's `util::stm32::clock_setup`), so the FDCAN timings can
+// be too: 170MHz off the 24MHz crystal.
+fn clock_setup(pwr: &device::PWR, rcc: &device::RCC, flash: &device::FLASH) {
+    use device::rcc::{cfgr, pllcfgr};
+    rcc.apb1enr1.modify(|_, w| w.pwren().set_bit());
+    pwr.cr5.modify(|_, w| w.r1mode().clear_bit());
+    rcc.cr.modify(|_, w| w.hseon().set_bit());
+    while rcc.cr.read().hserdy().bit_is_clear() {}
+    rcc.pllcfgr.write(|w| {
+        w.pllsrc()
+            .variant(pllcfgr::PLLSRC_A::HSE)
+            .pllm()
+            .variant(pllcfgr::PLLM_A::DIV6)
+            .plln()
+            .variant(pllcfgr::PLLN_A::DIV85)
+            .pllr()
+            .variant(pllcfgr::PLLR_A::DIV2)
+    });
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+    rcc.pllcfgr.modify(|_, w| w.pllren().set_bit());
+    // Wait states first, and ease into it through /2 on the AHB, same as the application does.
+    flash
+        .acr
+        .modify(|_, w| w.latency().variant(device::flash::acr::LATENCY_A::FOUR));
+    rcc.cfgr.modify(|_, w| w.hpre().variant(cfgr::HPRE_A::DIV2));
+    rcc.cfgr.modify(|_, w| w.sw().variant(cfgr::SW_A::PLL));
+    while rcc.cfgr.read().sws().variant() != cfgr::SWS_A::PLL {}
+    rcc.cfgr.modify(|_, w| w.hpre().variant(cfgr::HPRE_A::DIV1));
+}
+
+fn status(record: &BootRecord) -> BootStatusMsg {
+    BootStatusMsg {
+        active: Slot::raw(record.active),
+        pending: Slot::raw(record.pending),
+        target: record.target() as u32,
+        address: record.target().address(),
+    }
+}
+
+fn ack(request: MessageID, result: Result<u32, UpdateStatus>, updater: &Updater) -> UpdateAckMsg {
+    let (status, next) = match result {
+        Ok(next) => (UpdateStatus::Ok, next),
+        Err(status) => (status, updater.next()),
+    };
+    UpdateAckMsg {
+        request: request.into(),
+        status: status as u32,
+        next,
+    }
+}
+
+// Jumps to the image in `slot` as if it were coming out of reset.
+fn boot(slot: Slot) -> ! {
+    let vectors = slot.address() as *const u32;
+    // Safety: `decide` only ever hands back a slot whose image checks out, and nothing's been set up
+    // that the application wouldn't expect to find already set up.
+    unsafe {
+        (*cortex_m::peripheral::SCB::ptr())
+            .vtor
+            .write(slot.address());
+        cortex_m::asm::bootload(vectors)
+    }
+}
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let p = device::Peripherals::take().unwrap();
+    let mut flash = InternalFlash::new(&p.FLASH);
+
+    let mut handoff = read_handoff(&p.RCC, &p.PWR, &p.TAMP);
+    let mut record = match update::load_record(&flash) {
+        Some(record) => record,
+        None => match adopt(&flash) {
+            adopted if adopted.active.is_some() => {
+                update::save_record(&mut flash, &adopted).unwrap_or(adopted)
+            }
+            empty => empty,
+        },
+    };
+    let decision = update::decide(&record, &mut handoff, |image| update::verify(&flash, image));
+    if let Some(next) = decision.record {
+        // If this fails, the old record's still there; we'll be back here next boot either way.
+        record = update::save_record(&mut flash, &next).unwrap_or(next);
+    }
+    write_handoff(&p.TAMP, &handoff);
+    if let Some(slot) = decision.boot {
+        boot(slot);
+    }
+
+    clock_setup(&p.PWR, &p.RCC, &p.FLASH);
+    let node = record
+        .node
+        .unwrap_or_else(|| NodeAddress::new(DEFAULT_NODE.0, DEFAULT_NODE.1));
+    let mut can = Can::new(p.FDCAN1, &p.RCC, &p.GPIOA, node);
+    let mut updater = Updater::new(record.target());
+    can.send(&status(&record));
+
+    loop {
+        let message: FdcanMessage = match can.receive() {
+            Some(message) => message,
+            None => continue,
+        };
+        match MessageID::try_from(message.id as u8) {
+            Ok(MessageID::GetBootStatus) => can.send(&status(&record)),
+            Ok(MessageID::BeginUpdate) => {
+                let result = match BeginUpdateCmd::decode(&message) {
+                    Ok(cmd) => {
+                        let image = ImageHeader {
+                            address: cmd.address,
+                            length: cmd.length,
+                            crc: cmd.crc,
+                        };
+                        updater.begin(image).map(|_| 0)
+                    }
+                    Err(_) => Err(UpdateStatus::Malformed),
+                };
+                can.send(&ack(MessageID::BeginUpdate, result, &updater));
+            }
+            Ok(MessageID::UpdateChunk) => {
+                // A short chunk would otherwise read as zeroes and get written to flash as such.
+                let result = match UpdateChunkCmd::decode(&message) {
+                    Ok(cmd) => updater.chunk(&mut flash, cmd.offset, &cmd.data),
+                    Err(_) => Err(UpdateStatus::Malformed),
+                };
+                can.send(&ack(MessageID::UpdateChunk, result, &updater));
+            }
+            Ok(MessageID::FinishUpdate) => {
+                let result = updater.finish(&flash).and_then(|image| {
+                    update::save_record(&mut flash, &record.installed(image))
+                        .map_err(|_| UpdateStatus::Flash)
+                });
+                if let Ok(saved) = result {
+                    record = saved;
+                }
+                let next = updater.next();
+                can.send(&ack(
+                    MessageID::FinishUpdate,
+                    result.map(|_| next),
+                    &updater,
+                ));
+            }
+            // `stay`'s already been cleared, so this goes wherever `decide` says.
+            Ok(MessageID::Boot) => cortex_m::peripheral::SCB::sys_reset(),
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use protocol::ack::AckStatus;
+use protocol::hall::HallCalibrationStatus;
+use protocol::id::{Address, CanId};
+use protocol::messages::{
+    AckMsg, BeginUpdateCmd, BootCmd, BootStatusMsg, BusStatusMsg, CalibrateHallsCmd,
+    ClearFaultsCmd, DisableControlLoopCmd, EnterPosVelControlCmd, FaultStatusMsg, FinishUpdateCmd,
+    GetBootStatusCmd, GetBusStatusCmd, GetFaultsCmd, GetParamCmd, GetScopeStatusCmd,
+    HallCalibrationMsg, HeartbeatCmd, ListParamsCmd, MessageID, ParamAckMsg, ParamInfoMsg,
+    ParamValueMsg, ReadScopeCmd, RebootToBootloaderCmd, RestoreDefaultParamsCmd, SaveParamsCmd,
+    ScopeDataMsg, ScopeStatusMsg, SensorStateMsg, SetParamCmd, SetPosVelCmd, StartTelemetryCmd,
+    StatusMsg, StopTelemetryCmd, TorqueControlCmd, TriggerScopeCmd, UpdateAckMsg, UpdateChunkCmd,
+};
+use protocol::params::{ParamStatus, ParamType, ParamValue};
+use protocol::scope::{ScopeConfig, ScopeState};
+use protocol::telemetry::{Signals, TelemetryMsg};
+use protocol::update::{ImageHeader, Slot, UpdateStatus, UPDATE_CHUNK_BYTES};
+use protocol::{DecodeError, FdcanMessage, Frame};
+
+use crate::bus::Bus;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Nothing came back in time.
+    Timeout,
+    // The node turned down a parameter request.
+    Param(ParamStatus),
+    // A reply that doesn't hold what it should, e.g. it's too short.
+    Decode(DecodeError),
+    // A status or parameter type we don't know about. Probably newer firmware.
+    Unrecognized(u32),
+    // Asked for a scope trace before there was one to read, with the state the scope was in.
+    ScopeNotDone(ScopeState),
+    // The bootloader turned down part of an update.
+    Update(UpdateStatus),
+    // The node turned down a command, with the ack's `detail`; see `protocol::ack`.
+    Nack(AckStatus, u32),
+    // Hall calibration didn't work out.
+    HallCalibration(HallCalibrationStatus),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Timeout => write!(f, "Timed out waiting for a reply"),
+            Error::Param(status) => write!(f, "Parameter request failed: {:?}", status),
+            Error::Decode(error) => write!(f, "Couldn't decode reply: {:?}", error),
+            Error::Unrecognized(value) => write!(f, "Unrecognized value in reply: {}", value),
+            Error::ScopeNotDone(state) => write!(f, "Scope capture isn't done: {:?}", state),
+            Error::Update(status) => write!(f, "Update failed: {:?}", status),
+            Error::Nack(status, detail) => {
+                write!(f, "Command failed: {:?} (detail {:#x})", status, detail)
+            }
+            Error::HallCalibration(status) => write!(f, "Hall calibration failed: {:?}", status),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Self {
+        Error::Decode(error)
+    }
+}
+
+// What a node sends without being asked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Status(StatusMsg),
+    Sensors(SensorStateMsg),
+    Faults(FaultStatusMsg),
+    Telemetry(TelemetryMsg),
+    // A scope capture finished.
+    Scope(ScopeStatusMsg),
+    // Goes along with every `Status`, and whenever the bus state changes.
+    Bus(BusStatusMsg),
+    // A command that was sent without asking for an ack (e.g. a streamed setpoint) and got dropped
+    // because it didn't decode.
+    Dropped(AckMsg),
+}
+
+// A finished scope capture, from `read_scope`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeTrace {
+    pub signals: Signals,
+    pub decimation: u32,
+    // Which of `samples` was the trigger.
+    pub trigger_index: usize,
+    // Oldest first, each with a value per signal in `Signal` order.
+    pub samples: Vec<Vec<f32>>,
+}
+
+// One entry from `list_params`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamInfo {
+    pub id: u16,
+    pub name: String,
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub value: ParamValue,
+}
+
+fn param_status(status: u32) -> Result<(), Error> {
+    match ParamStatus::from_raw(status) {
+        Some(ParamStatus::Ok) => Ok(()),
+        Some(status) => Err(Error::Param(status)),
+        None => Err(Error::Unrecognized(status)),
+    }
+}
+
+fn ack_status(ack: &AckMsg) -> Result<(), Error> {
+    match AckStatus::from_raw(ack.status) {
+        Some(AckStatus::Ok) => Ok(()),
+        Some(status) => Err(Error::Nack(status, ack.detail)),
+        None => Err(Error::Unrecognized(ack.status)),
+    }
+}
+
+fn update_status(status: u32) -> Result<(), Error> {
+    match UpdateStatus::from_raw(status) {
+        Some(UpdateStatus::Ok) => Ok(()),
+        Some(status) => Err(Error::Update(status)),
+        None => Err(Error::Unrecognized(status)),
+    }
+}
+
+fn hall_calibration_status(status: u32) -> Result<(), Error> {
+    match HallCalibrationStatus::from_raw(status) {
+        Some(HallCalibrationStatus::Ok) => Ok(()),
+        Some(status) => Err(Error::HallCalibration(status)),
+        None => Err(Error::Unrecognized(status)),
+    }
+}
+
+// A hall calibration takes a couple of seconds of turning the rotor back and forth.
+const HALL_CALIBRATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the node gets to reset and bring the bootloader up.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(2);
+// How many times a chunk's sent before we give up on it.
+const CHUNK_RETRIES: u32 = 5;
+
+fn param_type(kind: u32) -> Result<ParamType, Error> {
+    ParamType::from_raw(kind).ok_or(Error::Unrecognized(kind))
+}
+
+// Talks to a single node. Requests block until the reply comes back or `timeout` runs out, and
+// anything else the node sends in the meantime is dropped; use `next_state` to keep up with those.
+pub struct Client<B: Bus> {
+    bus: B,
+    node: u8,
+    timeout: Duration,
+    // The last transaction handed out by `command`.
+    transaction: u8,
+}
+
+impl<B: Bus> Client<B> {
+    pub fn new(bus: B, node: u8) -> Client<B> {
+        Client {
+            bus,
+            node,
+            timeout: Duration::from_millis(100),
+            transaction: 0,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Client<B> {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn node(&self) -> u8 {
+        self.node
+    }
+
+    fn send(&mut self, message: &impl Frame) -> Result<(), Error> {
+        self.send_as(message, 0)
+    }
+
+    fn send_as(&mut self, message: &impl Frame, transaction: u8) -> Result<(), Error> {
+        let mut frame = message.encode();
+        frame.id = CanId {
+            command: frame.id as u8,
+            address: Address::Node(self.node),
+            from_node: false,
+            transaction,
+        }
+        .encode();
+        Ok(self.bus.send(&frame)?)
+    }
+
+    // The next frame from our node, with the ID cut back down to a bare `MessageID` so it decodes.
+    // Frames from anyone else, or that we don't understand, are dropped.
+    fn receive(&mut self, deadline: Instant) -> Result<Option<(MessageID, FdcanMessage)>, Error> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let mut frame = match self.bus.receive(deadline - now)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            let id = match CanId::decode(frame.id) {
+                Some(id) if id.from_node && id.address == Address::Node(self.node) => id,
+                _ => continue,
+            };
+            if let Ok(message) = MessageID::try_from(id.command) {
+                frame.id = message.into();
+                return Ok(Some((message, frame)));
+            }
+        }
+    }
+
+    // Wait for the `R` that `matches`.
+    fn reply<R: Frame>(&mut self, matches: impl Fn(&R) -> bool) -> Result<R, Error> {
+        self.reply_within(self.timeout, matches)
+    }
+
+    fn reply_within<R: Frame>(
+        &mut self,
+        timeout: Duration,
+        matches: impl Fn(&R) -> bool,
+    ) -> Result<R, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.receive(deadline)? {
+                None => return Err(Error::Timeout),
+                Some((id, frame)) if id == R::ID => {
+                    let reply = R::decode(&frame)?;
+                    if matches(&reply) {
+                        return Ok(reply);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // Sends any command, and waits for the node to ack it; see `protocol::ack`. Whatever it would
+    // have replied with otherwise is dropped.
+    pub fn command<F: Frame>(&mut self, message: &F) -> Result<(), Error> {
+        // Zero means no ack, so it's skipped when wrapping around.
+        self.transaction = self.transaction % u8::MAX + 1;
+        let transaction = self.transaction;
+        self.send_as(message, transaction)?;
+        let ack: AckMsg = self.reply(|ack: &AckMsg| ack.acks(F::ID, transaction))?;
+        ack_status(&ack)
+    }
+
+    // Control loops. None of these wait for the node, so they're fine to stream. If it won't start
+    // a loop it says so with a `FaultStatus`, or send the command through `command` to find out
+    // for sure.
+
+    pub fn enter_torque_control(&mut self, duration: f32, q: f32, d: f32) -> Result<(), Error> {
+        self.send(&TorqueControlCmd { duration, q, d })
+    }
+
+    pub fn enter_pos_vel_control(&mut self) -> Result<(), Error> {
+        self.send(&EnterPosVelControlCmd {})
+    }
+
+    pub fn set_pos_vel(&mut self, target: SetPosVelCmd) -> Result<(), Error> {
+        self.send(&target)
+    }
+
+    pub fn disable(&mut self) -> Result<(), Error> {
+        self.send(&DisableControlLoopCmd {})
+    }
+
+    // Keeps a running loop from timing out between commands.
+    pub fn heartbeat(&mut self) -> Result<(), Error> {
+        self.send(&HeartbeatCmd {})
+    }
+
+    // The next thing the node sends on its own, e.g. its periodic `Status`. `None` if there wasn't
+    // anything within `timeout`.
+    pub fn next_state(&mut self, timeout: Duration) -> Result<Option<State>, Error> {
+        let deadline = Instant::now() + timeout;
+        while let Some((id, frame)) = self.receive(deadline)? {
+            let state = match id {
+                MessageID::Status => State::Status(StatusMsg::decode(&frame)?),
+                MessageID::SensorState => State::Sensors(SensorStateMsg::decode(&frame)?),
+                MessageID::FaultStatus => State::Faults(FaultStatusMsg::decode(&frame)?),
+                MessageID::Telemetry => State::Telemetry(TelemetryMsg::decode(&frame)?),
+                MessageID::ScopeStatus => State::Scope(ScopeStatusMsg::decode(&frame)?),
+                MessageID::BusStatus => State::Bus(BusStatusMsg::decode(&frame)?),
+                // Anything with a transaction is for whoever's waiting on it in `command`.
+                MessageID::Ack => match AckMsg::decode(&frame)? {
+                    ack if ack.transaction == 0 => State::Dropped(ack),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            return Ok(Some(state));
+        }
+        Ok(None)
+    }
+
+    // Telemetry. Frames come in through `next_state` once it's started.
+
+    // Samples every `decimation` loop iterations; see `protocol::telemetry`.
+    pub fn start_telemetry(&mut self, signals: Signals, decimation: u32) -> Result<(), Error> {
+        self.send(&StartTelemetryCmd {
+            signals,
+            decimation,
+        })
+    }
+
+    pub fn stop_telemetry(&mut self) -> Result<(), Error> {
+        self.send(&StopTelemetryCmd {})
+    }
+
+    // Scope. The node sends a `ScopeStatus` through `next_state` when a capture finishes, or poll
+    // `scope_status` until it's `Done`.
+
+    // Starts over with a new capture. The node ignores configs it can't make sense of, so check the
+    // state that comes back.
+    pub fn arm_scope(&mut self, config: ScopeConfig) -> Result<ScopeStatusMsg, Error> {
+        self.send(&config.cmd())?;
+        self.reply(|_| true)
+    }
+
+    pub fn trigger_scope(&mut self) -> Result<(), Error> {
+        self.send(&TriggerScopeCmd {})
+    }
+
+    pub fn scope_status(&mut self) -> Result<ScopeStatusMsg, Error> {
+        self.send(&GetScopeStatusCmd {})?;
+        self.reply(|_| true)
+    }
+
+    // Downloads the whole trace, a frame at a time.
+    pub fn read_scope(&mut self) -> Result<ScopeTrace, Error> {
+        let status = self.scope_status()?;
+        match ScopeState::from_raw(status.state) {
+            Some(ScopeState::Done) => {}
+            Some(state) => return Err(Error::ScopeNotDone(state)),
+            None => return Err(Error::Unrecognized(status.state)),
+        }
+        let len = status.signals.len();
+        let total = status.samples as usize * len;
+        let mut values = Vec::with_capacity(total);
+        while values.len() < total {
+            let offset = values.len() as u32;
+            self.send(&ReadScopeCmd { offset })?;
+            let data: ScopeDataMsg = self.reply(|data: &ScopeDataMsg| data.offset == offset)?;
+            // Someone re-armed it out from under us.
+            if data.count == 0 {
+                let state = self.scope_status()?.state;
+                return Err(ScopeState::from_raw(state)
+                    .map_or(Error::Unrecognized(state), Error::ScopeNotDone));
+            }
+            let count = (data.count as usize).min(data.values.len());
+            values.extend_from_slice(&data.values[..count]);
+        }
+        Ok(ScopeTrace {
+            signals: status.signals,
+            decimation: status.decimation,
+            trigger_index: status.trigger_index as usize,
+            samples: values
+                .chunks_exact(len.max(1))
+                .map(|sample| sample.to_vec())
+                .collect(),
+        })
+    }
+
+    // Drags the rotor round to find where the hall sectors are, holding `current` amps on the d
+    // axis. The node starts using the new angles straight away, but they're only kept over a reset
+    // once the params are saved.
+    pub fn calibrate_halls(&mut self, current: f32) -> Result<HallCalibrationMsg, Error> {
+        self.command(&CalibrateHallsCmd { current })?;
+        let reply: HallCalibrationMsg = self.reply_within(HALL_CALIBRATION_TIMEOUT, |_| true)?;
+        hall_calibration_status(reply.status)?;
+        Ok(reply)
+    }
+
+    // Faults.
+
+    pub fn faults(&mut self) -> Result<FaultStatusMsg, Error> {
+        self.send(&GetFaultsCmd {})?;
+        self.reply(|_| true)
+    }
+
+    pub fn clear_faults(&mut self, faults: u32) -> Result<FaultStatusMsg, Error> {
+        self.send(&ClearFaultsCmd { faults })?;
+        self.reply(|_| true)
+    }
+
+    // Error counters and the like; see `protocol::bus`.
+    pub fn bus_status(&mut self) -> Result<BusStatusMsg, Error> {
+        self.send(&GetBusStatusCmd {})?;
+        self.reply(|_| true)
+    }
+
+    // Parameters.
+
+    fn param_value(&mut self, id: u16) -> Result<ParamValue, Error> {
+        let reply: ParamValueMsg = self.reply(|reply: &ParamValueMsg| reply.id == id)?;
+        param_status(reply.status as u32)?;
+        Ok(ParamValue::from_bits(
+            param_type(reply.kind as u32)?,
+            reply.bits,
+        ))
+    }
+
+    pub fn get_param(&mut self, id: u16) -> Result<ParamValue, Error> {
+        self.send(&GetParamCmd { id })?;
+        self.param_value(id)
+    }
+
+    // Takes effect right away, but doesn't stick around past a reset without a `save_params`.
+    // Returns the value the node ended up with.
+    pub fn set_param(&mut self, id: u16, value: ParamValue) -> Result<ParamValue, Error> {
+        self.send(&SetParamCmd {
+            id,
+            bits: value.to_bits(),
+        })?;
+        self.param_value(id)
+    }
+
+    pub fn list_params(&mut self) -> Result<Vec<ParamInfo>, Error> {
+        self.send(&ListParamsCmd {})?;
+        let mut params = vec![];
+        loop {
+            let info: ParamInfoMsg = self.reply(|_| true)?;
+            let kind = param_type(info.kind as u32)?;
+            let name = info
+                .name
+                .split(|&byte| byte == 0)
+                .next()
+                .unwrap_or_default();
+            params.push(ParamInfo {
+                id: info.id,
+                name: String::from_utf8_lossy(name).into_owned(),
+                min: ParamValue::from_bits(kind, info.min),
+                max: ParamValue::from_bits(kind, info.max),
+                value: ParamValue::from_bits(kind, info.value),
+            });
+            if info.index + 1 >= info.count {
+                return Ok(params);
+            }
+        }
+    }
+
+    fn param_ack(&mut self, request: MessageID) -> Result<(), Error> {
+        let ack: ParamAckMsg = self.reply(|ack: &ParamAckMsg| ack.request == request as u32)?;
+        param_status(ack.status)
+    }
+
+    pub fn save_params(&mut self) -> Result<(), Error> {
+        self.send(&SaveParamsCmd {})?;
+        self.param_ack(MessageID::SaveParams)
+    }
+
+    pub fn restore_default_params(&mut self) -> Result<(), Error> {
+        self.send(&RestoreDefaultParamsCmd {})?;
+        self.param_ack(MessageID::RestoreDefaultParams)
+    }
+
+    // Firmware updates; see `protocol::update`.
+
+    // Resets into the bootloader, and waits for it to say hello. Any control loop's stopped first.
+    pub fn reboot_to_bootloader(&mut self) -> Result<BootStatusMsg, Error> {
+        self.send(&RebootToBootloaderCmd {})?;
+        self.reply_within(REBOOT_TIMEOUT, |_| true)
+    }
+
+    // Only answered by the bootloader.
+    pub fn boot_status(&mut self) -> Result<BootStatusMsg, Error> {
+        self.send(&GetBootStatusCmd {})?;
+        self.reply(|_| true)
+    }
+
+    fn update_ack(&mut self, request: MessageID) -> Result<UpdateAckMsg, Error> {
+        self.reply(|ack: &UpdateAckMsg| ack.request == request as u32)
+    }
+
+    // Writes `image` to whichever slot the bootloader's updating, which it has to have been linked
+    // for. `progress` gets the bytes written so far and the total. Doesn't boot it; see `boot`.
+    pub fn update(
+        &mut self,
+        image: &[u8],
+        mut progress: impl FnMut(u32, u32),
+    ) -> Result<(), Error> {
+        let header =
+            ImageHeader::new(image).ok_or(Error::Update(match Slot::for_image(image) {
+                Some(_) => UpdateStatus::BadLength,
+                None => UpdateStatus::WrongSlot,
+            }))?;
+        self.send(&BeginUpdateCmd {
+            address: header.address,
+            length: header.length,
+            crc: header.crc,
+        })?;
+        update_status(self.update_ack(MessageID::BeginUpdate)?.status)?;
+
+        let mut next = 0;
+        let mut retries = 0;
+        while next < header.length {
+            let mut data = [0xFF; UPDATE_CHUNK_BYTES];
+            let chunk = &image[next as usize..image.len().min(next as usize + UPDATE_CHUNK_BYTES)];
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.send(&UpdateChunkCmd { offset: next, data })?;
+            // Erasing a page can hold up the ack a good while, so a timeout's worth a resend rather
+            // than giving up straight away.
+            let ack = match self.update_ack(MessageID::UpdateChunk) {
+                Err(Error::Timeout) if retries < CHUNK_RETRIES => {
+                    retries += 1;
+                    continue;
+                }
+                ack => ack?,
+            };
+            retries = 0;
+            match UpdateStatus::from_raw(ack.status) {
+                // We got ahead of it somehow; go back to where it's at.
+                Some(UpdateStatus::Ok) | Some(UpdateStatus::OutOfOrder) => next = ack.next,
+                _ => update_status(ack.status)?,
+            }
+            progress(next, header.length);
+        }
+
+        self.send(&FinishUpdateCmd {})?;
+        // Checking the CRC means reading back the whole slot.
+        let ack: UpdateAckMsg = self.reply_within(REBOOT_TIMEOUT, |ack: &UpdateAckMsg| {
+            ack.request == MessageID::FinishUpdate as u32
+        })?;
+        update_status(ack.status)
+    }
+
+    // Leaves the bootloader for whatever it thinks is best: a freshly written image if there is one,
+    // otherwise the last one that worked.
+    pub fn boot(&mut self) -> Result<(), Error> {
+        self.send(&BootCmd {})
+    }
+}
@@ -0,0 +1,20 @@
+//! Talking to controllers from a PC, over SocketCAN. `Client` has the typed commands, `sim` has a
+//! stand-in node that answers the same protocol, for trying things out without hardware.
+//!
+//! To play with both on a virtual bus:
+//!
+//! ```text
+//! sudo ip link add dev vcan0 type vcan && sudo ip link set vcan0 mtu 72 up
+//! pino --interface vcan0 sim &
+//! pino --interface vcan0 params list
+//! ```
+
+pub mod bus;
+pub mod client;
+pub mod sim;
+pub mod socketcan;
+
+pub use bus::{Bus, Loopback};
+pub use client::{Client, Error, ParamInfo, ScopeTrace, State};
+pub use sim::{MemoryFlash, SimulatedNode};
+pub use socketcan::SocketCan;
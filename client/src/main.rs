@@ -0,0 +1,567 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use pino::client::ParamInfo;
+use pino::{Client, Error, SimulatedNode, SocketCan, State};
+use protocol::bus::{BusState, ProtocolError};
+use protocol::id::NodeAddress;
+use protocol::messages::{
+    BootStatusMsg, BusStatusMsg, DisableControlLoopCmd, EnterPosVelControlCmd, SetPosVelCmd,
+    TorqueControlCmd,
+};
+use protocol::params::ParamValue;
+use protocol::scope::{ScopeConfig, ScopeState, ScopeTrigger};
+use protocol::telemetry::{Signal, Signals, TelemetryMsg};
+use protocol::update::Slot;
+
+// Command line for poking at controllers. Output's one line per value so it's easy to script
+// against. Help text comes from the `///` comments, so keep them user-facing.
+#[derive(Parser)]
+#[command(name = "pino", about = "Talk to Pino controllers over SocketCAN")]
+struct Args {
+    /// CAN-FD interface the controllers are on.
+    #[arg(short, long, default_value = "can0")]
+    interface: String,
+    /// Which controller to talk to.
+    #[arg(short, long, default_value_t = 1)]
+    node: u8,
+    /// How long to wait for replies, in milliseconds.
+    #[arg(long, default_value_t = 100)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run open-loop torque control for a while.
+    Torque {
+        /// Q-axis current, in amps.
+        q: f32,
+        /// D-axis current, in amps.
+        #[arg(short, long, default_value_t = 0.)]
+        d: f32,
+        /// Seconds.
+        #[arg(long, default_value_t = 1.)]
+        duration: f32,
+    },
+    /// Start the position/velocity loop, holding wherever the rotor is.
+    PosVel,
+    /// Set the position/velocity loop's target and gains.
+    Set {
+        /// Radians.
+        position: f32,
+        /// Radians/s.
+        #[arg(short, long, default_value_t = 0.)]
+        velocity: f32,
+        /// N*m/rad.
+        #[arg(short = 'k', long, default_value_t = 0.)]
+        stiffness: f32,
+        /// N*m/(rad/s).
+        #[arg(short = 'b', long, default_value_t = 0.)]
+        damping: f32,
+        /// N*m/A.
+        #[arg(short, long, default_value_t = 0.)]
+        torque_constant: f32,
+    },
+    /// Stop whatever loop is running.
+    Disable,
+    /// Find where the hall sectors are by dragging the rotor round. The rotor has to be free to
+    /// turn. Save the params afterwards to keep the result.
+    CalibrateHalls {
+        /// D-axis current to drag the rotor with, in amps.
+        #[arg(short, long, default_value_t = 5.)]
+        current: f32,
+    },
+    /// Print status as it comes in. Keeps the loop alive with heartbeats while it's at it.
+    Stream {
+        /// Stop after this many seconds instead of running forever.
+        #[arg(long)]
+        seconds: Option<f32>,
+    },
+    /// Stream signals from the control loop, one line per sample. Stops telemetry on the way out.
+    Telemetry {
+        /// Any of angle, velocity, angle_multiturn, current_d, current_q, voltage_d, voltage_q,
+        /// phase_a, phase_b, phase_c, v_bus, integrator_d, integrator_q and loop_time. Printed in
+        /// that order, whatever order they're given in.
+        #[arg(required = true, value_parser = parse_signal)]
+        signals: Vec<Signal>,
+        /// Sample every this many loop iterations. The loop runs at 40kHz.
+        #[arg(short, long, default_value_t = 40)]
+        decimation: u32,
+        /// Stop after this many seconds instead of running forever.
+        #[arg(long)]
+        seconds: Option<f32>,
+    },
+    /// Capture signals at up to the full loop rate around a trigger, then print them one line per
+    /// sample, numbered from the trigger.
+    Scope {
+        /// Same as for telemetry.
+        #[arg(required = true, value_parser = parse_signal)]
+        signals: Vec<Signal>,
+        /// Sample every this many loop iterations.
+        #[arg(short, long, default_value_t = 1)]
+        decimation: u32,
+        /// One of now, rising, falling or fault.
+        #[arg(short, long, default_value = "now", value_parser = parse_trigger)]
+        trigger: ScopeTrigger,
+        /// Signal to watch for rising and falling triggers.
+        #[arg(long, default_value = "angle", value_parser = parse_signal)]
+        on: Signal,
+        /// Level for rising and falling triggers.
+        #[arg(long, default_value_t = 0.)]
+        threshold: f32,
+        /// Samples to keep from before the trigger.
+        #[arg(short, long, default_value_t = 0)]
+        pre_trigger: u32,
+        /// Give up if it hasn't triggered and filled up after this many seconds.
+        #[arg(long, default_value_t = 10.)]
+        wait: f32,
+    },
+    /// Print the bus state, error counters and dropped frames.
+    Bus,
+    /// Print active and critical faults, clearing them first if asked.
+    Faults {
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Read and write parameters.
+    #[command(subcommand)]
+    Params(ParamsCommand),
+    /// Reset into the bootloader, and print which slots hold what.
+    Bootloader,
+    /// Install new firmware. Give it the image built for each slot, and it'll pick the one for the
+    /// slot being updated. The new image is on trial until it comes up and answers, and the old
+    /// one's booted instead if it doesn't.
+    Update {
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+        /// Stay in the bootloader afterwards instead of booting the new image.
+        #[arg(long)]
+        stay: bool,
+    },
+    /// Pretend to be a controller on the interface, at `node`.
+    Sim {
+        #[arg(short, long, default_value_t = 0)]
+        group: u8,
+    },
+}
+
+#[derive(Subcommand)]
+enum ParamsCommand {
+    /// Every parameter, with its ID, value and range.
+    List,
+    /// Read a parameter, by name or by ID.
+    Get { param: String },
+    /// Change a parameter. Takes effect right away, but is lost on reset unless saved.
+    Set { param: String, value: String },
+    /// Write the current values to flash.
+    Save,
+    /// Go back to the defaults. Also not saved until asked.
+    RestoreDefaults,
+}
+
+fn print_value(value: ParamValue) -> String {
+    match value {
+        ParamValue::F32(value) => format!("{}", value),
+        ParamValue::U32(value) => format!("{}", value),
+    }
+}
+
+fn parse_value(kind: ParamValue, value: &str) -> Result<ParamValue, String> {
+    let invalid = || format!("'{}' isn't a valid value", value);
+    Ok(match kind {
+        ParamValue::F32(_) => ParamValue::F32(value.parse().map_err(|_| invalid())?),
+        ParamValue::U32(_) => ParamValue::U32(value.parse().map_err(|_| invalid())?),
+    })
+}
+
+// IDs can be given in hex or decimal; anything else is looked up by name.
+fn find_param(
+    client: &mut Client<SocketCan>,
+    param: &str,
+) -> Result<ParamInfo, Box<dyn std::error::Error>> {
+    let id = match param.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => param.parse().ok(),
+    };
+    client
+        .list_params()?
+        .into_iter()
+        .find(|info| Some(info.id) == id || info.name == param)
+        .ok_or_else(|| format!("No parameter '{}'", param).into())
+}
+
+fn print_state(state: State) {
+    match state {
+        State::Status(status) => println!(
+            "status mode={} state={} active={:#x} critical={:#x}",
+            status.mode, status.state, status.active, status.critical
+        ),
+        State::Sensors(sensors) => println!(
+            "sensors angle={} multiturn={} velocity={} e_angle={} e_velocity={}",
+            sensors.angle,
+            sensors.angle_multiturn,
+            sensors.velocity,
+            sensors.electrical_angle,
+            sensors.electrical_velocity
+        ),
+        State::Faults(faults) => println!(
+            "faults active={:#x} critical={:#x} raised={:#x}",
+            faults.active, faults.critical, faults.raised
+        ),
+        State::Telemetry(frame) => println!(
+            "telemetry sequence={} samples={} dropped={}",
+            frame.sequence, frame.samples, frame.dropped
+        ),
+        State::Scope(status) => println!(
+            "scope state={} samples={} trigger_index={}",
+            status.state, status.samples, status.trigger_index
+        ),
+        State::Bus(status) => print_bus_status(&status),
+        State::Dropped(ack) => println!(
+            "dropped request={:#x} status={} detail={:#x}",
+            ack.request, ack.status, ack.detail
+        ),
+    }
+}
+
+fn print_bus_status(status: &BusStatusMsg) {
+    let state = BusState::from_raw(status.state)
+        .map_or(format!("{}", status.state), |state| format!("{:?}", state));
+    let error = |code| {
+        ProtocolError::from_raw(code).map_or(format!("{}", code), |error| format!("{:?}", error))
+    };
+    println!(
+        "bus state={} last_error={} last_data_error={} tx_errors={} rx_errors={} bus_offs={} \
+         protocol_errors={} rx_dropped={} rx_lost={} tx_dropped={}",
+        state,
+        error(status.last_error),
+        error(status.last_data_error),
+        status.tx_errors,
+        status.rx_errors,
+        status.bus_offs,
+        status.protocol_errors,
+        status.rx_dropped,
+        status.rx_lost,
+        status.tx_dropped
+    );
+}
+
+fn stream(client: &mut Client<SocketCan>, seconds: Option<f32>) -> Result<(), Error> {
+    let start = Instant::now();
+    let heartbeat = Duration::from_millis(100);
+    let mut last_heartbeat = start;
+    client.heartbeat()?;
+    while seconds.is_none_or(|seconds| start.elapsed().as_secs_f32() < seconds) {
+        if let Some(state) = client.next_state(heartbeat)? {
+            print_state(state);
+        }
+        if last_heartbeat.elapsed() >= heartbeat {
+            client.heartbeat()?;
+            last_heartbeat = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+fn parse_signal(name: &str) -> Result<Signal, String> {
+    Signal::from_name(name).ok_or_else(|| format!("No signal '{}'", name))
+}
+
+fn print_samples(frame: &TelemetryMsg) {
+    for (i, values) in frame.samples().enumerate() {
+        let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        println!(
+            "{} {}",
+            frame.sequence.wrapping_add(i as u32),
+            values.join(" ")
+        );
+    }
+}
+
+// Samples go to stdout under a header naming the columns, and anything missing goes to stderr.
+fn telemetry(
+    client: &mut Client<SocketCan>,
+    signals: Signals,
+    decimation: u32,
+    seconds: Option<f32>,
+) -> Result<(), Error> {
+    let names: Vec<&str> = signals.iter().map(Signal::name).collect();
+    println!("sequence {}", names.join(" "));
+    client.start_telemetry(signals, decimation)?;
+
+    let start = Instant::now();
+    let heartbeat = Duration::from_millis(100);
+    let mut last_heartbeat = start;
+    let mut expected = 0;
+    let mut dropped = 0;
+    while seconds.is_none_or(|seconds| start.elapsed().as_secs_f32() < seconds) {
+        if let Some(State::Telemetry(frame)) = client.next_state(heartbeat)? {
+            if frame.dropped != dropped {
+                eprintln!(
+                    "node dropped {} samples",
+                    frame.dropped.wrapping_sub(dropped)
+                );
+                dropped = frame.dropped;
+            }
+            // Either the node's drops from above, or frames that never made it here.
+            let skipped = frame.sequence.wrapping_sub(expected);
+            if skipped != 0 {
+                eprintln!("skipped {} samples", skipped);
+            }
+            print_samples(&frame);
+            expected = frame.next_sequence();
+        }
+        if last_heartbeat.elapsed() >= heartbeat {
+            client.heartbeat()?;
+            last_heartbeat = Instant::now();
+        }
+    }
+    client.stop_telemetry()
+}
+
+fn parse_trigger(name: &str) -> Result<ScopeTrigger, String> {
+    match name {
+        "now" => Ok(ScopeTrigger::Command),
+        "rising" => Ok(ScopeTrigger::Rising),
+        "falling" => Ok(ScopeTrigger::Falling),
+        "fault" => Ok(ScopeTrigger::Fault),
+        _ => Err(format!("No trigger '{}'", name)),
+    }
+}
+
+fn scope(
+    client: &mut Client<SocketCan>,
+    config: ScopeConfig,
+    wait: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = client.arm_scope(config)?;
+    if ScopeState::from_raw(status.state) != Some(ScopeState::Armed) {
+        return Err("Node wouldn't arm the scope".into());
+    }
+    if config.trigger == ScopeTrigger::Command {
+        client.trigger_scope()?;
+    }
+
+    // Polled rather than waiting on the node's own status, in case that gets lost.
+    let start = Instant::now();
+    loop {
+        let status = client.scope_status()?;
+        if ScopeState::from_raw(status.state) == Some(ScopeState::Done) {
+            break;
+        }
+        if start.elapsed().as_secs_f32() >= wait {
+            return Err("Timed out waiting for the scope to trigger".into());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let trace = client.read_scope()?;
+    let names: Vec<&str> = trace.signals.iter().map(Signal::name).collect();
+    println!("sample {}", names.join(" "));
+    for (i, values) in trace.samples.iter().enumerate() {
+        let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        println!(
+            "{} {}",
+            i as i64 - trace.trigger_index as i64,
+            values.join(" ")
+        );
+    }
+    Ok(())
+}
+
+fn print_boot_status(status: &BootStatusMsg) {
+    let slot = |raw| Slot::from_raw(raw).map_or("none".to_string(), |slot| format!("{:?}", slot));
+    println!(
+        "active={} pending={} target={} address={:#010x}",
+        slot(status.active),
+        slot(status.pending),
+        slot(status.target),
+        status.address
+    );
+}
+
+// Works whether the node's running the application or already sitting in the bootloader.
+fn enter_bootloader(client: &mut Client<SocketCan>) -> Result<BootStatusMsg, Error> {
+    match client.boot_status() {
+        Err(Error::Timeout) => client.reboot_to_bootloader(),
+        status => status,
+    }
+}
+
+fn update(
+    client: &mut Client<SocketCan>,
+    images: &[PathBuf],
+    stay: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = enter_bootloader(client)?;
+    print_boot_status(&status);
+    let target = Slot::from_raw(status.target).ok_or(Error::Unrecognized(status.target))?;
+    let mut image = None;
+    for path in images {
+        let bytes = std::fs::read(path)?;
+        if Slot::for_image(&bytes) == Some(target) {
+            image = Some((path, bytes));
+            break;
+        }
+    }
+    let (path, image) =
+        image.ok_or_else(|| format!("None of the images are for slot {:?}", target))?;
+    eprintln!("writing {}", path.display());
+    let mut percent = 0;
+    client.update(&image, |written, total| {
+        let now = written as u64 * 100 / total as u64;
+        if now / 10 != percent / 10 {
+            eprintln!("{}%", now);
+        }
+        percent = now;
+    })?;
+    if !stay {
+        client.boot()?;
+        confirm_image(client)?;
+    }
+    Ok(())
+}
+
+// The new image is only kept once it's heard from the host, so keep saying hello until it's done
+// calibrating and answers.
+fn confirm_image(client: &mut Client<SocketCan>) -> Result<(), Error> {
+    let start = Instant::now();
+    loop {
+        match client.faults() {
+            Err(Error::Timeout) if start.elapsed() < Duration::from_secs(10) => continue,
+            result => return result.map(|_| eprintln!("new image is up")),
+        }
+    }
+}
+
+fn params(
+    client: &mut Client<SocketCan>,
+    command: ParamsCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        ParamsCommand::List => {
+            for info in client.list_params()? {
+                println!(
+                    "{:#04x} {} = {} [{}, {}]",
+                    info.id,
+                    info.name,
+                    print_value(info.value),
+                    print_value(info.min),
+                    print_value(info.max)
+                );
+            }
+        }
+        ParamsCommand::Get { param } => {
+            let info = find_param(client, &param)?;
+            println!(
+                "{} = {}",
+                info.name,
+                print_value(client.get_param(info.id)?)
+            );
+        }
+        ParamsCommand::Set { param, value } => {
+            let info = find_param(client, &param)?;
+            let value = parse_value(info.value, &value)?;
+            println!(
+                "{} = {}",
+                info.name,
+                print_value(client.set_param(info.id, value)?)
+            );
+        }
+        ParamsCommand::Save => client.save_params()?,
+        ParamsCommand::RestoreDefaults => client.restore_default_params()?,
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let bus = SocketCan::open(&args.interface)?;
+    if let Command::Sim { group } = args.command {
+        let mut node = SimulatedNode::new(bus, NodeAddress::new(args.node as u32, group as u32));
+        return Ok(node.run(&AtomicBool::new(false))?);
+    }
+
+    let mut client = Client::new(bus, args.node).with_timeout(Duration::from_millis(args.timeout));
+    // One-offs wait for the ack, so anything the node turned down gets reported.
+    match args.command {
+        Command::Torque { q, d, duration } => {
+            client.command(&TorqueControlCmd { duration, q, d })?
+        }
+        Command::PosVel => client.command(&EnterPosVelControlCmd {})?,
+        Command::Set {
+            position,
+            velocity,
+            stiffness,
+            damping,
+            torque_constant,
+        } => client.command(&SetPosVelCmd {
+            position,
+            velocity,
+            stiffness_gain: stiffness,
+            damping_gain: damping,
+            torque_constant,
+        })?,
+        Command::Disable => client.command(&DisableControlLoopCmd {})?,
+        Command::CalibrateHalls { current } => {
+            let calibration = client.calibrate_halls(current)?;
+            println!("hall_angles={:?}", calibration.angles);
+        }
+        Command::Stream { seconds } => stream(&mut client, seconds)?,
+        Command::Telemetry {
+            signals,
+            decimation,
+            seconds,
+        } => telemetry(&mut client, Signals::new(&signals), decimation, seconds)?,
+        Command::Scope {
+            signals,
+            decimation,
+            trigger,
+            on,
+            threshold,
+            pre_trigger,
+            wait,
+        } => {
+            let config = ScopeConfig {
+                signals: Signals::new(&signals),
+                decimation: decimation.clamp(1, u16::MAX as u32) as u16,
+                trigger,
+                trigger_signal: on,
+                threshold,
+                pre_trigger: pre_trigger as usize,
+            };
+            scope(&mut client, config, wait)?
+        }
+        Command::Bus => print_bus_status(&client.bus_status()?),
+        Command::Faults { clear } => {
+            let faults = match clear {
+                // Every bit, whether or not this end knows what it means.
+                true => client.clear_faults(u32::MAX)?,
+                false => client.faults()?,
+            };
+            println!(
+                "active={:#x} critical={:#x}",
+                faults.active, faults.critical
+            );
+        }
+        Command::Params(command) => params(&mut client, command)?,
+        Command::Bootloader => print_boot_status(&enter_bootloader(&mut client)?),
+        Command::Update { images, stay } => update(&mut client, &images, stay)?,
+        Command::Sim { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
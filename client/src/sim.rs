@@ -0,0 +1,694 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use protocol::ack::{self, Nack};
+use protocol::bus::{BusState, ProtocolError};
+use protocol::group::GroupSetpoints;
+use protocol::id::{self, CanId, NodeAddress};
+use protocol::messages::{
+    ArmScopeCmd, BeginUpdateCmd, BootStatusMsg, BusStatusMsg, DrvRegistersMsg, FaultStatusMsg,
+    GetParamCmd, LoopTimingMsg, MessageID, ParamAckMsg, ParamInfoMsg, ParamValueMsg, ReadScopeCmd,
+    ScopeDataMsg, SetParamCmd, SetPosVelCmd, StartTelemetryCmd, StatusMsg, TorqueControlCmd,
+    UpdateAckMsg, UpdateChunkCmd, PARAM_NAME_BYTES,
+};
+use protocol::params::{ParamStatus, ParamValue, PARAMS};
+use protocol::scope::{Capture, ScopeConfig, ScopeState, SCOPE_CHUNK_VALUES};
+use protocol::telemetry::{Signal, TelemetryMsg};
+use protocol::update::{
+    self, BootRecord, FlashError, Handoff, ImageHeader, Slot, SlotFlash, UpdateStatus, Updater,
+    PAGE_SIZE,
+};
+use protocol::{FdcanMessage, Frame};
+
+use crate::bus::Bus;
+
+// A pretend controller for trying out the client (or anything else) without hardware. It answers
+// the same requests the firmware does, with the same frames, and runs a crude rigid-body model of
+// the rotor so control commands do something. Don't go tuning gains against it.
+//
+// All of the firmware's parameters are here, straight out of `protocol::params`, but only
+// `heartbeat_rate` does anything. There are never any faults or bus errors, and telemetry and the
+// scope only have the signals the model knows about; the rest read as zero.
+//
+// It has a bootloader too, with the real one's logic over a `MemoryFlash`. Images written through it
+// are only ever checked, never run: "booting" one just goes back to simulating the same old
+// firmware.
+
+// Same as the firmware's `LoopMode` and `LoopState`.
+const MODE_IDLE: u32 = 0;
+const MODE_TORQUE_CONTROL: u32 = 2;
+const MODE_POSITION_VELOCITY: u32 = 3;
+const STATE_RUNNING: u32 = 0;
+const STATE_IDLE: u32 = 2;
+
+const HEARTBEAT_RATE: u16 = 0x11;
+// Going back to the defaults keeps the node where it is on the bus, same as the firmware.
+const NODE_ID: u16 = 0x12;
+const NODE_GROUP: u16 = 0x13;
+
+// kg*m^2, N*m/(rad/s) and N*m/A. Made up, but in the right ballpark for a small gimbal motor.
+const INERTIA: f32 = 5e-5;
+const FRICTION: f32 = 1e-4;
+const TORQUE_CONSTANT: f32 = 0.047;
+// The model's stepped at this rate regardless of how often it's polled.
+const STEP: f32 = 1e-3;
+// Firmware loop iterations per step, for telemetry decimation. Samples taken within a step all
+// read the same.
+const LOOP_ITERATIONS: u32 = 40;
+const V_BUS: f32 = 24.;
+// Same as the firmware.
+const SCOPE_VALUES: usize = 4096;
+
+// The G4's 512K of flash, for the bootloader to write images to. Catches programming over anything
+// that hasn't been erased, same as the real thing.
+pub struct MemoryFlash {
+    bytes: Vec<u8>,
+}
+
+impl MemoryFlash {
+    const START: u32 = 0x0800_0000;
+    const SIZE: usize = 512 * 1024;
+
+    pub fn new() -> MemoryFlash {
+        MemoryFlash {
+            bytes: vec![0xFF; MemoryFlash::SIZE],
+        }
+    }
+
+    fn range(&self, address: u32, len: usize) -> Result<std::ops::Range<usize>, FlashError> {
+        let start = address.checked_sub(MemoryFlash::START).ok_or(FlashError)? as usize;
+        match start + len <= self.bytes.len() {
+            true => Ok(start..start + len),
+            false => Err(FlashError),
+        }
+    }
+}
+
+impl Default for MemoryFlash {
+    fn default() -> Self {
+        MemoryFlash::new()
+    }
+}
+
+impl SlotFlash for MemoryFlash {
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        if !address.is_multiple_of(PAGE_SIZE) {
+            return Err(FlashError);
+        }
+        let range = self.range(address, PAGE_SIZE as usize)?;
+        self.bytes[range].fill(0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let range = self.range(address, data.len())?;
+        if !address.is_multiple_of(8)
+            || !data.len().is_multiple_of(8)
+            || self.bytes[range.clone()].iter().any(|byte| *byte != 0xFF)
+        {
+            return Err(FlashError);
+        }
+        self.bytes[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, address: u32, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self
+                .range(address + i as u32, 1)
+                .map_or(0xFF, |range| self.bytes[range.start]);
+        }
+    }
+}
+
+// What's left across a reset: flash, and the backup registers. `updater` is only there while we're
+// in the bootloader.
+struct Boot {
+    flash: MemoryFlash,
+    handoff: Handoff,
+    updater: Option<Updater>,
+}
+
+#[derive(Clone, Copy)]
+enum Control {
+    Idle,
+    // Seconds left, and q-axis current.
+    Torque { remaining: f32, q: f32 },
+    PosVel(SetPosVelCmd),
+}
+
+pub struct SimulatedNode<B: Bus> {
+    bus: B,
+    address: NodeAddress,
+    values: Vec<ParamValue>,
+    control: Control,
+    // Target from a `GroupSetPosVel`, waiting on a `Sync`.
+    staged: Option<(f32, f32)>,
+    position: f32,
+    velocity: f32,
+    last_step: Instant,
+    // Seconds until the next `Status`.
+    until_status: f32,
+    // Torque the loop asked for last step, in N*m.
+    torque: f32,
+    // The frame being filled, if telemetry's running, and loop iterations until the next sample.
+    telemetry: Option<TelemetryMsg>,
+    until_sample: u32,
+    scope: Capture<Vec<f32>>,
+    boot: Boot,
+}
+
+impl<B: Bus> SimulatedNode<B> {
+    pub fn new(bus: B, address: NodeAddress) -> SimulatedNode<B> {
+        SimulatedNode {
+            bus,
+            address,
+            values: PARAMS.iter().map(|param| param.default).collect(),
+            control: Control::Idle,
+            staged: None,
+            position: 0.,
+            velocity: 0.,
+            last_step: Instant::now(),
+            until_status: 0.,
+            torque: 0.,
+            telemetry: None,
+            until_sample: 0,
+            scope: Capture::new(vec![0.; SCOPE_VALUES]),
+            boot: Boot {
+                flash: MemoryFlash::new(),
+                handoff: Handoff::default(),
+                updater: None,
+            },
+        }
+    }
+
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn in_bootloader(&self) -> bool {
+        self.boot.updater.is_some()
+    }
+
+    pub fn boot_record(&self) -> Option<BootRecord> {
+        update::load_record(&self.boot.flash)
+    }
+
+    // Handle whatever comes in over the next `timeout`, keeping the model and status up to date.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.step()?;
+            // Wake up at least once a step, so `Status` goes out on time.
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let wait = (deadline - now).min(Duration::from_secs_f32(STEP));
+            if let Some(frame) = self.bus.receive(wait)? {
+                self.handle(frame)?;
+            }
+        }
+    }
+
+    pub fn run(&mut self, stop: &AtomicBool) -> io::Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            self.poll(Duration::from_millis(10))?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: &impl Frame) -> io::Result<()> {
+        let mut frame = message.encode();
+        frame.id = self.address.reply(frame.id as u8);
+        self.bus.send(&frame)
+    }
+
+    fn step(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut elapsed = (now - self.last_step).as_secs_f32();
+        while elapsed >= STEP {
+            self.integrate(STEP);
+            self.sample()?;
+            self.sample_scope()?;
+            elapsed -= STEP;
+            self.until_status -= STEP;
+        }
+        self.last_step = now - Duration::from_secs_f32(elapsed);
+
+        if self.until_status <= 0. && !self.in_bootloader() {
+            let rate = match self.param(HEARTBEAT_RATE) {
+                Some(ParamValue::F32(rate)) => rate,
+                _ => 0.,
+            };
+            if rate > 0. {
+                self.until_status += 1. / rate;
+                self.send(&self.status())?;
+                self.send(&Self::bus_status())?;
+            } else {
+                self.until_status = 0.;
+            }
+        }
+        Ok(())
+    }
+
+    fn integrate(&mut self, dt: f32) {
+        // Torque driving the rotor, plus any damping the loop adds on top of friction. The damping's
+        // applied implicitly so stiff gains from the host can't blow the model up.
+        let (torque, damping) = match self.control {
+            Control::Idle => (0., 0.),
+            Control::Torque { remaining, q } => {
+                self.control = match remaining - dt {
+                    remaining if remaining > 0. => Control::Torque { remaining, q },
+                    _ => Control::Idle,
+                };
+                (q * TORQUE_CONSTANT, 0.)
+            }
+            Control::PosVel(target) => (
+                target.stiffness_gain * (target.position - self.position)
+                    + target.damping_gain * target.velocity,
+                target.damping_gain,
+            ),
+        };
+        self.velocity =
+            (self.velocity + torque / INERTIA * dt) / (1. + (FRICTION + damping) / INERTIA * dt);
+        self.position += self.velocity * dt;
+        self.torque = torque;
+    }
+
+    fn value(&self, signal: Signal) -> f32 {
+        // Same as the firmware, current loop signals are NaN when there's no loop running.
+        let current_loop = match self.control {
+            Control::Idle => f32::NAN,
+            _ => 0.,
+        };
+        match signal {
+            Signal::Angle => self.position.rem_euclid(std::f32::consts::TAU),
+            Signal::Velocity => self.velocity,
+            Signal::AngleMultiturn => self.position,
+            Signal::CurrentQ => current_loop + self.torque / TORQUE_CONSTANT,
+            Signal::CurrentD
+            | Signal::VoltageD
+            | Signal::VoltageQ
+            | Signal::IntegratorD
+            | Signal::IntegratorQ => current_loop,
+            Signal::VBus => V_BUS,
+            Signal::PhaseA | Signal::PhaseB | Signal::PhaseC | Signal::LoopTime => 0.,
+        }
+    }
+
+    // Take however many samples land in the last step, sending frames as they fill up.
+    fn sample(&mut self) -> io::Result<()> {
+        let mut frame = match self.telemetry {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let mut iterations = LOOP_ITERATIONS;
+        while iterations > self.until_sample {
+            iterations -= self.until_sample + 1;
+            self.until_sample = frame.decimation as u32 - 1;
+            let values: Vec<f32> = frame
+                .signals
+                .iter()
+                .map(|signal| self.value(signal))
+                .collect();
+            frame.push(&values);
+            if frame.is_full() {
+                self.send(&frame)?;
+                frame =
+                    TelemetryMsg::new(frame.signals, frame.decimation, frame.next_sequence(), 0);
+            }
+        }
+        self.until_sample -= iterations;
+        self.telemetry = Some(frame);
+        Ok(())
+    }
+
+    // Every loop iteration in the last step goes to the scope, same as the firmware. Lets the host
+    // know if that finishes a capture.
+    fn sample_scope(&mut self) -> io::Result<()> {
+        // Out of the way for a bit, so it can read from the model.
+        let mut scope = std::mem::replace(&mut self.scope, Capture::new(vec![]));
+        let before = scope.state();
+        for _ in 0..LOOP_ITERATIONS {
+            scope.sample(|signal| self.value(signal), 0);
+        }
+        self.scope = scope;
+        if before != ScopeState::Done && self.scope.state() == ScopeState::Done {
+            self.send(&self.scope.status())?;
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> StatusMsg {
+        let (mode, state) = match self.control {
+            Control::Idle => (MODE_IDLE, STATE_IDLE),
+            Control::Torque { .. } => (MODE_TORQUE_CONTROL, STATE_RUNNING),
+            Control::PosVel(_) => (MODE_POSITION_VELOCITY, STATE_RUNNING),
+        };
+        StatusMsg {
+            mode,
+            state,
+            active: 0,
+            critical: 0,
+        }
+    }
+
+    fn bus_status() -> BusStatusMsg {
+        BusStatusMsg {
+            state: BusState::Active as u32,
+            last_error: ProtocolError::None as u32,
+            last_data_error: ProtocolError::None as u32,
+            tx_errors: 0,
+            rx_errors: 0,
+            bus_offs: 0,
+            protocol_errors: 0,
+            rx_dropped: 0,
+            rx_lost: 0,
+            tx_dropped: 0,
+        }
+    }
+
+    fn param(&self, id: u16) -> Option<ParamValue> {
+        let index = PARAMS.iter().position(|param| param.id == id)?;
+        Some(self.values[index])
+    }
+
+    fn set_param(&mut self, id: u16, bits: u32) -> Result<ParamValue, ParamStatus> {
+        let index = PARAMS
+            .iter()
+            .position(|param| param.id == id)
+            .ok_or(ParamStatus::UnknownParam)?;
+        let param = &PARAMS[index];
+        let value = ParamValue::from_bits(param.kind, bits);
+        param.check(value)?;
+        self.values[index] = value;
+        Ok(value)
+    }
+
+    fn param_value(id: u16, status: ParamStatus, value: Option<ParamValue>) -> ParamValueMsg {
+        ParamValueMsg {
+            id,
+            kind: value.map_or(0, |value| value.kind() as u8),
+            status: status as u8,
+            bits: value.map_or(0, |value| value.to_bits()),
+        }
+    }
+
+    fn handle(&mut self, mut frame: FdcanMessage) -> io::Result<()> {
+        let filters = id::filters(&self.address);
+        if !filters.iter().any(|filter| filter.matches(frame.id)) {
+            return Ok(());
+        }
+        let id = match CanId::decode(frame.id) {
+            Some(id) if !id.from_node => id,
+            _ => return Ok(()),
+        };
+        frame.id = id.command as u32;
+        // The bootloader doesn't do acks, and syncs never make it as far as a handler.
+        let result = match MessageID::try_from(id.command) {
+            Ok(message) if self.in_bootloader() => return self.handle_boot(message, &frame),
+            Ok(MessageID::Sync) => return self.command(MessageID::Sync, &frame).map(|_| ()),
+            Ok(message) => {
+                self.boot.handoff.healthy = true;
+                self.command(message, &frame)?
+            }
+            Err(_) => Err(Nack::Unsupported),
+        };
+        if let Some(reply) = ack::reply(frame.id, id.transaction, result) {
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    // Same as the firmware's handlers: anything that doesn't decode is dropped and reported.
+    fn command(
+        &mut self,
+        message: MessageID,
+        frame: &FdcanMessage,
+    ) -> io::Result<Result<(), Nack>> {
+        macro_rules! decode {
+            ($cmd:ty) => {
+                match <$cmd>::decode(frame) {
+                    Ok(cmd) => cmd,
+                    Err(error) => return Ok(Err(error.into())),
+                }
+            };
+        }
+
+        match message {
+            MessageID::EnterTorqueControl => {
+                let cmd = decode!(TorqueControlCmd);
+                self.control = Control::Torque {
+                    remaining: cmd.duration,
+                    q: cmd.q,
+                };
+            }
+            MessageID::EnterPosVelControl => {
+                // Hold wherever we are until told otherwise.
+                self.control = Control::PosVel(SetPosVelCmd {
+                    position: self.position,
+                    velocity: 0.,
+                    stiffness_gain: 0.,
+                    damping_gain: 0.,
+                    torque_constant: TORQUE_CONSTANT,
+                });
+            }
+            MessageID::SetPosVel => {
+                let cmd = decode!(SetPosVelCmd);
+                match self.control {
+                    Control::PosVel(_) => self.control = Control::PosVel(cmd),
+                    _ => return Ok(Err(Nack::Rejected)),
+                }
+            }
+            MessageID::GroupSetPosVel => {
+                let group = decode!(GroupSetpoints);
+                if let Some(setpoint) = group.find(self.address.node) {
+                    self.staged = Some((setpoint.position, setpoint.velocity));
+                }
+            }
+            MessageID::Sync => {
+                if let (Some((position, velocity)), Control::PosVel(target)) =
+                    (self.staged.take(), &mut self.control)
+                {
+                    target.position = position;
+                    target.velocity = velocity;
+                }
+            }
+            MessageID::DisableControlLoop => self.control = Control::Idle,
+            MessageID::StartTelemetry => {
+                let cmd = decode!(StartTelemetryCmd);
+                let decimation = cmd.decimation.clamp(1, u16::MAX as u32) as u16;
+                self.telemetry = match cmd.signals.is_empty() {
+                    true => None,
+                    false => Some(TelemetryMsg::new(cmd.signals, decimation, 0, 0)),
+                };
+                self.until_sample = 0;
+            }
+            MessageID::StopTelemetry => self.telemetry = None,
+            MessageID::ArmScope => {
+                let armed = match ScopeConfig::from_cmd(&decode!(ArmScopeCmd)) {
+                    Some(config) => {
+                        self.scope.arm(config);
+                        Ok(())
+                    }
+                    None => Err(Nack::Rejected),
+                };
+                self.send(&self.scope.status())?;
+                return Ok(armed);
+            }
+            MessageID::TriggerScope => self.scope.force(),
+            MessageID::GetScopeStatus => self.send(&self.scope.status())?,
+            MessageID::ReadScope => {
+                let cmd = decode!(ReadScopeCmd);
+                let mut values = [0.; SCOPE_CHUNK_VALUES];
+                let count = self.scope.read(cmd.offset as usize, &mut values);
+                self.send(&ScopeDataMsg {
+                    offset: cmd.offset,
+                    count: count as u32,
+                    values,
+                })?;
+            }
+            MessageID::GetParam => {
+                let cmd = decode!(GetParamCmd);
+                let (reply, result) = match self.param(cmd.id) {
+                    Some(value) => (
+                        Self::param_value(cmd.id, ParamStatus::Ok, Some(value)),
+                        Ok(()),
+                    ),
+                    None => (
+                        Self::param_value(cmd.id, ParamStatus::UnknownParam, None),
+                        Err(Nack::Rejected),
+                    ),
+                };
+                self.send(&reply)?;
+                return Ok(result);
+            }
+            MessageID::SetParam => {
+                let cmd = decode!(SetParamCmd);
+                let (reply, result) = match self.set_param(cmd.id, cmd.bits) {
+                    Ok(value) => (
+                        Self::param_value(cmd.id, ParamStatus::Ok, Some(value)),
+                        Ok(()),
+                    ),
+                    // Failed sets reply with the value that's still there.
+                    Err(status) => (
+                        Self::param_value(cmd.id, status, self.param(cmd.id)),
+                        Err(Nack::Rejected),
+                    ),
+                };
+                self.send(&reply)?;
+                return Ok(result);
+            }
+            MessageID::ListParams => {
+                for (index, param) in PARAMS.iter().enumerate() {
+                    let mut name = [0; PARAM_NAME_BYTES];
+                    for (byte, c) in name.iter_mut().zip(param.name.bytes()) {
+                        *byte = c;
+                    }
+                    self.send(&ParamInfoMsg {
+                        id: param.id,
+                        kind: param.kind as u16,
+                        index: index as u16,
+                        count: PARAMS.len() as u16,
+                        min: param.min.to_bits(),
+                        max: param.max.to_bits(),
+                        value: self.values[index].to_bits(),
+                        name,
+                    })?;
+                }
+            }
+            // There's nowhere to save them to, but there's no harm in saying we did.
+            MessageID::SaveParams => {
+                self.send(&ParamAckMsg {
+                    request: MessageID::SaveParams as u32,
+                    status: ParamStatus::Ok as u32,
+                })?;
+            }
+            MessageID::RestoreDefaultParams => {
+                for (value, param) in self.values.iter_mut().zip(PARAMS) {
+                    if param.id != NODE_ID && param.id != NODE_GROUP {
+                        *value = param.default;
+                    }
+                }
+                self.send(&ParamAckMsg {
+                    request: MessageID::RestoreDefaultParams as u32,
+                    status: ParamStatus::Ok as u32,
+                })?;
+            }
+            MessageID::GetFaults | MessageID::ClearFaults => {
+                self.send(&FaultStatusMsg {
+                    active: 0,
+                    critical: 0,
+                    raised: 0,
+                })?;
+            }
+            MessageID::GetBusStatus => self.send(&Self::bus_status())?,
+            MessageID::DumpDrvRegisters => self.send(&DrvRegistersMsg { registers: [0; 7] })?,
+            MessageID::GetLoopTiming => self.send(&LoopTimingMsg {
+                samples: 0,
+                overruns: 0,
+                min: 0.,
+                average: 0.,
+                max: 0.,
+            })?,
+            // Same as `boot::reboot_to_bootloader`, and then the bootloader coming up.
+            MessageID::RebootToBootloader => {
+                self.control = Control::Idle;
+                self.telemetry = None;
+                self.boot.handoff.stay = true;
+                self.boot.handoff.node = Some(self.address);
+                self.reset()?;
+            }
+            // Never crashed.
+            MessageID::GetCrashRecord => self.bus.send(&FdcanMessage::new(
+                self.address.reply(MessageID::CrashLocation as u8),
+                &[],
+            ))?,
+            // Nothing times out in here.
+            MessageID::Heartbeat => {}
+            // Everything else is only ever sent by a node, or handled by the bootloader.
+            _ => return Ok(Err(Nack::Unsupported)),
+        }
+        Ok(Ok(()))
+    }
+
+    // The bootloader's side of a reset; see the `boot` crate. Stays in the bootloader if there's
+    // nothing to boot, otherwise pretends the image came up fine; it's kept once it's heard from
+    // the host, same as the firmware.
+    fn reset(&mut self) -> io::Result<()> {
+        let flash = &mut self.boot.flash;
+        let record = update::load_record(flash).unwrap_or_else(BootRecord::empty);
+        let decision = update::decide(&record, &mut self.boot.handoff, |image| {
+            update::verify(flash, image)
+        });
+        let record = match decision.record {
+            Some(next) => update::save_record(flash, &next).unwrap_or(next),
+            None => record,
+        };
+        match decision.boot {
+            Some(_) => self.boot.updater = None,
+            None => {
+                self.boot.updater = Some(Updater::new(record.target()));
+                self.send(&Self::boot_status(&record))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn boot_status(record: &BootRecord) -> BootStatusMsg {
+        BootStatusMsg {
+            active: Slot::raw(record.active),
+            pending: Slot::raw(record.pending),
+            target: record.target() as u32,
+            address: record.target().address(),
+        }
+    }
+
+    fn handle_boot(&mut self, message: MessageID, frame: &FdcanMessage) -> io::Result<()> {
+        let record = update::load_record(&self.boot.flash).unwrap_or_else(BootRecord::empty);
+        let (flash, updater) = match &mut self.boot.updater {
+            Some(updater) => (&mut self.boot.flash, updater),
+            None => return Ok(()),
+        };
+        let result = match message {
+            MessageID::GetBootStatus => return self.send(&Self::boot_status(&record)),
+            MessageID::BeginUpdate => match BeginUpdateCmd::decode(frame) {
+                Ok(cmd) => updater
+                    .begin(ImageHeader {
+                        address: cmd.address,
+                        length: cmd.length,
+                        crc: cmd.crc,
+                    })
+                    .map(|_| 0),
+                Err(_) => Err(UpdateStatus::Malformed),
+            },
+            MessageID::UpdateChunk => match UpdateChunkCmd::decode(frame) {
+                Ok(cmd) => updater.chunk(flash, cmd.offset, &cmd.data),
+                Err(_) => Err(UpdateStatus::Malformed),
+            },
+            MessageID::FinishUpdate => updater.finish(flash).and_then(|image| {
+                update::save_record(flash, &record.installed(image))
+                    .map(|_| 0)
+                    .map_err(|_| UpdateStatus::Flash)
+            }),
+            MessageID::Boot => return self.reset(),
+            _ => return Ok(()),
+        };
+        let (status, next) = match result {
+            Ok(next) if message == MessageID::UpdateChunk => (UpdateStatus::Ok, next),
+            Ok(_) => (UpdateStatus::Ok, updater.next()),
+            Err(status) => (status, updater.next()),
+        };
+        self.send(&UpdateAckMsg {
+            request: message as u32,
+            status: status as u32,
+            next,
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use protocol::FdcanMessage;
+
+use crate::bus::{fd_len, Bus};
+
+// A raw CAN-FD socket bound to one interface, e.g. `can0` or `vcan0`. The interface has to be up,
+// with CAN-FD enabled (`mtu 72`).
+pub struct SocketCan {
+    socket: OwnedFd,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        x if x < 0 => Err(io::Error::last_os_error()),
+        x => Ok(x),
+    }
+}
+
+impl SocketCan {
+    pub fn open(interface: &str) -> io::Result<SocketCan> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad interface name"))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = check(unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW) })?;
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        let enable: libc::c_int = 1;
+        check(unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_CAN_RAW,
+                libc::CAN_RAW_FD_FRAMES,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        })?;
+
+        let mut address: libc::sockaddr_can = unsafe { mem::zeroed() };
+        address.can_family = libc::AF_CAN as libc::sa_family_t;
+        address.can_ifindex = index as libc::c_int;
+        check(unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&address) as libc::socklen_t,
+            )
+        })?;
+
+        Ok(SocketCan { socket })
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Round up, so a sub-millisecond timeout doesn't turn into not waiting at all.
+        let millis = timeout
+            .as_micros()
+            .div_ceil(1000)
+            .min(libc::c_int::MAX as u128);
+        let ready = check(unsafe { libc::poll(&mut poll, 1, millis as libc::c_int) })?;
+        Ok(ready > 0)
+    }
+}
+
+impl Bus for SocketCan {
+    fn send(&mut self, frame: &FdcanMessage) -> io::Result<()> {
+        let len = fd_len(frame.size as usize);
+        let mut raw: libc::canfd_frame = unsafe { mem::zeroed() };
+        raw.can_id = (frame.id & libc::CAN_EFF_MASK) | libc::CAN_EFF_FLAG;
+        raw.len = len as u8;
+        raw.flags = libc::CANFD_BRS as u8;
+        raw.data[..len].copy_from_slice(&frame.bytes()[..len]);
+
+        let written = unsafe {
+            libc::write(
+                self.socket.as_raw_fd(),
+                &raw as *const _ as *const libc::c_void,
+                libc::CANFD_MTU,
+            )
+        };
+        match written {
+            x if x < 0 => Err(io::Error::last_os_error()),
+            x if x as usize != libc::CANFD_MTU => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Frame only partially sent",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<FdcanMessage>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !self.wait_readable(remaining)? {
+                return Ok(None);
+            }
+
+            let mut raw: libc::canfd_frame = unsafe { mem::zeroed() };
+            let read = unsafe {
+                libc::read(
+                    self.socket.as_raw_fd(),
+                    &mut raw as *mut _ as *mut libc::c_void,
+                    libc::CANFD_MTU,
+                )
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Classic frames come in at `CAN_MTU` with the same header, so they're fine. Standard
+            // IDs, remote requests and error frames aren't anything we'd send, so skip them.
+            let flags = libc::CAN_EFF_FLAG | libc::CAN_RTR_FLAG | libc::CAN_ERR_FLAG;
+            if raw.can_id & flags != libc::CAN_EFF_FLAG {
+                continue;
+            }
+            let len = (raw.len as usize).min(raw.data.len());
+            return Ok(Some(FdcanMessage::from_bytes(
+                raw.can_id & libc::CAN_EFF_MASK,
+                &raw.data[..len],
+            )));
+        }
+    }
+}
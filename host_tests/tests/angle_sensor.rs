@@ -0,0 +1,205 @@
+#[cfg(test)]
+mod tests {
+    use bldc::ic::angle_sensor::{AngleSensor, ErrorRecovery, SensorError, MAX_FRAME_WORDS};
+    use bldc::ic::as5047p::As5047p;
+    use bldc::ic::ma702::Ma702;
+    use bldc::ic::ma730::Ma730;
+    use bldc::ic::mt6835::Mt6835;
+    use bldc::util::crc::crc8;
+    use std::f32::consts::PI;
+
+    // What an MT6835 would send back for `angle`, with `status` in the bottom three bits.
+    fn mt6835_response(angle: u32, status: u8) -> [u16; 3] {
+        let high = (angle >> 13) as u8;
+        let middle = (angle >> 5) as u8;
+        let low = (((angle & 0x1F) as u8) << 3) | status;
+        let crc = crc8(&[high, middle, low]);
+        [
+            0xFFFF,
+            u16::from_be_bytes([high, middle]),
+            u16::from_be_bytes([low, crc]),
+        ]
+    }
+
+    // Sets the top bit so the frame has even parity, like the AS5047P does.
+    fn with_parity(frame: u16) -> u16 {
+        match frame.count_ones() % 2 {
+            0 => frame,
+            _ => frame | 0x8000,
+        }
+    }
+
+    // Just enough of an AS5047P to clear its error flag: replies a frame late, and keeps EF up in
+    // every angle it sends until ERRFL's been read.
+    struct FakeAs5047p {
+        command: u16,
+        error: bool,
+    }
+
+    impl FakeAs5047p {
+        fn frame(&mut self, command: u16, angle: u16) -> u16 {
+            let reply = match self.command {
+                // Reading ERRFL clears it.
+                0x4001 => std::mem::take(&mut self.error) as u16,
+                _ => angle | if self.error { 0x4000 } else { 0 },
+            };
+            self.command = command;
+            with_parity(reply)
+        }
+    }
+
+    // Polls a `FakeAs5047p` at 40kHz, `phase` microseconds out from its 50kHz frames, and counts
+    // up how the readings went: good, errors and recovering. `glitch` says whether the frame
+    // starting at a given time (also in microseconds) latches an error.
+    fn poll_as5047p(phase: u32, glitch: impl Fn(u32) -> bool) -> (u32, u32, u32) {
+        let mut sensor = FakeAs5047p {
+            command: As5047p::REQUEST[0],
+            error: false,
+        };
+        let mut recovery = ErrorRecovery::new::<As5047p>(20e-6);
+        let mut request = As5047p::REQUEST[0];
+        let mut response = 0;
+        let mut next_frame = 0;
+        let mut counts = (0, 0, 0);
+        for poll in 0..400 {
+            let now = phase + poll * 25;
+            while next_frame <= now {
+                sensor.error |= glitch(next_frame);
+                response = sensor.frame(request, 0x1234);
+                next_frame += 20;
+            }
+            let (reading, next) = recovery.update(As5047p::read(&[response]), 25e-6);
+            if let Some(next) = next {
+                request = next[0];
+            }
+            match reading {
+                Ok(reading) => {
+                    assert_eq!(reading.raw_angle, 0x1234, "Poll {}", poll);
+                    counts.0 += 1;
+                }
+                Err(SensorError::Recovering) => counts.2 += 1,
+                Err(error) => {
+                    assert_eq!(error, SensorError::ErrorFlag, "Poll {}", poll);
+                    counts.1 += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn as5047p_recovers_from_error_flag() {
+        // Wherever the polls land relative to the frames, one flagged frame is one error and a
+        // few polls' worth of recovering, and never a reply to the ERRFL read taken as an angle.
+        for phase in [0, 3, 7, 12, 19] {
+            let (good, errors, recovering) = poll_as5047p(phase, |time| time == 200);
+            assert_eq!(errors, 1, "Phase {}", phase);
+            assert!(
+                recovering <= 6,
+                "{} polls recovering at phase {}",
+                recovering,
+                phase
+            );
+            assert_eq!(good + errors + recovering, 400);
+        }
+
+        // Whereas a sensor that keeps on flagging errors keeps on being reported, so it'll still
+        // end up tripping `EncoderReadout`.
+        let (good, errors, _) = poll_as5047p(0, |_| true);
+        assert_eq!(good, 0);
+        assert!(errors > 50, "Only {} errors", errors);
+    }
+
+    #[test]
+    fn requests_fit() {
+        assert!(Ma702::REQUEST.len() <= MAX_FRAME_WORDS);
+        assert!(Ma730::REQUEST.len() <= MAX_FRAME_WORDS);
+        assert!(As5047p::REQUEST.len() <= MAX_FRAME_WORDS);
+        assert!(Mt6835::REQUEST.len() <= MAX_FRAME_WORDS);
+        // The AS5047P ignores commands with bad parity.
+        assert_eq!(As5047p::REQUEST[0].count_ones() % 2, 0);
+        let clear = As5047p::CLEAR_ERROR.unwrap();
+        assert_eq!(clear.len(), As5047p::REQUEST.len());
+        assert_eq!(clear[0].count_ones() % 2, 0);
+    }
+
+    #[test]
+    fn resolution_follows_bits() {
+        assert_eq!(Ma702::counts(), 4096);
+        assert_eq!(Ma730::counts(), 16384);
+        assert_eq!(As5047p::counts(), 16384);
+        assert_eq!(Mt6835::counts(), 1 << 21);
+        assert_eq!(Ma702::resolution().in_radians(), 2. * PI / 4096.);
+        assert_eq!(
+            Mt6835::resolution().in_radians(),
+            2. * PI / (1 << 21) as f32
+        );
+    }
+
+    #[test]
+    fn monolithic_angles_are_left_aligned() {
+        assert_eq!(Ma702::decode(&[0xABCD]), Ok(0xABC));
+        assert_eq!(Ma730::decode(&[0xABCD]), Ok(0xABCD >> 2));
+        let half_turn = Ma730::read(&[0x8000]).unwrap();
+        assert_eq!(half_turn.raw_angle, 8192);
+        assert!((half_turn.angle.in_radians() - PI).abs() < 1e-6);
+    }
+
+    #[test]
+    fn as5047p_checks_parity_and_error_flag() {
+        assert_eq!(As5047p::decode(&[with_parity(0x1234)]), Ok(0x1234));
+        assert_eq!(As5047p::decode(&[with_parity(0x3FFF)]), Ok(0x3FFF));
+        // Any single bit flipped breaks the parity.
+        for bit in 0..16 {
+            let frame = with_parity(0x1234) ^ (1 << bit);
+            assert_eq!(
+                As5047p::decode(&[frame]),
+                Err(SensorError::Parity),
+                "Bit {}",
+                bit
+            );
+        }
+        assert_eq!(
+            As5047p::decode(&[with_parity(0x4000 | 0x1234)]),
+            Err(SensorError::ErrorFlag)
+        );
+    }
+
+    #[test]
+    fn crc8_matches_reference() {
+        // The standard check value for CRC-8 with this polynomial.
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn mt6835_checks_crc_and_status() {
+        for angle in [0, 1, 0x1F, 0x12345, (1 << 21) - 1] {
+            assert_eq!(Mt6835::decode(&mt6835_response(angle, 0)), Ok(angle));
+        }
+        let quarter_turn = Mt6835::read(&mt6835_response(1 << 19, 0)).unwrap();
+        assert!((quarter_turn.angle.in_radians() - PI / 2.).abs() < 1e-6);
+
+        // Anything flipped on the way, including the CRC itself.
+        let good = mt6835_response(0x12345, 0);
+        for bit in 0..32 {
+            let mut response = good;
+            response[1 + bit / 16] ^= 1 << (bit % 16);
+            assert_eq!(
+                Mt6835::decode(&response),
+                Err(SensorError::Crc),
+                "Bit {}",
+                bit
+            );
+        }
+        // The command word's junk as far as the reply goes.
+        let mut response = good;
+        response[0] = 0;
+        assert_eq!(Mt6835::decode(&response), Ok(0x12345));
+
+        assert_eq!(
+            Mt6835::decode(&mt6835_response(0x12345, 0b010)),
+            Err(SensorError::Status(0b010))
+        );
+    }
+}
@@ -0,0 +1,156 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use bldc::comms::bus_monitor::{BusMonitor, BusSample};
+    use bldc::fault::{self, Fault, Faults};
+    use pino::{Client, Loopback, SimulatedNode, State};
+    use protocol::bus::{BusState, ProtocolError};
+    use protocol::id::NodeAddress;
+    use std::time::Duration;
+
+    const BO: u32 = 1 << 7;
+    const EP: u32 = 1 << 5;
+    const EW: u32 = 1 << 6;
+    // ACK error in the nominal phase, CRC in the data phase.
+    const ACK: u32 = 3;
+    const DATA_CRC: u32 = 6 << 8;
+    // "Nothing new" in both.
+    const NO_CHANGE: u32 = 7 | 7 << 8;
+
+    fn sample(psr: u32, tec: u32, rec: u32, cel: u32) -> BusSample {
+        BusSample {
+            psr,
+            ecr: tec | rec << 8 | cel << 16,
+            ..BusSample::default()
+        }
+    }
+
+    fn clean() -> BusSample {
+        sample(NO_CHANGE, 0, 0, 0)
+    }
+
+    // Polls until the monitor asks for a restart, returning how many it took.
+    fn until_restart(monitor: &mut BusMonitor) -> u32 {
+        for poll in 1..=10_000 {
+            if monitor.update(sample(BO, 248, 0, 0)) {
+                return poll;
+            }
+        }
+        panic!("Never restarted");
+    }
+
+    #[test]
+    fn decodes_registers() {
+        let bus_off = sample(BO | EP | EW | ACK | DATA_CRC, 255, 12, 40);
+        assert_eq!(bus_off.state(), BusState::BusOff);
+        assert_eq!(bus_off.last_error(), ProtocolError::Ack);
+        assert_eq!(bus_off.last_data_error(), ProtocolError::Crc);
+        assert_eq!(bus_off.tx_errors(), 255);
+        assert_eq!(bus_off.rx_errors(), 12);
+        assert_eq!(bus_off.logged_errors(), 40);
+
+        assert_eq!(sample(EP | EW, 130, 0, 0).state(), BusState::Passive);
+        assert_eq!(sample(EW, 100, 0, 0).state(), BusState::Warning);
+        let clean = clean();
+        assert_eq!(clean.state(), BusState::Active);
+        assert_eq!(clean.last_error(), ProtocolError::None);
+        assert_eq!(clean.last_data_error(), ProtocolError::None);
+    }
+
+    #[test]
+    fn counts_errors_and_drops() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut monitor = BusMonitor::new();
+        monitor.update(sample(ACK, 8, 0, 1));
+        monitor.update(sample(NO_CHANGE, 16, 0, 1));
+        monitor.update(BusSample {
+            fifo_lost: true,
+            rx_dropped: 2,
+            tx_dropped: 1,
+            ..sample(NO_CHANGE, 8, 3, 0)
+        });
+
+        let status = monitor.status();
+        assert_eq!(status.state, BusState::Active as u32);
+        // Sticks around after PSR's been read again.
+        assert_eq!(status.last_error, ProtocolError::Ack as u32);
+        assert_eq!(status.tx_errors, 8);
+        assert_eq!(status.rx_errors, 3);
+        assert_eq!(status.protocol_errors, 2);
+        assert_eq!(status.rx_lost, 1);
+        assert_eq!(status.rx_dropped, 2);
+        assert_eq!(status.tx_dropped, 1);
+        assert_eq!(
+            fault::active(),
+            Faults::from_bits(
+                Faults::from(Fault::FdcanTx).bits() | Faults::from(Fault::FdcanRxOverflow).bits()
+            )
+        );
+        assert!(!fault::tripped());
+
+        monitor.update(sample(EP | EW | NO_CHANGE, 128, 0, 0));
+        assert_eq!(monitor.state(), BusState::Passive);
+        assert!(fault::active().contains(Fault::FdcanErrorPassive));
+        assert!(!fault::tripped());
+    }
+
+    #[test]
+    fn recovers_from_bus_off_with_backoff() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut monitor = BusMonitor::new();
+        assert!(!monitor.update(clean()));
+        // Waits out the backoff before the first restart.
+        assert_eq!(until_restart(&mut monitor), 10);
+        assert_eq!(monitor.state(), BusState::BusOff);
+        assert_eq!(monitor.status().bus_offs, 1);
+        assert!(fault::active().contains(Fault::FdcanBusOff));
+        assert!(fault::tripped());
+
+        // Still stuck, so it waits longer each time, up to a second.
+        assert_eq!(until_restart(&mut monitor), 20);
+        assert_eq!(until_restart(&mut monitor), 40);
+        for _ in 0..10 {
+            until_restart(&mut monitor);
+        }
+        assert_eq!(until_restart(&mut monitor), 1000);
+        // That was all one bus-off.
+        assert_eq!(monitor.status().bus_offs, 1);
+
+        // Back on the bus. Going straight back off again keeps the backoff where it was...
+        assert!(!monitor.update(clean()));
+        assert_eq!(monitor.state(), BusState::Active);
+        assert_eq!(until_restart(&mut monitor), 1000);
+        assert_eq!(monitor.status().bus_offs, 2);
+
+        // ...but after a good long while without, it's back to the start.
+        for _ in 0..2000 {
+            assert!(!monitor.update(clean()));
+        }
+        assert_eq!(until_restart(&mut monitor), 10);
+        assert_eq!(monitor.status().bus_offs, 3);
+    }
+
+    #[test]
+    fn simulated_node_reports_a_clean_bus() {
+        let bus = Loopback::new();
+        let mut sim = SimulatedNode::new(bus.endpoint(), NodeAddress::new(2, 0));
+        let mut client = Client::new(bus.endpoint(), 2).with_timeout(Duration::from_millis(100));
+
+        // Goes out with the status.
+        sim.poll(Duration::from_millis(150)).unwrap();
+        let mut seen = false;
+        while let Some(state) = client.next_state(Duration::from_millis(10)).unwrap() {
+            if let State::Bus(status) = state {
+                assert_eq!(status.state, BusState::Active as u32);
+                seen = true;
+            }
+        }
+        assert!(seen);
+    }
+}
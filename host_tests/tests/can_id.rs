@@ -0,0 +1,81 @@
+#[cfg(test)]
+mod tests {
+    use bldc::comms::id::{self, Address, CanId, NodeAddress};
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for address in [
+            Address::Node(1),
+            Address::Node(id::MAX_NODE_ID),
+            Address::Group(0),
+            Address::Group(id::MAX_GROUP),
+            Address::Broadcast,
+        ] {
+            for from_node in [false, true] {
+                for transaction in [0, 1, 0xFF] {
+                    let can_id = CanId {
+                        command: 0x19,
+                        address,
+                        from_node,
+                        transaction,
+                    };
+                    let raw = can_id.encode();
+                    assert!(raw < 1 << 29, "{:#x} doesn't fit in 29 bits", raw);
+                    assert_eq!(CanId::decode(raw), Some(can_id));
+                }
+            }
+        }
+        assert_eq!(
+            CanId {
+                command: 0x19,
+                address: Address::Node(3),
+                from_node: true,
+                transaction: 0,
+            }
+            .encode(),
+            0x3_0319
+        );
+        assert_eq!(CanId::decode(0x22_0319).unwrap().transaction, 1);
+
+        // Address zero isn't valid, and neither's any other protocol version.
+        assert_eq!(CanId::decode(0x2_0019), None);
+        assert_eq!(CanId::decode(0x4_0319), None);
+    }
+
+    #[test]
+    fn nodes_only_hear_their_own_frames() {
+        let node = NodeAddress::new(3, 2);
+        let filters = id::filters(&node);
+        let accepted = |address, from_node| {
+            let raw = CanId {
+                command: 0x19,
+                address,
+                from_node,
+                transaction: 0x42,
+            }
+            .encode();
+            filters.iter().any(|filter| filter.matches(raw))
+        };
+
+        assert!(accepted(Address::Node(3), false));
+        assert!(accepted(Address::Group(2), false));
+        assert!(accepted(Address::Broadcast, false));
+        assert!(!accepted(Address::Node(4), false));
+        assert!(!accepted(Address::Group(1), false));
+        // Other nodes' replies aren't for us.
+        assert!(!accepted(Address::Node(3), true));
+        assert!(!accepted(Address::Broadcast, true));
+
+        // Replies come from our own address.
+        let reply = CanId::decode(node.reply(0x2A)).unwrap();
+        assert_eq!(reply.address, Address::Node(3));
+        assert!(reply.from_node);
+        assert_eq!(reply.transaction, 0);
+
+        // Out of range config gets clamped.
+        assert_eq!(
+            NodeAddress::new(0, 100),
+            NodeAddress::new(1, id::MAX_GROUP as u32)
+        );
+    }
+}
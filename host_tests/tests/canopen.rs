@@ -0,0 +1,610 @@
+#[cfg(test)]
+mod tests {
+    use bldc::canopen::dictionary::*;
+    use bldc::canopen::{Action, CanFrame, DriveState, Feedback, NmtState, Node, Outputs};
+    use bldc::fault::{Fault, Faults};
+    use protocol::messages::{
+        MAX_CURRENT, MAX_DAMPING, MAX_STIFFNESS, MAX_TORQUE_CONSTANT, MIN_TORQUE_CONSTANT,
+    };
+
+    const ID: u8 = 5;
+
+    #[derive(Default)]
+    struct Bus {
+        frames: Vec<CanFrame>,
+        actions: Vec<Action>,
+    }
+
+    impl Outputs for Bus {
+        fn send(&mut self, frame: CanFrame) {
+            self.frames.push(frame);
+        }
+        fn act(&mut self, action: Action) {
+            self.actions.push(action);
+        }
+    }
+
+    impl Bus {
+        fn take(&mut self) -> (Vec<CanFrame>, Vec<Action>) {
+            (
+                std::mem::take(&mut self.frames),
+                std::mem::take(&mut self.actions),
+            )
+        }
+    }
+
+    fn at(position: f32) -> Feedback {
+        Feedback {
+            position,
+            velocity: 0.,
+            faults: Faults::default(),
+        }
+    }
+
+    fn faulted(fault: Fault) -> Feedback {
+        Feedback {
+            faults: Faults::from_bits(1 << fault as u32),
+            ..at(0.)
+        }
+    }
+
+    fn started(bus: &mut Bus) -> Node {
+        let mut node = Node::new(ID);
+        node.start(0, bus);
+        bus.take();
+        node
+    }
+
+    fn receive(node: &mut Node, bus: &mut Bus, cob_id: u16, data: &[u8], feedback: &Feedback) {
+        node.receive(&CanFrame::new(cob_id, data), 0, feedback, bus);
+    }
+
+    // Sends an SDO request and returns the reply.
+    fn sdo(node: &mut Node, bus: &mut Bus, request: [u8; 8]) -> [u8; 8] {
+        bus.frames.clear();
+        receive(node, bus, 0x600 + ID as u16, &request, &at(0.));
+        let reply = bus.frames.pop().expect("No SDO reply");
+        assert_eq!(reply.cob_id, 0x580 + ID as u16);
+        reply.data
+    }
+
+    fn download(node: &mut Node, bus: &mut Bus, index: u16, sub: u8, bytes: &[u8]) -> [u8; 8] {
+        let index = index.to_le_bytes();
+        let mut request = [
+            0x23 | ((4 - bytes.len() as u8) << 2),
+            index[0],
+            index[1],
+            sub,
+            0,
+            0,
+            0,
+            0,
+        ];
+        request[4..4 + bytes.len()].copy_from_slice(bytes);
+        sdo(node, bus, request)
+    }
+
+    fn emergencies(frames: Vec<CanFrame>) -> Vec<CanFrame> {
+        frames
+            .into_iter()
+            .filter(|frame| frame.cob_id == 0x085)
+            .collect()
+    }
+
+    fn abort_code(reply: [u8; 8]) -> u32 {
+        assert_eq!(reply[0], 0x80);
+        u32::from_le_bytes([reply[4], reply[5], reply[6], reply[7]])
+    }
+
+    fn start(node: &mut Node, bus: &mut Bus) {
+        receive(node, bus, 0x000, &[0x01, ID], &at(0.));
+        bus.take();
+    }
+
+    // RPDO4 is controlword + target torque.
+    fn controlword(node: &mut Node, bus: &mut Bus, controlword: u16, feedback: &Feedback) {
+        let cw = controlword.to_le_bytes();
+        let torque = node.dictionary().get(TARGET_TORQUE, 0).to_le_bytes();
+        receive(
+            node,
+            bus,
+            0x500 + ID as u16,
+            &[cw[0], cw[1], torque[0], torque[1]],
+            feedback,
+        );
+    }
+
+    fn enable(node: &mut Node, bus: &mut Bus, feedback: &Feedback) {
+        for cw in [0x06, 0x07, 0x0F] {
+            controlword(node, bus, cw, feedback);
+        }
+        assert_eq!(node.drive_state(), DriveState::OperationEnabled);
+    }
+
+    #[test]
+    fn sdo_expedited_upload_and_download() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        // Device type, 4 bytes.
+        assert_eq!(
+            sdo(&mut node, &mut bus, [0x40, 0x00, 0x10, 0, 0, 0, 0, 0]),
+            [0x43, 0x00, 0x10, 0, 0x92, 0x01, 0x02, 0x00]
+        );
+        assert_eq!(
+            download(
+                &mut node,
+                &mut bus,
+                HEARTBEAT_TIME,
+                0,
+                &250u16.to_le_bytes()
+            ),
+            [0x60, 0x17, 0x10, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(node.dictionary().get(HEARTBEAT_TIME, 0), 250);
+
+        assert_eq!(
+            abort_code(download(&mut node, &mut bus, DEVICE_TYPE, 0, &[0; 4])),
+            0x0601_0002
+        );
+        assert_eq!(
+            abort_code(sdo(&mut node, &mut bus, [0x40, 0x34, 0x12, 0, 0, 0, 0, 0])),
+            0x0602_0000
+        );
+        assert_eq!(
+            abort_code(sdo(&mut node, &mut bus, [0x40, 0x17, 0x10, 1, 0, 0, 0, 0])),
+            0x0609_0011
+        );
+        // Heartbeat time's only two bytes.
+        assert_eq!(
+            abort_code(download(&mut node, &mut bus, HEARTBEAT_TIME, 0, &[0; 4])),
+            0x0607_0010
+        );
+        assert_eq!(
+            abort_code(download(&mut node, &mut bus, MODES_OF_OPERATION, 0, &[2])),
+            0x0609_0030
+        );
+        assert_eq!(
+            abort_code(download(
+                &mut node,
+                &mut bus,
+                GAINS,
+                1,
+                &f32::NAN.to_le_bytes()
+            )),
+            0x0609_0030
+        );
+        // Nothing past what the native protocol would take either.
+        for (index, sub, value) in [
+            (GAINS, 1, (MAX_STIFFNESS * 2.).to_bits()),
+            (GAINS, 2, (MAX_DAMPING * 2.).to_bits()),
+            (GAINS, 3, (MIN_TORQUE_CONSTANT / 2.).to_bits()),
+            (GAINS, 3, (MAX_TORQUE_CONSTANT * 2.).to_bits()),
+            (RATED_CURRENT, 0, (MAX_CURRENT * 2000.) as u32),
+        ] {
+            assert_eq!(
+                abort_code(download(
+                    &mut node,
+                    &mut bus,
+                    index,
+                    sub,
+                    &value.to_le_bytes()
+                )),
+                0x0609_0030
+            );
+        }
+    }
+
+    #[test]
+    fn sdo_segmented_transfers() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        let reply = sdo(&mut node, &mut bus, [0x40, 0x0A, 0x10, 0, 0, 0, 0, 0]);
+        assert_eq!(reply[0], 0x41);
+        let size = u32::from_le_bytes([reply[4], reply[5], reply[6], reply[7]]) as usize;
+
+        let mut received = vec![];
+        let mut toggle = 0;
+        loop {
+            let segment = sdo(&mut node, &mut bus, [0x60 | toggle, 0, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(segment[0] & 0x10, toggle);
+            let len = 7 - (segment[0] as usize >> 1 & 0b111);
+            received.extend_from_slice(&segment[1..1 + len]);
+            toggle ^= 0x10;
+            if segment[0] & 1 != 0 {
+                break;
+            }
+        }
+        assert_eq!(received.len(), size);
+        assert!(String::from_utf8(received).unwrap().starts_with("pino-rs "));
+
+        // Stiffness gain, four bytes in a single segment.
+        let gain = 12.5f32.to_le_bytes();
+        assert_eq!(
+            sdo(&mut node, &mut bus, [0x21, 0x00, 0x20, 1, 4, 0, 0, 0]),
+            [0x60, 0x00, 0x20, 1, 0, 0, 0, 0]
+        );
+        let last = 0x01 | (3 << 1);
+        let reply = sdo(
+            &mut node,
+            &mut bus,
+            [last, gain[0], gain[1], gain[2], gain[3], 0, 0, 0],
+        );
+        assert_eq!(reply[0], 0x20);
+        assert_eq!(node.dictionary().get_f32(GAINS, 1), 12.5);
+
+        // Wrong toggle.
+        sdo(&mut node, &mut bus, [0x21, 0x00, 0x20, 1, 4, 0, 0, 0]);
+        assert_eq!(
+            abort_code(sdo(&mut node, &mut bus, [0x10 | last, 0, 0, 0, 0, 0, 0, 0])),
+            0x0503_0000
+        );
+
+        // Abandoned by the master.
+        sdo(&mut node, &mut bus, [0x21, 0x00, 0x20, 1, 4, 0, 0, 0]);
+        bus.take();
+        node.poll(999, &at(0.), &mut bus);
+        assert!(bus.frames.iter().all(|frame| frame.cob_id != 0x585));
+        node.poll(1000, &at(0.), &mut bus);
+        let reply = bus
+            .frames
+            .iter()
+            .find(|frame| frame.cob_id == 0x585)
+            .unwrap();
+        assert_eq!(abort_code(reply.data), 0x0504_0000);
+    }
+
+    #[test]
+    fn nmt_and_heartbeat() {
+        let mut bus = Bus::default();
+        let mut node = Node::new(ID);
+        assert_eq!(node.state(), NmtState::Initialising);
+        node.start(0, &mut bus);
+        assert_eq!(bus.frames, vec![CanFrame::new(0x705, &[0])]);
+        assert_eq!(node.state(), NmtState::PreOperational);
+        bus.take();
+
+        node.poll(999, &at(0.), &mut bus);
+        assert!(bus.frames.is_empty());
+        node.poll(1000, &at(0.), &mut bus);
+        assert_eq!(bus.frames, vec![CanFrame::new(0x705, &[0x7F])]);
+
+        // Someone else's.
+        receive(&mut node, &mut bus, 0x000, &[0x01, ID + 1], &at(0.));
+        assert_eq!(node.state(), NmtState::PreOperational);
+        receive(&mut node, &mut bus, 0x000, &[0x01, ID], &at(0.));
+        assert_eq!(node.state(), NmtState::Operational);
+        // Everyone's.
+        receive(&mut node, &mut bus, 0x000, &[0x02, 0], &at(0.));
+        assert_eq!(node.state(), NmtState::Stopped);
+        bus.take();
+        receive(
+            &mut node,
+            &mut bus,
+            0x605,
+            &[0x40, 0, 0x10, 0, 0, 0, 0, 0],
+            &at(0.),
+        );
+        assert!(bus.frames.is_empty());
+        node.poll(2000, &at(0.), &mut bus);
+        assert_eq!(bus.frames, vec![CanFrame::new(0x705, &[0x04])]);
+
+        receive(&mut node, &mut bus, 0x000, &[0x80, ID], &at(0.));
+        download(&mut node, &mut bus, HEARTBEAT_TIME, 0, &0u16.to_le_bytes());
+        bus.take();
+        node.poll(10_000, &at(0.), &mut bus);
+        assert!(bus.frames.is_empty());
+
+        receive(&mut node, &mut bus, 0x000, &[0x82, ID], &at(0.));
+        assert_eq!(bus.frames, vec![CanFrame::new(0x705, &[0])]);
+        assert_eq!(node.dictionary().get(HEARTBEAT_TIME, 0), 1000);
+        assert_eq!(node.state(), NmtState::PreOperational);
+    }
+
+    #[test]
+    fn profile_torque() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        download(&mut node, &mut bus, MODES_OF_OPERATION, 0, &[4]);
+        download(
+            &mut node,
+            &mut bus,
+            RATED_CURRENT,
+            0,
+            &2000u32.to_le_bytes(),
+        );
+        start(&mut node, &mut bus);
+        node.dictionary_mut().set_i32(TARGET_TORQUE, 0, 250);
+        assert_eq!(node.drive_state(), DriveState::SwitchOnDisabled);
+
+        controlword(&mut node, &mut bus, 0x06, &at(0.));
+        assert_eq!(node.drive_state(), DriveState::ReadyToSwitchOn);
+        assert_eq!(node.dictionary().get(STATUSWORD, 0) & 0x6F, 0x21);
+        controlword(&mut node, &mut bus, 0x07, &at(0.));
+        assert_eq!(node.drive_state(), DriveState::SwitchedOn);
+        assert!(bus.take().1.is_empty());
+
+        controlword(&mut node, &mut bus, 0x0F, &at(0.));
+        assert_eq!(node.drive_state(), DriveState::OperationEnabled);
+        assert_eq!(node.dictionary().get(STATUSWORD, 0), 0x237);
+        assert_eq!(node.dictionary().get_i32(MODES_OF_OPERATION_DISPLAY, 0), 4);
+        // A quarter of 2A.
+        assert_eq!(bus.take().1, vec![Action::Torque(0.5)]);
+
+        // Same again only refreshes.
+        controlword(&mut node, &mut bus, 0x0F, &at(0.));
+        assert_eq!(bus.take().1, vec![Action::Refresh]);
+
+        // As much as 0x6071 can ask for is still held to what the native protocol allows.
+        node.dictionary_mut()
+            .set_i32(TARGET_TORQUE, 0, i16::MAX as i32);
+        controlword(&mut node, &mut bus, 0x0F, &at(0.));
+        assert_eq!(
+            bus.take().1,
+            vec![Action::Refresh, Action::Torque(MAX_CURRENT)]
+        );
+
+        // Quick stop.
+        controlword(&mut node, &mut bus, 0x0B, &at(0.));
+        assert_eq!(node.drive_state(), DriveState::QuickStopActive);
+        assert_eq!(bus.take().1, vec![Action::Refresh, Action::Disable]);
+        node.poll(0, &at(0.), &mut bus);
+        assert_eq!(node.drive_state(), DriveState::SwitchOnDisabled);
+    }
+
+    #[test]
+    fn profile_position_and_velocity() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        download(&mut node, &mut bus, MODES_OF_OPERATION, 0, &[1]);
+        download(&mut node, &mut bus, GAINS, 1, &2f32.to_le_bytes());
+        download(&mut node, &mut bus, GAINS, 2, &0.5f32.to_le_bytes());
+        start(&mut node, &mut bus);
+
+        // Holds where it is until there's a setpoint.
+        enable(&mut node, &mut bus, &at(1.));
+        let hold = |position, velocity, stiffness_gain| {
+            Action::PosVel(bldc::control_loops::pos_vel_control::PosVelState {
+                position,
+                velocity,
+                stiffness_gain,
+                damping_gain: 0.5,
+                torque_constant: 1.,
+            })
+        };
+        assert_eq!(bus.take().1, vec![hold(1., 0., 2.)]);
+        assert_ne!(node.dictionary().get(STATUSWORD, 0) & 1 << 10, 0);
+
+        // RPDO2: controlword + target position, with the new setpoint bit.
+        let target = 2000i32.to_le_bytes();
+        receive(
+            &mut node,
+            &mut bus,
+            0x305,
+            &[0x1F, 0, target[0], target[1], target[2], target[3]],
+            &at(1.),
+        );
+        assert_eq!(bus.take().1, vec![Action::Refresh, hold(2., 0., 2.)]);
+        let statusword = node.dictionary().get(STATUSWORD, 0);
+        assert_ne!(statusword & 1 << 12, 0);
+        assert_eq!(statusword & 1 << 10, 0);
+        node.poll(0, &at(1.995), &mut bus);
+        assert_ne!(node.dictionary().get(STATUSWORD, 0) & 1 << 10, 0);
+        node.poll(0, &at(-0.5), &mut bus);
+        assert_eq!(node.dictionary().get_i32(POSITION_ACTUAL, 0), -500);
+
+        // Switching to profile velocity drops the stiffness.
+        node.dictionary_mut().set_i32(TARGET_VELOCITY, 0, -3000);
+        download(&mut node, &mut bus, MODES_OF_OPERATION, 0, &[3]);
+        assert_eq!(bus.take().1, vec![hold(0., -3., 0.)]);
+    }
+
+    #[test]
+    fn faults_raise_emergencies() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        download(&mut node, &mut bus, MODES_OF_OPERATION, 0, &[4]);
+        start(&mut node, &mut bus);
+        enable(&mut node, &mut bus, &at(0.));
+        bus.take();
+
+        let overcurrent = faulted(Fault::Overcurrent);
+        node.poll(0, &overcurrent, &mut bus);
+        assert_eq!(node.drive_state(), DriveState::Fault);
+        assert_eq!(node.dictionary().get(ERROR_CODE, 0), 0x2300);
+        assert_eq!(node.dictionary().get(ERROR_REGISTER, 0), 0b11);
+        let (frames, actions) = bus.take();
+        assert_eq!(actions, vec![Action::Disable]);
+        let bit = 1u32 << Fault::Overcurrent as u32;
+        let bits = bit.to_le_bytes();
+        assert_eq!(
+            emergencies(frames),
+            vec![CanFrame::new(
+                0x085,
+                &[
+                    0x00,
+                    0x23,
+                    0b11,
+                    Fault::Overcurrent as u8,
+                    bits[0],
+                    bits[1],
+                    bits[2],
+                    bits[3]
+                ]
+            )]
+        );
+        // Only the once.
+        node.poll(0, &overcurrent, &mut bus);
+        assert!(emergencies(bus.take().0).is_empty());
+
+        // Enabling does nothing until it's been reset.
+        controlword(&mut node, &mut bus, 0x0F, &overcurrent);
+        assert_eq!(node.drive_state(), DriveState::Fault);
+        controlword(&mut node, &mut bus, 0x80, &overcurrent);
+        assert_eq!(node.drive_state(), DriveState::SwitchOnDisabled);
+        assert_eq!(bus.take().1, vec![Action::ClearFaults]);
+        node.poll(0, &at(0.), &mut bus);
+        assert_eq!(
+            emergencies(bus.take().0),
+            vec![CanFrame::new(0x085, &[0, 0, 0, 0xFF, 0, 0, 0, 0])]
+        );
+        assert_eq!(node.dictionary().get(ERROR_CODE, 0), 0);
+        enable(&mut node, &mut bus, &at(0.));
+
+        // Warnings show up in the statusword, but don't stop anything.
+        node.poll(0, &faulted(Fault::DrvOvertemperatureWarning), &mut bus);
+        assert_eq!(node.drive_state(), DriveState::OperationEnabled);
+        assert_ne!(node.dictionary().get(STATUSWORD, 0) & 1 << 7, 0);
+    }
+
+    #[test]
+    fn pdo_mapping() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        let entry = |index: u16, sub: u8, bits: u32| mapping(index, sub, bits).to_le_bytes();
+
+        // Can't touch the mapping of a PDO that's in use.
+        assert_eq!(
+            abort_code(download(&mut node, &mut bus, 0x1A01, 0, &[0])),
+            0x0800_0022
+        );
+        let invalid = (PDO_INVALID | 0x285).to_le_bytes();
+        download(&mut node, &mut bus, 0x1801, PDO_COB_ID, &invalid);
+        // Nor the entries while there are any mapped.
+        assert_eq!(
+            abort_code(download(
+                &mut node,
+                &mut bus,
+                0x1A01,
+                1,
+                &entry(ERROR_CODE, 0, 16)
+            )),
+            0x0800_0022
+        );
+        assert_eq!(download(&mut node, &mut bus, 0x1A01, 0, &[0])[0], 0x60);
+        assert_eq!(
+            abort_code(download(
+                &mut node,
+                &mut bus,
+                0x1A01,
+                1,
+                &entry(DEVICE_TYPE, 0, 32)
+            )),
+            0x0604_0041
+        );
+        assert_eq!(
+            abort_code(download(
+                &mut node,
+                &mut bus,
+                0x1A01,
+                1,
+                &entry(STATUSWORD, 0, 8)
+            )),
+            0x0604_0041
+        );
+        for sub in 1..=3 {
+            download(
+                &mut node,
+                &mut bus,
+                0x1A01,
+                sub,
+                &entry(POSITION_ACTUAL, 0, 32),
+            );
+        }
+        assert_eq!(
+            abort_code(download(&mut node, &mut bus, 0x1A01, 0, &[3])),
+            0x0604_0042
+        );
+        download(&mut node, &mut bus, 0x1A01, 2, &entry(ERROR_REGISTER, 0, 8));
+        assert_eq!(download(&mut node, &mut bus, 0x1A01, 0, &[2])[0], 0x60);
+        download(
+            &mut node,
+            &mut bus,
+            0x1801,
+            PDO_COB_ID,
+            &0x285u32.to_le_bytes(),
+        );
+
+        // Received ones have to be writable.
+        download(
+            &mut node,
+            &mut bus,
+            0x1400,
+            PDO_COB_ID,
+            &(PDO_INVALID | 0x205).to_le_bytes(),
+        );
+        download(&mut node, &mut bus, 0x1600, 0, &[0]);
+        assert_eq!(
+            abort_code(download(
+                &mut node,
+                &mut bus,
+                0x1600,
+                1,
+                &entry(STATUSWORD, 0, 16)
+            )),
+            0x0604_0041
+        );
+
+        // Every SYNC.
+        start(&mut node, &mut bus);
+        node.poll(0, &at(0.25), &mut bus);
+        bus.take();
+        receive(&mut node, &mut bus, 0x080, &[], &at(0.25));
+        let (frames, actions) = bus.take();
+        assert_eq!(actions, vec![Action::Heartbeat]);
+        let position = 250i32.to_le_bytes();
+        assert!(frames.contains(&CanFrame::new(
+            0x285,
+            &[position[0], position[1], position[2], position[3], 0]
+        )));
+
+        // Too short for controlword + target velocity.
+        receive(&mut node, &mut bus, 0x405, &[0x0F, 0, 1], &at(0.));
+        let frames = emergencies(bus.take().0);
+        assert_eq!(frames[0].data[..2], [0x10, 0x82]);
+        assert_eq!(node.dictionary().get(CONTROLWORD, 0), 0);
+    }
+
+    #[test]
+    fn event_tpdos() {
+        let mut bus = Bus::default();
+        let mut node = started(&mut bus);
+        start(&mut node, &mut bus);
+        // TPDO1 is statusword + mode display, whenever it changes.
+        node.poll(0, &at(0.), &mut bus);
+        let tpdo1 = |frames: &[CanFrame]| frames.iter().filter(|f| f.cob_id == 0x185).count();
+        assert_eq!(tpdo1(&bus.take().0), 1);
+        node.poll(1, &at(0.), &mut bus);
+        assert_eq!(tpdo1(&bus.take().0), 0);
+
+        download(
+            &mut node,
+            &mut bus,
+            0x1800,
+            PDO_EVENT_TIMER,
+            &100u16.to_le_bytes(),
+        );
+        download(
+            &mut node,
+            &mut bus,
+            0x1800,
+            PDO_INHIBIT_TIME,
+            &500u16.to_le_bytes(),
+        );
+        node.poll(100, &at(0.), &mut bus);
+        assert_eq!(tpdo1(&bus.take().0), 1);
+        // Changed, but inside the inhibit time.
+        controlword(&mut node, &mut bus, 0x06, &at(0.));
+        node.poll(149, &at(0.), &mut bus);
+        assert_eq!(tpdo1(&bus.take().0), 0);
+        node.poll(150, &at(0.), &mut bus);
+        assert_eq!(tpdo1(&bus.take().0), 1);
+
+        // None once it's back to pre-operational, and the drive's disabled.
+        enable(&mut node, &mut bus, &at(0.));
+        receive(&mut node, &mut bus, 0x000, &[0x80, 0], &at(0.));
+        assert_eq!(node.drive_state(), DriveState::SwitchOnDisabled);
+        bus.take();
+        node.poll(1000, &at(0.), &mut bus);
+        assert_eq!(tpdo1(&bus.take().0), 0);
+    }
+}
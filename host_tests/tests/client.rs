@@ -0,0 +1,283 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use pino::bus::Bus;
+    use pino::{Client, Error, Loopback, SimulatedNode, SocketCan, State};
+    use protocol::ack::AckStatus;
+    use protocol::group::{GroupSetpoints, Setpoint};
+    use protocol::id::{Address, CanId, NodeAddress};
+    use protocol::messages::{
+        AckMsg, DisableControlLoopCmd, EnterPosVelControlCmd, GetParamCmd, MessageID, SetPosVelCmd,
+        StatusMsg, SyncCmd,
+    };
+    use protocol::params::{ParamStatus, ParamValue};
+    use protocol::{FdcanMessage, Frame};
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    // Runs a simulated node on its own thread until the returned flag's set.
+    fn spawn_node<B: Bus + Send + 'static>(bus: B, node: u8, group: u8) -> Arc<AtomicBool> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        thread::spawn(move || {
+            let mut sim = SimulatedNode::new(bus, NodeAddress::new(node as u32, group as u32));
+            sim.run(&flag).unwrap();
+        });
+        stop
+    }
+
+    fn wait_for_status<B: Bus>(client: &mut Client<B>, matches: impl Fn(&StatusMsg) -> bool) {
+        for _ in 0..100 {
+            if let Some(State::Status(status)) = client.next_state(TIMEOUT).unwrap() {
+                if matches(&status) {
+                    return;
+                }
+            }
+        }
+        panic!("Never saw the status we were after");
+    }
+
+    #[test]
+    fn params_against_simulated_node() {
+        let bus = Loopback::new();
+        let stop = spawn_node(bus.endpoint(), 3, 0);
+        let mut client = Client::new(bus.endpoint(), 3).with_timeout(TIMEOUT);
+
+        let params = client.list_params().unwrap();
+        let kp = params
+            .iter()
+            .find(|info| info.name == "current_kp")
+            .unwrap();
+        assert_eq!(client.get_param(kp.id).unwrap(), kp.value);
+
+        assert_eq!(
+            client.set_param(kp.id, ParamValue::F32(2.5)).unwrap(),
+            ParamValue::F32(2.5)
+        );
+        assert_eq!(client.get_param(kp.id).unwrap(), ParamValue::F32(2.5));
+        assert!(matches!(
+            client.set_param(kp.id, ParamValue::F32(-1.)),
+            Err(Error::Param(ParamStatus::OutOfRange))
+        ));
+        assert!(matches!(
+            client.get_param(0xFFFF),
+            Err(Error::Param(ParamStatus::UnknownParam))
+        ));
+
+        client.restore_default_params().unwrap();
+        assert_eq!(client.get_param(kp.id).unwrap(), kp.value);
+        client.save_params().unwrap();
+
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn only_hears_its_own_node() {
+        let bus = Loopback::new();
+        let stop = spawn_node(bus.endpoint(), 1, 0);
+        let mut other = Client::new(bus.endpoint(), 2).with_timeout(Duration::from_millis(50));
+        assert!(matches!(other.faults(), Err(Error::Timeout)));
+        assert_eq!(other.next_state(Duration::from_millis(300)).unwrap(), None);
+
+        let mut client = Client::new(bus.endpoint(), 1).with_timeout(TIMEOUT);
+        assert_eq!(client.faults().unwrap().active, 0);
+
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    // Addressed as if it came from the host.
+    fn from_host(message: &impl Frame, address: Address) -> FdcanMessage {
+        let mut frame = message.encode();
+        frame.id = CanId {
+            command: frame.id as u8,
+            address,
+            from_node: false,
+            transaction: 0,
+        }
+        .encode();
+        frame
+    }
+
+    #[test]
+    fn pos_vel_control_moves_the_rotor() {
+        // Commands don't wait on replies, so the node can be run in between them instead.
+        let bus = Loopback::new();
+        let mut sim = SimulatedNode::new(bus.endpoint(), NodeAddress::new(1, 2));
+        let mut client = Client::new(bus.endpoint(), 1).with_timeout(TIMEOUT);
+
+        client.enter_pos_vel_control().unwrap();
+        client
+            .set_pos_vel(SetPosVelCmd {
+                position: 1.,
+                velocity: 0.,
+                stiffness_gain: 0.05,
+                damping_gain: 0.002,
+                torque_constant: 0.047,
+            })
+            .unwrap();
+        sim.poll(Duration::from_millis(500)).unwrap();
+        assert!((sim.position() - 1.).abs() < 0.05, "{}", sim.position());
+
+        // Group setpoints only land on the sync, which goes to everyone.
+        let mut raw = bus.endpoint();
+        let group = GroupSetpoints::new(&[Setpoint {
+            node: 1,
+            position: -1.,
+            velocity: 0.,
+        }]);
+        raw.send(&from_host(&group, Address::Group(2))).unwrap();
+        sim.poll(Duration::from_millis(200)).unwrap();
+        assert!((sim.position() - 1.).abs() < 0.05, "{}", sim.position());
+        raw.send(&from_host(&SyncCmd {}, Address::Broadcast))
+            .unwrap();
+        sim.poll(Duration::from_millis(500)).unwrap();
+        assert!((sim.position() + 1.).abs() < 0.05, "{}", sim.position());
+
+        client.disable().unwrap();
+        sim.poll(Duration::from_millis(200)).unwrap();
+        wait_for_status(&mut client, |status| status.mode == 0);
+    }
+
+    #[test]
+    fn commands_are_acked() {
+        let bus = Loopback::new();
+        let stop = spawn_node(bus.endpoint(), 1, 0);
+        let mut client = Client::new(bus.endpoint(), 1).with_timeout(TIMEOUT);
+        let target = SetPosVelCmd {
+            position: 1.,
+            velocity: 0.,
+            stiffness_gain: 0.05,
+            damping_gain: 0.002,
+            torque_constant: 0.047,
+        };
+
+        // Nothing to follow it yet.
+        assert!(matches!(
+            client.command(&target),
+            Err(Error::Nack(AckStatus::Rejected, 0))
+        ));
+        client.command(&EnterPosVelControlCmd {}).unwrap();
+        client.command(&target).unwrap();
+        client.command(&DisableControlLoopCmd {}).unwrap();
+        // Turned down, but with a reply of its own too.
+        assert!(matches!(
+            client.command(&GetParamCmd { id: 0xFFFF }),
+            Err(Error::Nack(AckStatus::Rejected, 0))
+        ));
+
+        // Short frames are turned away rather than read as zeroes.
+        let mut raw = bus.endpoint();
+        let mut frame = from_host(&target, Address::Node(1));
+        frame.size = 8;
+        frame.id |= 7 << 21;
+        raw.send(&frame).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        let ack = loop {
+            let mut reply = raw
+                .receive(deadline.saturating_duration_since(Instant::now()))
+                .unwrap()
+                .expect("Never got an ack");
+            if reply.id as u8 == MessageID::Ack as u8 {
+                reply.id = MessageID::Ack as u32;
+                break AckMsg::decode(&reply).unwrap();
+            }
+        };
+        assert!(ack.acks(MessageID::SetPosVel, 7));
+        assert_eq!(AckStatus::from_raw(ack.status), Some(AckStatus::Malformed));
+        assert_eq!(ack.detail, 20);
+
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn bad_setpoints_are_reported() {
+        let bus = Loopback::new();
+        let stop = spawn_node(bus.endpoint(), 1, 0);
+        let mut client = Client::new(bus.endpoint(), 1).with_timeout(TIMEOUT);
+        client.command(&EnterPosVelControlCmd {}).unwrap();
+
+        // Streamed, so nobody asked for an ack, but it still gets one saying why it was dropped.
+        client
+            .set_pos_vel(SetPosVelCmd {
+                position: f32::NAN,
+                velocity: 0.,
+                stiffness_gain: 0.05,
+                damping_gain: 0.002,
+                torque_constant: 0.047,
+            })
+            .unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        let ack = loop {
+            match client
+                .next_state(deadline.saturating_duration_since(Instant::now()))
+                .unwrap()
+            {
+                Some(State::Dropped(ack)) => break ack,
+                Some(_) => continue,
+                None => panic!("Bad setpoint was never reported"),
+            }
+        };
+        assert!(ack.acks(MessageID::SetPosVel, 0));
+        assert_eq!(AckStatus::from_raw(ack.status), Some(AckStatus::Invalid));
+        assert_eq!(ack.detail, 0);
+
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn ignores_frames_from_other_versions() {
+        let bus = Loopback::new();
+        let stop = spawn_node(bus.endpoint(), 1, 0);
+        let mut raw = bus.endpoint();
+        let mut frame = from_host(&GetParamCmd { id: 0x01 }, Address::Node(1));
+        // Right address, but the wrong version.
+        frame.id ^= 1 << 17;
+        raw.send(&frame).unwrap();
+
+        // Status still goes out as usual, but nothing answers the request.
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while let Some(reply) = raw
+            .receive(deadline.saturating_duration_since(Instant::now()))
+            .unwrap()
+        {
+            assert_ne!(reply.id as u8, MessageID::ParamValue as u8);
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    // Same as above, but over a real socket. Needs a vcan interface, which needs root to set up:
+    //
+    //   sudo ip link add dev vcan0 type vcan && sudo ip link set vcan0 mtu 72 up
+    //
+    // and then `cargo test -- --ignored params_over_vcan`.
+    #[test]
+    #[ignore = "needs vcan0, see above"]
+    fn params_over_vcan() {
+        let node = SocketCan::open("vcan0").expect("No vcan0");
+        let host = SocketCan::open("vcan0").expect("No vcan0");
+        let stop = spawn_node(node, 7, 0);
+        let mut client = Client::new(host, 7).with_timeout(TIMEOUT);
+
+        let params = client.list_params().unwrap();
+        let pole_pairs = params
+            .iter()
+            .find(|info| info.name == "pole_pairs")
+            .unwrap();
+        assert_eq!(
+            client
+                .set_param(pole_pairs.id, ParamValue::U32(14))
+                .unwrap(),
+            ParamValue::U32(14)
+        );
+        wait_for_status(&mut client, |status| status.mode == 0);
+
+        stop.store(true, Ordering::Relaxed);
+    }
+}
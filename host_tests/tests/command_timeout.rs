@@ -0,0 +1,122 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, motor};
+    use bldc::config::{self, Config};
+    use bldc::control_loops::command_timeout::{self, CommandTimeout};
+    use bldc::control_loops::pos_vel_control::{PosVelState, PositionVelocity};
+    use bldc::fault::{self, Fault, Faults};
+    use bldc::sim::{MotorParameters, Simulator};
+
+    const LOOP_FREQUENCY: f32 = 40_000.;
+
+    fn current_magnitude(sim: &Simulator) -> f32 {
+        let currents = sim.motor.phase_currents();
+        (2. / 3. * (currents.phase_a.powi(2) + currents.phase_b.powi(2) + currents.phase_c.powi(2)))
+            .sqrt()
+    }
+
+    #[test]
+    fn only_fresh_commands_recover() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        // 1ms.
+        let timeout = 0.001;
+        let mut deadman = CommandTimeout::new();
+        for _ in 0..100 {
+            command_timeout::heartbeat();
+            deadman.update(timeout, LOOP_FREQUENCY);
+        }
+        assert!(!deadman.timed_out());
+
+        for _ in 0..40 {
+            deadman.update(timeout, LOOP_FREQUENCY);
+        }
+        assert!(deadman.timed_out());
+        assert!(fault::active().contains(Fault::CommandTimeout));
+        assert_eq!(deadman.ramp(), 1.);
+        deadman.update(timeout, LOOP_FREQUENCY);
+        assert!(deadman.ramp() < 1.);
+
+        command_timeout::heartbeat();
+        deadman.update(timeout, LOOP_FREQUENCY);
+        assert!(deadman.timed_out());
+        command_timeout::command();
+        deadman.update(timeout, LOOP_FREQUENCY);
+        assert!(!deadman.timed_out());
+        assert_eq!(deadman.ramp(), 1.);
+
+        // Zero disables it.
+        for _ in 0..10_000 {
+            deadman.update(0., LOOP_FREQUENCY);
+        }
+        assert!(!deadman.timed_out());
+        fault::clear(Faults::ALL);
+    }
+
+    #[test]
+    fn pos_vel_ramps_down_without_commands() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+        config::set(Config {
+            pos_vel_timeout: 0.05,
+            ..Config::DEFAULT
+        });
+
+        let mut sim = Simulator::new(
+            MotorParameters {
+                // Something to hold against.
+                load_torque: 0.02,
+                ..motor()
+            },
+            24.,
+        );
+        let mut position_control = PositionVelocity::new();
+        let hold = || {
+            PositionVelocity::command(PosVelState {
+                position: 0.,
+                velocity: 0.,
+                stiffness_gain: 4.5,
+                damping_gain: 0.03,
+                torque_constant: 1.5 * 21. * 0.0015,
+            })
+        };
+        hold();
+
+        // Heartbeats keep it going...
+        sim.run(&mut position_control, 0.2, |_| command_timeout::heartbeat());
+        let timed_out = fault::active().contains(Fault::CommandTimeout);
+        let holding = current_magnitude(&sim);
+
+        // ...until they stop.
+        let mut peak_after_ramp: f32 = 0.;
+        sim.run(&mut position_control, 0.3, |sim| {
+            if sim.time() > 0.2 + 0.05 + 0.1 + 0.01 {
+                peak_after_ramp = peak_after_ramp.max(current_magnitude(sim));
+            }
+        });
+        let active = fault::active();
+
+        // A fresh command picks things back up.
+        hold();
+        sim.run(&mut position_control, 0.05, |_| {
+            command_timeout::heartbeat()
+        });
+        let resumed = current_magnitude(&sim);
+
+        config::set(Config::DEFAULT);
+        fault::clear(Faults::ALL);
+
+        assert!(!timed_out);
+        assert!(holding > 0.2, "Holding with {}A", holding);
+        assert_eq!(active, Fault::CommandTimeout.into());
+        assert!(
+            peak_after_ramp < 0.05,
+            "{}A after ramping down",
+            peak_after_ramp
+        );
+        assert!(resumed > 0.2, "Resumed with {}A", resumed);
+    }
+}
@@ -0,0 +1,40 @@
+// Bits shared between the test files. Each file under tests/ is its own crate and only uses some of
+// what's in here.
+#![allow(dead_code)]
+
+use bldc::sim::MotorParameters;
+use std::sync::{Mutex, MutexGuard};
+
+// Roughly the motor the current loop gains were tuned for: k / ki line up with L and R.
+pub fn motor() -> MotorParameters {
+    MotorParameters {
+        resistance: 0.32,
+        inductance_d: 143e-6,
+        inductance_q: 143e-6,
+        flux_linkage: 0.0015,
+        pole_pairs: 21,
+        inertia: 5e-5,
+        friction: 1e-5,
+        load_torque: 0.,
+    }
+}
+
+// Same motor, but heavy enough that the rotor barely moves and back-EMF stays out of the picture.
+pub fn heavy_motor() -> MotorParameters {
+    MotorParameters {
+        inertia: 100.,
+        friction: 0.,
+        ..motor()
+    }
+}
+
+static GLOBALS: Mutex<()> = Mutex::new(());
+
+// Config, fault state, command counters and where the loops leave their results are all global, so
+// tests that touch any of them take turns. One that fails while holding this shouldn't take the
+// rest down with it, hence ignoring the poisoning.
+pub fn globals() -> MutexGuard<'static, ()> {
+    GLOBALS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
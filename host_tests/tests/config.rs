@@ -0,0 +1,230 @@
+#[cfg(test)]
+mod tests {
+    use bldc::config::{Config, ConfigStore, Flash, FlashError, LoadError, CONFIG_WORDS};
+
+    // Small pages so that a handful of saves is enough to wrap around.
+    const PAGE_SIZE: u32 = 1024;
+    const PAGE_WORDS: usize = PAGE_SIZE as usize / 4;
+
+    // Behaves like NOR flash: erasing sets every bit, programming can only clear them.
+    struct MockFlash {
+        words: Vec<u32>,
+        erases: [u32; 2],
+        // Number of double words to program before "losing power".
+        power_budget: Option<usize>,
+    }
+
+    impl MockFlash {
+        fn new() -> MockFlash {
+            MockFlash {
+                words: vec![0xFFFF_FFFF; 2 * PAGE_WORDS],
+                erases: [0; 2],
+                power_budget: None,
+            }
+        }
+    }
+
+    impl Flash for MockFlash {
+        const PAGE_SIZE: u32 = PAGE_SIZE;
+
+        fn read(&self, offset: u32, buffer: &mut [u32]) {
+            let start = offset as usize / 4;
+            buffer.copy_from_slice(&self.words[start..start + buffer.len()]);
+        }
+
+        fn erase_page(&mut self, page: u32) -> Result<(), FlashError> {
+            let start = page as usize * PAGE_WORDS;
+            self.words[start..start + PAGE_WORDS].fill(0xFFFF_FFFF);
+            self.erases[page as usize] += 1;
+            Ok(())
+        }
+
+        fn program(&mut self, offset: u32, data: &[u32]) -> Result<(), FlashError> {
+            assert_eq!(offset % 8, 0, "Unaligned program at {}", offset);
+            assert_eq!(data.len() % 2, 0, "Partial double word");
+            let start = offset as usize / 4;
+            for (i, double_word) in data.chunks(2).enumerate() {
+                if let Some(budget) = &mut self.power_budget {
+                    if *budget == 0 {
+                        return Err(FlashError::Programming);
+                    }
+                    *budget -= 1;
+                }
+                for (j, word) in double_word.iter().enumerate() {
+                    let current = &mut self.words[start + 2 * i + j];
+                    assert_eq!(*current, 0xFFFF_FFFF, "Programming over unerased flash");
+                    *current &= word;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn config(pole_pairs: u8) -> Config {
+        Config {
+            pole_pairs,
+            gear_ratio: 9.,
+            ..Config::DEFAULT
+        }
+    }
+
+    #[test]
+    fn serialization_round_trip() {
+        let config = config(7);
+        assert_eq!(Config::from_words(&config.to_words()), config);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let words = config(7).to_words();
+        let truncated = Config::from_words(&words[..4]);
+        assert_eq!(truncated.gear_ratio, 9.);
+        assert_eq!(truncated.pole_pairs, Config::DEFAULT.pole_pairs);
+        assert_eq!(truncated.sense_gain, Config::DEFAULT.sense_gain);
+
+        // Extra words from a newer layout are ignored.
+        let mut extended = [0u32; CONFIG_WORDS + 2];
+        extended[..CONFIG_WORDS].copy_from_slice(&words);
+        assert_eq!(Config::from_words(&extended), config(7));
+    }
+
+    #[test]
+    fn empty_flash() {
+        let store = ConfigStore::new(MockFlash::new());
+        assert_eq!(store.load(), Err(LoadError::Empty));
+        assert_eq!(store.load().unwrap_or_default(), Config::DEFAULT);
+    }
+
+    #[test]
+    fn save_and_reload() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.save(&config(7)).unwrap();
+        assert_eq!(store.load(), Ok(config(7)));
+
+        let store = ConfigStore::new(store.release());
+        assert_eq!(store.load(), Ok(config(7)));
+    }
+
+    #[test]
+    fn wear_levels_across_pages() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        for i in 0..100 {
+            store.save(&config(i)).unwrap();
+            // Reopen every so often, which should pick up where it left off.
+            if i % 7 == 0 {
+                store = ConfigStore::new(store.release());
+            }
+        }
+        let flash = store.release();
+        // Several records fit in a page, so erases should be well under one per save and split
+        // evenly between the pages.
+        let [a, b] = flash.erases;
+        assert!(a + b < 30, "{} erases for 100 saves", a + b);
+        assert!(
+            (a as i32 - b as i32).abs() <= 1,
+            "Uneven wear: {} vs {}",
+            a,
+            b
+        );
+
+        let store = ConfigStore::new(flash);
+        assert_eq!(store.load(), Ok(config(99)));
+    }
+
+    #[test]
+    fn corrupted_record_falls_back_to_previous() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.save(&config(1)).unwrap();
+        store.save(&config(2)).unwrap();
+        let mut flash = store.release();
+        // Flip a bit in the newest record's payload.
+        let newest = flash.words.iter().rposition(|w| *w != 0xFFFF_FFFF).unwrap();
+        flash.words[newest - 2] ^= 0x10;
+
+        let mut store = ConfigStore::new(flash);
+        assert_eq!(store.load(), Ok(config(1)));
+        // And saving again still works, and wins.
+        store.save(&config(3)).unwrap();
+        assert_eq!(ConfigStore::new(store.release()).load(), Ok(config(3)));
+    }
+
+    #[test]
+    fn inconsistent_record_falls_back_to_previous() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.save(&config(1)).unwrap();
+        // Not something `params` would let through, but older firmware might've saved it.
+        store
+            .save(&Config {
+                min_v_bus: 40.,
+                max_v_bus: 20.,
+                ..config(2)
+            })
+            .unwrap();
+
+        let store = ConfigStore::new(store.release());
+        assert_eq!(store.load(), Ok(config(1)));
+    }
+
+    #[test]
+    fn out_of_range_record_falls_back_to_previous() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.save(&config(1)).unwrap();
+        store
+            .save(&Config {
+                max_duty: 2.,
+                ..config(2)
+            })
+            .unwrap();
+
+        let store = ConfigStore::new(store.release());
+        assert_eq!(store.load(), Ok(config(1)));
+    }
+
+    #[test]
+    fn all_records_corrupted() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.save(&config(1)).unwrap();
+        let mut flash = store.release();
+        flash.words[0] = 0xDEAD_BEEF;
+
+        let store = ConfigStore::new(flash);
+        assert_eq!(store.load(), Err(LoadError::Corrupted));
+        assert_eq!(store.load().unwrap_or_default(), Config::DEFAULT);
+    }
+
+    #[test]
+    fn interrupted_save_keeps_previous_config() {
+        // Cover losing power both within a page and right after switching over to the other one.
+        for saves in 1..8 {
+            // Every possible point partway through programming a record.
+            for budget in 0..6 {
+                let mut store = ConfigStore::new(MockFlash::new());
+                for i in 1..=saves {
+                    store.save(&config(i)).unwrap();
+                }
+                let previous = config(saves);
+
+                let mut flash = store.release();
+                flash.power_budget = Some(budget);
+                let mut store = ConfigStore::new(flash);
+                assert!(store.save(&config(100)).is_err());
+
+                let mut flash = store.release();
+                flash.power_budget = None;
+                let mut store = ConfigStore::new(flash);
+                assert_eq!(
+                    store.load(),
+                    Ok(previous),
+                    "{} saves, {} double words",
+                    saves,
+                    budget
+                );
+
+                // Flash should still be usable afterwards.
+                store.save(&config(101)).unwrap();
+                let store = ConfigStore::new(store.release());
+                assert_eq!(store.load(), Ok(config(101)));
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use bldc::crash::{CrashRecord, SavedCrash};
+
+    #[test]
+    fn long_text_is_truncated() {
+        let record = CrashRecord::new("src/main.rs", 12, 34, &"oh no");
+        assert_eq!(record.file(), "src/main.rs");
+        assert_eq!(record.message(), "oh no");
+        assert_eq!((record.line, record.column), (12, 34));
+        assert_eq!(record.file_words()[0], u32::from_le_bytes(*b"src/"));
+
+        // File names keep the end, since that's the interesting part.
+        let file = format!("{}/control_loops/interrupt.rs", "deeply/nested".repeat(10));
+        let message = format!(
+            "called `Option::unwrap()` on a `None` value {}",
+            "!".repeat(100)
+        );
+        let record = CrashRecord::new(&file, 1, 2, &message);
+        assert_eq!(record.file().len(), 56);
+        assert!(file.ends_with(record.file()));
+        assert_eq!(record.message().len(), 64);
+        assert!(message.starts_with(record.message()));
+
+        // Don't split characters.
+        let record = CrashRecord::new("", 0, 0, &"é".repeat(40));
+        assert_eq!(record.message(), "é".repeat(32));
+    }
+
+    #[test]
+    fn saved_record_is_validated() {
+        let record = CrashRecord::new("src/foc.rs", 100, 5, &"divide by zero");
+        let mut saved = SavedCrash::new(record);
+        assert_eq!(saved.record(), Some(record));
+        saved.invalidate();
+        assert_eq!(saved.record(), None);
+
+        // Whatever's in RAM after a power cycle shouldn't pass for a record.
+        // Safety: `SavedCrash` is nothing but integers.
+        let garbage: SavedCrash = unsafe { std::mem::transmute([0xA5u8; 136]) };
+        assert_eq!(garbage.record(), None);
+    }
+}
@@ -0,0 +1,87 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, heavy_motor};
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::control_loops::LoopState;
+    use bldc::fault::{self, Fault, Faults, Severity};
+    use bldc::foc::DQCurrents;
+    use bldc::sim::{SimEncoder, Simulator};
+
+    #[test]
+    fn faults_latch_until_cleared() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+        fault::take_unreported();
+
+        assert_eq!(Fault::LockContention.severity(), Severity::Warning);
+        fault::raise(Fault::LockContention);
+        assert!(fault::active().contains(Fault::LockContention));
+        assert!(!fault::tripped(), "Warnings shouldn't trip the bridge");
+
+        fault::raise(Fault::LoopParameters);
+        assert!(fault::tripped());
+        assert_eq!(fault::active().critical(), Fault::LoopParameters.into());
+
+        let raised = fault::take_unreported();
+        assert_eq!(
+            raised.iter().collect::<Vec<_>>(),
+            [Fault::LoopParameters, Fault::LockContention]
+        );
+        assert!(fault::take_unreported().is_empty(), "Only reported once");
+        // ...but still latched.
+        assert!(fault::tripped());
+
+        let remaining = fault::clear(Fault::LockContention.into());
+        assert_eq!(remaining, Fault::LoopParameters.into());
+        assert!(fault::clear(Faults::ALL).is_empty());
+        assert!(!fault::tripped());
+    }
+
+    #[test]
+    fn critical_fault_forces_safe_state() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut sim = Simulator::new(heavy_motor(), 24.);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 3., d: 0. });
+        sim.run(&mut torque_control, 0.002, |_| {});
+        assert!(sim.hw.pwm.is_enabled());
+
+        fault::raise(Fault::LoopParameters);
+        assert!(matches!(sim.step(&mut torque_control), LoopState::Idle));
+        assert!(!sim.hw.pwm.is_enabled());
+        assert_eq!(sim.hw.pwm.phase_duties(), [0.; 3]);
+
+        // Nothing runs until the fault's cleared.
+        assert_eq!(sim.enable_loop(), Err(Fault::LoopParameters.into()));
+        assert!(matches!(
+            sim.run(&mut torque_control, 0.002, |_| {}),
+            LoopState::Idle
+        ));
+        assert!(!sim.hw.pwm.is_enabled());
+
+        fault::clear(Faults::ALL);
+        assert!(matches!(
+            sim.run(&mut torque_control, 0.002, |_| {}),
+            LoopState::Running
+        ));
+        assert!(sim.hw.pwm.is_enabled());
+    }
+
+    #[test]
+    fn unstable_observer_raises_fault() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        SimEncoder::new(21, 200.);
+        assert!(fault::active().is_empty());
+
+        // Used to panic.
+        SimEncoder::new(21, 0.1);
+        assert!(fault::active().contains(Fault::ObserverGains));
+        assert!(fault::tripped());
+        fault::clear(Faults::ALL);
+    }
+}
@@ -0,0 +1,105 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common;
+    use bldc::fault::{self, Fault, Faults};
+    use bldc::gate_driver::{DrvFault, DrvMonitor, DrvStatus, Phase, Switch};
+
+    const VDS_HA: u16 = 1 << 5;
+    const VDS_OCP: u16 = 1 << 9;
+    const FAULT: u16 = 1 << 10;
+    const GDF: u16 = 1 << 8;
+    const UVLO: u16 = 1 << 7;
+    const SB_OC: u16 = 1 << 9;
+    const OTW: u16 = 1 << 7;
+    const VGS_LC: u16 = 1 << 0;
+
+    fn overcurrent() -> DrvStatus {
+        DrvStatus {
+            fault_status_1: FAULT | VDS_OCP | VDS_HA,
+            fault_status_2: 0,
+        }
+    }
+
+    #[test]
+    fn decodes_fault_registers() {
+        let status = DrvStatus {
+            fault_status_1: FAULT | VDS_OCP | GDF | UVLO | VDS_HA,
+            fault_status_2: SB_OC | OTW | VGS_LC,
+        };
+        assert_eq!(
+            status.faults().collect::<Vec<_>>(),
+            [
+                DrvFault::VdsOvercurrent(Phase::A, Switch::High),
+                DrvFault::GateDrive(Phase::C, Switch::Low),
+                DrvFault::SenseOvercurrent(Phase::B),
+                DrvFault::Undervoltage,
+                DrvFault::OvertemperatureWarning,
+            ]
+        );
+        assert!(!status.is_clear());
+        assert!(DrvStatus::default().is_clear());
+    }
+
+    #[test]
+    fn recoverable_faults_are_retried_before_tripping() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut monitor = DrvMonitor::new();
+        for retry in 1..=3 {
+            assert!(monitor.update(overcurrent()), "Retry {}", retry);
+            assert!(!monitor.update(DrvStatus::default()));
+            assert_eq!(monitor.retries(), retry);
+        }
+        assert_eq!(fault::active(), Fault::DrvRetried.into());
+        assert!(!fault::tripped());
+
+        // Out of retries.
+        assert!(!monitor.update(overcurrent()));
+        assert!(fault::active().contains(Fault::DrvOvercurrent));
+        assert!(fault::tripped());
+
+        // Retries reset after it's been quiet for a while.
+        fault::clear(Faults::ALL);
+        for _ in 0..=1000 {
+            monitor.update(DrvStatus::default());
+        }
+        assert_eq!(monitor.retries(), 0);
+        assert!(monitor.update(overcurrent()));
+        assert!(!fault::tripped());
+        fault::clear(Faults::ALL);
+    }
+
+    #[test]
+    fn unrecoverable_faults_trip_immediately() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut monitor = DrvMonitor::new();
+        let gate_drive = DrvStatus {
+            fault_status_1: FAULT | GDF,
+            fault_status_2: VGS_LC,
+        };
+        assert!(!monitor.update(gate_drive));
+        assert_eq!(fault::active(), Fault::DrvGateDrive.into());
+
+        // A warning on its own doesn't trip or retry anything.
+        fault::clear(Faults::ALL);
+        monitor.reset();
+        let warm = DrvStatus {
+            fault_status_1: 0,
+            fault_status_2: OTW,
+        };
+        assert!(!monitor.update(warm));
+        assert_eq!(fault::active(), Fault::DrvOvertemperatureWarning.into());
+        assert!(!fault::tripped());
+
+        // ...and only gets reported once, no matter how long it sticks around.
+        fault::take_unreported();
+        monitor.update(warm);
+        assert!(fault::take_unreported().is_empty());
+        fault::clear(Faults::ALL);
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::motor;
+    use bldc::comms::fdcan::{IncomingFdcanFrame, OutgoingFdcanFrame};
+    use bldc::comms::group::{GroupSetpoints, Setpoint, GROUP_SETPOINTS};
+    use bldc::config::Config;
+    use bldc::control_loops::pos_vel_control::{PosVelState, PositionVelocity};
+    use bldc::control_loops::sync;
+    use bldc::sim::Simulator;
+
+    #[test]
+    fn pack_unpack_round_trip() {
+        let setpoints: Vec<Setpoint> = (1..=GROUP_SETPOINTS as u8 + 1)
+            .map(|node| Setpoint {
+                node,
+                position: node as f32 * 0.1,
+                velocity: -(node as f32),
+            })
+            .collect();
+        let group = GroupSetpoints::new(&setpoints);
+        let frame = group.pack();
+        assert_eq!(GroupSetpoints::unpack(frame), group);
+
+        assert_eq!(group.find(2), Some(setpoints[1]));
+        // Only so many fit in a frame.
+        assert_eq!(group.find(GROUP_SETPOINTS as u8 + 1), None);
+
+        // Unused entries are left as node zero.
+        let group = GroupSetpoints::new(&setpoints[..2]);
+        let frame = group.pack();
+        assert!(frame.data[6..].iter().all(|word| *word == 0));
+        assert_eq!(group.setpoints[2..], [None; GROUP_SETPOINTS - 2]);
+        assert_eq!(GroupSetpoints::unpack(frame).find(3), None);
+    }
+
+    #[test]
+    fn staged_targets_wait_for_sync() {
+        let gear_ratio = Config::DEFAULT.gear_ratio;
+        let params = motor();
+        let mut sim = Simulator::new(params, 24.);
+        let mut position_control = PositionVelocity::new();
+
+        let reflected_inertia = params.inertia * gear_ratio * gear_ratio;
+        let omega = 50.;
+        PositionVelocity::command(PosVelState {
+            position: 0.,
+            velocity: 0.,
+            stiffness_gain: omega * omega * reflected_inertia,
+            damping_gain: 2. * omega * reflected_inertia / gear_ratio,
+            torque_constant: 1.5 * 21. * 0.0015,
+        });
+        let settle = |sim: &mut Simulator, position_control: &mut PositionVelocity| {
+            sim.run(position_control, 0.3, |_| {});
+            sim.motor.angle() / gear_ratio
+        };
+
+        // Staging on its own doesn't go anywhere...
+        PositionVelocity::stage(0.2, 0.);
+        let before_sync = settle(&mut sim, &mut position_control);
+        // ...until the sync comes in, and it keeps the gains from the last full command.
+        sync::sync();
+        let after_sync = settle(&mut sim, &mut position_control);
+        // A sync meant for everyone else leaves us where we are.
+        sync::sync();
+        let after_empty_sync = settle(&mut sim, &mut position_control);
+
+        assert!(before_sync.abs() < 0.005, "Moved to {}", before_sync);
+        assert!((after_sync - 0.2).abs() < 0.005, "Moved to {}", after_sync);
+        assert!(
+            (after_empty_sync - 0.2).abs() < 0.005,
+            "Moved to {}",
+            after_empty_sync
+        );
+    }
+}
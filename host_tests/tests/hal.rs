@@ -0,0 +1,196 @@
+#[cfg(test)]
+mod tests {
+    use bldc::control_loops::calibrate_adc::CalibrateADC;
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::control_loops::{Commutate, ControlHardware, LoopState, SensorState};
+    use bldc::current_sensing::PhaseCurrents;
+    use bldc::encoder::EncoderState;
+    use bldc::foc::DQCurrents;
+    use bldc::hal::{
+        BusVoltageSource, PendingCosSin, Peripherals, PhaseCurrentSource, RotorPositionSensor,
+        SinCos, ThreePhaseBridge,
+    };
+    use bldc::pwm::{PhaseVoltages, PwmDuty};
+    use third_party::ang::Angle;
+
+    // Bare-minimum peripherals: fixed readings in, whatever the loop commands recorded out.
+    struct MockPeripherals {}
+
+    impl Peripherals for MockPeripherals {
+        type CurrentSensor = MockCurrentSensor;
+        type Bridge = MockBridge;
+        type Encoder = MockEncoder;
+        type SinCos = MockSinCos;
+    }
+
+    struct MockCurrentSensor {
+        reading: PhaseCurrents,
+        calibration: Option<[f32; 3]>,
+    }
+
+    impl PhaseCurrentSource for MockCurrentSensor {
+        fn sample(&self) -> PhaseCurrents {
+            self.reading
+        }
+
+        fn sample_raw(&self) -> PhaseCurrents {
+            self.reading
+        }
+
+        fn set_calibration(&mut self, phase_a: f32, phase_b: f32, phase_c: f32) {
+            self.calibration = Some([phase_a, phase_b, phase_c]);
+        }
+    }
+
+    impl BusVoltageSource for MockCurrentSensor {
+        fn v_bus(&self) -> f32 {
+            24.
+        }
+    }
+
+    #[derive(Default)]
+    struct MockBridge {
+        voltages: Option<[f32; 3]>,
+        enabled: bool,
+    }
+
+    impl ThreePhaseBridge for MockBridge {
+        fn set_pwm_duty_cycles(&mut self, _pwms: PwmDuty) {}
+
+        fn set_voltages(&mut self, _v_bus: f32, voltages: PhaseVoltages) {
+            self.voltages = Some([voltages.a, voltages.b, voltages.c]);
+        }
+
+        fn arr(&self) -> u16 {
+            2125
+        }
+
+        fn loop_frequency(&self) -> f32 {
+            40_000.
+        }
+
+        fn enable_loop(&mut self) {
+            self.enabled = true;
+        }
+
+        fn disable_loop(&mut self) {
+            self.enabled = false;
+        }
+    }
+
+    struct MockEncoder {
+        state: Option<EncoderState>,
+    }
+
+    impl RotorPositionSensor for MockEncoder {
+        fn update(&mut self, _delta_t: f32) -> EncoderState {
+            self.state.unwrap()
+        }
+
+        fn state(&self) -> &Option<EncoderState> {
+            &self.state
+        }
+    }
+
+    struct MockSinCos {}
+
+    struct MockPending {
+        theta: f32,
+    }
+
+    impl PendingCosSin for MockPending {
+        fn get_result(self) -> [f32; 2] {
+            [self.theta.cos(), self.theta.sin()]
+        }
+    }
+
+    impl SinCos for MockSinCos {
+        type Pending<'a> = MockPending;
+
+        fn cos_sin(&mut self, theta: Angle) -> MockPending {
+            MockPending {
+                theta: theta.in_radians(),
+            }
+        }
+    }
+
+    fn encoder_state() -> EncoderState {
+        EncoderState {
+            raw_encoder: 0,
+            angle: Angle::Radians(0.),
+            velocity: Angle::Radians(0.),
+            angle_multiturn: Angle::Radians(0.),
+            electrical_angle: Angle::Radians(0.),
+            electrical_velocity: Angle::Radians(0.),
+        }
+    }
+
+    fn hardware(
+        reading: PhaseCurrents,
+        state: Option<EncoderState>,
+    ) -> ControlHardware<MockPeripherals> {
+        ControlHardware {
+            current_sensor: MockCurrentSensor {
+                reading,
+                calibration: None,
+            },
+            pwm: MockBridge::default(),
+            encoder: MockEncoder { state },
+            cordic: MockSinCos {},
+        }
+    }
+
+    fn sensor_state(hw: &ControlHardware<MockPeripherals>) -> SensorState {
+        SensorState::new(
+            &encoder_state(),
+            &hw.current_sensor.sample(),
+            hw.current_sensor.v_bus(),
+        )
+    }
+
+    #[test]
+    fn calibrate_adc_on_mock_hardware() {
+        let offsets = PhaseCurrents {
+            phase_a: 0.25,
+            phase_b: -0.5,
+            phase_c: 0.125,
+        };
+        let mut hw = hardware(offsets, Some(encoder_state()));
+        let sensors = sensor_state(&hw);
+        // 1ms at 40kHz
+        let mut calibrate = CalibrateADC::new(0.001, |_| {});
+        let mut periods = 0;
+        while let LoopState::Running = calibrate.commutate(LoopState::Running, &sensors, &mut hw) {
+            periods += 1;
+            assert!(periods < 100, "Calibration never finished");
+        }
+        assert_eq!(periods, 39);
+        assert_eq!(hw.current_sensor.calibration, Some([0.25, -0.5, 0.125]));
+    }
+
+    #[test]
+    fn torque_control_drives_mock_bridge() {
+        let mut hw = hardware(PhaseCurrents::new(), Some(encoder_state()));
+        let sensors = sensor_state(&hw);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 1., d: 0. });
+        let state = torque_control.commutate(LoopState::Running, &sensors, &mut hw);
+        assert!(matches!(state, LoopState::Running));
+
+        // With the rotor at zero electrical angle, positive q voltage lines up with b-c and leaves
+        // phase a alone.
+        let [a, b, c] = hw.pwm.voltages.expect("No voltages commanded");
+        assert!(a.abs() < 1e-4, "Phase a at {}", a);
+        assert!(b > 0., "Phase b at {}", b);
+        assert!((b + c).abs() < 1e-4, "Phases b and c at {} and {}", b, c);
+    }
+
+    #[test]
+    fn torque_control_waits_for_encoder() {
+        let mut hw = hardware(PhaseCurrents::new(), None);
+        let sensors = sensor_state(&hw);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 1., d: 0. });
+        let state = torque_control.commutate(LoopState::Running, &sensors, &mut hw);
+        assert!(matches!(state, LoopState::Running));
+        assert!(hw.pwm.voltages.is_none());
+    }
+}
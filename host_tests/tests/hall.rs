@@ -0,0 +1,235 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, motor};
+    use bldc::config::Config;
+    use bldc::control_loops::calibrate_halls::{CalibrateHalls, HallCalibrationResult};
+    use bldc::control_loops::pos_vel_control::{PosVelState, PositionVelocity};
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::control_loops::LoopState;
+    use bldc::fault::{self, Faults};
+    use bldc::foc::DQCurrents;
+    use bldc::hal::RotorPositionSensor;
+    use bldc::hall::{
+        self, HallCalibration, HallCalibrationStatus, HallCalibrator, HALL_SECTORS, SEQUENCE,
+    };
+    use bldc::sim::{SimEncoder, Simulator};
+    use std::f32::consts::PI;
+    use std::sync::Mutex;
+
+    const V_BUS: f32 = 24.;
+    const SECTOR_WIDTH: f32 = 2. * PI / HALL_SECTORS as f32;
+
+    // Where `CalibrateHalls` leaves its result.
+    static RESULT: Mutex<Option<HallCalibrationResult>> = Mutex::new(None);
+
+    fn finished(result: &HallCalibrationResult) {
+        *RESULT.lock().unwrap() = Some(*result);
+    }
+
+    fn sim_with_halls(offset: f32, calibration: HallCalibration) -> Simulator {
+        let params = motor();
+        let mut sim = Simulator::new(params, V_BUS);
+        sim.hw.encoder = SimEncoder::halls(
+            params.pole_pairs,
+            Config::DEFAULT.velocity_observer_bandwidth,
+            offset,
+        );
+        sim.hw.encoder.set_hall_calibration(calibration);
+        sim
+    }
+
+    // Where each sector's middle is, with sector 0 starting at `offset`.
+    fn shifted(offset: f32) -> HallCalibration {
+        let mut calibration = HallCalibration::EVEN;
+        for angle in calibration.angles.iter_mut() {
+            *angle = (*angle + offset).rem_euclid(2. * PI);
+        }
+        calibration
+    }
+
+    fn angle_error(a: f32, b: f32) -> f32 {
+        let error = (a - b).rem_euclid(2. * PI);
+        error.min(2. * PI - error)
+    }
+
+    fn assert_close(actual: &HallCalibration, expected: &HallCalibration, tolerance: f32) {
+        for (sector, (a, e)) in actual.angles.iter().zip(expected.angles).enumerate() {
+            assert!(
+                angle_error(*a, e) < tolerance,
+                "Sector {} at {}, expected {}",
+                sector,
+                a,
+                e
+            );
+        }
+    }
+
+    // Drags a pretend rotor `turns` electrical turns forwards and back. The halls read the rotor,
+    // which trails the commanded angle by `lag` whichever way it's going.
+    fn sweep(offset: f32, lag: f32, turns: f32, halls: impl Fn(usize) -> u8) -> HallCalibrator {
+        let mut calibrator = HallCalibrator::new();
+        let steps = 10_000;
+        let end = turns * 2. * PI;
+        let forward = (0..steps).map(|step| (step as f32 / steps as f32 * end, -lag));
+        let back = (0..steps).map(|step| ((1. - step as f32 / steps as f32) * end, lag));
+        for (angle, lag) in forward.chain(back) {
+            let sector = ((angle + lag - offset).rem_euclid(2. * PI) / SECTOR_WIDTH) as usize;
+            calibrator.add(angle, halls(sector.min(HALL_SECTORS - 1)));
+        }
+        calibrator
+    }
+
+    #[test]
+    fn sectors_follow_the_sequence() {
+        for (n, state) in SEQUENCE.iter().enumerate() {
+            assert_eq!(hall::sector(*state), Some(n));
+            // Only one sensor changes between neighbours.
+            let next = SEQUENCE[(n + 1) % HALL_SECTORS];
+            assert_eq!((state ^ next).count_ones(), 1);
+        }
+        assert_eq!(hall::sector(0b000), None);
+        assert_eq!(hall::sector(0b111), None);
+    }
+
+    #[test]
+    fn calibrator_finds_sectors() {
+        let offset = 1.;
+        let calibrator = sweep(offset, 0.1, 2., |sector| SEQUENCE[sector]);
+        assert_close(&calibrator.finish().unwrap(), &shifted(offset), 0.01);
+
+        // Sensors plugged in backwards; the sectors just come round the other way.
+        let calibrator = sweep(offset, 0.1, 2., |sector| {
+            SEQUENCE[HALL_SECTORS - 1 - sector]
+        });
+        let mut expected = shifted(offset);
+        expected.angles.reverse();
+        assert_close(&calibrator.finish().unwrap(), &expected, 0.01);
+    }
+
+    #[test]
+    fn calibrator_reports_bad_sensors() {
+        // Never got all the way round.
+        let calibrator = sweep(0., 0., 0.5, |sector| SEQUENCE[sector]);
+        assert_eq!(
+            calibrator.finish(),
+            Err(HallCalibrationStatus::MissingSector)
+        );
+
+        // One sensor stuck high, so there's an all-on in there.
+        let calibrator = sweep(0., 0., 2., |sector| SEQUENCE[sector] | 0b100);
+        assert_eq!(
+            calibrator.finish(),
+            Err(HallCalibrationStatus::InvalidState)
+        );
+
+        // Two sensors swapped over is just the sectors coming round backwards, which is fine.
+        let swapped = |state: u8| (state & 0b100) | ((state & 0b001) << 1) | ((state & 0b010) >> 1);
+        let calibrator = sweep(0., 0., 2., |sector| swapped(SEQUENCE[sector]));
+        assert!(calibrator.finish().is_ok());
+
+        // Missing every other sector, as if it were being read far too slowly.
+        let calibrator = sweep(0., 0., 2., |sector| SEQUENCE[(sector * 2) % HALL_SECTORS]);
+        assert_eq!(calibrator.finish(), Err(HallCalibrationStatus::Skipped));
+    }
+
+    #[test]
+    fn torque_control_on_halls() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let offset = 0.7;
+        let mut sim = sim_with_halls(offset, shifted(offset));
+        let duration = 0.05;
+        let mut torque_control = TorqueControl::new(duration, DQCurrents { q: 1., d: 0. });
+        sim.run(&mut torque_control, 2. * duration, |_| {});
+
+        // The observer's only told which sector the rotor's in, but should still have caught up
+        // with it once it's coasting.
+        let velocity = sim.motor.velocity();
+        assert!(velocity > 10., "Rotor only got to {} rad/s", velocity);
+        let estimate = sim.hw.encoder.state().unwrap().velocity.in_radians();
+        assert!(
+            (estimate - velocity).abs() < 0.05 * velocity,
+            "Observer at {} rad/s, rotor at {}",
+            estimate,
+            velocity
+        );
+    }
+
+    #[test]
+    fn calibration_finds_the_sensors() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let offset = 2.;
+        let mut sim = sim_with_halls(offset, HallCalibration::EVEN);
+        *RESULT.lock().unwrap() = None;
+        let mut calibrate = CalibrateHalls::new(5., finished);
+        let state = sim.run(&mut calibrate, 5., |_| {});
+        assert!(
+            matches!(state, LoopState::Idle),
+            "Calibration didn't finish"
+        );
+
+        let result = RESULT.lock().unwrap().take().expect("No result");
+        // The rotor lags a little, but going both ways cancels most of it.
+        assert_close(&result.unwrap(), &shifted(offset), 0.1);
+    }
+
+    #[test]
+    fn stopping_calibration_early_aborts() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let mut sim = sim_with_halls(0., HallCalibration::EVEN);
+        *RESULT.lock().unwrap() = None;
+        let mut calibrate = CalibrateHalls::new(5., finished);
+        sim.run(&mut calibrate, 0.5, |_| {});
+        sim.shutdown();
+        sim.step(&mut calibrate);
+        assert_eq!(
+            RESULT.lock().unwrap().take(),
+            Some(Err(HallCalibrationStatus::Aborted))
+        );
+    }
+
+    #[test]
+    fn position_control_on_halls() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+
+        let gear_ratio = Config::DEFAULT.gear_ratio;
+        let params = motor();
+        let offset = 0.3;
+        let mut sim = sim_with_halls(offset, shifted(offset));
+        let mut position_control = PositionVelocity::new();
+
+        // Softer than the encoder gets, since there's a lot less to go on.
+        let reflected_inertia = params.inertia * gear_ratio * gear_ratio;
+        let omega = 20.;
+        let target = 0.5;
+        PositionVelocity::command(PosVelState {
+            position: target,
+            velocity: 0.,
+            stiffness_gain: omega * omega * reflected_inertia,
+            damping_gain: 2. * omega * reflected_inertia / gear_ratio,
+            torque_constant: 1.5 * params.pole_pairs as f32 * params.flux_linkage,
+        });
+
+        // The halls start out wherever the rotor is, same as on the real thing.
+        let start = sim.motor.angle();
+        sim.run(&mut position_control, 1., |_| {});
+
+        // Only good to within a sector, mechanically.
+        let sector = SECTOR_WIDTH / params.pole_pairs as f32 / gear_ratio;
+        let position = (sim.motor.angle() - start) / gear_ratio;
+        assert!(
+            (position - target).abs() < sector,
+            "Ended up at {}, wanted {}",
+            position,
+            target
+        );
+    }
+}
@@ -0,0 +1,49 @@
+#[cfg(test)]
+mod tests {
+    use bldc::control_loops::timing::{LoopTiming, PERIOD_CYCLES};
+    use bldc::fault::Fault;
+
+    #[test]
+    fn tracks_execution_time() {
+        let mut timing = LoopTiming::new();
+        assert_eq!(timing.average(), 0);
+
+        for cycles in [1000, 3000, 2000] {
+            assert_eq!(timing.record(cycles), None);
+        }
+        assert_eq!(timing.samples, 3);
+        assert_eq!(timing.min, 1000);
+        assert_eq!(timing.max, 3000);
+        assert_eq!(timing.average(), 2000);
+        assert_eq!(timing.last, 2000);
+        assert_eq!(timing.overruns, 0);
+
+        // Right on the period is still on time.
+        assert_eq!(timing.record(PERIOD_CYCLES), None);
+        assert_eq!(timing.overruns, 0);
+        timing.record(PERIOD_CYCLES + 1);
+        assert_eq!(timing.overruns, 1);
+    }
+
+    #[test]
+    fn persistent_overruns_fault() {
+        let mut timing = LoopTiming::new();
+        let over = PERIOD_CYCLES * 2;
+
+        // The odd overrun gets counted, but as long as the loop catches back up it's fine.
+        for _ in 0..100 {
+            for _ in 0..39 {
+                assert_eq!(timing.record(over), None);
+            }
+            assert_eq!(timing.record(1000), None);
+        }
+        assert_eq!(timing.overruns, 3900);
+
+        // 1ms straight is too long.
+        for _ in 0..39 {
+            assert_eq!(timing.record(over), None);
+        }
+        assert_eq!(timing.record(over), Some(Fault::LoopOverrun));
+        assert_eq!(timing.record(over), Some(Fault::LoopOverrun));
+    }
+}
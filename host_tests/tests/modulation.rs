@@ -0,0 +1,143 @@
+#[cfg(test)]
+mod tests {
+    use bldc::foc::{modulate, Modulation};
+    use bldc::pwm::{PhaseVoltages, PwmDuty};
+
+    const V_BUS: f32 = 24.;
+    const SQRT_3: f32 = 1.7320508;
+    const EPSILON: f32 = 1e-4;
+
+    // Balanced, zero-sequence free phase voltages with amplitude `magnitude` at electrical angle
+    // `theta`.
+    fn balanced(magnitude: f32, theta: f32) -> PhaseVoltages {
+        use core::f32::consts::PI;
+        PhaseVoltages {
+            a: magnitude * theta.cos(),
+            b: magnitude * (theta - 2. * PI / 3.).cos(),
+            c: magnitude * (theta + 2. * PI / 3.).cos(),
+        }
+    }
+
+    fn duty(modulation: Modulation, voltages: PhaseVoltages, max_duty: f32) -> PwmDuty {
+        modulate(voltages, V_BUS, modulation).as_pwm(V_BUS, false, max_duty)
+    }
+
+    fn assert_duty(actual: PwmDuty, expected: [f32; 3]) {
+        let actual = [actual.a, actual.b, actual.c];
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!(
+                (a - e).abs() < EPSILON,
+                "Expected duty {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn sinusoidal_centers_on_half_duty() {
+        let pwm = duty(
+            Modulation::Sinusoidal,
+            PhaseVoltages {
+                a: 6.,
+                b: -3.,
+                c: -3.,
+            },
+            1.,
+        );
+        assert_duty(pwm, [0.75, 0.375, 0.375]);
+    }
+
+    #[test]
+    fn sinusoidal_saturates_past_half_bus() {
+        // At v_bus / sqrt(3) sinusoidal modulation runs out of headroom on phase A.
+        let pwm = duty(Modulation::Sinusoidal, balanced(V_BUS / SQRT_3, 0.), 1.);
+        assert_duty(pwm, [1., 0.5 - 0.5 / SQRT_3, 0.5 - 0.5 / SQRT_3]);
+    }
+
+    #[test]
+    fn space_vector_reaches_full_bus_utilization() {
+        // At 30° the line-to-line voltage between A and C is at its peak, and SVPWM uses the full
+        // bus to produce it.
+        let pwm = duty(
+            Modulation::SpaceVector,
+            balanced(V_BUS / SQRT_3, core::f32::consts::PI / 6.),
+            1.,
+        );
+        assert_duty(pwm, [1., 0.5, 0.]);
+        // At 0° the min/max offset pulls phase A back into the linear region.
+        let pwm = duty(Modulation::SpaceVector, balanced(V_BUS / SQRT_3, 0.), 1.);
+        let three_quarters = 0.75 / SQRT_3;
+        assert_duty(
+            pwm,
+            [
+                0.5 + three_quarters,
+                0.5 - three_quarters,
+                0.5 - three_quarters,
+            ],
+        );
+    }
+
+    #[test]
+    fn space_vector_preserves_line_to_line_voltage() {
+        for step in 0..36 {
+            let theta = step as f32 * core::f32::consts::PI / 18.;
+            let voltages = balanced(10., theta);
+            let (ab, bc) = (voltages.a - voltages.b, voltages.b - voltages.c);
+            let pwm = duty(Modulation::SpaceVector, voltages, 1.);
+            assert!(((pwm.a - pwm.b) * V_BUS - ab).abs() < 1e-3);
+            assert!(((pwm.b - pwm.c) * V_BUS - bc).abs() < 1e-3);
+            // Min-max injection always centers the waveform.
+            let max = pwm.a.max(pwm.b).max(pwm.c);
+            let min = pwm.a.min(pwm.b).min(pwm.c);
+            assert!((max + min - 1.).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn discontinuous_clamps_one_phase_to_a_rail() {
+        // Positive peak on phase A: A is clamped high.
+        let pwm = duty(Modulation::Discontinuous, balanced(8., 0.), 1.);
+        assert_duty(pwm, [1., 0.5, 0.5]);
+        // Negative peak on phase A: A is clamped low.
+        let pwm = duty(
+            Modulation::Discontinuous,
+            balanced(8., core::f32::consts::PI),
+            1.,
+        );
+        assert_duty(pwm, [0., 0.5, 0.5]);
+        for step in 0..36 {
+            let theta = step as f32 * core::f32::consts::PI / 18.;
+            let voltages = balanced(10., theta);
+            let ab = voltages.a - voltages.b;
+            let pwm = duty(Modulation::Discontinuous, voltages, 1.);
+            assert!(((pwm.a - pwm.b) * V_BUS - ab).abs() < 1e-3);
+            let clamped = [pwm.a, pwm.b, pwm.c]
+                .iter()
+                .filter(|d| d.abs() < EPSILON || (*d - 1.).abs() < EPSILON)
+                .count();
+            assert!(clamped >= 1);
+        }
+    }
+
+    #[test]
+    fn duty_is_clamped_to_max() {
+        let pwm = duty(Modulation::Discontinuous, balanced(8., 0.), 0.9);
+        assert_duty(pwm, [0.9, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn inverted_pwm() {
+        let pwm = modulate(
+            PhaseVoltages {
+                a: 6.,
+                b: -3.,
+                c: -3.,
+            },
+            V_BUS,
+            Modulation::Sinusoidal,
+        )
+        .as_pwm(V_BUS, true, 1.);
+        assert_duty(pwm, [0.25, 0.625, 0.625]);
+    }
+}
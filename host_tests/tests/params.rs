@@ -0,0 +1,144 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, heavy_motor};
+    use bldc::config::params::{self, ParamError, ParamValue, PARAMS};
+    use bldc::config::{self, Config};
+    use bldc::control_loops::command_timeout::TimeoutAction;
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::encoder::RotorSensorKind;
+    use bldc::foc::{DQCurrents, Modulation};
+    use bldc::hall::HallCalibration;
+    use bldc::pwm::DEFAULT_MAX_DUTY;
+    use bldc::sim::Simulator;
+    use std::collections::HashSet;
+
+    // Heavy enough that back-EMF stays out of the picture.
+    fn locked_rotor() -> Simulator {
+        Simulator::new(heavy_motor(), 24.)
+    }
+
+    #[test]
+    fn registry_is_consistent() {
+        let ids: HashSet<_> = PARAMS.iter().map(|param| param.info.id).collect();
+        let names: HashSet<_> = PARAMS.iter().map(|param| param.info.name).collect();
+        assert_eq!(ids.len(), PARAMS.len(), "Duplicate param IDs");
+        assert_eq!(names.len(), PARAMS.len(), "Duplicate param names");
+        // Everything the host knows about is hooked up to something.
+        assert_eq!(PARAMS.len(), protocol::params::PARAMS.len());
+
+        // Every default has to be settable, and land in the field its ID says it does.
+        let mut config = Config::DEFAULT;
+        for param in PARAMS {
+            let info = param.info;
+            let value = param.get(&Config::DEFAULT);
+            assert_eq!(value.kind(), info.kind, "{}", info.name);
+            assert_eq!(value, info.default, "{}", info.name);
+            assert_eq!(param.set(&mut config, value), Ok(()), "{}", info.name);
+        }
+        assert_eq!(config, Config::DEFAULT);
+    }
+
+    #[test]
+    fn defaults_match_the_firmware() {
+        let config = Config::DEFAULT;
+        assert_eq!(config.timeout_action, TimeoutAction::RampDown as u32);
+        assert_eq!(config.rotor_sensor, RotorSensorKind::Ma702 as u32);
+        assert_eq!(config.hall_angles, HallCalibration::EVEN.angles);
+        assert_eq!(config.modulation, Modulation::SpaceVector as u32);
+        assert_eq!(config.max_duty, DEFAULT_MAX_DUTY);
+    }
+
+    #[test]
+    fn get_and_set() {
+        let mut config = Config::DEFAULT;
+        let kp = params::find(0x01).unwrap();
+        assert_eq!(kp.info.name, "current_kp");
+        assert_eq!(params::set(&mut config, 0x01, ParamValue::F32(2.5)), Ok(()));
+        assert_eq!(config.current_kp, 2.5);
+        assert_eq!(params::get(&config, 0x01), Ok(ParamValue::F32(2.5)));
+
+        assert_eq!(params::set(&mut config, 0x05, ParamValue::U32(14)), Ok(()));
+        assert_eq!(config.pole_pairs, 14);
+        assert_eq!(params::get(&config, 0x05), Ok(ParamValue::U32(14)));
+    }
+
+    #[test]
+    fn rejected_writes_leave_config_alone() {
+        let mut config = Config::DEFAULT;
+        let attempts = [
+            (0x01, ParamValue::F32(-1.), ParamError::OutOfRange),
+            (0x01, ParamValue::F32(f32::NAN), ParamError::OutOfRange),
+            (0x01, ParamValue::U32(1), ParamError::WrongType),
+            (0x05, ParamValue::U32(0), ParamError::OutOfRange),
+            (0x05, ParamValue::U32(256), ParamError::OutOfRange),
+            // In range on their own, but not with the defaults for the other end.
+            (0x0A, ParamValue::F32(5.), ParamError::OutOfRange),
+            (0x0B, ParamValue::F32(40.), ParamError::OutOfRange),
+            (0xFFFF, ParamValue::F32(1.), ParamError::UnknownParam),
+        ];
+        for (id, value, error) in attempts {
+            assert_eq!(params::set(&mut config, id, value), Err(error));
+        }
+        assert_eq!(config, Config::DEFAULT);
+        assert_eq!(params::get(&config, 0xFFFF), Err(ParamError::UnknownParam));
+    }
+
+    #[test]
+    fn running_loop_picks_up_new_gains() {
+        let _guard = common::globals();
+        let mut sim = locked_rotor();
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 3., d: 0. });
+        sim.run(&mut torque_control, 0.005, |_| {});
+        let before = sim.motor.dq_currents().q;
+        assert!((before - 3.).abs() < 0.05, "i_q {} before", before);
+
+        // Clamp the current controller output to half a volt, which can only push ~1.6A through
+        // the winding resistance.
+        let mut limited = config::current();
+        params::set(&mut limited, 0x03, ParamValue::F32(0.5)).unwrap();
+        config::set(limited);
+        sim.run(&mut torque_control, 0.005, |_| {});
+        let after = sim.motor.dq_currents().q;
+        config::set(Config::DEFAULT);
+
+        assert!(after < 0.5 / 0.32 + 0.05, "i_q {} after clamping", after);
+    }
+
+    #[test]
+    fn running_loop_picks_up_new_modulation() {
+        let _guard = common::globals();
+        let mut sim = locked_rotor();
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 3., d: 0. });
+        let near_rail = |duty: f32| !(1e-3..=DEFAULT_MAX_DUTY - 1e-3).contains(&duty);
+
+        // Only takes a volt or so, so centered SVPWM keeps every phase well away from the rails.
+        sim.run(&mut torque_control, 0.005, |_| {});
+        assert!(!sim.hw.pwm.phase_duties().into_iter().any(near_rail));
+
+        // Whereas DPWM always has one phase sat on one.
+        let mut discontinuous = config::current();
+        let bits = Modulation::Discontinuous as u32;
+        params::set(&mut discontinuous, 0x1B, ParamValue::U32(bits)).unwrap();
+        config::set(discontinuous);
+        sim.run(&mut torque_control, 0.005, |_| {});
+        let duties = sim.hw.pwm.phase_duties();
+        let current = sim.motor.dq_currents().q;
+
+        // And nothing goes past the max duty.
+        params::set(&mut discontinuous, 0x1D, ParamValue::F32(0.5)).unwrap();
+        config::set(discontinuous);
+        sim.run(&mut torque_control, 0.005, |_| {});
+        let limited = sim.hw.pwm.phase_duties();
+        config::set(Config::DEFAULT);
+
+        assert!(duties.into_iter().any(near_rail), "Duties {:?}", duties);
+        assert!((current - 3.).abs() < 0.05, "i_q {} with DPWM", current);
+        assert!(
+            limited.iter().all(|duty| *duty <= 0.5),
+            "Duties {:?}",
+            limited
+        );
+    }
+}
@@ -0,0 +1,104 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, heavy_motor};
+    use bldc::config::{self, Config};
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::control_loops::LoopState;
+    use bldc::current_sensing::PhaseCurrents;
+    use bldc::fault::{self, Fault, Faults};
+    use bldc::foc::DQCurrents;
+    use bldc::protection::Protection;
+    use bldc::sim::Simulator;
+
+    fn currents(phase_a: f32) -> PhaseCurrents {
+        PhaseCurrents {
+            phase_a,
+            phase_b: -phase_a / 2.,
+            phase_c: -phase_a / 2.,
+        }
+    }
+
+    #[test]
+    fn limits_are_debounced() {
+        let _guard = common::globals();
+        config::set(Config::DEFAULT);
+        let debounce = Config::DEFAULT.protection_debounce;
+        let mut protection = Protection::new();
+
+        assert_eq!(protection.check(&currents(10.), 24.), None);
+
+        // A single spike doesn't trip...
+        assert_eq!(protection.check(&currents(-35.), 24.), None);
+        assert_eq!(protection.check(&currents(10.), 24.), None);
+        // ...but a sustained one does, on exactly the debounce'th cycle.
+        for _ in 1..debounce {
+            assert_eq!(protection.check(&currents(-35.), 24.), None);
+        }
+        assert_eq!(
+            protection.check(&currents(-35.), 24.),
+            Some(Fault::Overcurrent)
+        );
+
+        let mut protection = Protection::new();
+        let trip = |protection: &mut Protection, v_bus| {
+            (0..debounce)
+                .map(|_| protection.check(&currents(0.), v_bus))
+                .last()
+                .unwrap()
+        };
+        assert_eq!(trip(&mut protection, 24.), None);
+        assert_eq!(trip(&mut protection, 35.), Some(Fault::BusOvervoltage));
+        protection.reset();
+        assert_eq!(trip(&mut protection, 5.), Some(Fault::BusUndervoltage));
+    }
+
+    #[test]
+    fn limits_follow_config() {
+        let _guard = common::globals();
+        config::set(Config::DEFAULT);
+        let mut protection = Protection::new();
+        config::set(Config {
+            max_phase_current: 5.,
+            protection_debounce: 1,
+            ..Config::DEFAULT
+        });
+        let result = protection.check(&currents(6.), 24.);
+        config::set(Config::DEFAULT);
+        assert_eq!(result, Some(Fault::Overcurrent));
+    }
+
+    #[test]
+    fn overcurrent_trips_the_bridge() {
+        let _guard = common::globals();
+        fault::clear(Faults::ALL);
+        config::set(Config {
+            max_phase_current: 2.,
+            ..Config::DEFAULT
+        });
+
+        let mut sim = Simulator::new(heavy_motor(), 24.);
+        // Ask for more than the limit.
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 4., d: 0. });
+        let mut peak: f32 = 0.;
+        let state = sim.run(&mut torque_control, 0.01, |sim| {
+            let currents = sim.motor.phase_currents();
+            peak = peak
+                .max(currents.phase_a.abs())
+                .max(currents.phase_b.abs())
+                .max(currents.phase_c.abs());
+        });
+        config::set(Config::DEFAULT);
+        let active = fault::active();
+        fault::clear(Faults::ALL);
+
+        assert!(matches!(state, LoopState::Idle));
+        assert!(!sim.hw.pwm.is_enabled());
+        assert_eq!(active, Fault::Overcurrent.into());
+        assert!(sim.time() < 0.005, "Tripped after {}s", sim.time());
+        // The current loop's fast, so it can overshoot a little before the debounce runs out but
+        // nowhere near what was asked for.
+        assert!(peak < 3., "Peak phase current {}", peak);
+    }
+}
@@ -0,0 +1,347 @@
+#[cfg(test)]
+mod tests {
+    use protocol::ack::{self, AckStatus, Nack};
+    use protocol::group::{GroupSetpoints, Setpoint};
+    use protocol::messages::{
+        CalibrateEZeroCmd, ClearFaultsCmd, CrashMessageMsg, EnterPosVelControlCmd, MessageID,
+        ParamInfoMsg, SetParamCmd, SetPosVelCmd, StatusMsg, TorqueControlCmd,
+    };
+    use protocol::{
+        DecodeError, FdcanMessage, Frame, IncomingFdcanFrame, OutgoingFdcanFrame, Wire,
+    };
+
+    fn round_trip<T>(message: T)
+    where
+        T: Frame + IncomingFdcanFrame + OutgoingFdcanFrame + Clone + PartialEq + std::fmt::Debug,
+    {
+        let frame = message.pack();
+        assert_eq!(frame.id, T::ID as u32);
+        assert_eq!(frame.size as usize, T::SIZE);
+        assert_eq!(T::decode(&frame), Ok(message.clone()));
+        assert_eq!(T::unpack(frame), message);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        round_trip(SetPosVelCmd {
+            position: 1.5,
+            velocity: -2.,
+            stiffness_gain: 4.5,
+            damping_gain: 0.03,
+            torque_constant: 0.047,
+        });
+        round_trip(SetParamCmd { id: 3, bits: 42 });
+        round_trip(EnterPosVelControlCmd {});
+        round_trip(StatusMsg {
+            mode: 3,
+            state: 1,
+            active: 0x800,
+            critical: 0x800,
+        });
+        let mut name = [0; 44];
+        name[..10].copy_from_slice(b"current_kp");
+        round_trip(ParamInfoMsg {
+            id: 0,
+            kind: 0,
+            index: 0,
+            count: 19,
+            min: 0f32.to_bits(),
+            max: 10f32.to_bits(),
+            value: 1.2f32.to_bits(),
+            name,
+        });
+        // Takes up the whole frame.
+        round_trip(CrashMessageMsg {
+            message: [b'x'; 64],
+        });
+        assert_eq!(CrashMessageMsg::SIZE, 64);
+    }
+
+    #[test]
+    fn layouts_are_byte_exact() {
+        // Same as the hand-packed words they replaced.
+        let frame = SetPosVelCmd {
+            position: 1.5,
+            velocity: -2.,
+            stiffness_gain: 0.,
+            damping_gain: 0.,
+            torque_constant: 1.,
+        }
+        .pack();
+        assert_eq!(
+            frame.data[..5],
+            [1.5f32.to_bits(), (-2f32).to_bits(), 0, 0, 1f32.to_bits()]
+        );
+
+        // The ID's padded out to a full word.
+        let frame = SetParamCmd {
+            id: 0x1234,
+            bits: 0xDEAD_BEEF,
+        }
+        .pack();
+        assert_eq!(frame.size, 8);
+        assert_eq!(frame.data[..2], [0x1234, 0xDEAD_BEEF]);
+        assert_eq!(
+            frame.bytes()[..8],
+            [0x34, 0x12, 0, 0, 0xEF, 0xBE, 0xAD, 0xDE]
+        );
+
+        let message = FdcanMessage::new(MessageID::ClearFaults.into(), &[0x1800]);
+        assert_eq!(
+            ClearFaultsCmd::decode(&message),
+            Ok(ClearFaultsCmd { faults: 0x1800 })
+        );
+    }
+
+    #[test]
+    fn decoding_checks_id_and_length() {
+        let short = FdcanMessage::new(MessageID::SetPosVel.into(), &[0; 4]);
+        assert_eq!(
+            SetPosVelCmd::decode(&short),
+            Err(DecodeError::Length {
+                expected: 20,
+                actual: 16
+            })
+        );
+        let other = FdcanMessage::new(MessageID::SetParam.into(), &[0; 5]);
+        assert_eq!(
+            SetPosVelCmd::decode(&other),
+            Err(DecodeError::UnexpectedId(MessageID::SetParam as u32))
+        );
+        // Empty messages are always long enough.
+        let empty = FdcanMessage::new(MessageID::EnterPosVelControl.into(), &[]);
+        assert_eq!(
+            EnterPosVelControlCmd::decode(&empty),
+            Ok(EnterPosVelControlCmd {})
+        );
+        // Same thing through `TryFrom`, which is what the handlers get.
+        assert_eq!(
+            SetPosVelCmd::try_from(short),
+            Err(DecodeError::Length {
+                expected: 20,
+                actual: 16
+            })
+        );
+    }
+
+    #[test]
+    fn acks_say_why() {
+        let ok = ack::ack(MessageID::EnterTorqueControl as u32, 3, Ok(()));
+        assert!(ok.acks(MessageID::EnterTorqueControl, 3));
+        assert!(!ok.acks(MessageID::EnterTorqueControl, 4));
+        assert!(!ok.acks(MessageID::EnterPosVelControl, 3));
+        assert_eq!(AckStatus::from_raw(ok.status), Some(AckStatus::Ok));
+        assert_eq!(ok.detail, 0);
+
+        let faulted = ack::ack(0x17, 1, Err(Nack::Faulted(0x1800)));
+        assert_eq!(
+            AckStatus::from_raw(faulted.status),
+            Some(AckStatus::Faulted)
+        );
+        assert_eq!(faulted.detail, 0x1800);
+
+        let short = FdcanMessage::new(MessageID::SetPosVel.into(), &[0; 4]);
+        let error = SetPosVelCmd::decode(&short).unwrap_err();
+        let malformed = ack::ack(0x19, 1, Err(error.into()));
+        assert_eq!(
+            AckStatus::from_raw(malformed.status),
+            Some(AckStatus::Malformed)
+        );
+        assert_eq!(malformed.detail, 20);
+
+        let nan = SetPosVelCmd {
+            position: f32::NAN,
+            velocity: 0.,
+            stiffness_gain: 0.,
+            damping_gain: 0.,
+            torque_constant: 1.,
+        };
+        let error = SetPosVelCmd::decode(&nan.pack()).unwrap_err();
+        let invalid = ack::ack(0x19, 0, Err(error.into()));
+        assert_eq!(
+            AckStatus::from_raw(invalid.status),
+            Some(AckStatus::Invalid)
+        );
+        assert_eq!(invalid.detail, 0);
+
+        assert_eq!(AckStatus::from_raw(6), None);
+    }
+
+    #[test]
+    fn only_asked_for_or_undecodable_commands_get_replies() {
+        assert_eq!(ack::reply(0x19, 0, Ok(())), None);
+        assert_eq!(ack::reply(0x19, 0, Err(Nack::Rejected)), None);
+        assert!(ack::reply(0x19, 2, Ok(())).is_some());
+        let error = DecodeError::Length {
+            expected: 20,
+            actual: 16,
+        };
+        let reply = ack::reply(0x19, 0, Err(error.into())).unwrap();
+        assert_eq!(reply.transaction, 0);
+        assert_eq!(
+            AckStatus::from_raw(reply.status),
+            Some(AckStatus::Malformed)
+        );
+    }
+
+    #[test]
+    fn decoding_checks_values() {
+        let target = SetPosVelCmd {
+            position: 1.5,
+            velocity: -2.,
+            stiffness_gain: 4.5,
+            damping_gain: 0.03,
+            torque_constant: 0.047,
+        };
+        let decode = |cmd: SetPosVelCmd| SetPosVelCmd::decode(&cmd.pack());
+        assert_eq!(decode(target), Ok(target));
+        assert_eq!(
+            decode(SetPosVelCmd {
+                velocity: f32::INFINITY,
+                ..target
+            }),
+            Err(DecodeError::NotFinite {
+                field: "velocity",
+                offset: 4
+            })
+        );
+        // Range checks catch NaNs too, which would otherwise sail through any comparison.
+        assert_eq!(
+            decode(SetPosVelCmd {
+                damping_gain: f32::NAN,
+                ..target
+            }),
+            Err(DecodeError::NotFinite {
+                field: "damping_gain",
+                offset: 12
+            })
+        );
+        assert_eq!(
+            decode(SetPosVelCmd {
+                stiffness_gain: -1.,
+                ..target
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "stiffness_gain",
+                offset: 8
+            })
+        );
+        assert_eq!(
+            decode(SetPosVelCmd {
+                damping_gain: 1e6,
+                ..target
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "damping_gain",
+                offset: 12
+            })
+        );
+        // Torques get divided by it.
+        assert_eq!(
+            decode(SetPosVelCmd {
+                torque_constant: 0.,
+                ..target
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "torque_constant",
+                offset: 16
+            })
+        );
+
+        let torque = TorqueControlCmd {
+            duration: 1.,
+            q: -2.,
+            d: 0.,
+        };
+        let decode = |cmd: TorqueControlCmd| TorqueControlCmd::decode(&cmd.pack());
+        assert_eq!(decode(torque), Ok(torque));
+        assert_eq!(
+            decode(TorqueControlCmd {
+                duration: -1.,
+                ..torque
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "duration",
+                offset: 0
+            })
+        );
+        assert_eq!(
+            decode(TorqueControlCmd { q: 100., ..torque }),
+            Err(DecodeError::OutOfRange {
+                field: "q",
+                offset: 4
+            })
+        );
+        assert_eq!(
+            decode(TorqueControlCmd {
+                d: f32::NEG_INFINITY,
+                ..torque
+            }),
+            Err(DecodeError::NotFinite {
+                field: "d",
+                offset: 8
+            })
+        );
+
+        // Calibration drives current the same way.
+        let calibrate = CalibrateEZeroCmd {
+            duration: 1.,
+            q: 0.,
+            d: 2.,
+        };
+        let decode = |cmd: CalibrateEZeroCmd| CalibrateEZeroCmd::decode(&cmd.pack());
+        assert_eq!(decode(calibrate), Ok(calibrate));
+        assert_eq!(
+            decode(CalibrateEZeroCmd {
+                duration: 1e6,
+                ..calibrate
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "duration",
+                offset: 0
+            })
+        );
+        assert_eq!(
+            decode(CalibrateEZeroCmd {
+                q: f32::NAN,
+                ..calibrate
+            }),
+            Err(DecodeError::NotFinite {
+                field: "q",
+                offset: 4
+            })
+        );
+        assert_eq!(
+            decode(CalibrateEZeroCmd {
+                d: -100.,
+                ..calibrate
+            }),
+            Err(DecodeError::OutOfRange {
+                field: "d",
+                offset: 8
+            })
+        );
+
+        // Everyone's setpoints get checked, not only our own.
+        let setpoint = Setpoint {
+            node: 1,
+            position: 0.,
+            velocity: 0.,
+        };
+        let group = GroupSetpoints::new(&[
+            setpoint,
+            Setpoint {
+                node: 2,
+                position: f32::NAN,
+                ..setpoint
+            },
+        ]);
+        assert_eq!(
+            GroupSetpoints::decode(&group.pack()),
+            Err(DecodeError::NotFinite {
+                field: "position",
+                offset: 16
+            })
+        );
+    }
+}
@@ -0,0 +1,197 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use pino::{Client, Error, Loopback, SimulatedNode, State};
+    use protocol::id::NodeAddress;
+    use protocol::messages::ArmScopeCmd;
+    use protocol::scope::{Capture, ScopeConfig, ScopeState, ScopeTrigger};
+    use protocol::telemetry::{Signal, Signals};
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn config(trigger: ScopeTrigger, pre_trigger: usize) -> ScopeConfig {
+        ScopeConfig {
+            signals: Signals::new(&[Signal::Angle, Signal::Velocity]),
+            decimation: 1,
+            trigger,
+            trigger_signal: Signal::Angle,
+            threshold: 10.,
+            pre_trigger,
+        }
+    }
+
+    // Angle counts up from zero, velocity counts down.
+    fn run(capture: &mut Capture<Vec<f32>>, from: usize, to: usize, faults: u32) {
+        for i in from..to {
+            let i = i as f32;
+            capture.sample(
+                |signal| match signal {
+                    Signal::Angle => i,
+                    _ => -i,
+                },
+                faults,
+            );
+        }
+    }
+
+    fn trace(capture: &Capture<Vec<f32>>) -> Vec<f32> {
+        let mut values = vec![0.; capture.capacity() * 2];
+        assert_eq!(capture.read(0, &mut values), values.len());
+        values
+    }
+
+    #[test]
+    fn keeps_samples_from_before_the_trigger() {
+        // Room for 8 samples.
+        let mut capture = Capture::new(vec![0.; 17]);
+        capture.arm(config(ScopeTrigger::Rising, 3));
+        assert_eq!(capture.capacity(), 8);
+        assert_eq!(capture.state(), ScopeState::Armed);
+
+        run(&mut capture, 0, 10, 0);
+        assert_eq!(capture.state(), ScopeState::Armed);
+        // Crosses 10 here, and needs 4 more after it.
+        run(&mut capture, 10, 14, 0);
+        assert_eq!(capture.state(), ScopeState::Triggered);
+        let mut values = [0.; 4];
+        assert_eq!(capture.read(0, &mut values), 0);
+        run(&mut capture, 14, 15, 0);
+        assert_eq!(capture.state(), ScopeState::Done);
+
+        assert_eq!(capture.trigger_index(), 3);
+        let values = trace(&capture);
+        let angles: Vec<f32> = values.iter().step_by(2).copied().collect();
+        assert_eq!(angles, vec![7., 8., 9., 10., 11., 12., 13., 14.]);
+        assert_eq!(values[1], -7.);
+
+        // Nothing more goes in once it's done, and reads can start anywhere.
+        run(&mut capture, 15, 20, 0);
+        let mut tail = [0.; 4];
+        assert_eq!(capture.read(13, &mut tail), 3);
+        assert_eq!(&tail[..3], &[-13., 14., -14.]);
+    }
+
+    #[test]
+    fn falling_edges_and_decimation() {
+        let mut capture = Capture::new(vec![0.; 8]);
+        capture.arm(ScopeConfig {
+            decimation: 3,
+            trigger_signal: Signal::Velocity,
+            threshold: -10.,
+            ..config(ScopeTrigger::Falling, 1)
+        });
+        run(&mut capture, 0, 30, 0);
+        assert_eq!(capture.state(), ScopeState::Done);
+        // Only every third iteration is looked at, so it's 12 that crosses.
+        let values = trace(&capture);
+        assert_eq!(values, vec![9., -9., 12., -12., 15., -15., 18., -18.]);
+        assert_eq!(capture.trigger_index(), 1);
+    }
+
+    #[test]
+    fn commands_and_faults_trigger_right_away() {
+        let mut capture = Capture::new(vec![0.; 8]);
+        capture.arm(config(ScopeTrigger::Command, 2));
+        run(&mut capture, 0, 100, 0);
+        assert_eq!(capture.state(), ScopeState::Armed);
+        capture.force();
+        run(&mut capture, 100, 104, 0);
+        assert_eq!(capture.state(), ScopeState::Done);
+        // Only the last two from before, and not the whole buffer's worth of them.
+        assert_eq!(capture.trigger_index(), 2);
+        assert_eq!(trace(&capture)[0], 98.);
+
+        // Faults already there when it's armed don't count.
+        capture.arm(config(ScopeTrigger::Fault, 2));
+        run(&mut capture, 0, 10, 0b01);
+        assert_eq!(capture.state(), ScopeState::Armed);
+        run(&mut capture, 10, 11, 0b11);
+        assert_eq!(capture.state(), ScopeState::Triggered);
+        run(&mut capture, 11, 13, 0b11);
+        assert_eq!(capture.state(), ScopeState::Done);
+        assert_eq!(trace(&capture)[2 * 2], 10.);
+
+        // No signals, no capture.
+        capture.arm(ScopeConfig {
+            signals: Signals::default(),
+            ..config(ScopeTrigger::Command, 0)
+        });
+        assert_eq!(capture.state(), ScopeState::Idle);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_make_sense_of() {
+        let cmd = config(ScopeTrigger::Rising, 5).cmd();
+        assert_eq!(
+            ScopeConfig::from_cmd(&cmd),
+            Some(config(ScopeTrigger::Rising, 5))
+        );
+        let bogus = |trigger, trigger_signal| {
+            ScopeConfig::from_cmd(&ArmScopeCmd {
+                trigger,
+                trigger_signal,
+                ..cmd
+            })
+        };
+        assert_eq!(bogus(7, 0), None);
+        assert_eq!(bogus(ScopeTrigger::Rising as u32, 99), None);
+        // Doesn't matter what it's watching if it's not watching for edges.
+        assert!(bogus(ScopeTrigger::Fault as u32, 99).is_some());
+    }
+
+    #[test]
+    fn downloads_from_simulated_node() {
+        let bus = Loopback::new();
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let node = bus.endpoint();
+        thread::spawn(move || {
+            let mut sim = SimulatedNode::new(node, NodeAddress::new(1, 0));
+            sim.run(&flag).unwrap();
+        });
+        let mut client = Client::new(bus.endpoint(), 1).with_timeout(Duration::from_millis(500));
+
+        // The sim steps a millisecond (40 iterations) at a time, so this takes about 20ms to
+        // trigger and another 30 or so to fill.
+        let config = ScopeConfig {
+            signals: Signals::new(&[Signal::Velocity, Signal::CurrentQ, Signal::VBus]),
+            decimation: 1,
+            trigger: ScopeTrigger::Rising,
+            trigger_signal: Signal::Velocity,
+            threshold: 20.,
+            pre_trigger: 100,
+        };
+        let status = client.arm_scope(config).unwrap();
+        assert_eq!(ScopeState::from_raw(status.state), Some(ScopeState::Armed));
+        assert!(matches!(
+            client.read_scope(),
+            Err(Error::ScopeNotDone(ScopeState::Armed))
+        ));
+        client.enter_torque_control(1., 1., 0.).unwrap();
+
+        let mut done = None;
+        for _ in 0..100 {
+            if let Some(State::Scope(status)) = client.next_state(TIMEOUT).unwrap() {
+                done = Some(status);
+                break;
+            }
+        }
+        let done = done.expect("No status when the capture finished");
+        assert_eq!(ScopeState::from_raw(done.state), Some(ScopeState::Done));
+        assert_eq!(done.samples, 4096 / 3);
+
+        let trace = client.read_scope().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(trace.samples.len(), 4096 / 3);
+        assert_eq!(trace.trigger_index, 100);
+        let trigger = trace.trigger_index;
+        assert!(trace.samples[trigger - 1][0] < 20.);
+        assert!(trace.samples[trigger][0] >= 20.);
+        assert_eq!(trace.samples[trigger][1], 1.);
+        assert_eq!(trace.samples.last().unwrap()[2], 24.);
+    }
+}
@@ -0,0 +1,284 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::motor;
+    use bldc::config::Config;
+    use bldc::control_loops::calibrate_adc::CalibrateADC;
+    use bldc::control_loops::measure_inductance::MeasureInductance;
+    use bldc::control_loops::measure_resistance::{MeasureResistance, Phase, Resistance};
+    use bldc::control_loops::pos_vel_control::{PosVelState, PositionVelocity};
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::control_loops::LoopState;
+    use bldc::current_sensing::PhaseCurrents;
+    use bldc::foc::DQCurrents;
+    use bldc::hal::{PhaseCurrentSource, RotorPositionSensor};
+    use bldc::sim::{MotorParameters, Simulator};
+    use std::sync::Mutex;
+
+    const V_BUS: f32 = 24.;
+
+    fn torque_constant(params: &MotorParameters) -> f32 {
+        1.5 * params.pole_pairs as f32 * params.flux_linkage
+    }
+
+    // Last time the signal was outside `tolerance` of `target`.
+    fn settling_time(samples: &[(f32, f32)], target: f32, tolerance: f32) -> f32 {
+        samples
+            .iter()
+            .filter(|(_, value)| (value - target).abs() > tolerance * target.abs())
+            .map(|(time, _)| *time)
+            .fold(0., f32::max)
+    }
+
+    #[test]
+    fn torque_control_current_step() {
+        // Heavy enough that the rotor barely moves; isolates the current loop.
+        let mut sim = Simulator::new(
+            MotorParameters {
+                inertia: 100.,
+                ..motor()
+            },
+            V_BUS,
+        );
+        sim.motor.set_angle(0.3);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 2., d: 0. });
+
+        let mut q_samples = vec![];
+        let mut max_d: f32 = 0.;
+        sim.run(&mut torque_control, 0.01, |sim| {
+            let currents = sim.motor.dq_currents();
+            q_samples.push((sim.time(), currents.q));
+            max_d = max_d.max(currents.d.abs());
+        });
+
+        // Current loop bandwidth is ~5krad/s, so this should be done within a millisecond.
+        let settled = settling_time(&q_samples, 2., 0.05);
+        assert!(settled < 1e-3, "q current settled after {}s", settled);
+        let (_, final_q) = q_samples.last().unwrap();
+        assert!((final_q - 2.).abs() < 0.02, "Steady-state i_q {}", final_q);
+        // Overshoot
+        let peak = q_samples.iter().map(|(_, q)| *q).fold(0., f32::max);
+        assert!(peak < 2.3, "i_q overshoot to {}", peak);
+        // d axis should be held near zero throughout.
+        assert!(max_d < 0.2, "i_d excursion to {}", max_d);
+    }
+
+    #[test]
+    fn current_loop_matches_its_gains() {
+        // The PI's zero cancels the motor's pole, so the closed loop is first order with a time
+        // constant of L / kp, as long as every volt the loop asks for makes it to the phases. The
+        // default gains were tuned for ~5krad/s; get the PWM scaling (or the gains) wrong and the
+        // loop runs at twice or half that.
+        let params = motor();
+        let mut sim = Simulator::new(
+            MotorParameters {
+                inertia: 100.,
+                ..params
+            },
+            V_BUS,
+        );
+        sim.motor.set_angle(0.3);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 2., d: 0. });
+
+        let mut rise = None;
+        sim.run(&mut torque_control, 2e-3, |sim| {
+            if rise.is_none() && sim.motor.dq_currents().q > 2. * (1. - (-1f32).exp()) {
+                rise = Some(sim.time());
+            }
+        });
+
+        let tau = params.inductance_q / Config::DEFAULT.current_kp;
+        assert!(
+            (1. / tau - 5e3).abs() < 250.,
+            "Gains are tuned for {} rad/s",
+            1. / tau
+        );
+        let rise = rise.expect("i_q never got to 63%");
+        assert!(
+            (rise - tau).abs() < 0.25 * tau,
+            "i_q took {}s to get to 63%, expected {}",
+            rise,
+            tau
+        );
+    }
+
+    #[test]
+    fn current_loop_uses_the_whole_bus() {
+        // Takes 13V to push 13A through an ohm: more than half the bus, but still inside the
+        // v_bus/sqrt(3) SVPWM gets out of it.
+        let mut sim = Simulator::new(
+            MotorParameters {
+                resistance: 1.,
+                inertia: 100.,
+                ..motor()
+            },
+            V_BUS,
+        );
+        sim.motor.set_angle(0.3);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 13., d: 0. });
+        sim.run(&mut torque_control, 0.02, |_| {});
+
+        let q = sim.motor.dq_currents().q;
+        assert!((q - 13.).abs() < 0.1, "Only got to {}A", q);
+    }
+
+    #[test]
+    fn torque_control_accelerates_rotor() {
+        let params = motor();
+        let mut sim = Simulator::new(params, V_BUS);
+        // Torque for 50ms, then coast with zero current.
+        let duration = 0.05;
+        let mut torque_control = TorqueControl::new(duration, DQCurrents { q: 1., d: 0. });
+
+        let mut velocity = 0.;
+        sim.run(&mut torque_control, 2. * duration, |sim| {
+            if sim.time() <= duration {
+                velocity = sim.motor.velocity();
+            }
+        });
+
+        // Friction is small enough to ignore at these speeds.
+        let expected = torque_constant(&params) * 1. / params.inertia * duration;
+        assert!(
+            (velocity - expected).abs() < 0.05 * expected,
+            "Expected {} rad/s, got {}",
+            expected,
+            velocity
+        );
+        // The encoder observer lags while accelerating, but should have caught up while coasting.
+        let velocity = sim.motor.velocity();
+        let estimate = sim.hw.encoder.state().unwrap().velocity.in_radians();
+        assert!(
+            (estimate - velocity).abs() < 0.05 * velocity,
+            "Observer at {} rad/s, rotor at {}",
+            estimate,
+            velocity
+        );
+    }
+
+    #[test]
+    fn position_control_step_response() {
+        let gear_ratio = Config::DEFAULT.gear_ratio;
+        let params = motor();
+        let mut sim = Simulator::new(params, V_BUS);
+        let mut position_control = PositionVelocity::new();
+
+        // Output-side dynamics are J*N^2 * φ'' = k_p * e - k_d * N * φ'. Aim for a natural
+        // frequency of 50 rad/s, critically damped.
+        let reflected_inertia = params.inertia * gear_ratio * gear_ratio;
+        let omega = 50.;
+        let target = 0.2;
+        PositionVelocity::command(PosVelState {
+            position: target,
+            velocity: 0.,
+            stiffness_gain: omega * omega * reflected_inertia,
+            damping_gain: 2. * omega * reflected_inertia / gear_ratio,
+            torque_constant: torque_constant(&params),
+        });
+
+        let mut samples = vec![];
+        sim.run(&mut position_control, 0.5, |sim| {
+            samples.push((sim.time(), sim.motor.angle() / gear_ratio));
+        });
+
+        let settled = settling_time(&samples, target, 0.02);
+        assert!(settled < 0.2, "Position settled after {}s", settled);
+        let peak = samples.iter().map(|(_, p)| *p).fold(0., f32::max);
+        assert!(peak < target * 1.05, "Position overshoot to {}", peak);
+        let (_, position) = samples.last().unwrap();
+        assert!(
+            (position - target).abs() < 0.005,
+            "Steady-state position {}",
+            position
+        );
+
+        // Shutting down should bring the loop back to idle once the rotor stops.
+        sim.shutdown();
+        let mut state = sim.loop_state();
+        for _ in 0..1000 {
+            state = sim.step(&mut position_control);
+        }
+        assert!(matches!(state, LoopState::Idle));
+    }
+
+    #[test]
+    fn calibrate_adc_removes_offsets() {
+        let mut sim = Simulator::new(motor(), V_BUS);
+        sim.hw.current_sensor.set_adc_offsets(PhaseCurrents {
+            phase_a: 0.3,
+            phase_b: -0.2,
+            phase_c: 0.1,
+        });
+        let mut calibrate = CalibrateADC::new(0.01, |_| {});
+        let state = sim.run(&mut calibrate, 1., |_| {});
+        assert!(matches!(state, LoopState::Idle));
+
+        let sample = sim.hw.current_sensor.sample();
+        for current in [sample.phase_a, sample.phase_b, sample.phase_c] {
+            assert!(current.abs() < 1e-4, "Residual offset {}", current);
+        }
+    }
+
+    // The measurement loops hand back their results through plain `fn`s.
+    static RESISTANCE: Mutex<Option<f32>> = Mutex::new(None);
+    static INDUCTANCES: Mutex<Option<[f32; 3]>> = Mutex::new(None);
+
+    #[test]
+    fn measure_resistance_matches_motor() {
+        let params = motor();
+        for phase in [Phase::A, Phase::B, Phase::C] {
+            let mut sim = Simulator::new(
+                MotorParameters {
+                    inertia: 100.,
+                    ..params
+                },
+                V_BUS,
+            );
+            *RESISTANCE.lock().unwrap() = None;
+            let mut measure = MeasureResistance::new(0.1, 1., phase, |result: &Resistance| {
+                *RESISTANCE.lock().unwrap() = Some(result.resistance)
+            });
+            let state = sim.run(&mut measure, 1., |_| {});
+            assert!(matches!(state, LoopState::Idle));
+
+            let resistance = RESISTANCE.lock().unwrap().expect("No resistance reported");
+            assert!(
+                (resistance - params.resistance).abs() < 0.05 * params.resistance,
+                "Measured {} ohms, motor has {}",
+                resistance,
+                params.resistance
+            );
+        }
+    }
+
+    #[test]
+    fn measure_inductance_matches_motor() {
+        let params = motor();
+        let mut sim = Simulator::new(
+            MotorParameters {
+                inertia: 100.,
+                ..params
+            },
+            V_BUS,
+        );
+        let mut measure = MeasureInductance::new(0.1, 5000, 0.1, 0.5, |inductances| {
+            *INDUCTANCES.lock().unwrap() = Some(inductances)
+        });
+        let state = sim.run(&mut measure, 1., |_| {});
+        assert!(matches!(state, LoopState::Idle));
+
+        let inductances = INDUCTANCES
+            .lock()
+            .unwrap()
+            .expect("No inductances reported");
+        for inductance in inductances {
+            assert!(
+                (inductance - params.inductance_q).abs() < 0.05 * params.inductance_q,
+                "Measured {}H, motor has {}",
+                inductance,
+                params.inductance_q
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pino::{Client, Loopback, SimulatedNode, State};
+    use protocol::id::NodeAddress;
+    use protocol::telemetry::{Signal, Signals, TelemetryMsg, TELEMETRY_VALUES};
+    use protocol::{Frame, Wire};
+
+    #[test]
+    fn signals_go_out_in_order() {
+        let signals = Signals::new(&[Signal::LoopTime, Signal::Angle, Signal::CurrentQ]);
+        assert_eq!(signals.len(), 3);
+        assert_eq!(
+            signals.iter().collect::<Vec<_>>(),
+            vec![Signal::Angle, Signal::CurrentQ, Signal::LoopTime]
+        );
+        assert_eq!(signals.samples_per_frame(), 4);
+        assert_eq!(Signal::from_name("current_q"), Some(Signal::CurrentQ));
+        assert_eq!(Signal::from_name("nope"), None);
+
+        // Too many to fit, so the last couple are dropped, along with bits we don't know about.
+        let all = Signals::from_bits(u32::MAX);
+        assert_eq!(all.len(), TELEMETRY_VALUES);
+        assert!(!all.contains(Signal::IntegratorQ));
+        assert!(!all.contains(Signal::LoopTime));
+        assert_eq!(Signals::from_bits(0).samples_per_frame(), 0);
+    }
+
+    #[test]
+    fn frames_pack_and_unpack() {
+        let signals = Signals::new(&[Signal::Angle, Signal::Velocity, Signal::VBus]);
+        let mut frame = TelemetryMsg::new(signals, 40, 100, 2);
+        assert_eq!(TelemetryMsg::SIZE, 64);
+        for i in 0..4 {
+            assert!(frame.push(&[i as f32, -(i as f32), 24.]));
+        }
+        assert!(frame.is_full());
+        assert!(!frame.push(&[0., 0., 0.]));
+        assert_eq!(frame.next_sequence(), 104);
+
+        let decoded = TelemetryMsg::decode(&frame.encode()).unwrap();
+        assert_eq!(decoded, frame);
+        let samples: Vec<&[f32]> = decoded.samples().collect();
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples[2], &[2., -2., 24.]);
+
+        // Claiming more samples than fit doesn't read past the end.
+        let mut bogus = frame;
+        bogus.samples = 100;
+        assert_eq!(bogus.samples().count(), 4);
+    }
+
+    #[test]
+    fn streams_from_simulated_node() {
+        let bus = Loopback::new();
+        let mut sim = SimulatedNode::new(bus.endpoint(), NodeAddress::new(1, 0));
+        let mut client = Client::new(bus.endpoint(), 1);
+
+        // 1kHz, with two samples to a frame.
+        let signals = Signals::new(&[
+            Signal::AngleMultiturn,
+            Signal::Velocity,
+            Signal::CurrentQ,
+            Signal::VBus,
+            Signal::LoopTime,
+        ]);
+        client.start_telemetry(signals, 40).unwrap();
+        client.enter_torque_control(1., 1., 0.).unwrap();
+        sim.poll(Duration::from_millis(100)).unwrap();
+
+        let mut frames = vec![];
+        while let Some(state) = client.next_state(Duration::from_millis(10)).unwrap() {
+            if let State::Telemetry(frame) = state {
+                frames.push(frame);
+            }
+        }
+        // Don't count on exact timing, but there should be about 50.
+        assert!(frames.len() > 20, "{}", frames.len());
+        let mut sequence = 0;
+        for frame in &frames {
+            assert_eq!(frame.signals, signals);
+            assert_eq!(frame.samples, 2);
+            assert_eq!(frame.dropped, 0);
+            assert_eq!(frame.sequence, sequence);
+            sequence = frame.next_sequence();
+        }
+        let last = frames.last().unwrap().samples().last().unwrap();
+        assert!(last[0] > 0. && last[1] > 0., "{:?}", last);
+        assert_eq!(last[2], 1.);
+        assert_eq!(last[3], 24.);
+
+        // The sim catches up on the time it sat idle before it gets to the stop, so there can be a
+        // few more frames straight after. Nothing after that, but status still comes through.
+        client.stop_telemetry().unwrap();
+        sim.poll(Duration::from_millis(50)).unwrap();
+        while client
+            .next_state(Duration::from_millis(10))
+            .unwrap()
+            .is_some()
+        {}
+        sim.poll(Duration::from_millis(200)).unwrap();
+        let mut status = false;
+        while let Some(state) = client.next_state(Duration::from_millis(10)).unwrap() {
+            match state {
+                State::Telemetry(frame) => panic!("Telemetry after stopping: {:?}", frame),
+                State::Status(_) => status = true,
+                _ => {}
+            }
+        }
+        assert!(status);
+    }
+}
@@ -0,0 +1,55 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{self, heavy_motor};
+    use bldc::control_loops::torque_control::TorqueControl;
+    use bldc::foc::DQCurrents;
+    use bldc::sim::Simulator;
+
+    #[test]
+    fn new_setpoints_keep_the_integrators() {
+        let _guard = common::globals();
+        let mut sim = Simulator::new(heavy_motor(), 24.);
+        sim.motor.set_angle(0.3);
+        let mut torque_control = TorqueControl::new(1., DQCurrents { q: 2., d: 0. });
+        sim.run(&mut torque_control, 5e-3, |_| {});
+        let integrator = torque_control.foc().state().integrator_q;
+        assert!(integrator > 0., "Nothing in the integrator to lose");
+
+        // The same setpoint again, as the host would send it every time 0x6071 is written. A
+        // restarted loop would sag while the integrator winds back up.
+        TorqueControl::command(DQCurrents { q: 2., d: 0. });
+        let mut min_q = f32::MAX;
+        sim.run(&mut torque_control, 1e-3, |sim| {
+            min_q = min_q.min(sim.motor.dq_currents().q);
+        });
+        assert!(
+            min_q > 1.95,
+            "i_q sagged to {} on a repeated setpoint",
+            min_q
+        );
+        let drift = (torque_control.foc().state().integrator_q - integrator).abs();
+        assert!(drift < 0.05 * integrator, "Integrator moved by {}", drift);
+
+        // And a new one is picked up on the fly.
+        TorqueControl::command(DQCurrents { q: 3., d: 0. });
+        sim.run(&mut torque_control, 2e-3, |_| {});
+        let q = sim.motor.dq_currents().q;
+        assert!((q - 3.).abs() < 0.05, "i_q {} after moving the setpoint", q);
+    }
+
+    #[test]
+    fn unbounded_torque_keeps_going() {
+        let _guard = common::globals();
+        let mut bounded = Simulator::new(heavy_motor(), 24.);
+        let mut unbounded = Simulator::new(heavy_motor(), 24.);
+        let currents = DQCurrents { q: 2., d: 0. };
+        bounded.run(&mut TorqueControl::new(0.01, currents), 0.02, |_| {});
+        unbounded.run(&mut TorqueControl::unbounded(currents), 0.02, |_| {});
+
+        assert!(bounded.motor.dq_currents().q.abs() < 0.05);
+        let q = unbounded.motor.dq_currents().q;
+        assert!((q - 2.).abs() < 0.05, "i_q {} after the hold", q);
+    }
+}
@@ -0,0 +1,326 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use pino::bus::Bus;
+    use pino::{Client, Error, Loopback, MemoryFlash, SimulatedNode};
+    use protocol::id::{Address, CanId, NodeAddress};
+    use protocol::messages::{MessageID, UpdateAckMsg, UpdateChunkCmd};
+    use protocol::update::{
+        self, crc32, BootRecord, Handoff, ImageHeader, Slot, SlotFlash, UpdateStatus, Updater,
+        MAX_ATTEMPTS, UPDATE_CHUNK_BYTES,
+    };
+    use protocol::Frame;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    // Something that looks enough like an image linked for `slot`: a stack pointer, a reset vector
+    // just past the vector table, and then junk.
+    fn image(slot: Slot, len: usize) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+        image[..4].copy_from_slice(&0x2001_8000u32.to_le_bytes());
+        image[4..8].copy_from_slice(&(slot.address() + 0x1D9).to_le_bytes());
+        image
+    }
+
+    fn chunk(image: &[u8], offset: u32) -> [u8; UPDATE_CHUNK_BYTES] {
+        let mut data = [0; UPDATE_CHUNK_BYTES];
+        let end = image.len().min(offset as usize + UPDATE_CHUNK_BYTES);
+        data[..end - offset as usize].copy_from_slice(&image[offset as usize..end]);
+        data
+    }
+
+    fn write(flash: &mut MemoryFlash, updater: &mut Updater, image: &[u8]) {
+        updater.begin(ImageHeader::new(image).unwrap()).unwrap();
+        let mut next = 0;
+        while (next as usize) < image.len() {
+            next = updater.chunk(flash, next, &chunk(image, next)).unwrap();
+        }
+    }
+
+    fn header(slot: Slot) -> ImageHeader {
+        ImageHeader::new(&image(slot, 1000)).unwrap()
+    }
+
+    #[test]
+    fn images_go_by_their_reset_vector() {
+        assert_eq!(Slot::for_image(&image(Slot::A, 100)), Some(Slot::A));
+        assert_eq!(Slot::for_image(&image(Slot::B, 100)), Some(Slot::B));
+        // Linked for the start of flash, like bldc used to be.
+        let mut old = image(Slot::A, 100);
+        old[4..8].copy_from_slice(&0x0800_01D9u32.to_le_bytes());
+        assert_eq!(Slot::for_image(&old), None);
+        assert_eq!(ImageHeader::new(&old), None);
+        assert_eq!(Slot::for_image(&[0; 6]), None);
+
+        // Known value for CRC-32/ISO-HDLC.
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn writes_an_image_a_chunk_at_a_time() {
+        let mut flash = MemoryFlash::new();
+        let mut updater = Updater::new(Slot::B);
+        // Doesn't end on a chunk or a double word, and spans a few pages.
+        let image = image(Slot::B, 5003);
+        write(&mut flash, &mut updater, &image);
+
+        let header = updater.finish(&flash).unwrap();
+        assert_eq!(header, ImageHeader::new(&image).unwrap());
+        let mut written = vec![0; image.len() + 8];
+        flash.read(Slot::B.address(), &mut written);
+        assert_eq!(&written[..image.len()], &image[..]);
+        // The tail's padded with erased flash.
+        assert!(written[image.len()..].iter().all(|byte| *byte == 0xFF));
+        // And it's done with; the next one starts over.
+        assert_eq!(updater.finish(&flash), Err(UpdateStatus::NotStarted));
+    }
+
+    #[test]
+    fn resends_and_gaps() {
+        let mut flash = MemoryFlash::new();
+        let mut updater = Updater::new(Slot::A);
+        let image = image(Slot::A, 500);
+        let data = |offset| chunk(&image, offset);
+
+        assert_eq!(
+            updater.chunk(&mut flash, 0, &data(0)),
+            Err(UpdateStatus::NotStarted)
+        );
+        updater.begin(ImageHeader::new(&image).unwrap()).unwrap();
+        assert_eq!(updater.chunk(&mut flash, 0, &data(0)), Ok(56));
+        // The ack went missing, so it's sent again. Programming it twice would fail.
+        assert_eq!(updater.chunk(&mut flash, 0, &data(0)), Ok(56));
+        assert_eq!(
+            updater.chunk(&mut flash, 112, &data(112)),
+            Err(UpdateStatus::OutOfOrder)
+        );
+        assert_eq!(updater.next(), 56);
+        assert_eq!(updater.finish(&flash), Err(UpdateStatus::Incomplete));
+
+        let mut next = 56;
+        while next < 500 {
+            next = updater.chunk(&mut flash, next, &data(next)).unwrap();
+        }
+        assert_eq!(next, 500);
+        assert!(updater.finish(&flash).is_ok());
+    }
+
+    #[test]
+    fn turns_away_bad_images() {
+        let mut flash = MemoryFlash::new();
+        let mut updater = Updater::new(Slot::B);
+        assert_eq!(updater.begin(header(Slot::A)), Err(UpdateStatus::WrongSlot));
+        assert_eq!(
+            updater.begin(ImageHeader {
+                length: 0,
+                ..header(Slot::B)
+            }),
+            Err(UpdateStatus::BadLength)
+        );
+
+        // Claims a CRC that doesn't match what gets sent.
+        let image = image(Slot::B, 1000);
+        let header = ImageHeader {
+            crc: !crc32(&image),
+            ..ImageHeader::new(&image).unwrap()
+        };
+        updater.begin(header).unwrap();
+        let mut next = 0;
+        while next < 1000 {
+            next = updater
+                .chunk(&mut flash, next, &chunk(&image, next))
+                .unwrap();
+        }
+        assert_eq!(updater.finish(&flash), Err(UpdateStatus::BadCrc));
+    }
+
+    #[test]
+    fn records_alternate_pages() {
+        let mut flash = MemoryFlash::new();
+        assert_eq!(update::load_record(&flash), None);
+
+        let first = update::save_record(&mut flash, &BootRecord::empty()).unwrap();
+        assert_eq!(update::load_record(&flash), Some(first));
+        let installed = first.installed(header(Slot::A));
+        let second = update::save_record(&mut flash, &installed).unwrap();
+        assert_eq!(second.sequence, first.sequence + 1);
+        assert_eq!(second.pending, Some(Slot::A));
+        assert_eq!(update::load_record(&flash), Some(second));
+
+        // Losing power partway through the next one leaves the last good one there.
+        let page = update::BOOT_RECORD_ADDRESS;
+        flash.erase_page(page).unwrap();
+        flash.program(page, &[0x42; 16]).unwrap();
+        assert_eq!(update::load_record(&flash), Some(second));
+    }
+
+    #[test]
+    fn new_images_are_on_trial() {
+        let valid = |_: &ImageHeader| true;
+        let record = BootRecord {
+            active: Some(Slot::A),
+            images: [Some(header(Slot::A)), None],
+            ..BootRecord::empty()
+        };
+        assert_eq!(record.target(), Slot::B);
+        let record = record.installed(header(Slot::B));
+
+        // Booted up to `MAX_ATTEMPTS` times without it ever saying it's healthy...
+        let mut handoff = Handoff::default();
+        for attempt in 1..=MAX_ATTEMPTS {
+            let decision = update::decide(&record, &mut handoff, valid);
+            assert_eq!(decision.boot, Some(Slot::B));
+            assert_eq!(decision.record, None);
+            assert_eq!(handoff.attempts, attempt);
+        }
+        // ...and then it's back to the old one.
+        let decision = update::decide(&record, &mut handoff, valid);
+        assert_eq!(decision.boot, Some(Slot::A));
+        let fallen_back = decision.record.unwrap();
+        assert_eq!(fallen_back.active, Some(Slot::A));
+        assert_eq!(fallen_back.pending, None);
+
+        // Second time around it comes up, so it's kept.
+        let mut handoff = Handoff::default();
+        update::decide(&record, &mut handoff, valid);
+        handoff.healthy = true;
+        let decision = update::decide(&record, &mut handoff, valid);
+        assert_eq!(decision.boot, Some(Slot::B));
+        let promoted = decision.record.unwrap();
+        assert_eq!(promoted.active, Some(Slot::B));
+        assert_eq!(promoted.pending, None);
+        assert_eq!(promoted.target(), Slot::A);
+        assert!(!handoff.healthy);
+    }
+
+    #[test]
+    fn stays_when_asked_or_with_nothing_to_boot() {
+        let record = BootRecord {
+            active: Some(Slot::A),
+            images: [Some(header(Slot::A)), None],
+            ..BootRecord::empty()
+        };
+        let node = NodeAddress::new(5, 2);
+        let mut handoff = Handoff {
+            stay: true,
+            node: Some(node),
+            ..Handoff::default()
+        };
+        let decision = update::decide(&record, &mut handoff, |_| true);
+        assert_eq!(decision.boot, None);
+        // Remembers where it was on the bus, for after a power cycle.
+        assert_eq!(decision.record.unwrap().node, Some(node));
+        assert!(!handoff.stay);
+
+        // The active image has gone bad somehow.
+        let mut handoff = Handoff::default();
+        let decision = update::decide(&record, &mut handoff, |_| false);
+        assert_eq!(decision.boot, None);
+        let decision = update::decide(&BootRecord::empty(), &mut handoff, |_| true);
+        assert_eq!(decision.boot, None);
+    }
+
+    #[test]
+    fn handoff_survives_a_reset() {
+        let handoff = Handoff {
+            stay: true,
+            healthy: false,
+            node: Some(NodeAddress::new(9, 1)),
+            attempts: 2,
+        };
+        assert_eq!(Handoff::from_words(&handoff.to_words()), handoff);
+        // Power-on garbage.
+        assert_eq!(
+            Handoff::from_words(&[0x1234_5678, 0xFFFF_FFFF, 7]),
+            Handoff::default()
+        );
+    }
+
+    #[test]
+    fn updates_simulated_node() {
+        let bus = Loopback::new();
+        let endpoint = bus.endpoint();
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let sim = thread::spawn(move || {
+            let mut sim = SimulatedNode::new(endpoint, NodeAddress::new(4, 0));
+            sim.run(&flag).unwrap();
+            sim
+        });
+        let mut client = Client::new(bus.endpoint(), 4).with_timeout(TIMEOUT);
+
+        // The application doesn't answer the bootloader's requests.
+        assert!(matches!(client.boot_status(), Err(Error::Timeout)));
+        let status = client.reboot_to_bootloader().unwrap();
+        assert_eq!(Slot::from_raw(status.active), None);
+        assert_eq!(Slot::from_raw(status.target), Some(Slot::A));
+        assert!(matches!(
+            client.update(&image(Slot::B, 3000), |_, _| {}),
+            Err(Error::Update(UpdateStatus::WrongSlot))
+        ));
+
+        // A chunk cut short gets turned away instead of written out padded with zeroes.
+        let mut raw = bus.endpoint();
+        let mut frame = UpdateChunkCmd {
+            offset: 0,
+            data: chunk(&image(Slot::A, 3000), 0),
+        }
+        .encode();
+        frame.id = CanId {
+            command: frame.id as u8,
+            address: Address::Node(4),
+            from_node: false,
+            transaction: 0,
+        }
+        .encode();
+        frame.size = 8;
+        raw.send(&frame).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        let ack = loop {
+            let mut reply = raw
+                .receive(deadline.saturating_duration_since(Instant::now()))
+                .unwrap()
+                .expect("Never got an ack");
+            if reply.id as u8 == MessageID::UpdateAck as u8 {
+                reply.id = MessageID::UpdateAck as u32;
+                break UpdateAckMsg::decode(&reply).unwrap();
+            }
+        };
+        assert_eq!(ack.request, MessageID::UpdateChunk as u32);
+        assert_eq!(
+            UpdateStatus::from_raw(ack.status),
+            Some(UpdateStatus::Malformed)
+        );
+
+        let mut progress = vec![];
+        client
+            .update(&image(Slot::A, 3000), |written, total| {
+                progress.push((written, total))
+            })
+            .unwrap();
+        assert_eq!(progress.last(), Some(&(3000, 3000)));
+        client.boot().unwrap();
+        // Came up and heard from us (asking it to reboot counts), so the next trip through the
+        // bootloader keeps it.
+        thread::sleep(Duration::from_millis(50));
+        let status = client.reboot_to_bootloader().unwrap();
+        assert_eq!(Slot::from_raw(status.active), Some(Slot::A));
+        assert_eq!(Slot::from_raw(status.pending), None);
+        assert_eq!(Slot::from_raw(status.target), Some(Slot::B));
+        assert_eq!(status.address, Slot::B.address());
+
+        stop.store(true, Ordering::Relaxed);
+        let sim = sim.join().unwrap();
+        assert!(sim.in_bootloader());
+        let record = sim.boot_record().unwrap();
+        assert_eq!(record.node, Some(NodeAddress::new(4, 0)));
+        assert_eq!(
+            record.image(Slot::A),
+            ImageHeader::new(&image(Slot::A, 3000))
+        );
+    }
+}
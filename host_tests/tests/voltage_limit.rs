@@ -0,0 +1,73 @@
+#[cfg(test)]
+mod tests {
+    use bldc::foc::{limit_voltage_vector, DQVoltages, Modulation};
+    use bldc::pi_controller::PIController;
+
+    const V_BUS: f32 = 24.;
+    const SQRT_3: f32 = 1.7320508;
+    const EPSILON: f32 = 1e-4;
+
+    fn limit(q: f32, d: f32) -> DQVoltages {
+        limit_voltage_vector(DQVoltages { q, d }, V_BUS / SQRT_3)
+    }
+
+    #[test]
+    fn small_vectors_pass_through() {
+        let limited = limit(3., -2.);
+        assert!((limited.q - 3.).abs() < EPSILON);
+        assert!((limited.d + 2.).abs() < EPSILON);
+    }
+
+    #[test]
+    fn saturated_vector_lies_on_the_circle() {
+        let v_max = V_BUS / SQRT_3;
+        let limited = limit(20., 5.);
+        let magnitude = (limited.q * limited.q + limited.d * limited.d).sqrt();
+        assert!((magnitude - v_max).abs() < EPSILON);
+    }
+
+    #[test]
+    fn d_axis_has_priority() {
+        let v_max = V_BUS / SQRT_3;
+        // d is left untouched while q gets what's left over.
+        let limited = limit(-20., 8.);
+        assert!((limited.d - 8.).abs() < EPSILON);
+        assert!((limited.q + (v_max * v_max - 64.).sqrt()).abs() < EPSILON);
+        // A d request past the limit takes the whole circle.
+        let limited = limit(5., -30.);
+        assert!((limited.d + v_max).abs() < EPSILON);
+        assert!(limited.q.abs() < EPSILON);
+    }
+
+    #[test]
+    fn limit_follows_modulation() {
+        assert!((Modulation::Sinusoidal.max_voltage(V_BUS) - 12.).abs() < EPSILON);
+        assert!((Modulation::SpaceVector.max_voltage(V_BUS) - V_BUS / SQRT_3).abs() < EPSILON);
+        assert!((Modulation::Discontinuous.max_voltage(V_BUS) - V_BUS / SQRT_3).abs() < EPSILON);
+    }
+
+    #[test]
+    fn integrator_settles_at_saturated_output() {
+        let mut pi = PIController::new(1.4211424, 0.055681818, 24.);
+        let applied = 5.;
+        for _ in 0..10_000 {
+            let output = pi.update(0., 10.);
+            pi.saturate(output.min(applied));
+        }
+        // Without anti-windup the integrator would run into the 24V clamp.
+        assert!((pi.integral() - applied).abs() < 1e-2);
+    }
+
+    #[test]
+    fn recovers_quickly_when_saturation_ends() {
+        let mut pi = PIController::new(1.4211424, 0.055681818, 24.);
+        for _ in 0..10_000 {
+            let output = pi.update(0., 10.);
+            pi.saturate(output.min(5.));
+        }
+        // Target drops back to the measurement; the output should fall straight out of saturation
+        // instead of waiting for a wound-up integrator to unwind.
+        let output = pi.update(0., 0.);
+        assert!(output <= 5. + EPSILON);
+    }
+}
@@ -0,0 +1,116 @@
+// Acks for commands from the host. Any command sent with a non-zero transaction in its ID (see
+// `id`) gets an `AckMsg` back once the node's dealt with it, carrying the command and transaction
+// it's for and how it went. Commands that have a reply of their own still get one, on top of the
+// ack. Commands that don't decode get one regardless, with whatever transaction they came with;
+// see `reply`.
+//
+// `detail` depends on the status:
+// - `Faulted`: the critical faults that kept a loop from starting, same as `FaultStatusMsg`.
+// - `Malformed`: how many bytes the command should have had.
+// - `Invalid`: where the offending field starts in the payload.
+// - anything else: zero.
+
+use crate::messages::{AckMsg, MessageID};
+use crate::DecodeError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AckStatus {
+    Ok = 0,
+    // There's a critical fault latched, so nothing's going to run until it's cleared.
+    Faulted = 1,
+    // Couldn't be read, e.g. it was too short.
+    Malformed = 2,
+    // Made sense, but couldn't be done; e.g. a parameter that doesn't exist, or a scope config
+    // that doesn't add up.
+    Rejected = 3,
+    // Nothing on the node handles it. Probably meant for the bootloader, or newer firmware.
+    Unsupported = 4,
+    // Decoded, but something in it was NaN, infinite or out of range, so it was dropped.
+    Invalid = 5,
+}
+
+impl AckStatus {
+    pub fn from_raw(status: u32) -> Option<AckStatus> {
+        match status {
+            0 => Some(AckStatus::Ok),
+            1 => Some(AckStatus::Faulted),
+            2 => Some(AckStatus::Malformed),
+            3 => Some(AckStatus::Rejected),
+            4 => Some(AckStatus::Unsupported),
+            5 => Some(AckStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+// Why a command didn't go through, for the node to put in its ack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Nack {
+    // Critical faults, as bits.
+    Faulted(u32),
+    Malformed(DecodeError),
+    Rejected,
+    Unsupported,
+}
+
+impl Nack {
+    pub fn status(&self) -> AckStatus {
+        match self {
+            Nack::Faulted(_) => AckStatus::Faulted,
+            Nack::Malformed(DecodeError::NotFinite { .. } | DecodeError::OutOfRange { .. }) => {
+                AckStatus::Invalid
+            }
+            Nack::Malformed(_) => AckStatus::Malformed,
+            Nack::Rejected => AckStatus::Rejected,
+            Nack::Unsupported => AckStatus::Unsupported,
+        }
+    }
+
+    pub fn detail(&self) -> u32 {
+        match *self {
+            Nack::Faulted(faults) => faults,
+            Nack::Malformed(DecodeError::Length { expected, .. }) => expected as u32,
+            Nack::Malformed(
+                DecodeError::NotFinite { offset, .. } | DecodeError::OutOfRange { offset, .. },
+            ) => offset as u32,
+            _ => 0,
+        }
+    }
+}
+
+impl From<DecodeError> for Nack {
+    fn from(error: DecodeError) -> Self {
+        Nack::Malformed(error)
+    }
+}
+
+// `request` is the command's raw `MessageID`, since it might not be one we know about.
+pub fn ack(request: u32, transaction: u8, result: Result<(), Nack>) -> AckMsg {
+    let (status, detail) = match result {
+        Ok(()) => (AckStatus::Ok, 0),
+        Err(nack) => (nack.status(), nack.detail()),
+    };
+    AckMsg {
+        request,
+        transaction: transaction as u32,
+        status: status as u32,
+        detail,
+    }
+}
+
+// What goes back for a command, if anything. Acks that were asked for always go, and so does one
+// for anything that didn't decode: those get dropped, and a NaN setpoint that's quietly ignored
+// looks an awful lot like a joint that's stopped listening.
+pub fn reply(request: u32, transaction: u8, result: Result<(), Nack>) -> Option<AckMsg> {
+    match result {
+        Err(Nack::Malformed(_)) => Some(ack(request, transaction, result)),
+        _ if transaction != 0 => Some(ack(request, transaction, result)),
+        _ => None,
+    }
+}
+
+impl AckMsg {
+    pub fn acks(&self, request: MessageID, transaction: u8) -> bool {
+        self.request == request as u32 && self.transaction == transaction as u32
+    }
+}
@@ -0,0 +1,63 @@
+// How healthy a node's connection to the bus is, as reported in `BusStatusMsg`. The states and error
+// codes are straight out of the CAN spec (and the FDCAN's PSR; see RM0440 44.4.16), so they mean
+// the same thing whatever's on the other end.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BusState {
+    // Both error counters under 96.
+    Active = 0,
+    // One of them's at 96 or over. Still working normally, but something's not right.
+    Warning = 1,
+    // One of them's at 128 or over. Still sending and receiving, but can't flag errors in anyone
+    // else's frames, and has to back off between its own.
+    Passive = 2,
+    // Transmit errors went past 255 and the node took itself off the bus. It comes back on its own
+    // after a while; see the firmware's `comms::bus_monitor`.
+    BusOff = 3,
+}
+
+impl BusState {
+    pub fn from_raw(state: u32) -> Option<BusState> {
+        match state {
+            0 => Some(BusState::Active),
+            1 => Some(BusState::Warning),
+            2 => Some(BusState::Passive),
+            3 => Some(BusState::BusOff),
+            _ => None,
+        }
+    }
+}
+
+// What went wrong with the last frame that had a problem, sent or received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolError {
+    None = 0,
+    // More than five equal bits in a row.
+    Stuff = 1,
+    // A fixed format part of the frame was wrong.
+    Form = 2,
+    // Nobody acknowledged a frame we sent. Usually means we're alone on the bus, or nobody else is
+    // at the same bit rate.
+    Ack = 3,
+    // Sent a recessive bit but read back a dominant one, outside of arbitration.
+    Bit1 = 4,
+    // The other way around.
+    Bit0 = 5,
+    Crc = 6,
+}
+
+impl ProtocolError {
+    // 7 is the FDCAN's "nothing new since you last looked", which is as good as none.
+    pub fn from_raw(code: u32) -> Option<ProtocolError> {
+        match code {
+            0 | 7 => Some(ProtocolError::None),
+            1 => Some(ProtocolError::Stuff),
+            2 => Some(ProtocolError::Form),
+            3 => Some(ProtocolError::Ack),
+            4 => Some(ProtocolError::Bit1),
+            5 => Some(ProtocolError::Bit0),
+            6 => Some(ProtocolError::Crc),
+            _ => None,
+        }
+    }
+}
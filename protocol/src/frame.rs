@@ -0,0 +1,184 @@
+use crate::messages::MessageID;
+
+// Biggest payload a CAN-FD frame can carry.
+pub const MAX_LEN: usize = 64;
+
+// A frame as it sits in the FDCAN's message RAM: the payload's packed little-endian into words, so
+// byte 0 is the bottom byte of `data[0]`. `size` is in bytes. Frames coming off the bus are rounded
+// up to the next size CAN-FD can actually send (12, 16, 20, ...), so it can be a bit more than
+// what was sent, but never less.
+#[derive(Debug, Clone)]
+pub struct FdcanMessage {
+    pub id: u32,
+    pub data: [u32; 16],
+    pub size: u8,
+}
+
+impl FdcanMessage {
+    pub fn new<const T: usize>(message: u32, data: &[u32; T]) -> FdcanMessage {
+        // There's no real idiomatic way to zero-initialize-and-fill-in-up-to-length in Rust as of
+        // Aug '21.
+        let len = T.min(16);
+        let mut message = FdcanMessage {
+            id: message,
+            data: [0; 16],
+            size: (len * 4) as u8,
+        };
+        message.data[..len].copy_from_slice(&data[..len]);
+        message
+    }
+
+    pub fn from_bytes(message: u32, bytes: &[u8]) -> FdcanMessage {
+        let len = bytes.len().min(MAX_LEN);
+        let mut message = FdcanMessage {
+            id: message,
+            data: [0; 16],
+            size: len as u8,
+        };
+        for (i, byte) in bytes[..len].iter().enumerate() {
+            message.data[i / 4] |= (*byte as u32) << (8 * (i % 4));
+        }
+        message
+    }
+
+    // The whole payload, zero padded out to `MAX_LEN`.
+    pub fn bytes(&self) -> [u8; MAX_LEN] {
+        let mut bytes = [0; MAX_LEN];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(self.data) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+pub trait IncomingFdcanFrame {
+    // Unpack the message from a buffer.
+    fn unpack(message: FdcanMessage) -> Self;
+}
+
+pub trait OutgoingFdcanFrame {
+    // Pack the message into a buffer of up to 64 bytes, returning the number of bytes that were
+    // packed.
+    fn pack(&self) -> FdcanMessage;
+}
+
+// For anything that's already been packed, or doesn't fit a fixed layout.
+impl OutgoingFdcanFrame for FdcanMessage {
+    fn pack(&self) -> FdcanMessage {
+        self.clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    // Meant for some other message.
+    UnexpectedId(u32),
+    // Too short to hold everything the message needs, in bytes.
+    Length { expected: usize, actual: usize },
+    // A float that's NaN or infinite. `offset` is where the field starts in the payload.
+    NotFinite { field: &'static str, offset: usize },
+    // Outside the limits the message puts on it; see `#[derive(Frame)]`.
+    OutOfRange { field: &'static str, offset: usize },
+}
+
+// Something with a fixed, byte-exact layout. Everything's little-endian, and there's no padding
+// unless asked for; see `#[derive(Wire)]`.
+pub trait Wire: Sized {
+    const SIZE: usize;
+
+    // `bytes` is exactly `SIZE` long, and zeroed to begin with.
+    fn write(&self, bytes: &mut [u8]);
+    // Same here. Anything's a valid bit pattern; see `Frame::validate` for whether the values make
+    // sense.
+    fn read(bytes: &[u8]) -> Self;
+}
+
+// A message that goes out as a frame of its own; see `#[derive(Frame)]`. These are what get
+// declared in `messages`, and the only things that should go out on the bus.
+pub trait Frame: Wire {
+    const ID: MessageID;
+
+    fn encode(&self) -> FdcanMessage {
+        let mut bytes = [0; MAX_LEN];
+        self.write(&mut bytes[..Self::SIZE]);
+        FdcanMessage::from_bytes(Self::ID.into(), &bytes[..Self::SIZE])
+    }
+
+    // Checks the ID and length, then whatever `validate` does.
+    fn decode(message: &FdcanMessage) -> Result<Self, DecodeError> {
+        if message.id != Self::ID.into() {
+            return Err(DecodeError::UnexpectedId(message.id));
+        }
+        if (message.size as usize) < Self::SIZE {
+            return Err(DecodeError::Length {
+                expected: Self::SIZE,
+                actual: message.size as usize,
+            });
+        }
+        let frame = Self::read_payload(message);
+        frame.validate()?;
+        Ok(frame)
+    }
+
+    // Whether the values make sense, as far as the message itself can tell.
+    fn validate(&self) -> Result<(), DecodeError> {
+        Ok(())
+    }
+
+    // Doesn't check anything. A short frame reads as zeroes past its end.
+    fn read_payload(message: &FdcanMessage) -> Self {
+        Self::read(&message.bytes()[..Self::SIZE])
+    }
+}
+
+macro_rules! wire_primitive {
+    ( $( $t:ty ),* ) => {
+        $(
+            impl Wire for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+
+                fn write(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn read(bytes: &[u8]) -> Self {
+                    let mut buffer = [0; core::mem::size_of::<$t>()];
+                    buffer.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(buffer)
+                }
+            }
+        )*
+    };
+}
+
+wire_primitive!(u8, u16, u32, i8, i16, i32, f32);
+
+impl Wire for bool {
+    const SIZE: usize = 1;
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[0] = *self as u8;
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+impl<T: Wire + Copy + Default, const N: usize> Wire for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn write(&self, bytes: &mut [u8]) {
+        for (chunk, item) in bytes.chunks_exact_mut(T::SIZE.max(1)).zip(self) {
+            item.write(chunk);
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        let mut items = [T::default(); N];
+        for (item, chunk) in items.iter_mut().zip(bytes.chunks_exact(T::SIZE.max(1))) {
+            *item = T::read(chunk);
+        }
+        items
+    }
+}
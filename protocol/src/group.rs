@@ -0,0 +1,96 @@
+// Group setpoints: position/velocity targets for several joints packed into one 64-byte frame, sent
+// to a group or broadcast address. Each node picks out its own entry (if there is one) and stages
+// it until the next `Sync` frame; see `PositionVelocity::stage`.
+//
+// Each entry is three words: node ID, position, velocity. A node ID of zero marks an unused entry,
+// which is why zero isn't a valid node ID in the first place (see `id`).
+
+use crate::frame::{DecodeError, Wire};
+use crate::Frame;
+
+pub const GROUP_SETPOINTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Setpoint {
+    pub node: u8,
+    pub position: f32,
+    pub velocity: f32,
+}
+
+// The node ID gets a whole word to itself, and zero means there's nothing there.
+impl Wire for Option<Setpoint> {
+    const SIZE: usize = 12;
+
+    fn write(&self, bytes: &mut [u8]) {
+        if let Some(setpoint) = self {
+            (setpoint.node as u32).write(&mut bytes[0..4]);
+            setpoint.position.write(&mut bytes[4..8]);
+            setpoint.velocity.write(&mut bytes[8..12]);
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        match u32::read(&bytes[0..4]) as u8 {
+            0 => None,
+            node => Some(Setpoint {
+                node,
+                position: f32::read(&bytes[4..8]),
+                velocity: f32::read(&bytes[8..12]),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GroupSetPosVel)]
+#[validate(GroupSetpoints::validate_setpoints)]
+pub struct GroupSetpoints {
+    pub setpoints: [Option<Setpoint>; GROUP_SETPOINTS],
+}
+
+impl GroupSetpoints {
+    // Build a frame from up to `GROUP_SETPOINTS` setpoints; any beyond that are dropped.
+    pub fn new(setpoints: &[Setpoint]) -> GroupSetpoints {
+        let mut group = GroupSetpoints {
+            setpoints: [None; GROUP_SETPOINTS],
+        };
+        for (entry, setpoint) in group.setpoints.iter_mut().zip(setpoints) {
+            *entry = Some(*setpoint);
+        }
+        group
+    }
+
+    // Our entry, if the host sent us one. If a node shows up more than once the first one wins.
+    pub fn find(&self, node: u8) -> Option<Setpoint> {
+        self.setpoints
+            .iter()
+            .flatten()
+            .find(|setpoint| setpoint.node == node)
+            .copied()
+    }
+
+    // Every setpoint that's there has to be finite, including other nodes' so that a bad frame gets
+    // reported by everyone rather than only whoever it was meant for.
+    fn validate_setpoints(&self) -> Result<(), DecodeError> {
+        for (n, setpoint) in self.setpoints.iter().enumerate() {
+            let setpoint = match setpoint {
+                Some(setpoint) => setpoint,
+                None => continue,
+            };
+            let offset = n * <Option<Setpoint>>::SIZE;
+            if !setpoint.position.is_finite() {
+                return Err(DecodeError::NotFinite {
+                    field: "position",
+                    offset: offset + 4,
+                });
+            }
+            if !setpoint.velocity.is_finite() {
+                return Err(DecodeError::NotFinite {
+                    field: "velocity",
+                    offset: offset + 8,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+// Hall sensor calibration, as reported in `HallCalibrationMsg`. Three hall sensors split each
+// electrical turn into six sectors; the calibration is the electrical angle at the middle of each
+// one, in the order the sensors step through them going forwards. See the firmware's `hall`.
+
+pub const HALL_SECTORS: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HallCalibrationStatus {
+    Ok = 0,
+    // The rotor went all the way round without one of the sectors ever showing up. Usually a
+    // sensor that's stuck or not plugged in.
+    MissingSector = 1,
+    // All three sensors read the same, which they never should. Also usually a sensor that's not
+    // plugged in.
+    InvalidState = 2,
+    // The sensors jumped straight past a sector more often than they stepped through them in order.
+    // Either the rotor couldn't keep up, or one of the sensors is flaky.
+    Skipped = 3,
+    // The rotor's not on halls, so there's nothing to calibrate.
+    NotHalls = 4,
+    // Stopped before it was done, e.g. by a fault or `DisableControlLoop`.
+    Aborted = 5,
+}
+
+impl HallCalibrationStatus {
+    pub fn from_raw(status: u32) -> Option<HallCalibrationStatus> {
+        match status {
+            0 => Some(HallCalibrationStatus::Ok),
+            1 => Some(HallCalibrationStatus::MissingSector),
+            2 => Some(HallCalibrationStatus::InvalidState),
+            3 => Some(HallCalibrationStatus::Skipped),
+            4 => Some(HallCalibrationStatus::NotHalls),
+            5 => Some(HallCalibrationStatus::Aborted),
+            _ => None,
+        }
+    }
+}
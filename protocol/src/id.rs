@@ -0,0 +1,160 @@
+// Packing of the 29-bit extended identifier, so more than one joint can share a bus.
+//
+//   bits 0-7:   command, i.e. a `MessageID`
+//   bits 8-15:  address; a node ID, a group, or broadcast
+//   bit 16:     set on frames sent by a node, clear on frames from the host
+//   bits 17-20: `PROTOCOL_VERSION`
+//   bits 21-28: transaction; see `ack`
+//
+// Frames from the host are addressed to whoever should act on them. Frames from a node carry that
+// node's own ID, so the host can tell who's talking. Nodes only ever accept frames from the host
+// addressed to them, their group, or everyone; see `filters`.
+//
+// A command the host wants acked carries a non-zero transaction, and the node's `Ack` comes back
+// with the same one. Zero means nobody's waiting on it, which is what anything streamed (setpoints,
+// heartbeats, ...) should use. Everything a node sends has it zeroed.
+//
+// Frames from anything speaking a different version of the protocol don't decode, and don't make it
+// past a node's filters either. Better to be ignored than have a payload read with the wrong
+// layout.
+
+use crate::PROTOCOL_VERSION;
+
+pub const MAX_NODE_ID: u8 = 0xEF;
+pub const MAX_GROUP: u8 = 0x0E;
+
+const GROUP_BASE: u8 = 0xF0;
+const BROADCAST: u8 = 0xFF;
+const ADDRESS_SHIFT: u32 = 8;
+const FROM_NODE: u32 = 1 << 16;
+const VERSION_SHIFT: u32 = 17;
+const VERSION: u32 = 0xF << VERSION_SHIFT;
+const TRANSACTION_SHIFT: u32 = 21;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Address {
+    // 1 to `MAX_NODE_ID`. Zero is left unused so a blank config can't be mistaken for a node.
+    Node(u8),
+    // 0 to `MAX_GROUP`.
+    Group(u8),
+    Broadcast,
+}
+
+impl Address {
+    fn bits(&self) -> u32 {
+        let address = match *self {
+            Address::Node(node) => node,
+            Address::Group(group) => GROUP_BASE + group,
+            Address::Broadcast => BROADCAST,
+        };
+        (address as u32) << ADDRESS_SHIFT
+    }
+
+    fn from_bits(bits: u8) -> Option<Address> {
+        match bits {
+            0 => None,
+            BROADCAST => Some(Address::Broadcast),
+            x if x >= GROUP_BASE => Some(Address::Group(x - GROUP_BASE)),
+            x => Some(Address::Node(x)),
+        }
+    }
+}
+
+fn version() -> u32 {
+    (PROTOCOL_VERSION as u32) << VERSION_SHIFT
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CanId {
+    pub command: u8,
+    pub address: Address,
+    pub from_node: bool,
+    pub transaction: u8,
+}
+
+impl CanId {
+    pub fn encode(&self) -> u32 {
+        let from_node = match self.from_node {
+            true => FROM_NODE,
+            false => 0,
+        };
+        self.command as u32
+            | self.address.bits()
+            | from_node
+            | version()
+            | (self.transaction as u32) << TRANSACTION_SHIFT
+    }
+
+    pub fn decode(id: u32) -> Option<CanId> {
+        if id & VERSION != version() {
+            return None;
+        }
+        Some(CanId {
+            command: id as u8,
+            address: Address::from_bits((id >> ADDRESS_SHIFT) as u8)?,
+            from_node: id & FROM_NODE != 0,
+            transaction: (id >> TRANSACTION_SHIFT) as u8,
+        })
+    }
+}
+
+// Who we are on the bus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeAddress {
+    pub node: u8,
+    pub group: u8,
+}
+
+impl NodeAddress {
+    // Out of range values (e.g. from a config written by hand) get clamped rather than rejected;
+    // we'd rather be on the bus at the wrong address than not at all.
+    pub fn new(node: u32, group: u32) -> NodeAddress {
+        NodeAddress {
+            node: node.clamp(1, MAX_NODE_ID as u32) as u8,
+            group: group.min(MAX_GROUP as u32) as u8,
+        }
+    }
+
+    // How a frame we send should be addressed.
+    pub fn reply(&self, command: u8) -> u32 {
+        CanId {
+            command,
+            address: Address::Node(self.node),
+            from_node: true,
+            transaction: 0,
+        }
+        .encode()
+    }
+}
+
+// A classic ID/mask filter, as understood by the FDCAN's extended filter elements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub id: u32,
+    pub mask: u32,
+}
+
+impl Filter {
+    // Everything but the command and transaction has to match.
+    const MASK: u32 = 0x001F_FF00;
+
+    fn to(address: Address) -> Filter {
+        Filter {
+            id: address.bits() | version(),
+            mask: Filter::MASK,
+        }
+    }
+
+    pub fn matches(&self, id: u32) -> bool {
+        id & self.mask == self.id & self.mask
+    }
+}
+
+// The filters a node needs to hear everything meant for it, and nothing else.
+pub fn filters(address: &NodeAddress) -> [Filter; 3] {
+    [
+        Filter::to(Address::Node(address.node)),
+        Filter::to(Address::Group(address.group)),
+        Filter::to(Address::Broadcast),
+    ]
+}
@@ -0,0 +1,25 @@
+//! What goes over the bus, shared between the firmware and anything on the host side that wants to
+//! talk to it.
+#![no_std]
+
+// So the derives' `::protocol::...` paths work in here too.
+extern crate self as protocol;
+
+pub mod ack;
+pub mod bus;
+pub mod frame;
+pub mod group;
+pub mod hall;
+pub mod id;
+pub mod messages;
+pub mod params;
+pub mod scope;
+pub mod telemetry;
+pub mod update;
+
+pub use frame::{DecodeError, FdcanMessage, Frame, IncomingFdcanFrame, OutgoingFdcanFrame, Wire};
+pub use protocol_derive::{Frame, Wire};
+
+// Bump whenever a layout in `messages` changes or a message is removed. Carried in every frame's ID;
+// see `id`.
+pub const PROTOCOL_VERSION: u8 = 1;
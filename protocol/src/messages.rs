@@ -0,0 +1,535 @@
+// Every message that goes over the bus, declared once for both ends. The layouts are byte-exact;
+// see `frame::Wire`. Fields are raw where the firmware has its own types (fault bitmasks, param
+// values, loop modes), and it's up to either end to convert.
+//
+// The one-off calibration loops (resistance, inductance, current distribution, ...) only report
+// back through their callbacks. If one of them ever gets a handler, its frames go in here.
+
+use crate::hall::HALL_SECTORS;
+use crate::scope::SCOPE_CHUNK_VALUES;
+use crate::telemetry::Signals;
+use crate::update::UPDATE_CHUNK_BYTES;
+use crate::Frame;
+
+// Declares `MessageID` along with the conversion back from the command byte in a frame's ID, so the
+// two can't drift apart.
+macro_rules! message_ids {
+    ( $( $(#[$meta:meta])* $name:ident = $value:literal, )* ) => {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum MessageID {
+            $( $(#[$meta])* $name = $value, )*
+        }
+
+        impl TryFrom<u8> for MessageID {
+            type Error = u8;
+
+            fn try_from(command: u8) -> Result<Self, Self::Error> {
+                match command {
+                    $( $value => Ok(MessageID::$name), )*
+                    unknown => Err(unknown),
+                }
+            }
+        }
+    };
+}
+
+message_ids! {
+    CalibrateEZero = 0x15,
+    EZero = 0x16,
+    EnterTorqueControl = 0x17,
+    EnterPosVelControl = 0x18,
+    SetPosVel = 0x19,
+    DisableControlLoop = 0x1A,
+    SensorState = 0x1B,
+    GetParam = 0x20,
+    SetParam = 0x21,
+    ListParams = 0x22,
+    SaveParams = 0x23,
+    RestoreDefaultParams = 0x24,
+    // Replies
+    ParamValue = 0x25,
+    ParamInfo = 0x26,
+    ParamAck = 0x27,
+    GetFaults = 0x28,
+    ClearFaults = 0x29,
+    // Reply to either of the above, but also sent unprompted whenever a new fault is raised.
+    FaultStatus = 0x2A,
+    DumpDrvRegisters = 0x2B,
+    DrvRegisters = 0x2C,
+    // Sent whenever the gate driver's fault status changes.
+    DrvStatus = 0x2D,
+    GetLoopTiming = 0x2E,
+    LoopTiming = 0x2F,
+    GetCrashRecord = 0x30,
+    // Replies to the above.
+    CrashLocation = 0x31,
+    CrashMessage = 0x32,
+    // From the host, to keep control loops from timing out between commands.
+    Heartbeat = 0x33,
+    // Sent periodically at `heartbeat_rate`.
+    Status = 0x34,
+    // Position/velocity targets for several nodes at once, held until the next `Sync`. Sent to a
+    // group or broadcast address.
+    GroupSetPosVel = 0x35,
+    // Apply whatever's been staged. Handled straight from the Rx interrupt; see
+    // `control_loops::sync`.
+    Sync = 0x36,
+    StartTelemetry = 0x37,
+    StopTelemetry = 0x38,
+    // Sent as fast as samples come in once started; see `telemetry`.
+    Telemetry = 0x39,
+    ArmScope = 0x3A,
+    TriggerScope = 0x3B,
+    GetScopeStatus = 0x3C,
+    // Reply to the above or `ArmScope`, and sent unprompted when a capture finishes.
+    ScopeStatus = 0x3D,
+    ReadScope = 0x3E,
+    ScopeData = 0x3F,
+    // Handled by the application. Everything after it up to `Boot` is only ever handled by the
+    // bootloader.
+    RebootToBootloader = 0x40,
+    GetBootStatus = 0x41,
+    // Reply to the above, and sent when the bootloader starts.
+    BootStatus = 0x42,
+    BeginUpdate = 0x43,
+    UpdateChunk = 0x44,
+    FinishUpdate = 0x45,
+    // Reply to any of the three above.
+    UpdateAck = 0x46,
+    // Reset, and boot whatever's best; see `update::decide`.
+    Boot = 0x47,
+    GetBusStatus = 0x48,
+    // Reply to the above, and sent along with every `Status` and whenever the bus state changes.
+    BusStatus = 0x49,
+    // Sent for any command from the host that asks for one; see `ack`.
+    Ack = 0x4A,
+    CalibrateHalls = 0x4B,
+    // Reply to the above once it's done.
+    HallCalibration = 0x4C,
+}
+
+impl From<MessageID> for u32 {
+    fn from(id: MessageID) -> Self {
+        id as u32
+    }
+}
+
+// Control loops.
+
+// The most a command can ask for; anything past these doesn't decode. They're well beyond what any
+// joint should see, and only there to catch garbage.
+//
+// Seconds. `TorqueControl` counts loop iterations in a `u32`, so it can't go much past a day.
+pub const MAX_DURATION: f32 = 3600.;
+// Amps, which is as high as `max_phase_current` goes.
+pub const MAX_CURRENT: f32 = 60.;
+// N*m/rad and N*m/(rad/s).
+pub const MAX_STIFFNESS: f32 = 1000.;
+pub const MAX_DAMPING: f32 = 100.;
+// N*m/A. Torques get divided by it, so it can't be zero.
+pub const MIN_TORQUE_CONSTANT: f32 = 1e-4;
+pub const MAX_TORQUE_CONSTANT: f32 = 10.;
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(CalibrateEZero)]
+pub struct CalibrateEZeroCmd {
+    #[range(0., MAX_DURATION)]
+    pub duration: f32,
+    #[range(-MAX_CURRENT, MAX_CURRENT)]
+    pub q: f32,
+    #[range(-MAX_CURRENT, MAX_CURRENT)]
+    pub d: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(EZero)]
+pub struct EZeroMsg {
+    pub angle: f32,
+    pub angle_raw: u32,
+    pub e_angle: f32,
+    pub e_raw: f32,
+}
+
+// Turns the rotor through a few electrical turns with `current` on the d axis, and works out where
+// each hall sector is from where it's pulled to. It needs to be free to turn.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(CalibrateHalls)]
+pub struct CalibrateHallsCmd {
+    #[range(0., MAX_CURRENT)]
+    pub current: f32,
+}
+
+// `status` is a `HallCalibrationStatus`. `angles` are only meaningful if it's `Ok`, in which case
+// they've also been written to the `hall_angles` params, though not saved.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(HallCalibration)]
+pub struct HallCalibrationMsg {
+    pub status: u32,
+    pub angles: [f32; HALL_SECTORS],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(EnterTorqueControl)]
+pub struct TorqueControlCmd {
+    #[range(0., MAX_DURATION)]
+    pub duration: f32,
+    #[range(-MAX_CURRENT, MAX_CURRENT)]
+    pub q: f32,
+    #[range(-MAX_CURRENT, MAX_CURRENT)]
+    pub d: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(EnterPosVelControl)]
+pub struct EnterPosVelControlCmd {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(SetPosVel)]
+pub struct SetPosVelCmd {
+    #[finite]
+    pub position: f32,
+    #[finite]
+    pub velocity: f32,
+    #[range(0., MAX_STIFFNESS)]
+    pub stiffness_gain: f32,
+    #[range(0., MAX_DAMPING)]
+    pub damping_gain: f32,
+    #[range(MIN_TORQUE_CONSTANT, MAX_TORQUE_CONSTANT)]
+    pub torque_constant: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(DisableControlLoop)]
+pub struct DisableControlLoopCmd {}
+
+// Angles in radians, velocities in radians/s.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(SensorState)]
+pub struct SensorStateMsg {
+    pub angle: f32,
+    pub angle_multiturn: f32,
+    pub velocity: f32,
+    pub electrical_angle: f32,
+    pub electrical_velocity: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Heartbeat)]
+pub struct HeartbeatCmd {}
+
+// Which loop's running, what state it's in, and the active and critical fault bits.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Status)]
+pub struct StatusMsg {
+    pub mode: u32,
+    pub state: u32,
+    pub active: u32,
+    pub critical: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Sync)]
+pub struct SyncCmd {}
+
+// Parameters. Values are raw bits; how they're interpreted depends on `kind`, and `status` is a
+// `ParamStatus`. See `params`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetParam)]
+pub struct GetParamCmd {
+    pub id: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(SetParam)]
+pub struct SetParamCmd {
+    #[pad(2)]
+    pub id: u16,
+    pub bits: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ListParams)]
+pub struct ListParamsCmd {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(SaveParams)]
+pub struct SaveParamsCmd {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(RestoreDefaultParams)]
+pub struct RestoreDefaultParamsCmd {}
+
+// Reply to `GetParam`/`SetParam`, with the value the parameter has after the request. `kind` and
+// `bits` are zero if there isn't one.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ParamValue)]
+pub struct ParamValueMsg {
+    pub id: u16,
+    pub kind: u8,
+    pub status: u8,
+    pub bits: u32,
+}
+
+pub const PARAM_NAME_BYTES: usize = 44;
+
+// One of these is sent per parameter in reply to `ListParams`. The name is null-padded ASCII.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ParamInfo)]
+pub struct ParamInfoMsg {
+    pub id: u16,
+    pub kind: u16,
+    pub index: u16,
+    pub count: u16,
+    pub min: u32,
+    pub max: u32,
+    pub value: u32,
+    pub name: [u8; PARAM_NAME_BYTES],
+}
+
+// Reply to `SaveParams`/`RestoreDefaultParams`.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ParamAck)]
+pub struct ParamAckMsg {
+    pub request: u32,
+    pub status: u32,
+}
+
+// Faults, as bitmasks of the firmware's `Fault`s.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetFaults)]
+pub struct GetFaultsCmd {}
+
+// An empty message clears everything.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ClearFaults)]
+pub struct ClearFaultsCmd {
+    pub faults: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(FaultStatus)]
+pub struct FaultStatusMsg {
+    pub active: u32,
+    pub critical: u32,
+    // Faults raised since the last report.
+    pub raised: u32,
+}
+
+// Gate driver. Registers are sent raw, one per word; the bit layout is in the datasheet.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(DumpDrvRegisters)]
+pub struct DumpDrvRegistersCmd {}
+
+// All seven registers, in address order.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(DrvRegisters)]
+pub struct DrvRegistersMsg {
+    pub registers: [u32; 7],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(DrvStatus)]
+pub struct DrvStatusMsg {
+    pub fault_status_1: u32,
+    pub fault_status_2: u32,
+}
+
+// Diagnostics.
+
+// A non-zero `reset` resets the stats once they've been sent.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetLoopTiming)]
+pub struct GetLoopTimingCmd {
+    pub reset: u32,
+}
+
+// How long the loop interrupt's been taking. Times are in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(LoopTiming)]
+pub struct LoopTimingMsg {
+    pub samples: u32,
+    pub overruns: u32,
+    pub min: f32,
+    pub average: f32,
+    pub max: f32,
+}
+
+// Starts over if telemetry's already running. Samples are taken every `decimation` loop iterations,
+// so 40 gets 1kHz; it's clamped to 1..=65535. Nothing selected is the same as stopping.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(StartTelemetry)]
+pub struct StartTelemetryCmd {
+    pub signals: Signals,
+    pub decimation: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(StopTelemetry)]
+pub struct StopTelemetryCmd {}
+
+// Scope; see `scope`. `trigger` is a `ScopeTrigger`, `trigger_signal` a `Signal` and `state` a
+// `ScopeState`.
+
+// Starts a new capture, throwing away the last one. `pre_trigger` is in samples.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ArmScope)]
+pub struct ArmScopeCmd {
+    pub signals: Signals,
+    pub decimation: u32,
+    pub trigger: u32,
+    pub trigger_signal: u32,
+    #[finite]
+    pub threshold: f32,
+    pub pre_trigger: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(TriggerScope)]
+pub struct TriggerScopeCmd {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetScopeStatus)]
+pub struct GetScopeStatusCmd {}
+
+// `samples` is how long a full trace is, and `trigger_index` where in it the trigger landed.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ScopeStatus)]
+pub struct ScopeStatusMsg {
+    pub state: u32,
+    pub signals: Signals,
+    pub decimation: u32,
+    pub samples: u32,
+    pub trigger_index: u32,
+}
+
+// `offset` is in values, not samples; see `Capture::read`.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ReadScope)]
+pub struct ReadScopeCmd {
+    pub offset: u32,
+}
+
+// Empty if the offset's past the end or there's no finished capture.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(ScopeData)]
+pub struct ScopeDataMsg {
+    pub offset: u32,
+    pub count: u32,
+    pub values: [f32; SCOPE_CHUNK_VALUES],
+}
+
+// Firmware updates; see `update`. Slots are `Slot::raw`, and `status` an `UpdateStatus`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(RebootToBootloader)]
+pub struct RebootToBootloaderCmd {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetBootStatus)]
+pub struct GetBootStatusCmd {}
+
+// `target` is the slot an update would go to, and `address` where that is.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(BootStatus)]
+pub struct BootStatusMsg {
+    pub active: u32,
+    pub pending: u32,
+    pub target: u32,
+    pub address: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(BeginUpdate)]
+pub struct BeginUpdateCmd {
+    pub address: u32,
+    pub length: u32,
+    pub crc: u32,
+}
+
+// `offset` is in bytes from the start of the image. The last one's padded out to a whole chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(UpdateChunk)]
+pub struct UpdateChunkCmd {
+    pub offset: u32,
+    pub data: [u8; UPDATE_CHUNK_BYTES],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(FinishUpdate)]
+pub struct FinishUpdateCmd {}
+
+// `request` is the `MessageID` being acked, and `next` the offset of the chunk wanted next.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(UpdateAck)]
+pub struct UpdateAckMsg {
+    pub request: u32,
+    pub status: u32,
+    pub next: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Boot)]
+pub struct BootCmd {}
+
+// Bus health; see `bus`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetBusStatus)]
+pub struct GetBusStatusCmd {}
+
+// `state` is a `BusState`, and the errors `ProtocolError`s for the nominal and data phases. The
+// error counters are the FDCAN's own, and go down again as frames get through; everything after
+// them only ever counts up. `rx_dropped` is frames we couldn't keep up with, `rx_lost` ones the
+// hardware had no room for, and `tx_dropped` ones that never found room to go out.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(BusStatus)]
+pub struct BusStatusMsg {
+    pub state: u32,
+    pub last_error: u32,
+    pub last_data_error: u32,
+    pub tx_errors: u32,
+    pub rx_errors: u32,
+    pub bus_offs: u32,
+    pub protocol_errors: u32,
+    pub rx_dropped: u32,
+    pub rx_lost: u32,
+    pub tx_dropped: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(GetCrashRecord)]
+pub struct GetCrashRecordCmd {}
+
+pub const CRASH_FILE_BYTES: usize = 56;
+pub const CRASH_MESSAGE_BYTES: usize = 64;
+
+// Where the last panic happened, with the file name as NUL-padded UTF-8. Sent empty if the last
+// reset wasn't a panic.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(CrashLocation)]
+pub struct CrashLocationMsg {
+    pub line: u32,
+    pub column: u32,
+    pub file: [u8; CRASH_FILE_BYTES],
+}
+
+// The panic message, NUL-padded UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(CrashMessage)]
+pub struct CrashMessageMsg {
+    pub message: [u8; CRASH_MESSAGE_BYTES],
+}
+
+// Acks; see `ack`. `status` is an `AckStatus`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Ack)]
+pub struct AckMsg {
+    pub request: u32,
+    pub transaction: u32,
+    pub status: u32,
+    pub detail: u32,
+}
@@ -0,0 +1,211 @@
+// How parameter values and request outcomes go over the wire; see `messages::ParamValueMsg` and
+// friends. Also which parameters there are, and their ranges and defaults, so that anything on the
+// host side agrees with the firmware about them without having to ask.
+
+use core::f32::consts::PI;
+
+use crate::hall::HALL_SECTORS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamType {
+    F32 = 0,
+    U32 = 1,
+}
+
+impl ParamType {
+    pub fn from_raw(kind: u32) -> Option<ParamType> {
+        match kind {
+            0 => Some(ParamType::F32),
+            1 => Some(ParamType::U32),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamValue {
+    F32(f32),
+    U32(u32),
+}
+
+impl ParamValue {
+    pub fn kind(&self) -> ParamType {
+        match self {
+            ParamValue::F32(_) => ParamType::F32,
+            ParamValue::U32(_) => ParamType::U32,
+        }
+    }
+
+    pub fn to_bits(&self) -> u32 {
+        match self {
+            ParamValue::F32(value) => value.to_bits(),
+            ParamValue::U32(value) => *value,
+        }
+    }
+
+    pub fn from_bits(kind: ParamType, bits: u32) -> ParamValue {
+        match kind {
+            ParamType::F32 => ParamValue::F32(f32::from_bits(bits)),
+            ParamType::U32 => ParamValue::U32(bits),
+        }
+    }
+}
+
+// The `status` of a `ParamValue` or `ParamAck`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamStatus {
+    Ok = 0,
+    UnknownParam = 1,
+    WrongType = 2,
+    OutOfRange = 3,
+    FlashError = 4,
+}
+
+impl ParamStatus {
+    pub fn from_raw(status: u32) -> Option<ParamStatus> {
+        match status {
+            0 => Some(ParamStatus::Ok),
+            1 => Some(ParamStatus::UnknownParam),
+            2 => Some(ParamStatus::WrongType),
+            3 => Some(ParamStatus::OutOfRange),
+            4 => Some(ParamStatus::FlashError),
+            _ => None,
+        }
+    }
+}
+
+// Everything about a parameter but its current value. IDs are part of the wire protocol: never
+// reuse or renumber one.
+pub struct ParamInfo {
+    pub id: u16,
+    pub name: &'static str,
+    pub kind: ParamType,
+    // Inclusive.
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub default: ParamValue,
+}
+
+impl ParamInfo {
+    pub fn check(&self, value: ParamValue) -> Result<(), ParamStatus> {
+        let in_range = match (value, self.min, self.max) {
+            // NaN fails both comparisons, so never makes it in.
+            (ParamValue::F32(value), ParamValue::F32(min), ParamValue::F32(max)) => {
+                value >= min && value <= max
+            }
+            (ParamValue::U32(value), ParamValue::U32(min), ParamValue::U32(max)) => {
+                value >= min && value <= max
+            }
+            _ => return Err(ParamStatus::WrongType),
+        };
+        match in_range {
+            true => Ok(()),
+            false => Err(ParamStatus::OutOfRange),
+        }
+    }
+}
+
+// Peak of the overmodulation region. Six-step's fundamental is 2/pi * v_bus, which is about 1.10
+// times the v_bus/sqrt(3) that SVPWM manages linearly.
+pub const MAX_OVERMODULATION: f32 = 2. / PI * 1.732_050_8;
+
+// Evenly spaced hall sectors, with sector 0 starting at zero.
+const SECTOR_WIDTH: f32 = 2. * PI / HALL_SECTORS as f32;
+
+macro_rules! params {
+    ( $( $id:literal => $name:ident: $kind:ident [$min:expr, $max:expr] = $default:expr, )* ) => {
+        pub const PARAMS: &[ParamInfo] = &[
+            $(
+                ParamInfo {
+                    id: $id,
+                    name: stringify!($name),
+                    kind: ParamType::$kind,
+                    min: ParamValue::$kind($min),
+                    max: ParamValue::$kind($max),
+                    default: ParamValue::$kind($default),
+                },
+            )*
+        ];
+    };
+}
+
+params! {
+    // Originally tuned back when only half of what the current loop asked for made it to the
+    // phases, so `current_kp` is halved to keep the loop where it was: ~5krad/s with 143uH of phase
+    // inductance. `current_ki` is scaled by `current_kp` in the firmware's `PIController`, so it
+    // only places the zero and stays put.
+    0x01 => current_kp: F32 [0., 100.] = 0.7105712,
+    0x02 => current_ki: F32 [0., 10.] = 0.055681818,
+    // The FoC already holds the PIs to whatever the modulation can get out of the bus, so this is
+    // only for limiting it further.
+    0x03 => current_v_clamp: F32 [0., 60.] = 60.,
+    0x04 => gear_ratio: F32 [0.01, 1000.] = 6.,
+    0x05 => pole_pairs: U32 [1, 255] = 21,
+    0x06 => velocity_observer_bandwidth: F32 [1., 10_000.] = 200.,
+    // 24v with a 150k/10k voltage divider.
+    0x07 => v_bus_gain: F32 [0., 1000.] = 16.,
+    // DRV8323RS CSA at 40V/V across a 1mOhm shunt.
+    0x08 => sense_gain: F32 [0., 1000.] = 1. / (40. * 0.001),
+    // The CSA tops out around 41A either way.
+    0x09 => max_phase_current: F32 [0., 60.] = 30.,
+    // Headroom above the 24v supply for regen.
+    0x0A => max_v_bus: F32 [0., 60.] = 30.,
+    0x0B => min_v_bus: F32 [0., 60.] = 10.,
+    // 100us at 40kHz.
+    0x0C => protection_debounce: U32 [1, 40_000] = 4,
+    // Off unless asked for, so hosts that never send heartbeats keep working.
+    0x0D => pos_vel_timeout: F32 [0., 60.] = 0.,
+    // Torque control already stops after the duration it was given.
+    0x0E => torque_timeout: F32 [0., 60.] = 0.,
+    // Ramp down.
+    0x0F => timeout_action: U32 [0, 1] = 0,
+    0x10 => timeout_damping: F32 [0., 100.] = 0.05,
+    0x11 => heartbeat_rate: F32 [0., 1000.] = 10.,
+    0x12 => node_id: U32 [1, 0xEF] = 1,
+    0x13 => node_group: U32 [0, 0x0E] = 0,
+    // MA702.
+    0x14 => rotor_sensor: U32 [0, 4] = 0,
+    // Evenly spaced, which is close enough to spin the motor until it's been calibrated.
+    0x15 => hall_angles_0: F32 [0., 2. * PI] = 0.5 * SECTOR_WIDTH,
+    0x16 => hall_angles_1: F32 [0., 2. * PI] = 1.5 * SECTOR_WIDTH,
+    0x17 => hall_angles_2: F32 [0., 2. * PI] = 2.5 * SECTOR_WIDTH,
+    0x18 => hall_angles_3: F32 [0., 2. * PI] = 3.5 * SECTOR_WIDTH,
+    0x19 => hall_angles_4: F32 [0., 2. * PI] = 4.5 * SECTOR_WIDTH,
+    0x1A => hall_angles_5: F32 [0., 2. * PI] = 5.5 * SECTOR_WIDTH,
+    // Space vector.
+    0x1B => modulation: U32 [0, 2] = 1,
+    0x1C => overmodulation: F32 [0., MAX_OVERMODULATION] = 1.,
+    // The forced deadtime cuts the duty off at 2083 / 2125 anyway.
+    0x1D => max_duty: F32 [0., 1.] = 2083. / 2125.,
+}
+
+pub fn find(id: u16) -> Option<&'static ParamInfo> {
+    PARAMS.iter().find(|param| param.id == id)
+}
+
+// Same as `find`, but for consts: the firmware builds its params and `Config::DEFAULT` out of
+// these at compile time, where a missing ID (or the wrong type) doesn't compile.
+pub const fn info(id: u16) -> &'static ParamInfo {
+    let mut index = 0;
+    while index < PARAMS.len() {
+        if PARAMS[index].id == id {
+            return &PARAMS[index];
+        }
+        index += 1;
+    }
+    panic!("No such param")
+}
+
+pub const fn default_f32(id: u16) -> f32 {
+    match info(id).default {
+        ParamValue::F32(value) => value,
+        ParamValue::U32(_) => panic!("Not an F32 param"),
+    }
+}
+
+pub const fn default_u32(id: u16) -> u32 {
+    match info(id).default {
+        ParamValue::U32(value) => value,
+        ParamValue::F32(_) => panic!("Not a U32 param"),
+    }
+}
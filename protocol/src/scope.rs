@@ -0,0 +1,283 @@
+// Triggered capture of control loop signals into RAM, for when telemetry can't keep up. The host
+// arms it with `ArmScopeCmd`, the loop interrupt feeds it every iteration, and once it's triggered
+// and full the host reads the trace back a frame at a time with `ReadScopeCmd`.
+//
+// The capture itself lives here rather than in the firmware so the simulated node can run the same
+// thing; it doesn't care where the buffer is or where the samples come from.
+
+use crate::messages::{ArmScopeCmd, ScopeStatusMsg};
+use crate::telemetry::{Signal, Signals};
+
+// Values per `ScopeDataMsg`.
+pub const SCOPE_CHUNK_VALUES: usize = 14;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeTrigger {
+    // Only when the host sends `TriggerScope`.
+    Command = 0,
+    // When the trigger signal crosses the threshold going up, or down.
+    Rising = 1,
+    Falling = 2,
+    // When a fault that wasn't already active is raised.
+    Fault = 3,
+}
+
+impl ScopeTrigger {
+    pub fn from_raw(trigger: u32) -> Option<ScopeTrigger> {
+        match trigger {
+            0 => Some(ScopeTrigger::Command),
+            1 => Some(ScopeTrigger::Rising),
+            2 => Some(ScopeTrigger::Falling),
+            3 => Some(ScopeTrigger::Fault),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeState {
+    Idle = 0,
+    // Capturing pre-trigger samples, waiting on the trigger.
+    Armed = 1,
+    // Filling the rest of the buffer.
+    Triggered = 2,
+    // Ready to be read.
+    Done = 3,
+}
+
+impl ScopeState {
+    pub fn from_raw(state: u32) -> Option<ScopeState> {
+        match state {
+            0 => Some(ScopeState::Idle),
+            1 => Some(ScopeState::Armed),
+            2 => Some(ScopeState::Triggered),
+            3 => Some(ScopeState::Done),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScopeConfig {
+    pub signals: Signals,
+    // Loop iterations per sample.
+    pub decimation: u16,
+    pub trigger: ScopeTrigger,
+    // What `Rising` and `Falling` watch. Doesn't have to be one of `signals`.
+    pub trigger_signal: Signal,
+    pub threshold: f32,
+    // Samples to keep from before the trigger. Edges aren't looked for until there are this many,
+    // so the trace always has them; commands and faults trigger right away regardless.
+    pub pre_trigger: usize,
+}
+
+impl ScopeConfig {
+    // `None` if the trigger isn't one we know, or it's an edge on a signal we don't know.
+    pub fn from_cmd(cmd: &ArmScopeCmd) -> Option<ScopeConfig> {
+        let trigger = ScopeTrigger::from_raw(cmd.trigger)?;
+        let trigger_signal = match (trigger, Signal::from_raw(cmd.trigger_signal)) {
+            (_, Some(signal)) => signal,
+            // Only edges look at it.
+            (ScopeTrigger::Command | ScopeTrigger::Fault, None) => Signal::Angle,
+            _ => return None,
+        };
+        Some(ScopeConfig {
+            signals: cmd.signals,
+            decimation: cmd.decimation.clamp(1, u16::MAX as u32) as u16,
+            trigger,
+            trigger_signal,
+            threshold: cmd.threshold,
+            pre_trigger: cmd.pre_trigger as usize,
+        })
+    }
+
+    pub fn cmd(&self) -> ArmScopeCmd {
+        ArmScopeCmd {
+            signals: self.signals,
+            decimation: self.decimation as u32,
+            trigger: self.trigger as u32,
+            trigger_signal: self.trigger_signal as u32,
+            threshold: self.threshold,
+            pre_trigger: self.pre_trigger as u32,
+        }
+    }
+}
+
+// Works on any buffer, in whole samples; whatever's left over at the end isn't used.
+pub struct Capture<B> {
+    buffer: B,
+    config: ScopeConfig,
+    state: ScopeState,
+    // Loop iterations until the next sample.
+    countdown: u16,
+    // Buffer slot (in samples) the next sample goes in.
+    head: usize,
+    // Samples written since arming, up to `capacity`.
+    filled: usize,
+    // Samples left to take after the trigger.
+    remaining: usize,
+    // Once triggered, where the trace starts and how far into it the trigger was.
+    start: usize,
+    trigger_index: usize,
+    forced: bool,
+    previous: f32,
+    faults: Option<u32>,
+}
+
+impl<B: AsRef<[f32]> + AsMut<[f32]>> Capture<B> {
+    pub fn new(buffer: B) -> Capture<B> {
+        Capture {
+            buffer,
+            config: ScopeConfig {
+                signals: Signals::default(),
+                decimation: 1,
+                trigger: ScopeTrigger::Command,
+                trigger_signal: Signal::Angle,
+                threshold: 0.,
+                pre_trigger: 0,
+            },
+            state: ScopeState::Idle,
+            countdown: 1,
+            head: 0,
+            filled: 0,
+            remaining: 0,
+            start: 0,
+            trigger_index: 0,
+            forced: false,
+            previous: f32::NAN,
+            faults: None,
+        }
+    }
+
+    // Throws away whatever was there and starts over. Nothing's captured if there aren't any
+    // signals, or the buffer can't hold even one sample.
+    pub fn arm(&mut self, config: ScopeConfig) {
+        self.config = ScopeConfig {
+            decimation: config.decimation.max(1),
+            ..config
+        };
+        self.config.pre_trigger = config.pre_trigger.min(self.capacity().saturating_sub(1));
+        self.state = match self.capacity() {
+            0 => ScopeState::Idle,
+            _ => ScopeState::Armed,
+        };
+        self.countdown = 1;
+        self.head = 0;
+        self.filled = 0;
+        self.remaining = 0;
+        self.start = 0;
+        self.trigger_index = 0;
+        self.forced = false;
+        self.previous = f32::NAN;
+        self.faults = None;
+    }
+
+    // Trigger on the next sample, if we're armed.
+    pub fn force(&mut self) {
+        self.forced = true;
+    }
+
+    pub fn state(&self) -> ScopeState {
+        self.state
+    }
+
+    pub fn config(&self) -> ScopeConfig {
+        self.config
+    }
+
+    // How many samples a full trace has.
+    pub fn capacity(&self) -> usize {
+        match self.config.signals.len() {
+            0 => 0,
+            len => self.buffer.as_ref().len() / len,
+        }
+    }
+
+    // Which sample in the trace was the trigger. Only meaningful once it's done.
+    pub fn trigger_index(&self) -> usize {
+        self.trigger_index
+    }
+
+    pub fn status(&self) -> ScopeStatusMsg {
+        ScopeStatusMsg {
+            state: self.state as u32,
+            signals: self.config.signals,
+            decimation: self.config.decimation as u32,
+            samples: self.capacity() as u32,
+            trigger_index: self.trigger_index as u32,
+        }
+    }
+
+    fn triggered(&mut self, value: f32, faults: u32) -> bool {
+        let previous = core::mem::replace(&mut self.previous, value);
+        let new_faults = faults & !self.faults.replace(faults).unwrap_or(faults);
+        if self.forced {
+            return true;
+        }
+        let threshold = self.config.threshold;
+        match self.config.trigger {
+            ScopeTrigger::Command => false,
+            ScopeTrigger::Fault => new_faults != 0,
+            // Not enough from before the trigger yet.
+            _ if self.filled <= self.config.pre_trigger => false,
+            ScopeTrigger::Rising => previous < threshold && value >= threshold,
+            ScopeTrigger::Falling => previous > threshold && value <= threshold,
+        }
+    }
+
+    // Called every loop iteration with where to get the signals from and the active fault bits.
+    pub fn sample(&mut self, value: impl Fn(Signal) -> f32, faults: u32) {
+        if !matches!(self.state, ScopeState::Armed | ScopeState::Triggered) {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return;
+        }
+        self.countdown = self.config.decimation;
+
+        let len = self.config.signals.len();
+        let slot = self.head;
+        let buffer = &mut self.buffer.as_mut()[slot * len..];
+        for (i, signal) in self.config.signals.iter().enumerate() {
+            buffer[i] = value(signal);
+        }
+        self.head = (slot + 1) % self.capacity();
+        self.filled = (self.filled + 1).min(self.capacity());
+
+        match self.state {
+            ScopeState::Armed => {
+                if self.triggered(value(self.config.trigger_signal), faults) {
+                    // Keep up to `pre_trigger` samples from before this one, and fill the rest of
+                    // the buffer after it.
+                    let before = (self.filled - 1).min(self.config.pre_trigger);
+                    self.trigger_index = before;
+                    self.start = (slot + self.capacity() - before) % self.capacity();
+                    self.remaining = self.capacity() - before - 1;
+                    self.state = ScopeState::Triggered;
+                }
+            }
+            _ => self.remaining -= 1,
+        }
+        if self.state == ScopeState::Triggered && self.remaining == 0 {
+            self.state = ScopeState::Done;
+        }
+    }
+
+    // Copy out the trace from `offset`, as values: sample by sample, each in `Signal` order.
+    // Returns how many were copied, which is zero past the end or if it's not done yet.
+    pub fn read(&self, offset: usize, values: &mut [f32]) -> usize {
+        if self.state != ScopeState::Done {
+            return 0;
+        }
+        let len = self.config.signals.len();
+        let total = self.capacity() * len;
+        let count = values.len().min(total.saturating_sub(offset));
+        for (i, value) in values[..count].iter_mut().enumerate() {
+            let sample = (offset + i) / len;
+            let slot = (self.start + sample) % self.capacity();
+            *value = self.buffer.as_ref()[slot * len + (offset + i) % len];
+        }
+        count
+    }
+}
@@ -0,0 +1,214 @@
+// High-rate telemetry. The host picks a set of signals and a decimation with `StartTelemetryCmd`,
+// and the node samples them from the loop interrupt every `decimation` iterations, packing as many
+// samples as fit into each `TelemetryMsg`.
+//
+// Every sample gets a sequence number, counting from zero when telemetry's started. Samples the node
+// couldn't find room for are counted in `dropped` and skip a sequence number, so a gap that isn't
+// accounted for by `dropped` means a frame went missing on the bus.
+
+use crate::frame::Wire;
+use crate::Frame;
+
+// Values per frame, after the header. Also the most signals that can be streamed at once.
+pub const TELEMETRY_VALUES: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    // Radians and radians/s.
+    Angle = 0,
+    Velocity = 1,
+    AngleMultiturn = 2,
+    // Amps.
+    CurrentD = 3,
+    CurrentQ = 4,
+    // What the current controllers asked for after limiting, in volts.
+    VoltageD = 5,
+    VoltageQ = 6,
+    // Amps.
+    PhaseA = 7,
+    PhaseB = 8,
+    PhaseC = 9,
+    VBus = 10,
+    // The current controllers' integrators, in volts.
+    IntegratorD = 11,
+    IntegratorQ = 12,
+    // How long the loop interrupt took, in microseconds.
+    LoopTime = 13,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 14] = [
+        Signal::Angle,
+        Signal::Velocity,
+        Signal::AngleMultiturn,
+        Signal::CurrentD,
+        Signal::CurrentQ,
+        Signal::VoltageD,
+        Signal::VoltageQ,
+        Signal::PhaseA,
+        Signal::PhaseB,
+        Signal::PhaseC,
+        Signal::VBus,
+        Signal::IntegratorD,
+        Signal::IntegratorQ,
+        Signal::LoopTime,
+    ];
+
+    pub fn from_raw(signal: u32) -> Option<Signal> {
+        Signal::ALL.get(signal as usize).copied()
+    }
+
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Angle => "angle",
+            Signal::Velocity => "velocity",
+            Signal::AngleMultiturn => "angle_multiturn",
+            Signal::CurrentD => "current_d",
+            Signal::CurrentQ => "current_q",
+            Signal::VoltageD => "voltage_d",
+            Signal::VoltageQ => "voltage_q",
+            Signal::PhaseA => "phase_a",
+            Signal::PhaseB => "phase_b",
+            Signal::PhaseC => "phase_c",
+            Signal::VBus => "v_bus",
+            Signal::IntegratorD => "integrator_d",
+            Signal::IntegratorQ => "integrator_q",
+            Signal::LoopTime => "loop_time",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Signal> {
+        Signal::ALL.into_iter().find(|signal| signal.name() == name)
+    }
+}
+
+// A set of `Signal`s, as a bitmask. Values always go out in `Signal` order, regardless of the order
+// they were asked for in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Signals(u32);
+
+impl Signals {
+    pub fn new(signals: &[Signal]) -> Signals {
+        Signals::from_bits(signals.iter().fold(0, |bits, signal| bits | signal.bit()))
+    }
+
+    // Bits we don't know about are dropped, as is anything past the first `TELEMETRY_VALUES`
+    // signals.
+    pub fn from_bits(bits: u32) -> Signals {
+        let bits = Signal::ALL
+            .into_iter()
+            .filter(|signal| bits & signal.bit() != 0)
+            .take(TELEMETRY_VALUES)
+            .fold(0, |bits, signal| bits | signal.bit());
+        Signals(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.0 & signal.bit() != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Signal> {
+        let signals = *self;
+        Signal::ALL
+            .into_iter()
+            .filter(move |signal| signals.contains(*signal))
+    }
+
+    // How many whole samples fit in a frame.
+    pub fn samples_per_frame(&self) -> usize {
+        match self.len() {
+            0 => 0,
+            len => TELEMETRY_VALUES / len,
+        }
+    }
+}
+
+impl Wire for Signals {
+    const SIZE: usize = 4;
+
+    fn write(&self, bytes: &mut [u8]) {
+        self.0.write(bytes);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Signals::from_bits(u32::read(bytes))
+    }
+}
+
+// Samples are back to back in `values`, each `signals.len()` long. Samples in a frame always have
+// consecutive sequence numbers, starting at `sequence`.
+#[derive(Clone, Copy, Debug, PartialEq, Frame)]
+#[frame(Telemetry)]
+pub struct TelemetryMsg {
+    pub signals: Signals,
+    pub sequence: u32,
+    // Total since telemetry was started.
+    pub dropped: u32,
+    pub samples: u16,
+    pub decimation: u16,
+    pub values: [f32; TELEMETRY_VALUES],
+}
+
+impl TelemetryMsg {
+    pub fn new(signals: Signals, decimation: u16, sequence: u32, dropped: u32) -> TelemetryMsg {
+        TelemetryMsg {
+            signals,
+            sequence,
+            dropped,
+            samples: 0,
+            decimation,
+            values: [0.; TELEMETRY_VALUES],
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.samples as usize >= self.signals.samples_per_frame()
+    }
+
+    // Sequence number the next sample would have to have to go in this frame.
+    pub fn next_sequence(&self) -> u32 {
+        self.sequence.wrapping_add(self.samples as u32)
+    }
+
+    // Add one sample's worth of values, in `Signal` order. Anything past `signals.len()` is ignored.
+    // Returns false if there's no room left.
+    pub fn push(&mut self, values: &[f32]) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        let len = self.signals.len();
+        let start = self.samples as usize * len;
+        for (slot, value) in self.values[start..start + len].iter_mut().zip(values) {
+            *slot = *value;
+        }
+        self.samples += 1;
+        true
+    }
+
+    // Each sample's values, in `Signal` order. A frame claiming more samples than could possibly fit
+    // only gets as many as do.
+    pub fn samples(&self) -> impl Iterator<Item = &[f32]> {
+        self.values
+            .chunks_exact(self.signals.len().max(1))
+            .take(match self.signals.len() {
+                0 => 0,
+                _ => self.samples as usize,
+            })
+    }
+}
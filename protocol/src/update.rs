@@ -0,0 +1,601 @@
+// Firmware updates over the bus, through the resident bootloader (the `boot` crate). Flash is laid
+// out as:
+//
+//   0x0800_0000   32K  bootloader
+//   0x0800_8000    4K  boot record; see `BootRecord`
+//   0x0800_9000    4K  config; see the firmware's `config::storage`
+//   0x0800_A000  216K  unused
+//   0x0804_0000  128K  slot A
+//   0x0806_0000  128K  slot B
+//
+// Both slots are in bank 2 and everything that gets written at runtime is in bank 1, so saving the
+// config never stalls whichever image is running (and the commutation interrupt with it). Bank 1's
+// mostly empty as a result, but there's no way to split it with a slot without losing that.
+//
+// Images are plain binaries, linked to run from one slot or the other (see the firmware's build.rs),
+// so which one an image is for can be read straight off its reset vector. The host always writes to
+// the slot that isn't known to work, and the bootloader only switches over once the new image has
+// come up and heard from the host; until then it's on trial, and if it never makes it the old one
+// is booted instead.
+//
+// An update is a `BeginUpdate` with the image's address, length and CRC, then `UpdateChunk`s in
+// order, then a `FinishUpdate` that checks the CRC against what actually ended up in flash. Every
+// one of them is acked with the offset the bootloader wants next, so a lost frame's just a resend;
+// a chunk that's already been written is acked again rather than written twice.
+//
+// Everything here's independent of the hardware, so the bootloader and the host can share it, and
+// it can all be tested against a pretend flash.
+
+use crate::id::NodeAddress;
+
+pub const PAGE_SIZE: u32 = 2048;
+pub const SLOT_SIZE: u32 = 128 * 1024;
+// Two pages; records alternate between them so there's always a good one.
+pub const BOOT_RECORD_ADDRESS: u32 = 0x0800_8000;
+// Payload of an `UpdateChunkCmd`. A whole number of double words, which is what the G4 programs.
+pub const UPDATE_CHUNK_BYTES: usize = 56;
+// How many times a new image gets booted without coming up before we go back to the old one.
+pub const MAX_ATTEMPTS: u32 = 3;
+// `Option<Slot>` on the wire, for `None`.
+pub const NO_SLOT: u32 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    pub fn from_raw(slot: u32) -> Option<Slot> {
+        match slot {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    pub fn raw(slot: Option<Slot>) -> u32 {
+        slot.map_or(NO_SLOT, |slot| slot as u32)
+    }
+
+    pub fn address(self) -> u32 {
+        match self {
+            Slot::A => 0x0804_0000,
+            Slot::B => 0x0806_0000,
+        }
+    }
+
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn containing(address: u32) -> Option<Slot> {
+        [Slot::A, Slot::B]
+            .into_iter()
+            .find(|slot| (slot.address()..slot.address() + SLOT_SIZE).contains(&address))
+    }
+
+    // Which slot an image was linked for, going by its reset vector (the second word of the vector
+    // table, with the Thumb bit set).
+    pub fn for_image(image: &[u8]) -> Option<Slot> {
+        let reset = u32::from_le_bytes(image.get(4..8)?.try_into().ok()?);
+        Slot::containing(reset & !1)
+    }
+}
+
+// CRC-32 (IEEE 802.3), same as the firmware's `util::crc`. Table driven, since the bootloader runs
+// it over a whole image on every boot before the clocks are up.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// For when the bytes don't all come at once.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(0xFFFF_FFFF)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = CRC_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+// What's in a slot, or about to be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageHeader {
+    pub address: u32,
+    pub length: u32,
+    pub crc: u32,
+}
+
+impl ImageHeader {
+    // `None` if it's not linked for either slot, or won't fit.
+    pub fn new(image: &[u8]) -> Option<ImageHeader> {
+        let slot = Slot::for_image(image)?;
+        if image.len() as u32 > SLOT_SIZE {
+            return None;
+        }
+        Some(ImageHeader {
+            address: slot.address(),
+            length: image.len() as u32,
+            crc: crc32(image),
+        })
+    }
+
+    // Which slot it starts at the beginning of, if any.
+    pub fn slot(&self) -> Option<Slot> {
+        Slot::containing(self.address).filter(|slot| slot.address() == self.address)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateStatus {
+    Ok = 0,
+    // The image isn't for the slot we're writing; see `BootRecord::target`.
+    WrongSlot = 1,
+    // Empty, or doesn't fit in a slot.
+    BadLength = 2,
+    // Chunks or a finish without a `BeginUpdate` first.
+    NotStarted = 3,
+    // A chunk past the one we're waiting on.
+    OutOfOrder = 4,
+    Flash = 5,
+    // Everything arrived, but what's in flash doesn't match the CRC.
+    BadCrc = 6,
+    // Finished before all of the image was sent.
+    Incomplete = 7,
+    // The command was too short to be what its ID says; nothing was done with it.
+    Malformed = 8,
+}
+
+impl UpdateStatus {
+    pub fn from_raw(status: u32) -> Option<UpdateStatus> {
+        match status {
+            0 => Some(UpdateStatus::Ok),
+            1 => Some(UpdateStatus::WrongSlot),
+            2 => Some(UpdateStatus::BadLength),
+            3 => Some(UpdateStatus::NotStarted),
+            4 => Some(UpdateStatus::OutOfOrder),
+            5 => Some(UpdateStatus::Flash),
+            6 => Some(UpdateStatus::BadCrc),
+            7 => Some(UpdateStatus::Incomplete),
+            8 => Some(UpdateStatus::Malformed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashError;
+
+// Flash, by absolute address. `program` is only ever called with whole double words at double word
+// aligned addresses, on erased flash.
+pub trait SlotFlash {
+    // The page starting at `address`.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError>;
+    fn read(&self, address: u32, buffer: &mut [u8]);
+}
+
+// Whether what's in flash matches `image`.
+pub fn verify(flash: &impl SlotFlash, image: &ImageHeader) -> bool {
+    let mut crc = Crc32::new();
+    let mut buffer = [0; 256];
+    let mut offset = 0;
+    while offset < image.length {
+        let len = (image.length - offset).min(buffer.len() as u32) as usize;
+        flash.read(image.address + offset, &mut buffer[..len]);
+        crc.update(&buffer[..len]);
+        offset += len as u32;
+    }
+    crc.finish() == image.crc
+}
+
+// The bootloader's end of an update. Pages are erased as the chunks get to them, so a small image
+// doesn't wait on the whole slot being erased first.
+pub struct Updater {
+    target: Slot,
+    image: Option<ImageHeader>,
+    // Bytes of the image written so far, and bytes of the slot erased.
+    written: u32,
+    erased: u32,
+}
+
+impl Updater {
+    pub fn new(target: Slot) -> Updater {
+        Updater {
+            target,
+            image: None,
+            written: 0,
+            erased: 0,
+        }
+    }
+
+    pub fn target(&self) -> Slot {
+        self.target
+    }
+
+    // The offset of the next chunk we want.
+    pub fn next(&self) -> u32 {
+        self.written
+    }
+
+    // Throws away anything from an update that was already going.
+    pub fn begin(&mut self, image: ImageHeader) -> Result<(), UpdateStatus> {
+        self.image = None;
+        if image.address != self.target.address() {
+            return Err(UpdateStatus::WrongSlot);
+        }
+        if image.length == 0 || image.length > SLOT_SIZE {
+            return Err(UpdateStatus::BadLength);
+        }
+        self.image = Some(image);
+        self.written = 0;
+        self.erased = 0;
+        Ok(())
+    }
+
+    // Returns the offset of the next chunk we want. Anything in `data` past the end of the image is
+    // ignored.
+    pub fn chunk(
+        &mut self,
+        flash: &mut impl SlotFlash,
+        offset: u32,
+        data: &[u8; UPDATE_CHUNK_BYTES],
+    ) -> Result<u32, UpdateStatus> {
+        let image = self.image.ok_or(UpdateStatus::NotStarted)?;
+        // A resend of something we've already got, most likely because the ack went missing.
+        if offset < self.written {
+            return Ok(self.written);
+        }
+        if offset > self.written {
+            return Err(UpdateStatus::OutOfOrder);
+        }
+        let len = (image.length.saturating_sub(offset) as usize).min(UPDATE_CHUNK_BYTES);
+        if len == 0 {
+            return Ok(self.written);
+        }
+        // The end of the image is padded out to a double word with what erased flash reads as.
+        let mut bytes = [0xFF; UPDATE_CHUNK_BYTES];
+        bytes[..len].copy_from_slice(&data[..len]);
+        let padded = (len + 7) & !7;
+
+        while self.erased < offset + padded as u32 {
+            flash
+                .erase_page(image.address + self.erased)
+                .map_err(|_| UpdateStatus::Flash)?;
+            self.erased += PAGE_SIZE;
+        }
+        flash
+            .program(image.address + offset, &bytes[..padded])
+            .map_err(|_| UpdateStatus::Flash)?;
+        self.written += len as u32;
+        Ok(self.written)
+    }
+
+    // Checks what's in flash against the CRC, and hands back the header to boot it with if it's
+    // good. Either way, the next update has to start over.
+    pub fn finish(&mut self, flash: &impl SlotFlash) -> Result<ImageHeader, UpdateStatus> {
+        let image = self.image.ok_or(UpdateStatus::NotStarted)?;
+        if self.written < image.length {
+            return Err(UpdateStatus::Incomplete);
+        }
+        self.image = None;
+        match verify(flash, &image) {
+            true => Ok(image),
+            false => Err(UpdateStatus::BadCrc),
+        }
+    }
+}
+
+// What the bootloader knows about the slots. `active` is the image that's known to come up, and
+// `pending` a new one that's still on trial. Laid out in flash as:
+//
+//   0: MAGIC
+//   1: sequence number, newest wins
+//   2: active, 3: pending (`Slot::raw`)
+//   4: node address, so the bootloader's reachable at the same address after a power cycle
+//   5-10: address, length and CRC of the image in each slot, zeroes if there isn't one
+//   11: CRC32 of everything before it
+pub const BOOT_RECORD_WORDS: usize = 12;
+const RECORD_MAGIC: u32 = 0x5049_4E42;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootRecord {
+    pub sequence: u32,
+    pub active: Option<Slot>,
+    pub pending: Option<Slot>,
+    pub node: Option<NodeAddress>,
+    pub images: [Option<ImageHeader>; 2],
+}
+
+impl BootRecord {
+    // Nothing's ever been installed through the bootloader.
+    pub fn empty() -> BootRecord {
+        BootRecord {
+            sequence: 0,
+            active: None,
+            pending: None,
+            node: None,
+            images: [None; 2],
+        }
+    }
+
+    pub fn image(&self, slot: Slot) -> Option<ImageHeader> {
+        self.images[slot as usize]
+    }
+
+    // Where the next update goes: never over the one that's known to work.
+    pub fn target(&self) -> Slot {
+        self.active.map_or(Slot::A, Slot::other)
+    }
+
+    // After `image` has been written and checked.
+    pub fn installed(&self, image: ImageHeader) -> BootRecord {
+        let mut record = *self;
+        if let Some(slot) = image.slot() {
+            record.images[slot as usize] = Some(image);
+            record.pending = Some(slot);
+        }
+        record
+    }
+
+    pub fn to_words(&self) -> [u32; BOOT_RECORD_WORDS] {
+        let mut words = [0; BOOT_RECORD_WORDS];
+        words[0] = RECORD_MAGIC;
+        words[1] = self.sequence;
+        words[2] = Slot::raw(self.active);
+        words[3] = Slot::raw(self.pending);
+        words[4] = node_to_word(self.node);
+        for (i, image) in self.images.iter().enumerate() {
+            if let Some(image) = image {
+                words[5 + i * 3..8 + i * 3].copy_from_slice(&[
+                    image.address,
+                    image.length,
+                    image.crc,
+                ]);
+            }
+        }
+        words[BOOT_RECORD_WORDS - 1] = crc32_words(&words[..BOOT_RECORD_WORDS - 1]);
+        words
+    }
+
+    // `None` if it's blank, or was only partly written.
+    pub fn from_words(words: &[u32; BOOT_RECORD_WORDS]) -> Option<BootRecord> {
+        if words[0] != RECORD_MAGIC
+            || words[BOOT_RECORD_WORDS - 1] != crc32_words(&words[..BOOT_RECORD_WORDS - 1])
+        {
+            return None;
+        }
+        let image = |i: usize| {
+            let image = ImageHeader {
+                address: words[5 + i * 3],
+                length: words[6 + i * 3],
+                crc: words[7 + i * 3],
+            };
+            Some(image).filter(|image| image.length != 0)
+        };
+        Some(BootRecord {
+            sequence: words[1],
+            active: Slot::from_raw(words[2]),
+            pending: Slot::from_raw(words[3]),
+            node: node_from_word(words[4]),
+            images: [image(0), image(1)],
+        })
+    }
+
+    // The newer of the two pages' records.
+    pub fn newest(a: Option<BootRecord>, b: Option<BootRecord>) -> Option<BootRecord> {
+        match (a, b) {
+            (Some(a), Some(b)) => match b.sequence.wrapping_sub(a.sequence) as i32 > 0 {
+                true => Some(b),
+                false => Some(a),
+            },
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+const RECORD_PAGES: [u32; 2] = [BOOT_RECORD_ADDRESS, BOOT_RECORD_ADDRESS + PAGE_SIZE];
+
+fn read_record(flash: &impl SlotFlash, page: u32) -> Option<BootRecord> {
+    let mut bytes = [0; BOOT_RECORD_WORDS * 4];
+    flash.read(page, &mut bytes);
+    let mut words = [0; BOOT_RECORD_WORDS];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    BootRecord::from_words(&words)
+}
+
+// The newest good record out of the two pages.
+pub fn load_record(flash: &impl SlotFlash) -> Option<BootRecord> {
+    BootRecord::newest(
+        read_record(flash, RECORD_PAGES[0]),
+        read_record(flash, RECORD_PAGES[1]),
+    )
+}
+
+// Writes `record` as the newest, over whichever page isn't holding the current one, so losing power
+// partway through leaves the current one be. Returns it as written, with its new sequence number.
+pub fn save_record(
+    flash: &mut impl SlotFlash,
+    record: &BootRecord,
+) -> Result<BootRecord, FlashError> {
+    let current = load_record(flash);
+    let page = match current {
+        Some(current) if read_record(flash, RECORD_PAGES[0]) == Some(current) => RECORD_PAGES[1],
+        _ => RECORD_PAGES[0],
+    };
+    let record = BootRecord {
+        sequence: current.map_or(0, |current| current.sequence.wrapping_add(1)),
+        ..*record
+    };
+    let mut bytes = [0; BOOT_RECORD_WORDS * 4];
+    for (bytes, word) in bytes.chunks_exact_mut(4).zip(record.to_words()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    flash.erase_page(page)?;
+    flash.program(page, &bytes)?;
+    Ok(record)
+}
+
+fn crc32_words(words: &[u32]) -> u32 {
+    let mut crc = Crc32::new();
+    for word in words {
+        crc.update(&word.to_le_bytes());
+    }
+    crc.finish()
+}
+
+const NODE_VALID: u32 = 1 << 16;
+
+fn node_to_word(node: Option<NodeAddress>) -> u32 {
+    node.map_or(0, |node| {
+        NODE_VALID | (node.group as u32) << 8 | node.node as u32
+    })
+}
+
+fn node_from_word(word: u32) -> Option<NodeAddress> {
+    match word & NODE_VALID {
+        0 => None,
+        _ => Some(NodeAddress::new(word & 0xFF, (word >> 8) & 0xFF)),
+    }
+}
+
+// Passed between the application and the bootloader across a reset, in the backup registers. They
+// survive anything short of a power cycle, after which they read as garbage and `from_words` gives
+// the default.
+pub const HANDOFF_WORDS: usize = 3;
+const HANDOFF_MAGIC: u32 = 0x4842_0000;
+const HANDOFF_STAY: u32 = 1 << 0;
+const HANDOFF_HEALTHY: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Handoff {
+    // From the application: stay in the bootloader rather than booting straight back into it.
+    pub stay: bool,
+    // From the application: it came up and heard from the host, so if it's on trial it can be kept.
+    pub healthy: bool,
+    // From the application: where it was on the bus.
+    pub node: Option<NodeAddress>,
+    // From the bootloader: how many times the pending image has been booted.
+    pub attempts: u32,
+}
+
+impl Handoff {
+    pub fn to_words(&self) -> [u32; HANDOFF_WORDS] {
+        let mut flags = HANDOFF_MAGIC;
+        if self.stay {
+            flags |= HANDOFF_STAY;
+        }
+        if self.healthy {
+            flags |= HANDOFF_HEALTHY;
+        }
+        [flags, node_to_word(self.node), self.attempts]
+    }
+
+    pub fn from_words(words: &[u32; HANDOFF_WORDS]) -> Handoff {
+        if words[0] & 0xFFFF_0000 != HANDOFF_MAGIC {
+            return Handoff::default();
+        }
+        Handoff {
+            stay: words[0] & HANDOFF_STAY != 0,
+            healthy: words[0] & HANDOFF_HEALTHY != 0,
+            node: node_from_word(words[1]),
+            attempts: words[2],
+        }
+    }
+}
+
+// What the bootloader does on the way up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootDecision {
+    // Which slot to jump to, or `None` to stay in the bootloader.
+    pub boot: Option<Slot>,
+    // The record, if it changed and needs saving.
+    pub record: Option<BootRecord>,
+}
+
+// Decides what to boot after a reset, updating `handoff` to pass on to whatever runs next. `valid`
+// checks a slot's image against its CRC.
+pub fn decide(
+    record: &BootRecord,
+    handoff: &mut Handoff,
+    valid: impl Fn(&ImageHeader) -> bool,
+) -> BootDecision {
+    let mut next = *record;
+    if let Some(node) = handoff.node {
+        next.node = Some(node);
+    }
+    let image_ok =
+        |record: &BootRecord, slot: Slot| record.image(slot).is_some_and(|image| valid(&image));
+
+    if let Some(pending) = next.pending {
+        if handoff.healthy {
+            next.active = Some(pending);
+            next.pending = None;
+        } else if handoff.attempts >= MAX_ATTEMPTS || !image_ok(&next, pending) {
+            next.pending = None;
+        }
+    }
+    if next.pending.is_none() {
+        handoff.attempts = 0;
+    }
+    handoff.healthy = false;
+
+    let boot = match core::mem::take(&mut handoff.stay) {
+        true => None,
+        false => match next.pending {
+            Some(pending) => {
+                handoff.attempts += 1;
+                Some(pending)
+            }
+            None => next.active.filter(|active| image_ok(&next, *active)),
+        },
+    };
+    BootDecision {
+        boot,
+        record: Some(next).filter(|next| next != record),
+    }
+}
@@ -0,0 +1,223 @@
+//! Derives for the `protocol` crate's wire format; see `protocol::frame` for what they generate and
+//! `protocol::messages` for how they're used.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Ident, Index, LitInt, Path, Token,
+};
+
+// Lays the fields out back to back, in declaration order. `#[pad(n)]` on a field leaves `n` zero
+// bytes after it, for keeping things word aligned.
+#[proc_macro_derive(Wire, attributes(pad))]
+pub fn derive_wire(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    wire(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// A `Wire` struct that goes out as a frame of its own, e.g. `#[frame(SetPosVel)]`, where the
+// argument is the `MessageID` it's sent with.
+//
+// Decoding checks float fields marked `#[finite]` aren't NaN or infinite, and ones marked
+// `#[range(min, max)]` are within those limits too. Anything else can be checked with a
+// `#[validate(path)]` on the struct, where `path` is a `fn(&Self) -> Result<(), DecodeError>`.
+#[proc_macro_derive(Frame, attributes(frame, pad, finite, range, validate))]
+pub fn derive_frame(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    wire(&input)
+        .and_then(|wire| {
+            let frame = frame(&input)?;
+            Ok(quote! { #wire #frame })
+        })
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn padding(field: &syn::Field) -> syn::Result<TokenStream2> {
+    match field.attrs.iter().find(|attr| attr.path.is_ident("pad")) {
+        None => Ok(quote!(0)),
+        Some(attr) => {
+            let bytes: LitInt = attr.parse_args()?;
+            Ok(quote!(#bytes))
+        }
+    }
+}
+
+fn fields(input: &DeriveInput) -> syn::Result<&Fields> {
+    match &input.data {
+        Data::Struct(data) => Ok(&data.fields),
+        _ => Err(Error::new_spanned(
+            &input.ident,
+            "Only structs can be sent over the wire",
+        )),
+    }
+}
+
+fn member(index: usize, field: &syn::Field) -> TokenStream2 {
+    match &field.ident {
+        Some(ident) => quote!(#ident),
+        None => {
+            let index = Index::from(index);
+            quote!(#index)
+        }
+    }
+}
+
+// Where each field starts, in bytes.
+fn offsets(fields: &Fields) -> syn::Result<Vec<TokenStream2>> {
+    let mut size = quote!(0);
+    let mut offsets = vec![];
+    for field in fields {
+        let ty = &field.ty;
+        offsets.push(size.clone());
+        let pad = padding(field)?;
+        size = quote! { #size + <#ty as ::protocol::frame::Wire>::SIZE + #pad };
+    }
+    Ok(offsets)
+}
+
+fn wire(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fields = fields(input)?;
+
+    let mut size = quote!(0);
+    let mut writes = vec![];
+    let mut reads = vec![];
+    for (index, field) in fields.iter().enumerate() {
+        let ty = &field.ty;
+        let member = member(index, field);
+        let range = quote! { (#size)..(#size + <#ty as ::protocol::frame::Wire>::SIZE) };
+        writes.push(quote! {
+            ::protocol::frame::Wire::write(&self.#member, &mut bytes[#range]);
+        });
+        reads.push(quote! {
+            <#ty as ::protocol::frame::Wire>::read(&bytes[#range])
+        });
+        let pad = padding(field)?;
+        size = quote! { #size + <#ty as ::protocol::frame::Wire>::SIZE + #pad };
+    }
+
+    let construct = match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|field| &field.ident);
+            quote! { #name { #( #idents: #reads, )* } }
+        }
+        Fields::Unnamed(_) => quote! { #name( #( #reads, )* ) },
+        Fields::Unit => quote! { #name },
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::protocol::frame::Wire for #name #ty_generics #where_clause {
+            const SIZE: usize = #size;
+
+            #[allow(unused_variables)]
+            fn write(&self, bytes: &mut [u8]) {
+                #( #writes )*
+            }
+
+            #[allow(unused_variables)]
+            fn read(bytes: &[u8]) -> Self {
+                #construct
+            }
+        }
+    })
+}
+
+// The body of `Frame::validate`.
+fn checks(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = fields(input)?;
+    let mut checks = vec![];
+    for ((index, field), offset) in fields.iter().enumerate().zip(offsets(fields)?) {
+        let member = member(index, field);
+        let name = member.to_string();
+        let finite = field.attrs.iter().any(|attr| attr.path.is_ident("finite"));
+        let range = field.attrs.iter().find(|attr| attr.path.is_ident("range"));
+        if finite || range.is_some() {
+            checks.push(quote! {
+                if !f32::is_finite(self.#member) {
+                    return Err(::protocol::frame::DecodeError::NotFinite {
+                        field: #name,
+                        offset: #offset,
+                    });
+                }
+            });
+        }
+        if let Some(attr) = range {
+            let limits = attr.parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)?;
+            let (min, max) = match (limits.len(), limits.first(), limits.last()) {
+                (2, Some(min), Some(max)) => (min, max),
+                _ => return Err(Error::new_spanned(attr, "Expected #[range(min, max)]")),
+            };
+            checks.push(quote! {
+                if !((#min)..=(#max)).contains(&self.#member) {
+                    return Err(::protocol::frame::DecodeError::OutOfRange {
+                        field: #name,
+                        offset: #offset,
+                    });
+                }
+            });
+        }
+    }
+    if let Some(attr) = input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("validate"))
+    {
+        let validate: Path = attr.parse_args()?;
+        checks.push(quote! { #validate(self)?; });
+    }
+    Ok(quote! {
+        #( #checks )*
+        Ok(())
+    })
+}
+
+fn frame(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let id: Ident = input
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("frame"))
+        .ok_or_else(|| Error::new_spanned(name, "Frames need a #[frame(MessageID)]"))?
+        .parse_args()?;
+    let checks = checks(input)?;
+
+    Ok(quote! {
+        impl ::protocol::frame::Frame for #name {
+            const ID: ::protocol::messages::MessageID = ::protocol::messages::MessageID::#id;
+
+            fn validate(&self) -> Result<(), ::protocol::frame::DecodeError> {
+                #checks
+            }
+        }
+
+        const _: () = assert!(
+            <#name as ::protocol::frame::Wire>::SIZE <= ::protocol::frame::MAX_LEN,
+            "Doesn't fit in a single frame"
+        );
+
+        impl ::protocol::frame::IncomingFdcanFrame for #name {
+            fn unpack(message: ::protocol::frame::FdcanMessage) -> Self {
+                <Self as ::protocol::frame::Frame>::read_payload(&message)
+            }
+        }
+
+        impl ::protocol::frame::OutgoingFdcanFrame for #name {
+            fn pack(&self) -> ::protocol::frame::FdcanMessage {
+                ::protocol::frame::Frame::encode(self)
+            }
+        }
+
+        impl TryFrom<::protocol::frame::FdcanMessage> for #name {
+            type Error = ::protocol::frame::DecodeError;
+
+            fn try_from(message: ::protocol::frame::FdcanMessage) -> Result<Self, Self::Error> {
+                <Self as ::protocol::frame::Frame>::decode(&message)
+            }
+        }
+    })
+}